@@ -29,8 +29,12 @@
 //
 //   import io from std::io           // Standard library (always available)
 //   import math from std::math       // Standard library math
-//   import util from ./util          // Relative path (sibling)
-//   import config from ../config     // Parent directory
+//   import text from str             // The str package's text module (std::text)
+//   import fs from str               // Files: readFile, writeFile, listDir (std::fs)
+//   import env from str              // env.args() and env.get("HOME") (std::env)
+//   import util from "./util.str"    // Relative path (sibling)
+//   import config from "../config"   // Parent directory, .str implied
+//   import shapes from "shapes.str"  // Found in the --module-path directories
 //   import http from myapp::http     // Package: myapp/modules/http.str
 //
 // MODULE PATH RESOLUTION:
@@ -62,8 +66,9 @@
 //
 // MODULE DEFINITION:
 //
-// A module is a .str file that exports a namespace. All top-level bindings
-// (functions, types, constants) are part of the module's public API.
+// A module is a .str file that exports a namespace. Its top-level functions
+// and let/const bindings are part of the module's public API; var bindings
+// stay private, and struct types are shared by the whole program.
 //
 // Example module: myapp/util.str
 //
@@ -107,7 +112,10 @@
 //
 //   std::io         → input/output, print, read, file operations
 //   std::math       → arithmetic functions, sin, cos, sqrt, etc.
-//   std::text       → string operations, split, join, trim
+//   std::text       → string operations, split, join, trim, UTF-8 bytes
+//   std::encode     → base64 and hex encodings of text or bytes
+//   std::hash       → md5, sha256 and crc32 digests as hex strings
+//   std::csv        → RFC 4180 parsing and writing
 //   std::util       → misc utilities (later expansion point)
 //   std::time       → time/date operations (future)
 //
@@ -438,6 +446,21 @@
 // 2. Output: dist/my-app.js
 // 3. Each Strata module → JavaScript module with namespace
 //
+// EMBEDDING (import { Parser, TypeChecker, Interpreter } from "strata-lang"):
+// The compiler stays in this one file, but every stage is exported so
+// tools (LSP, test harnesses, embedders) can drive it without the CLI:
+//   Lexer / Token            → tokens with locations
+//   Parser / Expr / Stmt     → AST
+//   TypeChecker              → check(statements), warnings; checkProgram returns a TypedProgram
+//   Interpreter              → interpret(statements), evaluate(expr, bindings);
+//                              Interpreter.fromTyped(typed) starts with call sites resolved
+//   CGenerator               → generate(statements) returns C source
+//   CompiledExpr             → parse once, evaluate against many bindings
+//   withPrelude              → program statements with the prelude's in front
+//   execute(source)          → output, value, diagnostics and timing of a run, in one report
+//   explainableAt            → expressions on a line, for the explain option
+//   main(args)               → the CLI itself; only runs on import if executed directly
+//
 // ============================================================================
 // 7. DESIGN RATIONALE
 // ============================================================================
@@ -481,21 +504,35 @@
 
 import * as fs from "fs";
 import * as process from "process";
+import * as path from "path";
+import * as readline from "readline";
+import { createHash } from "crypto";
+import { fileURLToPath, pathToFileURL } from "url";
+import * as http from "http";
+import * as os from "os";
+import { spawnSync } from "child_process";
+import { Worker, isMainThread, parentPort, workerData } from "worker_threads";
 
 // ============================================================================
 // TYPE SYSTEM - Support for union types, primitives, interfaces, and optionals
 // ============================================================================
 
-type PrimitiveType = "int" | "float" | "bool" | "char" | "string" | "any" | "i8" | "i16" | "i32" | "i64" | "u8" | "u16" | "u32" | "u64" | "f32" | "f64" | "array" | "list" | "map" | "dict" | "set" | "tuple" | "option" | "result" | "promise" | "void" | "null" | "undefined" | "regex" | "pattern" | "complex" | "matrix" | "dataframe" | "callable" | "lambda" | "closure";
+export type PrimitiveType = "int" | "float" | "bool" | "char" | "string" | "any" | "i8" | "i16" | "i32" | "i64" | "u8" | "u16" | "u32" | "u64" | "f32" | "f64" | "array" | "list" | "map" | "dict" | "set" | "tuple" | "option" | "result" | "promise" | "void" | "null" | "undefined" | "regex" | "pattern" | "complex" | "matrix" | "dataframe" | "callable" | "lambda" | "closure" | "range" | "datetime";
 
-interface TypeDef {
-    kind: "primitive" | "union" | "interface" | "optional" | "generic";
+export interface TypeDef {
+    kind: "primitive" | "union" | "interface" | "optional" | "generic" | "function" | "array" | "map" | "struct" | "enum";
     name?: string;
     primitive?: PrimitiveType;
     types?: TypeDef[];
     fields?: Record<string, TypeDef>;
     innerType?: TypeDef;
     typeParams?: string[];
+    params?: TypeDef[];
+    returnType?: TypeDef;
+    size?: number; // fixed-length arrays: int[3]
+    sizeExpr?: Expr; // int[N] with a constant N, folded into size by the checker
+    keyType?: TypeDef; // map<K, V>
+    valueType?: TypeDef;
 }
 
 const TYPE_REGISTRY: Record<string, TypeDef> = {
@@ -552,6 +589,12 @@ const TYPE_REGISTRY: Record<string, TypeDef> = {
     callable: { kind: "primitive", primitive: "callable" },
     lambda: { kind: "primitive", primitive: "lambda" },
     closure: { kind: "primitive", primitive: "closure" },
+
+    // Ranges (Rust, Kotlin, Swift)
+    range: { kind: "primitive", primitive: "range" },
+
+    // UTC instants (Python, Go, Rust chrono)
+    datetime: { kind: "primitive", primitive: "datetime" },
 };
 
 // ============================================================================
@@ -560,9 +603,15 @@ const TYPE_REGISTRY: Record<string, TypeDef> = {
 // This registry provides language features from: Ruby, Python, JavaScript,
 // TypeScript, Go, Rust, C++, C#, R, and C while maintaining Strata syntax
 
+// A field the value holds itself, not one its prototype lends it
+function ownField(value: any, key: any): any {
+    return value !== null && value !== undefined && Object.hasOwn(Object(value), key) ? value[key] : undefined;
+}
+
 const BUILTIN_FUNCTIONS: Record<string, (args: any[]) => any> = {
     // STRING OPERATIONS (Python, Ruby, JavaScript)
     strlen: (args) => args[0]?.length ?? 0,
+    length: (args) => args[0]?.length ?? args[0]?.size ?? 0,
     substr: (args) => args[0]?.substring(args[1], args[2]) ?? "",
     toUpperCase: (args) => args[0]?.toUpperCase?.() ?? "",
     toLowerCase: (args) => args[0]?.toLowerCase?.() ?? "",
@@ -584,16 +633,17 @@ const BUILTIN_FUNCTIONS: Record<string, (args: any[]) => any> = {
     shift: (args) => args[0]?.shift?.(),
     unshift: (args) => { args[0]?.unshift?.(args[1]); return args[0]; },
     splice: (args) => args[0]?.splice?.(args[1], args[2]) ?? [],
-    map: (args) => args[0]?.map?.(args[1]) ?? [],
-    filter: (args) => args[0]?.filter?.(args[1]) ?? [],
-    reduce: (args) => args[0]?.reduce?.(args[1], args[2]),
-    forEach: (args) => { args[0]?.forEach?.(args[1]); },
+    // Strata functions take only the element, not JavaScript's index and array
+    map: (args) => args[0]?.map?.((x: any) => args[1](x)) ?? [],
+    filter: (args) => args[0]?.filter?.((x: any) => args[1](x)) ?? [],
+    reduce: (args) => args[0]?.reduce?.((acc: any, x: any) => args[1](acc, x), args[2]),
+    forEach: (args) => { args[0]?.forEach?.((x: any) => args[1](x)); },
     find: (args) => args[0]?.find?.(args[1]),
     findIndex: (args) => args[0]?.findIndex?.(args[1]) ?? -1,
     some: (args) => args[0]?.some?.(args[1]) ?? false,
     every: (args) => args[0]?.every?.(args[1]) ?? false,
     reverse: (args) => { args[0]?.reverse?.(); return args[0]; },
-    sort: (args) => { args[0]?.sort?.(args[1]); return args[0]; },
+    sort: (args) => { args[0]?.sort?.(args[1] ?? compareValues); return args[0]; },
     concat: (args) => args[0]?.concat?.(args[1]) ?? [],
     flat: (args) => args[0]?.flat?.(args[1] ?? 1) ?? [],
     flatMap: (args) => args[0]?.flatMap?.(args[1]) ?? [],
@@ -601,14 +651,29 @@ const BUILTIN_FUNCTIONS: Record<string, (args: any[]) => any> = {
     lastIndexOf: (args) => args[0]?.lastIndexOf?.(args[1]) ?? -1,
 
     // DICTIONARY/MAP OPERATIONS (Python, JavaScript, Go, Rust)
-    keys: (args) => Object.keys(args[0] ?? {}) ?? [],
-    values: (args) => Object.values(args[0] ?? {}) ?? [],
-    entries: (args) => Object.entries(args[0] ?? {}) ?? [],
-    has: (args) => (args[0] ?? {})?.[args[1]] !== undefined,
-    delete: (args) => { delete (args[0] ?? {})[args[1]]; return args[0]; },
-    clear: (args) => { for (let k in args[0]) delete args[0][k]; return args[0]; },
-    get: (args) => (args[0] ?? {})[args[1]],
-    set: (args) => { (args[0] ?? {})[args[1]] = args[2]; return args[0]; },
+    keys: (args) => args[0] instanceof Map ? [...args[0].keys()] : Object.keys(args[0] ?? {}),
+    values: (args) => args[0] instanceof Map ? [...args[0].values()] : Object.values(args[0] ?? {}),
+    entries: (args) => args[0] instanceof Map ? [...args[0].entries()] : Object.entries(args[0] ?? {}),
+    // Records only have their own fields, never ones from the host prototype
+    has: (args) => args[0] instanceof Map ? args[0].has(args[1]) : ownField(args[0], args[1]) !== undefined,
+    delete: (args) => {
+        if (args[0] instanceof Map) args[0].delete(args[1]);
+        else if (ownField(args[0], args[1]) !== undefined) delete args[0][args[1]];
+        return args[0];
+    },
+    clear: (args) => {
+        if (args[0] instanceof Map) args[0].clear();
+        else for (let k in args[0]) delete args[0][k];
+        return args[0];
+    },
+    get: (args) => args[0] instanceof Map ? args[0].get(args[1]) ?? null : ownField(args[0], args[1]),
+    set: (args) => {
+        if (args[0] instanceof Map) args[0].set(args[1], args[2]);
+        else if (args[0] !== null && typeof args[0] === "object") {
+            Object.defineProperty(args[0], args[1], { value: args[2], writable: true, enumerable: true, configurable: true });
+        }
+        return args[0];
+    },
 
     // SET OPERATIONS (Python, Go, Rust)
     add: (args) => { args[0]?.add?.(args[1]); return args[0]; },
@@ -655,6 +720,15 @@ const BUILTIN_FUNCTIONS: Record<string, (args: any[]) => any> = {
     parseInt: (args) => parseInt(args[0], args[1] ?? 10),
     parseFloat: (args) => parseFloat(args[0]),
     toString: (args) => String(args[0]),
+    // format("{} + {} = {}", 1, 2, 3); "{{" and "}}" are literal braces
+    format: (args) => {
+        let next = 1;
+        return String(args[0]).replace(/\{\}|\{\{|\}\}/g, (m) => {
+            if (m !== "{}") return m[0];
+            if (next >= args.length) throw new Error(message("runtime.format-argument", { index: next }));
+            return formatValue(args[next++]);
+        });
+    },
     toBoolean: (args) => Boolean(args[0]),
     toNumber: (args) => Number(args[0]),
     isNaN: (args) => isNaN(args[0]),
@@ -670,6 +744,10 @@ const BUILTIN_FUNCTIONS: Record<string, (args: any[]) => any> = {
     catch: (args) => args[0] instanceof Error ? args[1]?.(args[0]) : args[0],
     panic: (args) => { throw new Error(args[0]); },
     defer: (args) => { /* deferred execution placeholder */ return args[0]; },
+    assert: (args) => {
+        if (args[0] === true) return;
+        throw new Error(args[1] === undefined ? message("runtime.assert") : message("runtime.assert-message", { message: args[1] }));
+    },
 
     // FILE OPERATIONS (Python, Go, C, C++)
     readFile: (args) => { try { return fs.readFileSync(args[0], "utf-8"); } catch { return null; } },
@@ -721,23 +799,31 @@ const BUILTIN_FUNCTIONS: Record<string, (args: any[]) => any> = {
     // OPTIONAL/NULL HANDLING (Rust, TypeScript, Go)
     Some: (args) => ({ type: "some", value: args[0] }),
     None: (args) => ({ type: "none" }),
-    unwrap: (args) => args[0]?.type === "some" ? args[0].value : (() => { throw new Error("unwrap of None"); })(),
-    unwrapOr: (args) => args[0]?.type === "some" ? args[0].value : args[1],
+    unwrap: (args) => {
+        if (args[0]?.type === "some" || args[0]?.type === "ok") return args[0].value;
+        if (args[0]?.type === "err") throw new Error(message("runtime.unwrap-err", { error: formatValue(args[0].error) }));
+        throw new Error(message("runtime.unwrap-none"));
+    },
+    unwrapOr: (args) => args[0]?.type === "some" || args[0]?.type === "ok" ? args[0].value : args[1],
     isSome: (args) => args[0]?.type === "some",
-    isNone: (args) => args[0]?.type === "none",
+    isNone: (args) => isEmptyOptional(args[0]),
 
     // RESULT OPERATIONS (Rust, Go)
     Ok: (args) => ({ type: "ok", value: args[0] }),
     Err: (args) => ({ type: "err", error: args[0] }),
     isOk: (args) => args[0]?.type === "ok",
     isErr: (args) => args[0]?.type === "err",
+    unwrapErr: (args) => {
+        if (args[0]?.type === "err") return args[0].error;
+        throw new Error(message("runtime.unwrap-err-of", { value: formatValue(args[0]) }));
+    },
 
     // ITERATOR/GENERATOR OPERATIONS (Python, JavaScript, Go)
     range: (args) => Array.from({ length: args[1] - args[0] }, (_, i) => i + args[0]),
     enumerate: (args) => args[0]?.map?.((v: any, i: number) => [i, v]) ?? [],
     zip: (args) => args[0]?.map?.((v: any, i: number) => [v, args[1]?.[i]]) ?? [],
     reversed: (args) => [...args[0] ?? []].reverse(),
-    sorted: (args) => [...args[0] ?? []].sort(args[1]),
+    sorted: (args) => [...args[0] ?? []].sort(args[1] ?? compareValues),
     iter: (args) => (args[0] ?? [])[Symbol.iterator]?.(),
     next: (args) => args[0]?.next?.(),
 
@@ -803,6 +889,340 @@ const BUILTIN_FUNCTIONS: Record<string, (args: any[]) => any> = {
     unsignedRightShift: (args) => args[0] >>> args[1],
 };
 
+// The builtins a sandboxed interpreter keeps, which only work on the
+// program's own values. Left out are the FILE OPERATIONS, REFLECTION and
+// assign, which reach host objects, promises, iterators and symbols, and
+// try, which would catch the sandbox's own timeout
+const SANDBOX_BUILTINS = new Set([
+    "strlen", "length", "substr", "toUpperCase", "toLowerCase", "trim", "split", "join", "startsWith", "endsWith",
+    "includes", "indexOf", "replace", "replaceAll", "repeat", "slice", "push", "pop", "shift", "unshift", "splice",
+    "map", "filter", "reduce", "forEach", "find", "findIndex", "some", "every", "reverse", "sort", "concat", "flat",
+    "flatMap", "includes_arr", "lastIndexOf", "keys", "values", "entries", "has", "delete", "clear", "get", "set",
+    "add", "remove", "union", "intersection", "difference", "abs", "sqrt", "pow", "sin", "cos", "tan", "asin",
+    "acos", "atan", "exp", "log", "ceil", "floor", "round", "trunc", "max", "min", "gcd", "lcm", "random",
+    "randomInt", "randomFloat", "typeof", "parseInt", "parseFloat", "toString", "format", "toBoolean", "toNumber",
+    "isNaN", "isFinite", "isInteger", "isArray", "isObject", "isNull", "isUndefined", "panic", "assert", "match",
+    "test", "search", "matchAll", "now", "timestamp", "getDate", "getMonth", "getYear", "getHours", "getMinutes",
+    "getSeconds", "tuple", "untuple", "Some", "None", "unwrap", "unwrapOr", "isSome", "isNone", "Ok", "Err", "isOk",
+    "isErr", "unwrapErr", "range", "enumerate", "zip", "reversed", "sorted", "hash", "clone", "deepEqual", "uint",
+    "sint", "byte", "rune", "compose", "pipe", "curry", "partial", "memoize", "bitwiseAnd", "bitwiseOr",
+    "bitwiseXor", "bitwiseNot", "leftShift", "rightShift", "unsignedRightShift",
+]);
+
+const INT_MAX = 2147483647;
+const INT_MIN = -2147483648;
+
+// ============================================================================
+// MODULE CONSTANTS - Folded at check time, so they can appear in const
+// initializers and array sizes: math.PI, int.MAX
+// ============================================================================
+
+interface ModuleConstant {
+    value: any;
+    type: TypeDef;
+    c?: string; // C spelling when the literal value would be wrong or unclear
+    js?: string; // JavaScript spelling, likewise
+}
+
+const STDLIB_CONSTANTS: Record<string, Record<string, ModuleConstant>> = {
+    "std::math": {
+        PI: { value: Math.PI, type: TYPE_REGISTRY.float, js: "Math.PI" },
+        E: { value: Math.E, type: TYPE_REGISTRY.float, js: "Math.E" },
+        TAU: { value: 2 * Math.PI, type: TYPE_REGISTRY.float, js: "(2 * Math.PI)" },
+        SQRT2: { value: Math.SQRT2, type: TYPE_REGISTRY.float, js: "Math.SQRT2" },
+        LN2: { value: Math.LN2, type: TYPE_REGISTRY.float, js: "Math.LN2" },
+        LN10: { value: Math.LN10, type: TYPE_REGISTRY.float, js: "Math.LN10" },
+    },
+    // Type namespaces need no import. int matches the C backend's 32-bit int,
+    // float its double.
+    int: {
+        MAX: { value: INT_MAX, type: TYPE_REGISTRY.int, c: "INT_MAX" },
+        MIN: { value: INT_MIN, type: TYPE_REGISTRY.int, c: "INT_MIN" },
+    },
+    float: {
+        MAX: { value: Number.MAX_VALUE, type: TYPE_REGISTRY.float, c: "DBL_MAX", js: "Number.MAX_VALUE" },
+        MIN: { value: -Number.MAX_VALUE, type: TYPE_REGISTRY.float, c: "(-DBL_MAX)", js: "(-Number.MAX_VALUE)" },
+        EPSILON: { value: Number.EPSILON, type: TYPE_REGISTRY.float, c: "DBL_EPSILON", js: "Number.EPSILON" },
+        INFINITY: { value: Infinity, type: TYPE_REGISTRY.float, c: "INFINITY", js: "Infinity" },
+        NAN: { value: NaN, type: TYPE_REGISTRY.float, c: "NAN", js: "NaN" },
+    },
+};
+
+function fnType(params: TypeDef[], returnType: TypeDef): TypeDef {
+    return { kind: "function", params, returnType };
+}
+
+const charPredicate = fnType([TYPE_REGISTRY.char], TYPE_REGISTRY.bool);
+const charMapping = fnType([TYPE_REGISTRY.char], TYPE_REGISTRY.char);
+const datePart = fnType([TYPE_REGISTRY.datetime], TYPE_REGISTRY.int);
+const dateShift = fnType([TYPE_REGISTRY.datetime, TYPE_REGISTRY.int], TYPE_REGISTRY.datetime);
+
+// UTF-8 bytes as returned by text.bytes
+const bytesType: TypeDef = { kind: "array", innerType: TYPE_REGISTRY.int };
+const textMapping = fnType([TYPE_REGISTRY.string], TYPE_REGISTRY.string);
+const textPredicate = fnType([TYPE_REGISTRY.string, TYPE_REGISTRY.string], TYPE_REGISTRY.bool);
+const checkedIntFn = fnType([TYPE_REGISTRY.int, TYPE_REGISTRY.int], { kind: "optional", innerType: TYPE_REGISTRY.int });
+
+// Signatures of stdlib functions the checker verifies calls against;
+// calls to other stdlib members are left unchecked
+const ioSignatures: Record<string, TypeDef> = {
+    readLine: fnType([], TYPE_REGISTRY.string),
+    nextLine: fnType([], { kind: "optional", innerType: TYPE_REGISTRY.string }),
+    readInt: fnType([], TYPE_REGISTRY.int),
+    readFloat: fnType([], TYPE_REGISTRY.float),
+};
+
+const STDLIB_SIGNATURES: Record<string, Record<string, TypeDef>> = {
+    "std::io": ioSignatures,
+    str: ioSignatures,
+    "std::math": {
+        checkedAdd: checkedIntFn,
+        checkedMul: checkedIntFn,
+    },
+    "std::text": {
+        length: fnType([TYPE_REGISTRY.string], TYPE_REGISTRY.int),
+        upper: textMapping,
+        lower: textMapping,
+        toUpperCase: textMapping,
+        toLowerCase: textMapping,
+        trim: textMapping,
+        substring: fnType([TYPE_REGISTRY.string, TYPE_REGISTRY.int, TYPE_REGISTRY.int], TYPE_REGISTRY.string),
+        indexOf: fnType([TYPE_REGISTRY.string, TYPE_REGISTRY.string], TYPE_REGISTRY.int),
+        split: fnType([TYPE_REGISTRY.string, TYPE_REGISTRY.string], { kind: "array", innerType: TYPE_REGISTRY.string }),
+        replace: fnType([TYPE_REGISTRY.string, TYPE_REGISTRY.string, TYPE_REGISTRY.string], TYPE_REGISTRY.string),
+        contains: textPredicate,
+        includes: textPredicate,
+        compare: fnType([TYPE_REGISTRY.string, TYPE_REGISTRY.string], TYPE_REGISTRY.int),
+        compareIgnoreCase: fnType([TYPE_REGISTRY.string, TYPE_REGISTRY.string], TYPE_REGISTRY.int),
+        byteLength: fnType([TYPE_REGISTRY.string], TYPE_REGISTRY.int),
+        chars: fnType([TYPE_REGISTRY.string], { kind: "array", innerType: TYPE_REGISTRY.char }),
+        bytes: fnType([TYPE_REGISTRY.string], bytesType),
+        fromBytes: fnType([bytesType], TYPE_REGISTRY.string),
+    },
+    "std::env": {
+        args: fnType([], { kind: "array", innerType: TYPE_REGISTRY.string }),
+        get: fnType([TYPE_REGISTRY.string], { kind: "optional", innerType: TYPE_REGISTRY.string }),
+    },
+    "std::fs": {
+        readFile: fnType([TYPE_REGISTRY.string], TYPE_REGISTRY.result),
+        writeFile: fnType([TYPE_REGISTRY.string, TYPE_REGISTRY.string], TYPE_REGISTRY.result),
+        appendFile: fnType([TYPE_REGISTRY.string, TYPE_REGISTRY.string], TYPE_REGISTRY.result),
+        exists: fnType([TYPE_REGISTRY.string], TYPE_REGISTRY.bool),
+        listDir: fnType([TYPE_REGISTRY.string], TYPE_REGISTRY.result),
+        open: fnType([TYPE_REGISTRY.string], TYPE_REGISTRY.result),
+        create: fnType([TYPE_REGISTRY.string], TYPE_REGISTRY.result),
+    },
+    "std::encode": {
+        base64: fnType([TYPE_REGISTRY.any], TYPE_REGISTRY.string),
+        fromBase64: fnType([TYPE_REGISTRY.string], bytesType),
+        hex: fnType([TYPE_REGISTRY.any], TYPE_REGISTRY.string),
+        fromHex: fnType([TYPE_REGISTRY.string], bytesType),
+    },
+    "std::hash": {
+        md5: fnType([TYPE_REGISTRY.any], TYPE_REGISTRY.string),
+        sha256: fnType([TYPE_REGISTRY.any], TYPE_REGISTRY.string),
+        crc32: fnType([TYPE_REGISTRY.any], TYPE_REGISTRY.string),
+    },
+    "std::csv": {
+        parse: fnType([TYPE_REGISTRY.string], { kind: "array", innerType: { kind: "array", innerType: TYPE_REGISTRY.string } }),
+        parseRecords: fnType([TYPE_REGISTRY.string], {
+            kind: "array",
+            innerType: { kind: "map", keyType: TYPE_REGISTRY.string, valueType: TYPE_REGISTRY.string },
+        }),
+        stringify: fnType([TYPE_REGISTRY.any], TYPE_REGISTRY.string),
+    },
+    datetime: {
+        now: fnType([], TYPE_REGISTRY.datetime),
+        date: fnType([TYPE_REGISTRY.int, TYPE_REGISTRY.int, TYPE_REGISTRY.int], TYPE_REGISTRY.datetime),
+        of: fnType(Array(6).fill(TYPE_REGISTRY.int), TYPE_REGISTRY.datetime),
+        fromTimestamp: fnType([TYPE_REGISTRY.int], TYPE_REGISTRY.datetime),
+        parse: fnType([TYPE_REGISTRY.string, TYPE_REGISTRY.string], TYPE_REGISTRY.datetime),
+        format: fnType([TYPE_REGISTRY.datetime, TYPE_REGISTRY.string], TYPE_REGISTRY.string),
+        addDays: dateShift,
+        addHours: dateShift,
+        addMinutes: dateShift,
+        addSeconds: dateShift,
+        year: datePart,
+        month: datePart,
+        day: datePart,
+        hour: datePart,
+        minute: datePart,
+        second: datePart,
+        weekday: datePart,
+        dayOfYear: datePart,
+        timestamp: datePart,
+        secondsBetween: fnType([TYPE_REGISTRY.datetime, TYPE_REGISTRY.datetime], TYPE_REGISTRY.int),
+    },
+    char: {
+        isDigit: charPredicate,
+        isAlpha: charPredicate,
+        isAlphanumeric: charPredicate,
+        isSpace: charPredicate,
+        isUpper: charPredicate,
+        isLower: charPredicate,
+        isAsciiDigit: charPredicate,
+        isAsciiAlpha: charPredicate,
+        isAsciiAlphanumeric: charPredicate,
+        isAsciiSpace: charPredicate,
+        toUpper: charMapping,
+        toLower: charMapping,
+        toAsciiUpper: charMapping,
+        toAsciiLower: charMapping,
+        code: fnType([TYPE_REGISTRY.char], TYPE_REGISTRY.int),
+        fromCode: fnType([TYPE_REGISTRY.int], TYPE_REGISTRY.char),
+    },
+};
+
+// Stdlib functions that were renamed, by module and old name. The old names
+// keep working; the checker warns W0005 where they are called and
+// strata fix --migrate rewrites the calls (migrateSource)
+interface Deprecation {
+    replacement: string;
+    since: string; // the Strata version that renamed it
+    note?: string;
+}
+
+const STDLIB_DEPRECATIONS: Record<string, Record<string, Deprecation>> = {
+    "std::text": {
+        toUpperCase: { replacement: "upper", since: "1.0.0" },
+        toLowerCase: { replacement: "lower", since: "1.0.0" },
+        includes: { replacement: "contains", since: "1.0.0", note: "includes(s, part) is the same test as part in s" },
+    },
+};
+
+function isVoidOrAny(type: TypeDef): boolean {
+    return type.kind === "primitive" && (type.primitive === "void" || type.primitive === "any");
+}
+
+// What a block yields as a value: its last statement, when that is an expression
+function blockValue(body: Stmt[]): Expr | undefined {
+    const last = body[body.length - 1];
+    return last?.kind === "expression" ? last.expr : undefined;
+}
+
+// Whether running a block can return, or break or continue past its end;
+// loops inside it keep their own breaks and continues
+function leavesBlock(body: Stmt[], inLoop = false): boolean {
+    return body.some((stmt) => {
+        switch (stmt.kind) {
+            case "return":
+                return true;
+            case "break":
+            case "continue":
+                return !inLoop;
+            case "while":
+            case "for":
+            case "forIn":
+                return leavesBlock(stmt.body, true);
+            case "function":
+            case "test":
+                return false;
+            case "expression":
+                if (stmt.expr.kind === "block" || stmt.expr.kind === "loop") {
+                    return leavesBlock(stmt.expr.body, inLoop || stmt.expr.kind === "loop");
+                }
+                return false;
+            default:
+                return leavesBlock(childStatements(stmt), inLoop);
+        }
+    });
+}
+
+// File handles opened by calls that pass isOpen and not provably closed:
+// that takes a let of the handle followed in the same block by
+// defer name.close(), which is what with (f = fs.open(path)) becomes
+function unclosedFiles(statements: Stmt[], isOpen: (call: Extract<Expr, { kind: "call" }>) => boolean): Extract<Expr, { kind: "call" }>[] {
+    const opened: Extract<Expr, { kind: "call" }>[] = [];
+    const visitExpr = (expr: Expr): void => {
+        if (expr.kind === "call") {
+            if (isOpen(expr)) opened.push(expr);
+            visitExpr(expr.func);
+        }
+        if (expr.kind === "member") visitExpr(expr.object);
+        if (expr.kind === "match") expr.arms.forEach((arm) => visit(arm.body));
+        if (expr.kind === "if") visit([...expr.then, ...expr.else]);
+        if (expr.kind === "block" || expr.kind === "loop") visit(expr.body);
+        if (expr.kind === "lambda") visit(expr.fn.body);
+        exprOperands(expr).forEach(visitExpr);
+    };
+    const closes = (stmt: Stmt, name: string): boolean => {
+        if (stmt.kind !== "defer" || stmt.body.kind !== "expression") return false;
+        const call = stmt.body.expr;
+        return call.kind === "call" && call.func.kind === "member" && call.func.property === "close" &&
+            call.func.object.kind === "identifier" && call.func.object.name === name;
+    };
+    const visit = (body: Stmt[]): void => {
+        body.forEach((stmt, i) => {
+            const before = opened.length;
+            statementExprs(stmt).forEach(visitExpr);
+            if (stmt.kind === "let" && body.slice(i + 1).some((s) => closes(s, stmt.name))) opened.length = before;
+            visit(childStatements(stmt));
+        });
+    };
+    visit(statements);
+    return opened;
+}
+
+// Whether a block returns on every path: a return, an if/else whose
+// branches both return, a match with a catch-all arm or enum variant arms
+// (which must cover the enum) where every arm returns, or a { ... } block (a with block too) that returns. With
+// loopExits a break or continue leaves the block too
+function alwaysReturns(body: Stmt[], loopExits = false): boolean {
+    return body.some((stmt) => {
+        switch (stmt.kind) {
+            case "return":
+                return true;
+            case "break":
+            case "continue":
+                return loopExits;
+            case "if":
+                return stmt.else !== undefined && alwaysReturns(stmt.then, loopExits) && alwaysReturns(stmt.else, loopExits);
+            case "match": {
+                // A wildcard or binding arm, or both bools, covers every value
+                const literals = stmt.arms.flatMap((a) => (a.pattern.kind === "literal" ? [a.pattern.value] : []));
+                const exhaustive = literals.length < stmt.arms.length || (literals.includes(true) && literals.includes(false));
+                return exhaustive && stmt.arms.every((a) => alwaysReturns(a.body, loopExits));
+            }
+            case "while":
+                // while (true) without a break of its own is only left by returning
+                return stmt.binding === undefined && stmt.condition.kind === "literal" && stmt.condition.value === true && !breaksOut(stmt.body);
+            case "expression":
+                return stmt.expr.kind === "block" && alwaysReturns(stmt.expr.body, loopExits);
+            default:
+                return false;
+        }
+    });
+}
+
+// Whether body breaks out of the loop it belongs to, rather than out of a
+// loop nested in it
+function breaksOut(body: Stmt[]): boolean {
+    const inExpr = (expr: Expr): boolean => {
+        if (expr.kind === "if" && breaksOut([...expr.then, ...expr.else])) return true;
+        if (expr.kind === "block" && breaksOut(expr.body)) return true;
+        if (expr.kind === "match" && breaksOut(expr.arms.flatMap((arm) => arm.body))) return true;
+        return exprOperands(expr).some(inExpr);
+    };
+    return body.some((stmt) => {
+        if (stmt.kind === "break") return true;
+        if (stmt.kind === "while" || stmt.kind === "for" || stmt.kind === "forIn" || stmt.kind === "function") return false;
+        return statementExprs(stmt).some(inExpr) || breaksOut(childStatements(stmt));
+    });
+}
+
+// Overflow-checked int arithmetic: Some(result), or None outside int's range
+function checkedIntOp(result: number): any {
+    return Number.isInteger(result) && result >= INT_MIN && result <= INT_MAX
+        ? { type: "some", value: result }
+        : { type: "none" };
+}
+
+function constantValues(module: string): Record<string, any> {
+    return Object.fromEntries(
+        Object.entries(STDLIB_CONSTANTS[module] ?? {}).map(([name, c]) => [name, c.value])
+    );
+}
+
 function parseTypeAnnotation(token: string): TypeDef | null {
     if (token in TYPE_REGISTRY) return TYPE_REGISTRY[token];
     if (token.endsWith("?"))
@@ -816,9 +1236,90 @@ function parseTypeAnnotation(token: string): TypeDef | null {
     return { kind: "primitive", primitive: "any" };
 }
 
+function formatType(type: TypeDef): string {
+    switch (type.kind) {
+        case "primitive":
+            return type.primitive ?? "any";
+        case "function":
+            return `(${(type.params ?? []).map(formatType).join(", ")}) => ${formatType(type.returnType!)}`;
+        case "optional":
+            return `${formatSuffixed(type.innerType!)}?`;
+        case "array":
+            return `${formatSuffixed(type.innerType!)}[${type.size ?? ""}]`;
+        case "map":
+            return `map<${formatType(type.keyType!)}, ${formatType(type.valueType!)}>`;
+        case "union":
+            return (type.types ?? []).map(formatType).join(" | ");
+        default:
+            return type.name ?? type.kind;
+    }
+}
+
+// A union of the given types, with nested unions flattened and repeats dropped
+function unionOf(types: TypeDef[]): TypeDef {
+    const members: TypeDef[] = [];
+    for (const type of types.flatMap((t) => (t.kind === "union" ? t.types ?? [] : [t]))) {
+        if (!members.some((m) => formatType(m) === formatType(type))) members.push(type);
+    }
+    return members.length === 1 ? members[0] : { kind: "union", types: members };
+}
+
+// A type followed by ? or [], in parentheses if it is a union
+function formatSuffixed(type: TypeDef): string {
+    return type.kind === "union" ? `(${formatType(type)})` : formatType(type);
+}
+
 function typeCompatible(actual: TypeDef, expected: TypeDef): boolean {
     if (expected.primitive === "any" || actual.primitive === "any")
         return true;
+    // A union value fits only where each of its members does; a value fits
+    // a union when it fits one of the members
+    if (actual.kind === "union") {
+        return (actual.types ?? []).every((t) => typeCompatible(t, expected));
+    }
+    if (expected.kind === "union") {
+        return (expected.types ?? []).some((e) => typeCompatible(actual, e));
+    }
+    if (actual.kind === "function" && expected.kind === "function") {
+        const actualParams = actual.params ?? [];
+        const expectedParams = expected.params ?? [];
+        return (
+            actualParams.length === expectedParams.length &&
+            expectedParams.every((p, i) => typeCompatible(p, actualParams[i])) &&
+            typeCompatible(actual.returnType!, expected.returnType!)
+        );
+    }
+    if (actual.kind === "array" && expected.kind === "array") {
+        if (expected.size !== undefined && actual.size !== expected.size) return false;
+        return typeCompatible(actual.innerType!, expected.innerType!);
+    }
+    if (actual.kind === "optional" && expected.kind === "optional") {
+        return typeCompatible(actual.innerType!, expected.innerType!);
+    }
+    if (actual.primitive === "null" && expected.kind === "optional") return true;
+    if ((actual.kind === "struct" && expected.kind === "struct") || (actual.kind === "enum" && expected.kind === "enum")) {
+        return actual.name === expected.name;
+    }
+    if (actual.kind === "map" && expected.kind === "map") {
+        return typeCompatible(actual.keyType!, expected.keyType!) &&
+            typeCompatible(actual.valueType!, expected.valueType!);
+    }
+    // Typed arrays and maps are interchangeable with their untyped primitives
+    const untypedArray = (t: TypeDef) => t.primitive === "array" || t.primitive === "list";
+    const untypedMap = (t: TypeDef) => t.primitive === "map" || t.primitive === "dict";
+    if (
+        (actual.kind === "array" && untypedArray(expected)) ||
+        (expected.kind === "array" && untypedArray(actual)) ||
+        (actual.kind === "map" && untypedMap(expected)) ||
+        (expected.kind === "map" && untypedMap(actual))
+    )
+        return true;
+    // Any function value satisfies the untyped callable primitives
+    if (
+        actual.kind === "function" &&
+        ["callable", "lambda", "closure"].includes(expected.primitive ?? "")
+    )
+        return true;
     if (actual.kind === "primitive" && expected.kind === "primitive") {
         if (actual.primitive === expected.primitive) return true;
         // Allow numeric conversions: int → float
@@ -835,15 +1336,6 @@ function typeCompatible(actual: TypeDef, expected: TypeDef): boolean {
             return true;
         return false;
     }
-    if (actual.kind === "union" && expected.kind === "union") {
-        return (
-            (actual.types
-                ?.every(
-                    (t) =>
-                        expected.types?.some((e) => typeCompatible(t, e))
-                ) ?? false)
-        );
-    }
     return false;
 }
 
@@ -851,19 +1343,477 @@ function typeCompatible(actual: TypeDef, expected: TypeDef): boolean {
 // LOCATION TRACKING - For better error messages
 // ============================================================================
 
-interface Location {
+export interface Location {
     line: number;
     column: number;
     source: string;
+    offset: number; // index of the first character in the input
+    end: number; // index just past the token
+}
+
+// Where an AST node sits in the source: input indices (UTF-16 code units,
+// as LSP positions count them) plus the line and column of its start
+export interface SourceRange {
+    start: number;
+    end: number;
+    line: number;
+    column: number;
+}
+
+// ============================================================================
+// DIAGNOSTICS - Errors and warnings with a code, severity and source span
+// ============================================================================
+//
+// Codes identify the phase that reported them:
+//   E0001 lexical error      E0003 type error
+//   E0002 syntax error       E0004 runtime error
+//   E0005 cancelled run      E0006 internal compiler error
+//   E0007 unresolved import  E0008 unsupported edition
+//   W0001 unreachable match arm
+//   W0002 non-exhaustive match
+//   W0003 unused variable
+//   W0004 unclosed file
+//   W0005 deprecated stdlib function
+
+export type Severity = "error" | "warning";
+
+// column is absent when only the line is known; the whole line is underlined
+export interface Span {
+    line: number;
+    column?: number;
+    length?: number;
+}
+
+export interface Diagnostic {
+    code: string;
+    severity: Severity;
+    message: string;
+    span?: Span;
+    notes: string[];
+    // The imported file the span points into; absent for the file being compiled
+    file?: string;
+}
+
+export class StrataError extends Error {
+    diagnostic: Diagnostic;
+    // All diagnostics when several are reported at once, starting with diagnostic
+    diagnostics: Diagnostic[];
+
+    constructor(diagnostic: Diagnostic, diagnostics: Diagnostic[] = [diagnostic]) {
+        super(diagnostic.message);
+        this.diagnostic = diagnostic;
+        this.diagnostics = diagnostics;
+    }
+}
+
+// Message catalog: the English template of every compiler and runtime
+// message, addressed by id. {name} placeholders are filled from the params
+// passed to message(). setMessages installs a translation; ids it leaves
+// out fall back to English. Messages thrown by std:: library functions
+// are not in the catalog.
+export const MESSAGES = {
+    "diagnostic.error": "error",
+    "diagnostic.warning": "warning",
+    "diagnostic.note": "note",
+    "diagnostic.aborting": "aborting due to {count} errors",
+    "diagnostic.internal": "internal compiler error in the {phase}: {detail}",
+    "diagnostic.internal-frame": "raised {frame}",
+    "diagnostic.internal-report": "this is a bug in Strata {version}, not in your program; please file an issue at {url} with the smallest program that still triggers it; strata minimize <file> finds one",
+    "location.line": " at line {line}",
+
+    "lexer.unterminated-interpolation": "Unterminated interpolation at line {line}, column {column}",
+    "lexer.empty-interpolation": "Empty interpolation at line {line}, column {column}",
+    "lexer.unterminated-char": "Unterminated character literal at line {line}, column {column}",
+    "lexer.char-note": 'a char literal holds exactly one character; use "..." for strings',
+    "lexer.invalid-escape": "Invalid escape sequence {sequence} at line {line}, column {column}",
+    "lexer.escape-note": 'known escapes are \\n, \\t, \\r, \\0, \\\\, \\", \\\', \\$ and \\u{...}; write \\\\ for a backslash',
+    "lexer.invalid-number": "Invalid number literal {text} at line {line}, column {column}",
+    "lexer.number-note": "digits may be separated by single underscores; 0x, 0o and 0b take hexadecimal, octal and binary digits",
+    "lexer.int-range": "Int literal {text} is out of range at line {line}, column {column}",
+    "lexer.int-range-note": "an int holds -2147483648 to 2147483647; a fraction or exponent, as in 3e9, makes a float",
+
+    "parser.expected": "Expected {token} at line {line}",
+    "parser.expected-at-end": "Expected {token} at end of input",
+    "parser.property-name": "Expected property name after .",
+    "parser.unexpected-end": "Unexpected end of input",
+    "parser.unexpected-token": "Unexpected token: {token}",
+    "parser.pipe-target": "Right side of |> must be a function or call at line {line}",
+    "parser.match-pattern": "Invalid match pattern: {token} at line {line}",
+    "parser.in-interpolation": "{message} in interpolation at line {line}",
+    "parser.module-top-level": "A module block can only be declared at the top level of a file",
+    "parser.test-top-level": "A test block can only be declared at the top level of a file",
+
+    "type.mismatch": "Type mismatch: expected {expected}, got {actual}",
+    "type.optional-operand": "Operator {op} cannot be applied to the optional {type}; unwrap it first, e.g. with ??",
+    "type.coalesce": "?? needs an optional on its left, got {type}",
+    "type.null-note": "null only fits an optional type; declare it as {type}? to allow null",
+    "type.operator": "Operator {op} cannot be applied to {left} and {right}",
+    "type.arity": "Function {name} expects {expected} arguments, got {actual}",
+    "type.argument": "Argument {index} of {name}: expected {expected}, got {actual}",
+    "type.return-mismatch": "Return type mismatch in {name}: expected {expected}, got {actual}",
+    "type.propagate-operand": "? needs a result or an optional, not {type}",
+    "type.propagate-outside": "? can only be used inside a function, which it returns the failure from",
+    "type.propagate-err": "? returns an Err from {name}, which returns {type}, not result",
+    "type.propagate-none": "? returns None from {name}, which returns {type}, not an optional",
+    "type.void-return": "Function {name} returns void, got a return value of type {type}",
+    "type.empty-return": "Function {name} must return {type}, got an empty return",
+    "type.missing-return": "Function {name} must return {type} on every path",
+    "type.pub-const": "pub const {name} must be initialized with a compile-time constant",
+    "type.array-size": "Array size must be a non-negative integer constant",
+    "type.unknown-struct": "Unknown struct: {name}",
+    "type.unknown-field": "Unknown field: {field} on {type}",
+    "type.duplicate-struct-field": "Duplicate field: {field} in struct {struct}",
+    "type.duplicate-field": "Duplicate field: {field} in {struct}",
+    "type.duplicate-variant": "Duplicate variant: {variant} in enum {enum}",
+    "type.unknown-enum": "Unknown enum: {name}",
+    "type.unknown-variant": "Unknown variant: {variant} of enum {enum}",
+    "type.variant-payload": "{enum}.{variant} carries a payload: construct it with {enum}.{variant}(...)",
+    "type.variant-call": "{enum}.{variant} has no payload: write it without parentheses",
+    "type.variant-bindings": "Pattern {enum}.{variant} binds {actual} values, but the variant carries {expected}{where}",
+    "type.field-mismatch": "Field {field} of {struct}: expected {expected}, got {actual}",
+    "type.missing-field": "Missing field {fields} in {struct}",
+    "type.missing-fields": "Missing fields {fields} in {struct}",
+    "type.membership": "Cannot test membership in {type}",
+    "type.membership-item": "Cannot test membership of {item} in {container}",
+    "type.pattern": "Match pattern {pattern} of type {type} cannot match {subject}{where}",
+    "type.unreachable-arm": "Unreachable match arm{where}",
+    "type.non-exhaustive": "Non-exhaustive match{where}: add a _ arm to handle every value",
+    "type.missing-variants": "Match on {enum} does not cover {variants}{where}: add arms for them or a _ arm",
+    "type.if-branches": "Branches of if yield different types: {then} and {else}{where}",
+    "type.undefined-variable": "Undefined variable {name}{where}",
+    "type.before-definition": "Variable {name} is used before its definition{where}",
+    "type.defer-exit": "A deferred statement cannot return, break or continue{where}",
+    "type.unclosed-file": "File opened{where} may never be closed; use with (f = fs.open(path)) { ... } or defer f.close()",
+    "type.deprecated": "{name} is deprecated since Strata {since}{where}; call {replacement} instead",
+    "type.deprecated-fix": "strata fix --migrate rewrites these calls",
+    "type.guard-exit": "The else block of guard must return on every path{where}",
+    "type.guard-exit-loop": "The else block of guard must return, break or continue on every path{where}",
+    "type.break-value": "break with a value outside a loop expression{where}",
+    "type.loop-control": "{keyword} outside a loop{where}",
+    "type.declared-at": "{name} is declared at line {line}",
+    "type.var-note": "declare {name} with var instead of let to allow reassigning it",
+    "type.while-let": "while (let {name} = ...) needs an optional value, got {type}{where}",
+    "type.unused-variable": "Unused variable {name}{where}; prefix it with _ if that is intended",
+    "type.format-template": "Function format expects a template string",
+    "type.bind-function": "func.bind expects a function, got {type}",
+    "type.bind-arity": "func.bind: {type} takes {expected} arguments, got {actual}",
+    "type.bind-argument": "func.bind: argument {index} expected {expected}, got {actual}",
+    "type.compose-functions": "func.compose expects two functions",
+    "type.compose-mismatch": "func.compose: cannot pass the {type} result of {from} to {to}",
+
+    "module.not-found": "Cannot find module {module}",
+    "module.searched": "looked for {paths}",
+    "module.search-path": "paths that don't start with ./ or ../ are looked up in the --module-path directories and the Strataumfile modulePath entries",
+    "module.cycle": "Circular import: {chain}",
+    "module.no-export": "Module {module} has no export {name}",
+    "module.export-note": "a module exports its top-level functions and let and const bindings; var bindings stay private",
+    "module.namespace-conflict": "{name} is declared both as a module block and as a top-level name",
+    "module.program-name": "{name} would refer to a declaration of the importing program",
+    "module.scope-note": "a module sees only its own declarations, its imports, the prelude and the builtins; rename one of the two",
+
+    "edition.newer": "This file requires a newer compiler: it is written for edition {edition}, and Strata {version} reads editions up to {latest}",
+    "edition.unknown": "Unknown edition {edition}",
+    "edition.invalid": "Invalid edition {edition}: expected a version such as {latest}",
+    "edition.list-note": "strata --list-editions shows the editions this compiler reads",
+
+    "ast.not-interchange": "Not a Strata AST file: expected an object whose format is strata-ast",
+    "ast.newer": "This AST file has version {version}, and Strata {strata} reads AST versions up to {supported}",
+    "ast.bad-version": "Malformed AST: version must be a positive integer",
+    "ast.bad-program": "Malformed AST: program must be an array of statements",
+    "ast.bad-statement": "Malformed AST: statement {index} has no kind",
+
+    "runtime.undefined-variable": "Undefined variable: {name}",
+    "runtime.immutable": "Cannot reassign immutable variable: {name}",
+    "runtime.operator": "Operator {op} cannot be applied to {left} and {right}",
+    "runtime.arity": "Function {name} expects {expected} arguments, got {actual}",
+    "runtime.division-by-zero": "Division by zero",
+    "runtime.remainder-by-zero": "Remainder by zero",
+    "runtime.integer-overflow": "Integer overflow: {left} {op} {right} does not fit in an int",
+    "runtime.compare": "Cannot compare {left} and {right}",
+    "runtime.range-step": "Range step cannot be zero",
+    "runtime.iterate": "Cannot iterate over {value}",
+    "runtime.membership": "Cannot test membership in {value}",
+    "runtime.key-not-found": "Key {key} not found in map",
+    "runtime.index-bounds": "Index {index} out of bounds for length {length}",
+    "runtime.unknown-field": "Unknown field: {field}",
+    "runtime.index-assign": "Cannot assign to an index of {value}",
+    "runtime.module-not-found": "Module not found: {module}",
+    "runtime.no-match-arm": "No match arm for {value}{where}",
+    "runtime.not-a-function": "Not a function",
+    "runtime.not-a-method": "Not a function: {name}",
+    "runtime.unwrap-none": "unwrap of None",
+    "runtime.unwrap-err": "unwrap of Err: {error}",
+    "runtime.unwrap-err-of": "unwrapErr of {value}",
+    "runtime.propagate-outside": "? can only be used inside a function, which it returns the failure from",
+    "runtime.fs-failed": "{fn} {path}: {reason}",
+    "runtime.file-closed": "{fn} on {path} after it was closed",
+    "runtime.end-of-input": "{fn}: no more input",
+    "runtime.read-int": "io.readInt: expected an integer, got {text}",
+    "runtime.read-float": "io.readFloat: expected a number, got {text}",
+    "runtime.format-argument": "format: no argument for placeholder {index}",
+    "runtime.column-length": "Column length mismatch: {name} has {actual} rows, expected {expected}",
+    "runtime.host-stack": "Stack overflow{where}: {name} exhausted the host stack at call depth {depth}",
+    "runtime.call-depth": "Stack overflow{where}: {name} exceeded the call depth limit of {limit}",
+    "runtime.cancelled": "Execution cancelled",
+    "runtime.assert": "Assertion failed",
+    "runtime.assert-message": "Assertion failed: {message}",
+    "runtime.sandboxed": "{fn} is not available in the sandbox",
+    "runtime.bytes-expected": "{fn} expects bytes (int[] with values 0..255), got {value}",
+    "runtime.invalid-utf8": "{fn}: bytes are not valid UTF-8",
+    "runtime.invalid-base64": "encode.fromBase64: invalid base64 input",
+    "runtime.invalid-hex": "encode.fromHex: invalid hex input",
+    "runtime.csv-unterminated": "csv.parse: unterminated quoted field starting on line {line}",
+    "runtime.csv-after-quote": "csv.parse: unexpected text after quoted field on line {line}",
+    "runtime.csv-stray-quote": "csv.parse: quote inside unquoted field on line {line}",
+    "runtime.csv-record-width": "csv.parseRecords: the record on line {line} has {actual} fields, the header has {expected}",
+    "runtime.csv-rows": "csv.stringify expects an array of rows, got {value}",
+    "runtime.csv-row": "csv.stringify: row {row} is not an array",
+    "runtime.csv-record": "csv.stringify: row {row} is not a map, as the first row is",
+    "runtime.datetime-expected": "datetime.{fn} expects a datetime, got {value}",
+    "runtime.invalid-datetime": "Invalid datetime: {datetime}",
+    "runtime.datetime-range": "Datetime out of range: datetimes reach only about 275,000 years either side of 1970",
+    "runtime.datetime-directive": "datetime.{fn}: unknown directive %{directive}",
+    "runtime.datetime-mismatch": "datetime.parse: \"{text}\" does not match \"{format}\"",
+    "runtime.char-expected": "char.{fn} expects a char, got {value}",
+    "runtime.code-point": "char.fromCode: {code} is not a valid code point",
+    "runtime.empty-array": "{fn} expects a non-empty array, got {value}",
+    "serve.time-limit": "The program ran past its {ms} ms time limit and was stopped",
+    "serve.memory-limit": "The program ran out of memory (limit {mb} MB)",
+    "serve.worker-failed": "The evaluation worker failed: {reason}",
+    "runtime.frame": "in {name}",
+    "runtime.frame-called": "in {name}, called at line {line}",
+    "runtime.more-frames": "... {count} more calls",
+    "runtime.frame-at": "at {name} ({location})",
+
+    "repl.type-expression": ":type expects a single expression",
+
+    "minimize.no-failure": "The program runs without an error, so there is nothing to minimize",
+
+    "wasm.unsupported": "The wasm target does not support {what}",
+    "js.unsupported": "The js target does not support {what}",
+    "c.unsupported": "The c target does not support {what}",
+    "vm.unsupported": "The vm backend does not support {what}",
+};
+
+export type MessageId = keyof typeof MESSAGES;
+
+let translation: Partial<Record<MessageId, string>> = {};
+
+// Installs a translation for later messages; {} restores English. Returns
+// the ids it doesn't know, which are ignored.
+export function setMessages(messages: Record<string, string>): string[] {
+    translation = {};
+    const unknown: string[] = [];
+    for (const [id, template] of Object.entries(messages)) {
+        if (id in MESSAGES) translation[id as MessageId] = template;
+        else unknown.push(id);
+    }
+    return unknown;
+}
+
+export function message(id: MessageId, params: Record<string, any> = {}): string {
+    const template = translation[id] ?? MESSAGES[id];
+    return template.replace(/\{(\w+)\}/g, (placeholder, name) => (name in params ? String(params[name]) : placeholder));
+}
+
+// " at line N", or nothing without a line
+function atLine(line?: number): string {
+    return line !== undefined ? message("location.line", { line }) : "";
+}
+
+export function errorAt(code: string, message: string, span?: Span, notes: string[] = []): StrataError {
+    return new StrataError({ code, severity: "error", message, span, notes });
+}
+
+// Int literals past 32 bits, in either the lexer or, for 2147483648
+// without a minus, the parser
+function intRangeError(text: string, location: { line: number; column: number }): StrataError {
+    return errorAt(
+        "E0001",
+        message("lexer.int-range", { text, line: location.line, column: location.column }),
+        { line: location.line, column: location.column, length: text.length },
+        [message("lexer.int-range-note")]
+    );
+}
+
+// Plain errors from builtins and helpers take the code and span of the
+// phase that caught them; StrataErrors already carry their own
+function asStrataError(error: unknown, code: string, span?: Span, notes: string[] = []): StrataError {
+    if (error instanceof StrataError) return error;
+    return errorAt(code, error instanceof Error ? error.message : String(error), span, notes);
+}
+
+const ISSUES_URL = "https://github.com/VSS-CO/Strata/issues";
+
+// The passes between source text and a checked program or generated code
+export type CompilerPhase =
+    | "lexer"
+    | "parser"
+    | "type checker"
+    | "bytecode compiler"
+    | "C generator"
+    | "wasm generator"
+    | "js generator";
+
+// Passes report problems with the program as StrataErrors or plain Errors.
+// An error the JavaScript engine raises by itself, such as reading a field
+// of undefined, means the compiler is broken instead
+function isCompilerBug(error: unknown): boolean {
+    return (
+        error instanceof TypeError ||
+        error instanceof ReferenceError ||
+        error instanceof RangeError ||
+        error instanceof SyntaxError
+    );
+}
+
+// An engine error escaping a pass, reported at the span the pass was
+// working on, with the innermost frame that raised it
+export function internalCompilerError(error: unknown, phase: CompilerPhase, span?: Span): StrataError {
+    const detail = error instanceof Error ? `${error.name}: ${error.message}` : String(error);
+    const frame = error instanceof Error ? error.stack?.split("\n").find((l) => /^\s+at /.test(l))?.trim() : undefined;
+    const notes = frame ? [message("diagnostic.internal-frame", { frame })] : [];
+    notes.push(message("diagnostic.internal-report", { version: STRATA_VERSION, url: ISSUES_URL }));
+    return errorAt("E0006", message("diagnostic.internal", { phase, detail }), span, notes);
+}
+
+// Runs a pass, turning compiler bugs into internal compiler errors; span
+// is asked for only then
+function runPhase(phase: CompilerPhase, span: () => Span | undefined, run: () => any): any {
+    try {
+        return run();
+    } catch (error) {
+        throw isCompilerBug(error) ? internalCompilerError(error, phase, span()) : error;
+    }
+}
+
+//   error[E0003]: Type mismatch: expected int, got string
+//    --> main.str:3:14
+//     |
+//   3 | let x: int = "hi"
+//     |              ^^^^
+export function renderDiagnostic(diagnostic: Diagnostic, source?: string, file = "<input>"): string {
+    const { span } = diagnostic;
+    const out = [`${message(`diagnostic.${diagnostic.severity}`)}[${diagnostic.code}]: ${diagnostic.message}`];
+    if (span) {
+        out.push(` --> ${file}:${span.line}${span.column !== undefined ? `:${span.column}` : ""}`);
+        const text = source?.split(/\r?\n/)[span.line - 1];
+        if (text !== undefined && text.trim() !== "") {
+            const gutter = " ".repeat(String(span.line).length);
+            const start = span.column !== undefined ? span.column - 1 : text.length - text.trimStart().length;
+            const width = Math.min(span.length ?? (span.column !== undefined ? 1 : text.trim().length), text.length - start);
+            // Keep tabs so the carets line up with the source above them
+            const pad = text.slice(0, start).replace(/[^\t]/g, " ");
+            out.push(`${gutter} |`, `${span.line} | ${text}`, `${gutter} | ${pad}${"^".repeat(Math.max(width, 1))}`);
+        }
+    }
+    for (const note of diagnostic.notes) out.push(`  = ${message("diagnostic.note")}: ${note}`);
+    return out.join("\n");
+}
+
+// A segment of an interpolated string: literal text, or the source of a ${...}
+export type StringPart = string | { source: string; location: Location };
+
+// What a token is, decided once by the lexer so the parser never has to
+// guess from its text. Keyword aliases are already replaced by then
+export type TokenKind = "ident" | "int" | "float" | "string" | "char" | "keyword" | "symbol";
+
+export interface Token {
+    kind: TokenKind;
+    token: string; // the text; numbers in decimal, strings and chars with their quotes
+    location: Location;
+    parts?: StringPart[];
+}
+
+// Modules of the str package besides io: import text from str is std::text
+const STR_PACKAGE: Record<string, string> = {
+    text: "std::text",
+    fs: "std::fs",
+    env: "std::env",
+};
+
+// Reserved words of the grammar
+export const KEYWORDS = [
+    "import", "from", "pub", "let", "const", "var", "struct", "enum", "func", "return", "if",
+    "else", "guard", "defer", "with", "for", "in", "step", "while", "loop", "break", "continue", "match", "not", "true", "false", "null",
+];
+
+// Alternative spellings the lexer reads as a keyword or primitive type name,
+// e.g. a localized teaching dialect: { "si": "if", "entero": "int" }. A
+// Lexer or Parser only applies the aliases it is given, so the CLI passes a
+// Strataumfile's "keywords" to the project's files and never to the
+// built-in prelude
+export type KeywordAliases = ReadonlyMap<string, string>;
+
+const NO_KEYWORD_ALIASES: KeywordAliases = new Map();
+
+// The valid entries of a "keywords" table, and the ones that were rejected
+export function keywordAliases(table: Record<string, string>): { aliases: KeywordAliases; rejected: string[] } {
+    const aliases = new Map<string, string>();
+    const rejected: string[] = [];
+    for (const [alias, keyword] of Object.entries(table)) {
+        const valid =
+            /^[a-zA-Z_][a-zA-Z0-9_]*$/.test(alias) &&
+            !KEYWORDS.includes(alias) &&
+            (KEYWORDS.includes(keyword) || keyword in TYPE_REGISTRY);
+        if (valid) aliases.set(alias, keyword);
+        else rejected.push(`${alias} -> ${keyword}`);
+    }
+    return { aliases, rejected };
+}
+
+// Language editions this compiler reads, oldest first. A file names the
+// one it is written for with a `//! strata 1.0` line ahead of its code;
+// without one it gets the Strataumfile "edition", else the latest.
+// Changes that would break existing files go behind a new edition
+export const EDITIONS: { edition: string; summary: string }[] = [
+    { edition: "1.0", summary: "the language of Strata 1.0" },
+];
+
+export const LATEST_EDITION = EDITIONS[EDITIONS.length - 1].edition;
+
+let defaultEdition = LATEST_EDITION;
+
+// The edition of files without a pragma; throws for one this compiler can't read
+export function setDefaultEdition(edition: string): void {
+    checkEdition(edition);
+    defaultEdition = edition;
+}
+
+function checkEdition(edition: string, span?: Span): void {
+    if (EDITIONS.some((e) => e.edition === edition)) return;
+    const notes = [message("edition.list-note")];
+    const params = { edition, version: STRATA_VERSION, latest: LATEST_EDITION };
+    if (!/^[0-9]+\.[0-9]+$/.test(edition)) throw errorAt("E0008", message("edition.invalid", params), span, notes);
+    const [major, minor] = edition.split(".").map(Number);
+    const [latestMajor, latestMinor] = LATEST_EDITION.split(".").map(Number);
+    const newer = major > latestMajor || (major === latestMajor && minor > latestMinor);
+    throw errorAt("E0008", message(newer ? "edition.newer" : "edition.unknown", params), span, notes);
+}
+
+// The edition a //! strata pragma names, if one comes before the first
+// line of code; its span underlines the edition
+function editionPragma(source: string): { edition: string; span: Span } | undefined {
+    const lines = source.split("\n");
+    for (let i = 0; i < lines.length; i++) {
+        const pragma = /^(\s*\/\/!\s*strata\s+)(\S+)/.exec(lines[i]);
+        if (pragma) return { edition: pragma[2], span: { line: i + 1, column: pragma[1].length + 1, length: pragma[2].length } };
+        const text = lines[i].trim();
+        if (text !== "" && !text.startsWith("//")) return undefined;
+    }
+    return undefined;
 }
 
-class Lexer {
+// What the character after a backslash stands for in string and char literals
+const ESCAPES: Record<string, string> = { n: "\n", t: "\t", r: "\r", "0": "\0", "\\": "\\", '"': '"', "'": "'", $: "$" };
+
+export class Lexer {
     private pos = 0;
     private line = 1;
     private column = 1;
     private lineStart = 0;
 
-    constructor(private input: string) { }
+    constructor(private input: string, private aliases: KeywordAliases = NO_KEYWORD_ALIASES) { }
 
     private peek(): string | undefined {
         return this.input[this.pos];
@@ -881,15 +1831,90 @@ class Lexer {
         return ch;
     }
 
-    private getLocation(): Location {
+    // The source between ${ and its matching }, skipping braces inside nested strings
+    private readInterpolation(): { source: string; location: Location } {
+        const location = this.getLocation();
+        this.advance();
+        this.advance();
+        let source = "";
+        let depth = 0;
+        while (this.peek() && !(depth === 0 && this.peek() === "}")) {
+            const ch = this.advance();
+            source += ch;
+            if (ch === "{") depth++;
+            if (ch === "}") depth--;
+            if (ch === '"') {
+                while (this.peek() && this.peek() !== '"') {
+                    if (this.peek() === "\\") source += this.advance();
+                    source += this.advance();
+                }
+                if (this.peek()) source += this.advance();
+            }
+        }
+        if (!this.peek()) {
+            throw errorAt(
+                "E0001",
+                message("lexer.unterminated-interpolation", location),
+                { line: location.line, column: location.column, length: 2 }
+            );
+        }
+        this.advance(); // Skip closing brace
+        if (source.trim() === "") {
+            throw errorAt(
+                "E0001",
+                message("lexer.empty-interpolation", location),
+                { line: location.line, column: location.column, length: source.length + 3 }
+            );
+        }
+        return { source, location };
+    }
+
+    // The character a backslash escape stands for, with the lexer on the
+    // backslash: \n \t \r \0 \\ \" \' \$ or \u{...} with 1 to 6 hex digits
+    private readEscape(): string {
+        const loc = this.getLocation();
+        const start = this.pos;
+        this.advance();
+        const escaped = this.advance();
+        if (escaped !== undefined && escaped in ESCAPES) return ESCAPES[escaped];
+        if (escaped === "u" && this.peek() === "{") {
+            this.advance();
+            let hex = "";
+            while (/[0-9a-fA-F]/.test(this.peek() || "")) hex += this.advance();
+            const code = parseInt(hex, 16);
+            if (this.peek() === "}" && hex.length <= 6 && code <= 0x10ffff && !(code >= 0xd800 && code <= 0xdfff)) {
+                this.advance();
+                return String.fromCodePoint(code);
+            }
+            if (this.peek() === "}") this.advance();
+        }
+        const sequence = this.input.slice(start, this.pos);
+        throw errorAt(
+            "E0001",
+            message("lexer.invalid-escape", { sequence, line: loc.line, column: loc.column }),
+            { line: loc.line, column: loc.column, length: sequence.length },
+            [message("lexer.escape-note")]
+        );
+    }
+
+    // Where the next token starts
+    getLocation(): Location {
         return {
             line: this.line,
             column: this.column,
             source: this.input.substring(this.lineStart, this.pos),
+            offset: this.pos,
+            end: this.pos,
         };
     }
 
-    nextToken(): { token: string; location: Location } | null {
+    nextToken(): Token | null {
+        const token = this.readToken();
+        if (token) token.location.end = this.pos;
+        return token;
+    }
+
+    private readToken(): Token | null {
         // Skip whitespace
         while (
             this.peek() === " " ||
@@ -906,7 +1931,7 @@ class Lexer {
             this.input[this.pos + 1] === "/"
         ) {
             while (this.peek() && this.peek() !== "\n") this.advance();
-            return this.nextToken();
+            return this.readToken();
         }
 
         if (!this.peek()) return null;
@@ -914,6 +1939,12 @@ class Lexer {
         const loc = this.getLocation();
 
         // Multi-character operators
+        if (this.input.startsWith("..=", this.pos)) {
+            this.advance();
+            this.advance();
+            this.advance();
+            return { kind: "symbol", token: "..=", location: loc };
+        }
         const twoCharOps = [
             "==",
             "!=",
@@ -924,12 +1955,16 @@ class Lexer {
             "&&",
             "++",
             "--",
+            "::",
+            "|>",
+            "..",
+            "??",
         ];
         const twoChar = this.input.substring(this.pos, this.pos + 2);
         if (twoCharOps.includes(twoChar)) {
             this.advance();
             this.advance();
-            return { token: twoChar, location: loc };
+            return { kind: "symbol", token: twoChar, location: loc };
         }
 
         // Identifiers / keywords
@@ -937,37 +1972,235 @@ class Lexer {
             let word = "";
             while (/[a-zA-Z0-9_]/.test(this.peek() || ""))
                 word += this.advance();
-            return { token: word, location: loc };
+            const token = this.aliases.get(word) ?? word;
+            return { kind: KEYWORDS.includes(token) ? "keyword" : "ident", token, location: loc };
         }
 
-        // Strings
+        // Strings; "${expr}" segments are split out for the parser, "\${" is literal
         if (this.peek() === '"') {
             this.advance(); // Skip opening quote
             let str = "";
+            const parts: StringPart[] = [];
             while (this.peek() && this.peek() !== '"') {
                 if (this.peek() === "\\") {
-                    this.advance();
-                    const escaped = this.advance();
-                    str += escaped === "n" ? "\n" : escaped;
+                    str += this.readEscape();
+                } else if (this.peek() === "$" && this.input[this.pos + 1] === "{") {
+                    parts.push(str);
+                    str = "";
+                    parts.push(this.readInterpolation());
                 } else {
                     str += this.advance();
                 }
             }
             if (this.peek() === '"') this.advance(); // Skip closing quote
-            return { token: `"${str}"`, location: loc };
+            if (parts.length === 0) return { kind: "string", token: `"${str}"`, location: loc };
+            parts.push(str);
+            return { kind: "string", token: `"${parts.map((p) => (typeof p === "string" ? p : "${}")).join("")}"`, location: loc, parts };
+        }
+
+        // Characters
+        if (this.peek() === "'") {
+            this.advance(); // Skip opening quote
+            let ch: string;
+            if (this.peek() === "\\") {
+                ch = this.readEscape();
+            } else {
+                ch = this.advance();
+                if (/[\ud800-\udbff]/.test(ch)) ch += this.advance(); // Rest of a surrogate pair: '😀'
+            }
+            if (this.peek() !== "'") {
+                throw errorAt(
+                    "E0001",
+                    message("lexer.unterminated-char", loc),
+                    { line: loc.line, column: loc.column },
+                    [message("lexer.char-note")]
+                );
+            }
+            this.advance(); // Skip closing quote
+            return { kind: "char", token: `'${ch}'`, location: loc };
         }
 
-        // Numbers
+        // Numbers (a "." only continues the number when a digit follows,
+        // so 0..10 lexes as 0, .., 10). A fraction or exponent makes a float.
+        // 0x/0o/0b literals and _ separators become plain decimal tokens,
+        // so the parser only ever sees digits
         if (/[0-9]/.test(this.peek() || "")) {
+            const invalid = (text: string) => errorAt(
+                "E0001",
+                message("lexer.invalid-number", { text, line: loc.line, column: loc.column }),
+                { line: loc.line, column: loc.column, length: text.length },
+                [message("lexer.number-note")]
+            );
+            const radix = ({ "0x": 16, "0o": 8, "0b": 2 } as Record<string, number>)[this.input.slice(this.pos, this.pos + 2)];
+            if (radix) {
+                const prefix = this.advance() + this.advance();
+                let digits = "";
+                while (/[0-9a-zA-Z_]/.test(this.peek() || ""))
+                    digits += this.advance();
+                if (!/^[0-9a-z]+(_[0-9a-z]+)*$/i.test(digits) || [...digits.replace(/_/g, "")].some((d) => !(parseInt(d, radix) < radix))) {
+                    throw invalid(prefix + digits);
+                }
+                const value = BigInt(prefix + digits.replace(/_/g, ""));
+                if (value > BigInt(INT_MAX)) throw intRangeError(prefix + digits, loc);
+                return { kind: "int", token: value.toString(), location: loc };
+            }
             let num = "";
-            while (/[0-9.]/.test(this.peek() || ""))
+            while (/[0-9_]/.test(this.peek() || ""))
+                num += this.advance();
+            if (this.peek() === "." && /[0-9]/.test(this.input[this.pos + 1] || "")) {
+                num += this.advance();
+                while (/[0-9_]/.test(this.peek() || ""))
+                    num += this.advance();
+            }
+            if (/^[eE][+-]?[0-9]/.test(this.input.slice(this.pos, this.pos + 3))) {
                 num += this.advance();
-            return { token: num, location: loc };
+                if (this.peek() === "+" || this.peek() === "-") num += this.advance();
+                while (/[0-9]/.test(this.peek() || ""))
+                    num += this.advance();
+            }
+            if (!/^[0-9]+(_[0-9]+)*(\.[0-9]+(_[0-9]+)*)?([eE][+-]?[0-9]+)?$/.test(num)) throw invalid(num);
+            // One past INT_MAX is left to the parser, which takes it after a minus as INT_MIN
+            const int = !/[.eE]/.test(num);
+            if (int && BigInt(num.replace(/_/g, "")) > BigInt(INT_MAX) + 1n) throw intRangeError(num, loc);
+            return { kind: int ? "int" : "float", token: num.replace(/_/g, ""), location: loc };
         }
 
         // Single character tokens
         const ch = this.advance();
-        return { token: ch, location: loc };
+        return { kind: "symbol", token: ch, location: loc };
+    }
+}
+
+// ============================================================================
+// SYNTAX HIGHLIGHTING - Classified tokens for editors, comments included
+// ============================================================================
+//
+// tokenizeForHighlighting runs the Lexer and sorts what it reads into
+// categories, so editor plugins and a language server needn't lex Strata
+// themselves. Comments, which the lexer skips, are recovered from the gaps
+// between tokens, and the ${...} parts of a string are highlighted as code.
+// It never throws: a line the lexer rejects becomes an "invalid" token and
+// lexing resumes on the next line.
+
+export type HighlightCategory =
+    | "keyword"
+    | "identifier"
+    | "type" // a primitive type name: int, string
+    | "number"
+    | "string"
+    | "char"
+    | "constant" // true, false, null
+    | "comment"
+    | "operator"
+    | "punctuation"
+    | "invalid";
+
+export interface HighlightToken {
+    span: SourceRange;
+    category: HighlightCategory;
+}
+
+const PUNCTUATION = new Set(["(", ")", "{", "}", "[", "]", ",", ";", ":", ".", "::"]);
+
+export function tokenizeForHighlighting(source: string): HighlightToken[] {
+    const lineStarts = [0];
+    for (let i = 0; i < source.length; i++) if (source[i] === "\n") lineStarts.push(i + 1);
+    const tokens: HighlightToken[] = [];
+    const push = (start: number, end: number, category: HighlightCategory) => {
+        if (end <= start) return;
+        let low = 0;
+        let high = lineStarts.length - 1;
+        while (low < high) {
+            const mid = (low + high + 1) >> 1;
+            if (lineStarts[mid] <= start) low = mid;
+            else high = mid - 1;
+        }
+        tokens.push({ span: { start, end, line: low + 1, column: start - lineStarts[low] + 1 }, category });
+    };
+    highlightRange(source, 0, source.length, push);
+    // module and test are keywords only where they open a module or test block
+    const text = (token?: HighlightToken) => (token ? source.slice(token.span.start, token.span.end) : "");
+    tokens.forEach((token, i) => {
+        if (token.category === "identifier" && text(token) === "module" && tokens[i + 1]?.category === "identifier" && text(tokens[i + 2]) === "{") {
+            token.category = "keyword";
+        }
+        if (token.category === "identifier" && text(token) === "test" && tokens[i + 1]?.category === "string" && text(tokens[i + 2]) === "{") {
+            token.category = "keyword";
+        }
+    });
+    return tokens;
+}
+
+// Classifies source[from, to): the whole file, what follows a line the
+// lexer rejected, or the inside of a ${...}
+function highlightRange(source: string, from: number, to: number, push: (start: number, end: number, category: HighlightCategory) => void): void {
+    const text = source.slice(from, to);
+    const lexer = new Lexer(text);
+    const comments = (start: number, end: number) => {
+        for (const match of text.slice(start, end).matchAll(/\/\/[^\n]*/g)) {
+            const at = from + start + (match.index ?? 0);
+            push(at, at + match[0].length, "comment");
+        }
+    };
+    let last = 0;
+    for (;;) {
+        let token: Token | null;
+        try {
+            token = lexer.nextToken();
+        } catch {
+            // The token the lexer gave up on starts after any whitespace and
+            // comments; from there the line is invalid
+            let start = last;
+            while (start < text.length && (/\s/.test(text[start]) || text.startsWith("//", start))) {
+                if (text.startsWith("//", start)) while (start < text.length && text[start] !== "\n") start++;
+                else start++;
+            }
+            comments(last, start);
+            const lineEnd = text.indexOf("\n", start);
+            push(from + start, from + (lineEnd < 0 ? text.length : lineEnd), "invalid");
+            if (lineEnd >= 0) highlightRange(source, from + lineEnd + 1, to, push);
+            return;
+        }
+        if (!token) break;
+        const { offset, end } = token.location;
+        comments(last, offset);
+        last = end;
+        if (token.kind !== "string" || !token.parts) {
+            push(from + offset, from + end, highlightCategory(token));
+            continue;
+        }
+        // "a ${b} c": string, ${, the code inside, }, string
+        let at = offset;
+        for (const part of token.parts) {
+            if (typeof part === "string") continue;
+            const open = part.location.offset;
+            const close = open + 2 + part.source.length;
+            push(from + at, from + open, "string");
+            push(from + open, from + open + 2, "punctuation");
+            highlightRange(source, from + open + 2, from + close, push);
+            push(from + close, from + close + 1, "punctuation");
+            at = close + 1;
+        }
+        push(from + at, from + end, "string");
+    }
+    comments(last, text.length);
+}
+
+function highlightCategory(token: Token): HighlightCategory {
+    switch (token.kind) {
+        case "keyword":
+            return ["true", "false", "null"].includes(token.token) ? "constant" : "keyword";
+        case "ident":
+            return TYPE_REGISTRY[token.token]?.kind === "primitive" ? "type" : "identifier";
+        case "int":
+        case "float":
+            return "number";
+        case "string":
+            return "string";
+        case "char":
+            return "char";
+        case "symbol":
+            return PUNCTUATION.has(token.token) ? "punctuation" : "operator";
     }
 }
 
@@ -975,77 +2208,209 @@ class Lexer {
 // AST DEFINITIONS
 // ============================================================================
 
-type Expr =
+// Per-call-site inline cache. The first execution of a call resolves the
+// callee (module member or builtin) and records it against the call node;
+// later executions reuse the target as long as the receiver is the same
+// object. Each interpreter keeps its own, so programs can be shared
+interface CallSiteCache {
+    receiver: any;
+    target: (...args: any[]) => any;
+    version?: number;
+}
+
+// match patterns: 0, "a", 'c', true, _ (wildcard), n (binds the value),
+// Color.Red and Shape.Circle(r) (binds the payload; _ skips a value)
+export type Pattern =
     | { kind: "literal"; value: any; type: TypeDef }
-    | { kind: "identifier"; name: string }
-    | { kind: "binary"; op: string; left: Expr; right: Expr }
-    | { kind: "unary"; op: string; operand: Expr }
-    | { kind: "call"; func: Expr; args: Expr[] }
-    | { kind: "member"; object: Expr; property: string };
+    | { kind: "wildcard" }
+    | { kind: "binding"; name: string }
+    | { kind: "variant"; enum: string; variant: string; bindings: string[] };
+
+export interface MatchArm {
+    pattern: Pattern;
+    body: Stmt[];
+}
 
-type Stmt =
-    | { kind: "let"; name: string; type: TypeDef; value: Expr; mutable: boolean }
+// Nodes from the parser carry the source range they were read from
+export type Expr = { span?: SourceRange } & (
+    | { kind: "literal"; value: any; type: TypeDef }
+    | { kind: "identifier"; name: string }
+    | { kind: "binary"; op: string; left: Expr; right: Expr; integer?: boolean } // integer: int arithmetic, set by the checker
+    | { kind: "unary"; op: string; operand: Expr; integer?: boolean } // integer: int negation, set by the checker
+    | { kind: "call"; func: Expr; args: Expr[]; line?: number; module?: string } // module: of a math.sqrt callee, set by the checker
+    | { kind: "member"; object: Expr; property: string } // callee of a method call: io.print(...)
+    | { kind: "field"; object: Expr; name: string } // plain access: math.PI, point.x
+    | { kind: "index"; object: Expr; index: Expr }
+    | { kind: "array"; elements: Expr[] }
+    | { kind: "map"; entries: { key: Expr; value: Expr }[] }
+    | { kind: "structLiteral"; name: string; fields: { name: string; value: Expr }[] }
+    | { kind: "match"; subject: Expr; arms: MatchArm[]; line?: number; type?: TypeDef }
+    | { kind: "if"; condition: Expr; then: Stmt[]; else: Stmt[]; line?: number; type?: TypeDef } // type: of the value, set by the checker
+    | { kind: "block"; body: Stmt[]; line?: number; type?: TypeDef }
+    | { kind: "loop"; body: Stmt[]; line?: number; type?: TypeDef } // the value of the break that ends it
+    | { kind: "range"; start: Expr; end: Expr; inclusive: boolean; step?: Expr }
+    // (x: int) => x * 2, as a function <lambda> returning the body's value;
+    // the checker sets its return type from the body
+    | { kind: "lambda"; fn: Extract<Stmt, { kind: "function" }>; line?: number }
+    // value? is an Ok or Some's value; an Err or None is returned from the function
+    | { kind: "propagate"; value: Expr; line?: number }
+);
+
+// Every statement records the line it starts on for diagnostics, and
+// statements linked in from an imported file the file's path
+export type Stmt = { line?: number; span?: SourceRange; file?: string } & (
+    | { kind: "let"; name: string; type: TypeDef; value: Expr; mutable: boolean; constant?: boolean; exported?: boolean }
     | { kind: "assignment"; target: string; value: Expr }
+    | { kind: "indexAssignment"; object: Expr; index: Expr; value: Expr }
     | { kind: "expression"; expr: Expr }
-    | { kind: "if"; condition: Expr; then: Stmt[]; else?: Stmt[] }
-    | { kind: "while"; condition: Expr; body: Stmt[] }
+    // guard: from guard (c) else { ... }, read as if (!c) { ... }
+    | { kind: "if"; condition: Expr; then: Stmt[]; else?: Stmt[]; guard?: boolean }
+    // binding: while (let line = io.nextLine()) runs while the optional is Some,
+    // binding its value; bindingType, the value's type, is set by the checker
+    | { kind: "while"; condition: Expr; body: Stmt[]; binding?: string; bindingType?: TypeDef }
     | { kind: "for"; init: Stmt; condition: Expr; update: Stmt; body: Stmt[] }
+    | { kind: "forIn"; variable: string; iterable: Expr; body: Stmt[] }
     | { kind: "return"; value?: Expr }
-    | { kind: "break" }
+    | { kind: "break"; value?: Expr } // value: only in a loop expression
     | { kind: "continue" }
+    | { kind: "defer"; body: Stmt } // body runs when the enclosing block is left
     | { kind: "function"; name: string; params: { name: string; type: TypeDef }[]; returnType: TypeDef; body: Stmt[] }
-    | { kind: "import"; name: string; module: string };
+    | { kind: "import"; name: string; module: string; path?: boolean } // path: a quoted .str file, linked by ModuleLoader
+    | { kind: "struct"; name: string; fields: { name: string; type: TypeDef }[] }
+    | { kind: "enum"; name: string; variants: EnumVariant[] }
+    | { kind: "match"; subject: Expr; arms: MatchArm[]; line?: number }
+    // test "name" { ... }: only at the top level, run by strata test and skipped otherwise
+    | { kind: "test"; name: string; body: Stmt[] }
+);
+
+// Red, or Circle(float) with the types of its payload
+export interface EnumVariant {
+    name: string;
+    params: TypeDef[];
+}
 
 // ============================================================================
 // PARSER
 // ============================================================================
 
-class Parser {
-    private tokens: { token: string; location: Location }[] = [];
-    private pos = 0;
+// Binding strength of binary operators; higher binds tighter
+const BINARY_PRECEDENCE: Record<string, number> = {
+    "|>": 1,
+    "||": 2,
+    "&&": 3,
+    "==": 4,
+    "!=": 4,
+    "in": 4,
+    "not in": 4,
+    "<": 5,
+    ">": 5,
+    "<=": 5,
+    ">=": 5,
+    "??": 6,
+    "..": 6,
+    "..=": 6,
+    "+": 7,
+    "-": 7,
+    "*": 8,
+    "/": 8,
+    "%": 8,
+};
 
-    constructor(input: string) {
-        const lexer = new Lexer(input);
-        let token;
-        while ((token = lexer.nextToken())) {
-            this.tokens.push(token);
-        }
+export class Parser {
+    private tokens: Token[] = [];
+    private pos = 0;
+    private diagnostics: Diagnostic[] = [];
+    // The token right after a unary minus, where 2147483648 spells INT_MIN
+    private negatedAt = -1;
+    // Struct and enum names declared anywhere in the file, so types and
+    // construction literals can refer to types declared later
+    private typeNames: Map<string, "struct" | "enum">;
+    // The edition the source is written for, from its pragma or the default
+    readonly edition: string;
+    private aliases: KeywordAliases;
+
+    // firstLine and origin place embedded sources such as "${...}" where they
+    // are in the file; typeNames is shared by parsers of pieces of one program
+    constructor(
+        input: string,
+        firstLine = 1,
+        typeNames = new Map<string, "struct" | "enum">(),
+        origin = { offset: 0, column: 1 },
+        aliases: KeywordAliases = NO_KEYWORD_ALIASES,
+    ) {
+        this.typeNames = typeNames;
+        this.aliases = aliases;
+        const pragma = editionPragma(input);
+        if (pragma) checkEdition(pragma.edition, { ...pragma.span, line: pragma.span.line + firstLine - 1 });
+        this.edition = pragma?.edition ?? defaultEdition;
+        const lexer = new Lexer(input, aliases);
+        const place = (location: Location) => {
+            if (location.line === 1) location.column += origin.column - 1;
+            location.line += firstLine - 1;
+            location.offset += origin.offset;
+            location.end += origin.offset;
+        };
+        const lexerSpan = () => {
+            const location = lexer.getLocation();
+            place(location);
+            return { line: location.line, column: location.column };
+        };
+        runPhase("lexer", lexerSpan, () => {
+            let token;
+            while ((token = lexer.nextToken())) {
+                place(token.location);
+                this.tokens.push(token);
+            }
+        });
+        this.tokens.forEach((t, i) => {
+            if ((t.token === "struct" || t.token === "enum") && this.tokens[i + 1]) this.typeNames.set(this.tokens[i + 1].token, t.token);
+        });
     }
 
     private current() {
         return this.tokens[this.pos];
     }
 
+    // The token being parsed, or the last one at the end of input
+    private tokenSpan(): Span | undefined {
+        const token = this.current() ?? this.tokens[this.tokens.length - 1];
+        return token && { line: token.location.line, column: token.location.column, length: token.token.length };
+    }
+
     private advance() {
         this.pos++;
     }
 
     private expect(token: string) {
         if (!this.current() || this.current().token !== token) {
-            throw new Error(
-                `Expected ${token} at line ${this.current()?.location.line}`
+            throw this.error(
+                this.current()
+                    ? message("parser.expected", { token, line: this.current().location.line })
+                    : message("parser.expected-at-end", { token })
             );
         }
         this.advance();
     }
 
+    // A syntax error at the current token, or just past the last one at end of input
+    private error(message: string): StrataError {
+        const token = this.current();
+        if (token) {
+            return errorAt("E0002", message, { line: token.location.line, column: token.location.column, length: token.token.length });
+        }
+        const last = this.tokens[this.tokens.length - 1];
+        const span = last && { line: last.location.line, column: last.location.column + last.token.length };
+        return errorAt("E0002", message, span);
+    }
+
+    // From the token at index start to the last one consumed
+    private rangeFrom(start: number): SourceRange {
+        const first = this.tokens[start].location;
+        return { start: first.offset, end: this.tokens[this.pos - 1].location.end, line: first.line, column: first.column };
+    }
+
     private precedence(op: string): number {
-        const precs: Record<string, number> = {
-            "||": 1,
-            "&&": 2,
-            "==": 3,
-            "!=": 3,
-            "<": 4,
-            ">": 4,
-            "<=": 4,
-            ">=": 4,
-            "+": 5,
-            "-": 5,
-            "*": 6,
-            "/": 6,
-            "%": 6,
-        };
-        return precs[op] ?? 0;
+        return BINARY_PRECEDENCE[op] ?? 0;
     }
 
     private parseUnary(): Expr {
@@ -1053,29 +2418,86 @@ class Parser {
             this.current() &&
             ["!", "-", "+", "~"].includes(this.current().token)
         ) {
+            const start = this.pos;
             const op = this.current().token;
             this.advance();
-            return { kind: "unary", op, operand: this.parseUnary() };
+            if (op === "-") this.negatedAt = this.pos;
+            const operand = this.parseUnary();
+            return { kind: "unary", op, operand, span: this.rangeFrom(start) };
         }
         return this.parsePrimary();
     }
 
+    // A parenthesized expression keeps the range inside the parentheses
     private parsePrimary(): Expr {
+        const start = this.pos;
+        const line = this.current()?.location.line;
+        const atom = this.parseAtom();
+        atom.span ??= this.rangeFrom(start);
+        return this.parsePostfix(atom, line, start);
+    }
+
+    // Calls, member access and indexing chain on any primary:
+    // f(x).g(), "abc".length(), (a |> f)[0], grid[1][0]
+    private parsePostfix(expr: Expr, line: number | undefined, start: number): Expr {
+        while (this.current()) {
+            const token = this.current().token;
+            // ( and [ must stay on the line of the expression they apply to,
+            // otherwise a new statement starting with ( or [ would chain
+            const sameLine = this.current().location.line === this.tokens[this.pos - 1]?.location.line;
+            if (token === "(" && sameLine) {
+                const func: Expr = expr.kind === "field"
+                    ? { kind: "member", object: expr.object, property: expr.name }
+                    : expr;
+                expr = { kind: "call", func, args: this.parseCallArgs(), line };
+            } else if (token === "[" && sameLine) {
+                this.advance();
+                const index = this.parseBinary();
+                this.expect("]");
+                expr = { kind: "index", object: expr, index };
+            } else if (token === ".") {
+                this.advance();
+                const property = this.current()?.token;
+                if (!property) throw this.error(message("parser.property-name"));
+                this.advance();
+                expr = { kind: "field", object: expr, name: property };
+            } else if (token === "?" && sameLine) {
+                this.advance();
+                expr = { kind: "propagate", value: expr, line };
+            } else {
+                return expr;
+            }
+            expr.span = this.rangeFrom(start);
+        }
+        return expr;
+    }
+
+    private parseAtom(): Expr {
         if (!this.current())
-            throw new Error("Unexpected end of input");
+            throw this.error(message("parser.unexpected-end"));
 
-        const token = this.current().token;
+        const { kind, token } = this.current();
 
-        if (/^[0-9]/.test(token)) {
+        if (kind === "int" || kind === "float") {
+            const location = this.current().location;
+            const negated = this.negatedAt === this.pos && ![".", "["].includes(this.tokens[this.pos + 1]?.token);
+            if (kind === "int" && parseInt(token) > INT_MAX && !negated) throw intRangeError(token, location);
             this.advance();
+            const float = kind === "float";
             return {
                 kind: "literal",
-                value: token.includes(".") ? parseFloat(token) : parseInt(token),
-                type: token.includes(".") ? { kind: "primitive", primitive: "float" } : { kind: "primitive", primitive: "int" },
+                value: float ? parseFloat(token) : parseInt(token),
+                type: float ? { kind: "primitive", primitive: "float" } : { kind: "primitive", primitive: "int" },
             };
         }
 
-        if (token.startsWith('"')) {
+        if (kind === "string" && this.current().parts) {
+            const { parts, location } = this.current();
+            this.advance();
+            return this.parseInterpolation(parts!, location.line);
+        }
+
+        if (kind === "string") {
             this.advance();
             return {
                 kind: "literal",
@@ -1084,6 +2506,15 @@ class Parser {
             };
         }
 
+        if (kind === "char") {
+            this.advance();
+            return {
+                kind: "literal",
+                value: token.slice(1, -1),
+                type: { kind: "primitive", primitive: "char" },
+            };
+        }
+
         if (token === "true" || token === "false") {
             this.advance();
             return {
@@ -1093,39 +2524,64 @@ class Parser {
             };
         }
 
-        if (/[a-zA-Z_]/.test(token)) {
-            let expr: Expr = { kind: "identifier", name: token };
+        // null is an empty optional: it fits int? but not int
+        if (token === "null") {
             this.advance();
+            return { kind: "literal", value: null, type: { kind: "primitive", primitive: "null" } };
+        }
 
-            // Handle member access and calls: io.print(...), obj.method(...), etc.
-            while (this.current()?.token === ".") {
-                this.advance();
-                const property = this.current()?.token;
-                if (!property) throw new Error("Expected property name after .");
-                this.advance();
+        // match used as a value: let s: string = match (n) { 0 => "none", _ => "some" }
+        if (this.atMatch()) {
+            return this.parseMatch(true);
+        }
 
-                // Check for function call
-                if (this.current()?.token === "(") {
-                    this.advance();
-                    const args: Expr[] = [];
-                    while (this.current()?.token !== ")") {
-                        args.push(this.parseUnary());
-                        if (this.current()?.token === ",") this.advance();
-                    }
-                    this.expect(")");
-                    
-                    expr = {
-                        kind: "call",
-                        func: { kind: "member", object: expr, property },
-                        args,
-                    } as any;
-                } else {
-                    // Property access without call
-                    expr = { kind: "member", object: expr, property } as any;
-                }
+        // if used as a value: let x: int = if (c) { 1 } else { 2 }
+        if (token === "if") {
+            return this.parseIfExpression();
+        }
+
+        // loop used as a value: let n: int = loop { ... break n }
+        if (token === "loop" && this.tokens[this.pos + 1]?.token === "{") {
+            return this.parseLoop();
+        }
+
+        // Struct construction: Point { x: 1, y: 2 }
+        if (this.typeNames.get(token) === "struct" && this.tokens[this.pos + 1]?.token === "{") {
+            this.advance();
+            this.advance();
+            const fields: { name: string; value: Expr }[] = [];
+            while (this.current()?.token !== "}") {
+                const name = this.current()!.token;
+                this.advance();
+                this.expect(":");
+                fields.push({ name, value: this.parseBinary() });
+                if (this.current()?.token === ",") this.advance();
             }
+            this.expect("}");
+            return { kind: "structLiteral", name: token, fields };
+        }
 
-            return expr;
+        // Keywords after true/false/match are names too: func.bind(f, 1)
+        if (kind === "ident" || kind === "keyword") {
+            this.advance();
+            return { kind: "identifier", name: token };
+        }
+
+        // Anonymous functions: (x: int) => x * 2
+        if (token === "(" && this.atLambda()) {
+            const line = this.current().location.line;
+            const params = this.parseParams();
+            this.expect("=>");
+            const value = this.parseBinary();
+            const fn: Extract<Stmt, { kind: "function" }> = {
+                kind: "function",
+                name: "<lambda>",
+                params,
+                returnType: { kind: "primitive", primitive: "any" },
+                body: [{ kind: "return", value, line }],
+                line,
+            };
+            return { kind: "lambda", fn, line };
         }
 
         if (token === "(") {
@@ -1135,106 +2591,636 @@ class Parser {
             return expr;
         }
 
-        throw new Error(`Unexpected token: ${token}`);
+        // A block used as a value: { let y: int = 2  y * y }
+        if (token === "{" && !this.atMapLiteral()) {
+            const line = this.current().location.line;
+            this.advance();
+            const body = this.valueBody(this.parseBlockBody());
+            this.expect("}");
+            return { kind: "block", body, line };
+        }
+
+        // Map literals: { "a": 1, "b": 2 }
+        if (token === "{") {
+            this.advance();
+            const entries: { key: Expr; value: Expr }[] = [];
+            while (this.current()?.token !== "}") {
+                const key = this.parseBinary();
+                this.expect(":");
+                entries.push({ key, value: this.parseBinary() });
+                if (this.current()?.token === ",") this.advance();
+            }
+            this.expect("}");
+            return { kind: "map", entries };
+        }
+
+        // Array literals: [1, 2, 3]
+        if (token === "[") {
+            this.advance();
+            const elements: Expr[] = [];
+            while (this.current()?.token !== "]") {
+                elements.push(this.parseBinary());
+                if (this.current()?.token === ",") this.advance();
+            }
+            this.expect("]");
+            return { kind: "array", elements };
+        }
+
+        throw this.error(message("parser.unexpected-token", { token }));
+    }
+
+    // Type annotations: int, string, (int, int) => int, int[], int[3], int?,
+    // int | string and (int | string)[]
+    private parseType(): TypeDef {
+        const types = [this.parseSuffixedType()];
+        while (this.current()?.token === "|") {
+            this.advance();
+            types.push(this.parseSuffixedType());
+        }
+        return types.length === 1 ? types[0] : unionOf(types);
+    }
+
+    private parseSuffixedType(): TypeDef {
+        let type = this.parseBaseType();
+        while (this.current()?.token === "[" || this.current()?.token === "?") {
+            if (this.current().token === "?") {
+                this.advance();
+                type = { kind: "optional", innerType: type };
+                continue;
+            }
+            this.advance();
+            let size: number | undefined;
+            let sizeExpr: Expr | undefined;
+            if (this.current()?.kind === "int") {
+                size = parseInt(this.current().token);
+                this.advance();
+            } else if (this.current()?.token !== "]") {
+                sizeExpr = this.parseBinary();
+            }
+            this.expect("]");
+            type = { kind: "array", innerType: type, size, sizeExpr };
+        }
+        return type;
+    }
+
+    private parseBaseType(): TypeDef {
+        // map<string, int>
+        const token = this.current()?.token;
+        if ((token === "map" || token === "dict") && this.tokens[this.pos + 1]?.token === "<") {
+            this.advance();
+            this.advance();
+            const keyType = this.parseType();
+            this.expect(",");
+            const valueType = this.parseType();
+            this.expect(">");
+            return { kind: "map", keyType, valueType };
+        }
+        if (this.current()?.token === "(") {
+            this.advance();
+            const params: TypeDef[] = [];
+            while (this.current()?.token !== ")") {
+                params.push(this.parseType());
+                if (this.current()?.token === ",") this.advance();
+            }
+            this.expect(")");
+            // (int | string) without an arrow only groups
+            if (params.length === 1 && this.current()?.token !== "=>") return params[0];
+            this.expect("=>");
+            return { kind: "function", params, returnType: this.parseType() };
+        }
+        const typeStr = this.current()!.token;
+        this.advance();
+        // Fields are filled in by the checker from the declaration
+        const declared = this.typeNames.get(typeStr);
+        if (declared) return { kind: declared, name: typeStr };
+        return parseTypeAnnotation(typeStr) || { kind: "primitive", primitive: "any" };
+    }
+
+    private parseCallArgs(): Expr[] {
+        this.expect("(");
+        const args: Expr[] = [];
+        while (this.current()?.token !== ")") {
+            args.push(this.parseBinary());
+            if (this.current()?.token === ",") this.advance();
+        }
+        this.expect(")");
+        return args;
+    }
+
+    // `not in` is two tokens but a single operator
+    private currentOperator(): string | undefined {
+        const token = this.current()?.token;
+        if (token === "not" && this.tokens[this.pos + 1]?.token === "in") return "not in";
+        return token;
     }
 
     private parseBinary(minPrec = 0): Expr {
+        const start = this.pos;
         let left = this.parseUnary();
 
         while (
             this.current() &&
-            this.precedence(this.current().token) >= minPrec
+            this.precedence(this.currentOperator()!) > 0 &&
+            this.precedence(this.currentOperator()!) >= minPrec
         ) {
-            const op = this.current().token;
+            const op = this.currentOperator()!;
             const prec = this.precedence(op);
             this.advance();
+            if (op === "not in") this.advance();
             const right = this.parseBinary(prec + 1);
-            left = { kind: "binary", op, left, right };
+            if (op === "|>") {
+                left = this.desugarPipe(left, right);
+            } else if (op === ".." || op === "..=") {
+                let step: Expr | undefined;
+                // 0..10 step 2
+                if (this.current()?.token === "step") {
+                    this.advance();
+                    step = this.parseBinary(prec + 1);
+                }
+                left = { kind: "range", start: left, end: right, inclusive: op === "..=", step };
+            } else {
+                left = { kind: "binary", op, left, right };
+            }
+            left.span = this.rangeFrom(start);
         }
 
         return left;
     }
 
+    // value |> f |> g(2)  becomes  g(f(value), 2)
+    private desugarPipe(value: Expr, target: Expr): Expr {
+        if (target.kind === "call") {
+            return { kind: "call", func: target.func, args: [value, ...target.args], line: target.line };
+        }
+        if (target.kind === "identifier") {
+            return { kind: "call", func: target, args: [value], line: this.current()?.location.line };
+        }
+        if (target.kind === "field") {
+            const func: Expr = { kind: "member", object: target.object, property: target.name };
+            return { kind: "call", func, args: [value], line: this.current()?.location.line };
+        }
+        throw this.error(message("parser.pipe-target", { line: this.current()?.location.line }));
+    }
+
+    // { } and { key: value ... } are maps; any other { starts a block
+    // A parenthesized list followed by =>
+    private atLambda(): boolean {
+        let depth = 0;
+        for (let i = this.pos; i < this.tokens.length; i++) {
+            const token = this.tokens[i].token;
+            if (token === "(") depth++;
+            if (token === ")" && --depth === 0) return this.tokens[i + 1]?.token === "=>";
+        }
+        return false;
+    }
+
+    // (name: type, ...) of a function or lambda
+    private parseParams(): { name: string; type: TypeDef }[] {
+        this.expect("(");
+        const params = [];
+        while (this.current()?.token !== ")") {
+            const pname = this.current()!.token;
+            this.advance();
+            this.expect(":");
+            params.push({
+                name: pname,
+                type: this.parseType(),
+            });
+            if (this.current()?.token === ",") this.advance();
+        }
+        this.expect(")");
+        return params;
+    }
+
+    private atMapLiteral(): boolean {
+        if (this.tokens[this.pos + 1]?.token === "}") return true;
+        const start = this.pos;
+        try {
+            this.advance();
+            this.parseBinary();
+            return this.current()?.token === ":";
+        } catch (error) {
+            if (!(error instanceof StrataError)) throw error;
+            return false;
+        } finally {
+            this.pos = start;
+        }
+    }
+
+    // if (c) { ... } else { ... } as a value; the else is required and may be another if
+    private parseIfExpression(): Extract<Expr, { kind: "if" }> {
+        const line = this.current().location.line;
+        this.expect("if");
+        this.expect("(");
+        const condition = this.parseBinary();
+        this.expect(")");
+        const then = this.parseValueBlock();
+        this.expect("else");
+        if (this.current()?.token !== "if") return { kind: "if", condition, then, else: this.parseValueBlock(), line };
+        const chained = this.parseIfExpression();
+        return { kind: "if", condition, then, else: [{ kind: "expression", expr: chained, line: chained.line }], line };
+    }
+
+    private parseLoop(): Extract<Expr, { kind: "loop" }> {
+        const line = this.current().location.line;
+        this.expect("loop");
+        this.expect("{");
+        const body = this.parseBlockBody();
+        this.expect("}");
+        return { kind: "loop", body, line };
+    }
+
+    private parseValueBlock(): Stmt[] {
+        this.expect("{");
+        const body = this.valueBody(this.parseBlockBody());
+        this.expect("}");
+        return body;
+    }
+
+    // A block's value is its last expression. A trailing if/else, or a match
+    // with a catch-all arm or enum variant arms, yields the value of the branch it takes.
+    private valueBody(body: Stmt[]): Stmt[] {
+        const last = body[body.length - 1];
+        if (last?.kind === "if" && last.else) {
+            const expr: Expr = { kind: "if", condition: last.condition, then: this.valueBody(last.then), else: this.valueBody(last.else), line: last.line };
+            return [...body.slice(0, -1), { kind: "expression", expr, line: last.line, span: last.span }];
+        }
+        if (last?.kind === "match" && last.arms.some((arm) => arm.pattern.kind !== "literal")) {
+            const arms = last.arms.map((arm) => ({ ...arm, body: this.valueBody(arm.body) }));
+            const expr: Expr = { kind: "match", subject: last.subject, arms, line: last.line };
+            return [...body.slice(0, -1), { kind: "expression", expr, line: last.line, span: last.span }];
+        }
+        return body;
+    }
+
+    // match (x) { ... } as opposed to a call to the regex builtin match(text, re)
+    private atMatch(): boolean {
+        if (this.current()?.token !== "match" || this.tokens[this.pos + 1]?.token !== "(") return false;
+        let depth = 0;
+        for (let i = this.pos + 1; i < this.tokens.length; i++) {
+            const t = this.tokens[i].token;
+            if (t === "(") depth++;
+            if (t === ")" && --depth === 0) return this.tokens[i + 1]?.token === "{";
+        }
+        return false;
+    }
+
+    // match (subject) { pattern => body ... }. An arm body is a { block }
+    // or a single statement; as an expression, a single expression.
+    private parseMatch(asExpression: boolean): Extract<Expr, { kind: "match" }> {
+        const line = this.current().location.line;
+        this.expect("match");
+        this.expect("(");
+        const subject = this.parseBinary();
+        this.expect(")");
+        this.expect("{");
+        const arms: MatchArm[] = [];
+        while (this.current()?.token !== "}") {
+            const pattern = this.parsePattern();
+            this.expect("=>");
+            let body: Stmt[];
+            if (this.current()?.token === "{") {
+                this.advance();
+                body = this.parseBlockBody();
+                this.expect("}");
+            } else if (asExpression) {
+                body = [{ kind: "expression", expr: this.parseBinary() }];
+            } else {
+                body = [this.parseStatement()];
+            }
+            arms.push({ pattern, body });
+            if (this.current()?.token === ",") this.advance();
+        }
+        this.expect("}");
+        return { kind: "match", subject, arms, line };
+    }
+
+    private parsePattern(): Pattern {
+        const token = this.current()?.token;
+        const kind = this.current()?.kind;
+        if (token === "_") {
+            this.advance();
+            return { kind: "wildcard" };
+        }
+        if (token === "-" || kind === "int" || kind === "float" || kind === "string" || kind === "char" || token === "true" || token === "false") {
+            const literal = this.parseUnary();
+            if (literal.kind === "literal") return literal;
+            if (literal.kind === "unary" && literal.operand.kind === "literal") {
+                return { kind: "literal", value: -literal.operand.value, type: literal.operand.type };
+            }
+        }
+        // Color.Red, Shape.Rect(w, _)
+        if (kind === "ident" && this.tokens[this.pos + 1]?.token === ".") {
+            this.advance();
+            this.advance();
+            const variant = this.current()!.token;
+            this.advance();
+            const bindings: string[] = [];
+            if (this.current()?.token === "(") {
+                this.advance();
+                while (this.current()?.token !== ")") {
+                    bindings.push(this.current()!.token);
+                    this.advance();
+                    if (this.current()?.token === ",") this.advance();
+                }
+                this.expect(")");
+            }
+            return { kind: "variant", enum: token!, variant, bindings };
+        }
+        if (kind === "ident" || kind === "keyword") {
+            this.advance();
+            return { kind: "binding", name: token! };
+        }
+        throw this.error(message("parser.match-pattern", { token, line: this.current()?.location.line }));
+    }
+
+    // "hi ${name}!" desugars to format("hi {}!", name)
+    private parseInterpolation(parts: StringPart[], line: number): Expr {
+        let template = "";
+        const args: Expr[] = [];
+        for (const part of parts) {
+            if (typeof part === "string") {
+                template += part.replace(/[{}]/g, "$&$&");
+                continue;
+            }
+            const origin = { offset: part.location.offset + 2, column: part.location.column + 2 };
+            const parser = new Parser(part.source, part.location.line, this.typeNames, origin, this.aliases);
+            try {
+                args.push(parser.parseExpression());
+            } catch (error) {
+                if (error instanceof StrataError && error.diagnostic.code === "E0006") throw error;
+                // Columns inside the embedded source don't map onto the file; point at the ${
+                const { code } = asStrataError(error, "E0002").diagnostic;
+                throw errorAt(
+                    code,
+                    message("parser.in-interpolation", { message: (error as Error).message, line: part.location.line }),
+                    { line: part.location.line, column: part.location.column, length: part.source.length + 3 }
+                );
+            }
+            template += "{}";
+        }
+        return {
+            kind: "call",
+            func: { kind: "identifier", name: "format" },
+            args: [{ kind: "literal", value: template, type: { kind: "primitive", primitive: "string" } }, ...args],
+            line,
+        };
+    }
+
+    parseExpression(): Expr {
+        const expr = runPhase("parser", () => this.tokenSpan(), () => this.parseBinary());
+        if (this.current()) {
+            throw this.error(message("parser.unexpected-token", { token: this.current().token }));
+        }
+        return expr;
+    }
+
+    // Every syntax error is collected, then thrown together once the whole
+    // input has been read
     parse(): Stmt[] {
         const statements: Stmt[] = [];
-        while (this.current()) {
-            statements.push(this.parseStatement());
+        const namespaces: Namespace[] = [];
+        runPhase("parser", () => this.tokenSpan(), () => {
+            while (this.current()) {
+                if (this.atModule()) this.parseModule(statements, namespaces);
+                else if (this.atTest()) this.parseTest(statements);
+                else this.parseRecovering(statements, false);
+            }
+        });
+        if (this.diagnostics.length > 0) {
+            throw new StrataError(this.diagnostics[0], this.diagnostics);
+        }
+        if (namespaces.length > 0) new ModuleLoader().linkNamespaces(statements, namespaces);
+        return statements;
+    }
+
+    // module geometry { ... }; module stays usable as a name elsewhere
+    private atModule(): boolean {
+        return this.current()?.token === "module" && this.tokens[this.pos + 1]?.kind === "ident" && this.tokens[this.pos + 2]?.token === "{";
+    }
+
+    // The body's statements go in place among the file's, to be renamed
+    // once the whole file is parsed
+    private parseModule(statements: Stmt[], namespaces: Namespace[]): void {
+        const start = this.pos;
+        try {
+            this.advance();
+            const name = this.current().token;
+            this.advance();
+            const span = this.rangeFrom(start);
+            this.expect("{");
+            const body = this.parseBlockBody();
+            this.expect("}");
+            namespaces.push({ name, body, span });
+            statements.push(...body);
+        } catch (error) {
+            if (!(error instanceof StrataError)) throw error;
+            this.diagnostics.push(...error.diagnostics);
+            this.synchronize(start, false);
+        }
+    }
+
+    // test "name" { ... }; test stays usable as a name elsewhere
+    private atTest(): boolean {
+        return this.current()?.token === "test" && this.tokens[this.pos + 1]?.kind === "string" && this.tokens[this.pos + 2]?.token === "{";
+    }
+
+    private parseTest(statements: Stmt[]): void {
+        const start = this.pos;
+        const line = this.current().location.line;
+        try {
+            this.advance();
+            const name = this.current().token.slice(1, -1);
+            this.advance();
+            this.expect("{");
+            const body = this.parseBlockBody();
+            this.expect("}");
+            statements.push({ kind: "test", name, body, line, span: this.rangeFrom(start) });
+        } catch (error) {
+            if (!(error instanceof StrataError)) throw error;
+            this.diagnostics.push(...error.diagnostics);
+            this.synchronize(start, false);
+        }
+    }
+
+    // Statements up to the } that closes the block, which is left in place
+    private parseBlockBody(): Stmt[] {
+        const statements: Stmt[] = [];
+        while (this.current() && this.current().token !== "}") {
+            this.parseRecovering(statements, true);
         }
         return statements;
     }
 
+    // On a syntax error, record it and skip to where the next statement can start
+    private parseRecovering(statements: Stmt[], inBlock: boolean): void {
+        const start = this.pos;
+        try {
+            statements.push(this.parseStatement());
+        } catch (error) {
+            if (!(error instanceof StrataError)) throw error;
+            this.diagnostics.push(...error.diagnostics);
+            this.synchronize(start, inBlock);
+        }
+    }
+
+    // Resume at a keyword or identifier starting a later line than the bad
+    // statement, or before the } closing the enclosing block. Braces opened
+    // while skipping are skipped as a whole; at the top level an unmatched }
+    // belongs to the bad statement and is skipped too.
+    private synchronize(start: number, inBlock: boolean): void {
+        const startLine = this.tokens[start]?.location.line ?? 0;
+        if (this.pos === start) this.advance();
+        let depth = 0;
+        while (this.current()) {
+            const { kind, token, location } = this.current();
+            if (depth === 0 && token === "}" && inBlock) return;
+            if (depth === 0 && location.line > startLine && (kind === "ident" || kind === "keyword")) return;
+            if (token === "{") depth++;
+            if (token === "}" && depth > 0) depth--;
+            this.advance();
+        }
+    }
+
     private parseStatement(): Stmt {
+        const start = this.pos;
+        const line = this.current()?.location.line;
+        const stmt = this.parseStatementKind();
+        if (stmt.line === undefined) stmt.line = line;
+        stmt.span ??= this.rangeFrom(start);
+        return stmt;
+    }
+
+    private parseStatementKind(): Stmt {
         const token = this.current()?.token;
+        if (this.atModule()) throw this.error(message("parser.module-top-level"));
+        if (this.atTest()) throw this.error(message("parser.test-top-level"));
 
         if (token === "import") {
             this.advance();
             const name = this.current()!.token;
             this.advance();
             this.expect("from");
-            const module = this.current()!.token;
+            // import geometry from "./geometry.str"
+            if (this.current()?.kind === "string") {
+                const module = this.current().token.slice(1, -1);
+                this.advance();
+                return { kind: "import", name, module, path: true };
+            }
+            let module = this.current()!.token;
             this.advance();
+            // Namespaced module paths: std::math
+            while (this.current()?.token === "::") {
+                this.advance();
+                module += "::" + this.current()!.token;
+                this.advance();
+            }
+            // import text from str names a module of the str package
+            if (module === "str" && STR_PACKAGE[name]) module = STR_PACKAGE[name];
             return { kind: "import", name, module };
         }
 
+        // pub const LIMIT: int = 10 is part of the module's public API
+        if (token === "pub" && this.tokens[this.pos + 1]?.token === "const") {
+            this.advance();
+            const decl = this.parseStatement();
+            return { ...decl, exported: true } as Stmt;
+        }
+
         if (token === "let" || token === "const" || token === "var") {
             const mutable = token === "var";
+            const constant = token === "const";
             this.advance();
             const name = this.current()!.token;
             this.advance();
             this.expect(":");
-            const typeStr = this.current()!.token;
-            this.advance();
+            const type = this.parseType();
             this.expect("=");
             const value = this.parseBinary();
             return {
                 kind: "let",
                 name,
-                type: parseTypeAnnotation(typeStr) || { kind: "primitive", primitive: "any" },
+                type,
                 value,
                 mutable,
+                constant,
             };
         }
 
-        if (token === "func") {
+        if (this.atMatch()) {
+            return this.parseMatch(false);
+        }
+
+        // struct Point { x: int, y: int }
+        if (token === "struct") {
             this.advance();
             const name = this.current()!.token;
             this.advance();
-            this.expect("(");
-            const params = [];
-            while (this.current()?.token !== ")") {
-                const pname = this.current()!.token;
+            this.expect("{");
+            const fields: { name: string; type: TypeDef }[] = [];
+            while (this.current()?.token !== "}") {
+                const fname = this.current()!.token;
                 this.advance();
                 this.expect(":");
-                const ptype = this.current()!.token;
-                this.advance();
-                params.push({
-                    name: pname,
-                    type: parseTypeAnnotation(ptype) || { kind: "primitive", primitive: "any" },
-                });
+                fields.push({ name: fname, type: this.parseType() });
                 if (this.current()?.token === ",") this.advance();
             }
-            this.expect(")");
-            this.expect("=>");
-            const returnTypeStr = this.current()!.token;
+            this.expect("}");
+            return { kind: "struct", name, fields };
+        }
+
+        // enum Shape { Circle(float), Rect(float, float), Empty }
+        if (token === "enum") {
+            this.advance();
+            const name = this.current()!.token;
             this.advance();
             this.expect("{");
-            const body = [];
+            const variants: EnumVariant[] = [];
             while (this.current()?.token !== "}") {
-                body.push(this.parseStatement());
+                const vname = this.current()!.token;
+                this.advance();
+                const params: TypeDef[] = [];
+                if (this.current()?.token === "(") {
+                    this.advance();
+                    while (this.current()?.token !== ")") {
+                        params.push(this.parseType());
+                        if (this.current()?.token === ",") this.advance();
+                    }
+                    this.expect(")");
+                }
+                variants.push({ name: vname, params });
+                if (this.current()?.token === ",") this.advance();
             }
             this.expect("}");
+            return { kind: "enum", name, variants };
+        }
+
+        // `func.bind(...)` is a call on the std::func module, not a declaration
+        if (token === "func" && this.tokens[this.pos + 1]?.token !== ".") {
+            this.advance();
+            const name = this.current()!.token;
+            this.advance();
+            const params = this.parseParams();
+            this.expect("=>");
+            const returnType = this.parseType();
+            this.expect("{");
+            const body = this.parseBlockBody();
+            this.expect("}");
             return {
                 kind: "function",
                 name,
                 params,
-                returnType: parseTypeAnnotation(returnTypeStr) || { kind: "primitive", primitive: "any" },
+                returnType,
                 body,
             };
         }
 
         if (token === "return") {
+            const line = this.current().location.line;
             this.advance();
-            const value = this.current() ? this.parseBinary() : undefined;
+            // A bare return ends at the closing brace or the end of its line
+            const next = this.current();
+            const value = next && next.token !== "}" && next.location.line === line ? this.parseBinary() : undefined;
             return { kind: "return", value };
         }
 
@@ -1244,31 +3230,105 @@ class Parser {
             const condition = this.parseBinary();
             this.expect(")");
             this.expect("{");
-            const then = [];
-            while (this.current()?.token !== "}") {
-                then.push(this.parseStatement());
-            }
+            const then = this.parseBlockBody();
+            this.expect("}");
+            if (this.current()?.token !== "else") return { kind: "if", condition, then };
+            this.advance();
+            if (this.current()?.token === "if") return { kind: "if", condition, then, else: [this.parseStatement()] };
+            this.expect("{");
+            const otherwise = this.parseBlockBody();
             this.expect("}");
-            return { kind: "if", condition, then };
+            return { kind: "if", condition, then, else: otherwise };
         }
 
-        if (token === "while") {
+        // defer io.print("done") runs the statement once its block is left,
+        // after any defers that follow it
+        if (token === "defer") {
+            this.advance();
+            return { kind: "defer", body: this.parseStatement() };
+        }
+
+        // with (f = fs.open(path)) { ... } is a block that unwraps the handle
+        // and defers f.close(), so the file is closed however the block is left
+        if (token === "with") {
+            const line = this.current().location.line;
+            this.advance();
+            this.expect("(");
+            const name = this.current()!.token;
+            this.advance();
+            this.expect("=");
+            const value = this.parseBinary();
+            this.expect(")");
+            this.expect("{");
+            const body = this.parseBlockBody();
+            this.expect("}");
+            const handle: Expr = { kind: "call", func: { kind: "identifier", name: "unwrap" }, args: [value], line };
+            const close: Expr = { kind: "call", func: { kind: "member", object: { kind: "identifier", name }, property: "close" }, args: [], line };
+            const opened: Stmt[] = [
+                { kind: "let", name, type: TYPE_REGISTRY.any, value: handle, mutable: false, line },
+                { kind: "defer", body: { kind: "expression", expr: close, line }, line },
+            ];
+            return { kind: "expression", expr: { kind: "block", body: [...opened, ...body], line } };
+        }
+
+        // guard (c) else { return } runs the block, which has to leave, unless c holds
+        if (token === "guard") {
             this.advance();
             this.expect("(");
             const condition = this.parseBinary();
             this.expect(")");
+            this.expect("else");
             this.expect("{");
-            const body = [];
-            while (this.current()?.token !== "}") {
-                body.push(this.parseStatement());
+            const then = this.parseBlockBody();
+            this.expect("}");
+            return { kind: "if", condition: { kind: "unary", op: "!", operand: condition }, then, guard: true };
+        }
+
+        // for (x in iterable) { ... }
+        if (token === "for" && this.tokens[this.pos + 3]?.token === "in") {
+            this.advance();
+            this.expect("(");
+            const variable = this.current()!.token;
+            this.advance();
+            this.expect("in");
+            const iterable = this.parseBinary();
+            this.expect(")");
+            this.expect("{");
+            const body = this.parseBlockBody();
+            this.expect("}");
+            return { kind: "forIn", variable, iterable, body };
+        }
+
+        if (token === "while") {
+            this.advance();
+            this.expect("(");
+            let binding: string | undefined;
+            if (this.current()?.token === "let" && this.tokens[this.pos + 2]?.token === "=") {
+                this.advance();
+                binding = this.current().token;
+                this.advance();
+                this.advance();
             }
+            const condition = this.parseBinary();
+            this.expect(")");
+            this.expect("{");
+            const body = this.parseBlockBody();
             this.expect("}");
-            return { kind: "while", condition, body };
+            return binding === undefined ? { kind: "while", condition, body } : { kind: "while", condition, body, binding };
+        }
+
+        // loop { ... } is while (true); break value is for loops used as values
+        if (token === "loop" && this.tokens[this.pos + 1]?.token === "{") {
+            const { body } = this.parseLoop();
+            return { kind: "while", condition: { kind: "literal", value: true, type: TYPE_REGISTRY.bool }, body };
         }
 
         if (token === "break") {
+            const line = this.current().location.line;
             this.advance();
-            return { kind: "break" };
+            const next = this.current();
+            if (!next || next.token === "}" || next.location.line !== line) return { kind: "break" };
+            return { kind: "break", value: this.parseBinary() };
         }
 
         if (token === "continue") {
@@ -1286,6 +3346,13 @@ class Parser {
             return { kind: "assignment", target, value } as any;
         }
 
+        // Element assignment: arr[i] = value
+        if (this.current()?.token === "=" && expr.kind === "index") {
+            this.advance();
+            const value = this.parseBinary();
+            return { kind: "indexAssignment", object: expr.object, index: expr.index, value };
+        }
+
         return { kind: "expression", expr };
     }
 }
@@ -1294,57 +3361,307 @@ class Parser {
 // TYPE CHECKER
 // ============================================================================
 
+// value is set for consts whose initializer folds at check time; line
+// for locals, which are reported when their scope ends without a use;
+// declaredAt and keyword (let or const) explain a rejected assignment
+interface TypeVar {
+    type: TypeDef;
+    mutable: boolean;
+    value?: any;
+    line?: number;
+    used?: boolean;
+    declaredAt?: number;
+    keyword?: string;
+}
+
+type TypeVars = Map<string, TypeVar>;
+
 interface TypeEnv {
-    vars: Map<string, { type: TypeDef; mutable: boolean }>;
+    vars: TypeVars;
+    // lets further down the block being checked, not yet declared
+    pending?: TypeVars;
+    // the scope of a function body: names pending outside it may be used,
+    // since the function can only run once its caller has got that far
+    function?: boolean;
     functions: Map<string, { params: TypeDef[]; returnType: TypeDef }>;
     parent?: TypeEnv;
 }
 
-class TypeChecker {
+// A checked program for Interpreter.fromTyped: the statements with the
+// checker's annotations, and the module each import name stands for
+export interface TypedProgram {
+    statements: Stmt[];
+    imports: Map<string, string>;
+}
+
+export class TypeChecker {
     private env: TypeEnv = {
         vars: new Map(),
         functions: new Map(),
     };
     private modules: Map<string, TypeEnv> = new Map();
+    private imports: Map<string, string> = new Map();
+    private structs: Map<string, TypeDef> = new Map();
+    private enums: Map<string, EnumVariant[]> = new Map();
+    // Non-fatal findings such as non-exhaustive matches
+    warnings: Diagnostic[] = [];
+    // Line of the statement being checked, the span of any type error
+    // outside an expression, and the imported file it came from
+    private line?: number;
+    private file?: string;
+    // Innermost expression a type error came out of, underlined in its place
+    private errorExpr?: Expr;
+    // Functions whose bodies are being checked, innermost last
+    private functionStack: Extract<Stmt, { kind: "function" }>[] = [];
+    // Enclosing loops of the function being checked, innermost last: the
+    // types of the break values of a loop expression, undefined for the others
+    private loops: (TypeDef[] | undefined)[] = [];
+    // Set by lower: type errors skip the statement and unknown names are any
+    private lenient = false;
 
     check(statements: Stmt[]): void {
+        this.errorExpr = undefined;
+        this.file = undefined;
+        this.loops = [];
+        try {
+            this.checkBlock(statements);
+        } catch (error) {
+            const span = this.errorSpan() ?? (this.line !== undefined ? { line: this.line } : undefined);
+            const reported = isCompilerBug(error)
+                ? internalCompilerError(error, "type checker", span)
+                : asStrataError(error, "E0003", span);
+            reported.diagnostic.file ??= this.file;
+            throw reported;
+        }
+    }
+
+    // The annotations the runners and generators read, int arithmetic above
+    // all, without reporting type errors: what runs in place of the checker
+    // with --no-typecheck and for compiled expressions, so 7 / 2 is 3 either
+    // way. A statement with a type error keeps what was annotated before it
+    lower(statements: Stmt[]): void {
+        this.lenient = true;
+        try {
+            this.check(statements);
+        } catch (error) {
+            // A duplicate declaration, found before any statement is checked
+            if (!(error instanceof StrataError) || error.diagnostic.code === "E0006") throw error;
+        } finally {
+            this.lenient = false;
+        }
+    }
+
+    lowerExpression(expr: Expr): void {
+        this.lenient = true;
+        try {
+            this.inferType(expr);
+        } catch (error) {
+            if (isCompilerBug(error)) throw internalCompilerError(error, "type checker", this.errorSpan());
+        } finally {
+            this.lenient = false;
+        }
+    }
+
+    checkProgram(statements: Stmt[]): TypedProgram {
+        this.check(statements);
+        const opens = (call: Extract<Expr, { kind: "call" }>) =>
+            call.func.kind === "member" && ["open", "create"].includes(call.func.property) &&
+            call.func.object.kind === "identifier" && this.imports.get(call.func.object.name) === "std::fs";
+        for (const call of unclosedFiles(statements, opens)) {
+            this.warn("W0004", message("type.unclosed-file", { where: atLine(call.line) }), call.line);
+        }
+        return { statements, imports: new Map(this.imports) };
+    }
+
+    // Type of an expression in the scope checked so far, for the REPL's :type
+    typeOf(expr: Expr): TypeDef {
+        this.errorExpr = undefined;
+        try {
+            return this.inferType(expr);
+        } catch (error) {
+            if (isCompilerBug(error)) throw internalCompilerError(error, "type checker", this.errorSpan());
+            throw asStrataError(error, "E0003", this.errorSpan());
+        }
+    }
+
+    // Only the first line of an expression spread over several is underlined
+    private errorSpan(): Span | undefined {
+        const range = this.errorExpr?.span;
+        return range && { line: range.line, column: range.column, length: range.end - range.start };
+    }
+
+    private warn(code: string, message: string, line?: number, notes: string[] = []): void {
+        const span = line !== undefined ? { line } : undefined;
+        this.warnings.push({ code, severity: "warning", message, span, notes, file: this.file });
+    }
+
+    // text.toUpperCase(s) still works, with a warning naming text.upper
+    private warnDeprecated(call: Extract<Expr, { kind: "call" }>, member: Extract<Expr, { kind: "member" }>): void {
+        const module = this.stdlibModule(member.object);
+        const deprecation = module ? STDLIB_DEPRECATIONS[module]?.[member.property] : undefined;
+        if (!deprecation || member.object.kind !== "identifier") return;
+        const params = {
+            name: `${member.object.name}.${member.property}`,
+            replacement: `${member.object.name}.${deprecation.replacement}`,
+            since: deprecation.since,
+            where: atLine(call.line),
+        };
+        const notes = [...(deprecation.note ? [deprecation.note] : []), message("type.deprecated-fix")];
+        this.warn("W0005", message("type.deprecated", params), call.line, notes);
+    }
+
+    // Function signatures, structs and enums are registered before any body
+    // is checked so they can be used regardless of declaration order
+    private checkBlock(statements: Stmt[]): void {
+        for (const stmt of statements) {
+            if (stmt.kind === "function") this.declareFunction(stmt);
+            if (stmt.kind === "struct") this.declareStruct(stmt);
+            if (stmt.kind === "enum") this.declareEnum(stmt);
+        }
+        this.env.pending = new Map();
+        this.declarePending(statements);
+        for (const stmt of statements) {
+            if (!this.lenient) {
+                this.checkStatement(stmt);
+                continue;
+            }
+            try {
+                this.checkStatement(stmt);
+            } catch (error) {
+                if (isCompilerBug(error)) throw error;
+            }
+        }
+    }
+
+    // lets in the block and in the if and loop bodies that share its scope
+    private declarePending(statements: Stmt[]): void {
         for (const stmt of statements) {
-            this.checkStatement(stmt);
+            if (stmt.kind === "let") this.env.pending!.set(stmt.name, { type: stmt.type, mutable: stmt.mutable });
+            if (stmt.kind === "if" || stmt.kind === "while" || stmt.kind === "for" || stmt.kind === "forIn") {
+                this.declarePending(childStatements(stmt));
+            }
+        }
+    }
+
+    private declareFunction(stmt: Extract<Stmt, { kind: "function" }>): void {
+        this.env.functions.set(stmt.name, {
+            params: stmt.params.map((p) => p.type),
+            returnType: stmt.returnType,
+        });
+        this.env.vars.set(stmt.name, {
+            type: {
+                kind: "function",
+                params: stmt.params.map((p) => p.type),
+                returnType: stmt.returnType,
+            },
+            mutable: false,
+            declaredAt: stmt.line,
+        });
+    }
+
+    private declareStruct(stmt: Extract<Stmt, { kind: "struct" }>): void {
+        const fields: Record<string, TypeDef> = {};
+        for (const field of stmt.fields) {
+            if (field.name in fields) {
+                throw new Error(message("type.duplicate-struct-field", { field: field.name, struct: stmt.name }));
+            }
+            fields[field.name] = field.type;
+        }
+        this.structs.set(stmt.name, { kind: "struct", name: stmt.name, fields });
+    }
+
+    private declareEnum(stmt: Extract<Stmt, { kind: "enum" }>): void {
+        const names = new Set<string>();
+        for (const variant of stmt.variants) {
+            if (names.has(variant.name)) {
+                throw new Error(message("type.duplicate-variant", { variant: variant.name, enum: stmt.name }));
+            }
+            names.add(variant.name);
         }
+        this.enums.set(stmt.name, stmt.variants);
     }
 
     private checkStatement(stmt: Stmt): void {
+        if (stmt.line !== undefined) {
+            this.line = stmt.line;
+            this.file = stmt.file;
+        }
         switch (stmt.kind) {
             case "let":
-                this.env.vars.set(stmt.name, {
+                stmt.type = this.resolveType(stmt.type);
+                const folded = stmt.constant ? this.constValue(stmt.value) : undefined;
+                if (stmt.exported && !folded) {
+                    throw new Error(message("type.pub-const", { name: stmt.name }));
+                }
+                // The initializer still sees what the name meant before
+                this.checkExpression(stmt.value, stmt.type);
+                const declared: TypeVar = this.env.pending?.get(stmt.name) ?? { type: stmt.type, mutable: stmt.mutable };
+                this.env.pending?.delete(stmt.name);
+                Object.assign(declared, {
                     type: stmt.type,
                     mutable: stmt.mutable,
+                    value: folded?.value,
+                    declaredAt: stmt.line,
+                    keyword: stmt.constant ? "const" : stmt.mutable ? "var" : "let",
                 });
-                this.checkExpression(stmt.value, stmt.type);
+                // Top-level bindings may be read by hosts through globals()
+                if (this.env.parent) declared.line = stmt.line;
+                this.env.vars.set(stmt.name, declared);
                 break;
+            case "assignment": {
+                const target = this.variable(stmt.target, false);
+                if (target && !target.mutable) throw this.immutableAssignment(stmt.target, target);
+                this.inferType(stmt.value);
+                break;
+            }
             case "function":
-                this.env.functions.set(stmt.name, {
-                    params: stmt.params.map((p) => p.type),
-                    returnType: stmt.returnType,
-                });
-                const oldEnv = this.env;
-                this.env = { vars: new Map(), functions: new Map(), parent: oldEnv };
+                this.declareFunction(stmt);
+                const params: TypeVars = new Map();
                 for (const param of stmt.params) {
-                    this.env.vars.set(param.name, {
+                    params.set(param.name, {
                         type: param.type,
                         mutable: false,
+                        declaredAt: stmt.line,
                     });
                 }
-                for (const s of stmt.body) {
-                    this.checkStatement(s);
+                this.functionStack.push(stmt);
+                const outerLoops = this.loops;
+                this.loops = [];
+                try {
+                    this.inScope(params, () => this.checkBlock(stmt.body), true);
+                } finally {
+                    this.functionStack.pop();
+                    this.loops = outerLoops;
                 }
-                this.env = oldEnv;
+                if (!isVoidOrAny(stmt.returnType) && !alwaysReturns(stmt.body)) {
+                    this.line = stmt.line;
+                    throw new Error(
+                        message("type.missing-return", { name: stmt.name, type: formatType(stmt.returnType) })
+                    );
+                }
+                break;
+            case "test": {
+                const outerLoops = this.loops;
+                this.loops = [];
+                try {
+                    this.inScope(new Map(), () => this.checkBlock(stmt.body));
+                } finally {
+                    this.loops = outerLoops;
+                }
+                break;
+            }
+            case "return":
+                this.checkReturn(stmt.value);
                 break;
             case "if":
                 this.checkExpression(stmt.condition, { kind: "primitive", primitive: "bool" });
                 for (const s of stmt.then) {
                     this.checkStatement(s);
                 }
+                if (stmt.guard && !alwaysReturns(stmt.then, this.loops.length > 0)) {
+                    this.line = stmt.line;
+                    throw new Error(message(this.loops.length > 0 ? "type.guard-exit-loop" : "type.guard-exit", { where: atLine(stmt.line) }));
+                }
                 if (stmt.else) {
                     for (const s of stmt.else) {
                         this.checkStatement(s);
@@ -1352,844 +3669,9680 @@ class TypeChecker {
                 }
                 break;
             case "while":
-                this.checkExpression(stmt.condition, { kind: "primitive", primitive: "bool" });
-                for (const s of stmt.body) {
-                    this.checkStatement(s);
+                if (stmt.binding !== undefined) {
+                    this.checkWhileLet(stmt);
+                } else {
+                    this.checkExpression(stmt.condition, { kind: "primitive", primitive: "bool" });
+                }
+                this.checkLoopBody(stmt.body, undefined);
+                break;
+            case "forIn":
+                const iterableType = this.inferType(stmt.iterable);
+                this.env.vars.set(stmt.variable, {
+                    // for-in over a map visits its keys
+                    type: iterableType.kind === "map" ? iterableType.keyType! : this.elementType(iterableType),
+                    mutable: true,
+                    line: this.env.parent ? stmt.line : undefined,
+                });
+                this.checkLoopBody(stmt.body, undefined);
+                break;
+            case "defer":
+                // The deferred statement already runs on the way out
+                if (leavesBlock([stmt.body])) {
+                    throw new Error(message("type.defer-exit", { where: atLine(this.line) }));
+                }
+                this.checkStatement(stmt.body);
+                break;
+            case "continue":
+                if (this.loops.length === 0) throw new Error(message("type.loop-control", { keyword: "continue", where: atLine(this.line) }));
+                break;
+            case "break":
+                if (this.loops.length === 0 && !stmt.value) throw new Error(message("type.loop-control", { keyword: "break", where: atLine(this.line) }));
+                if (stmt.value) {
+                    const values = this.loops[this.loops.length - 1];
+                    if (!values) throw new Error(message("type.break-value", { where: atLine(this.line) }));
+                    values.push(this.inferType(stmt.value));
+                } else {
+                    this.loops[this.loops.length - 1]?.push({ kind: "primitive", primitive: "void" });
                 }
                 break;
+            case "indexAssignment":
+                const containerType = this.inferType(stmt.object);
+                this.checkExpression(stmt.index, this.keyType(containerType));
+                this.checkExpression(stmt.value, this.elementType(containerType));
+                break;
+            case "match":
+                this.checkMatch(stmt);
+                break;
             case "expression":
                 this.checkExpression(stmt.expr, { kind: "primitive", primitive: "any" });
                 break;
             case "import":
+                this.imports.set(stmt.name, stmt.module);
                 break;
         }
     }
 
+    // breaks collects the types of the break values of a loop expression
+    private checkLoopBody(body: Stmt[], breaks: TypeDef[] | undefined): void {
+        this.loops.push(breaks);
+        try {
+            for (const s of body) {
+                this.checkStatement(s);
+            }
+        } finally {
+            this.loops.pop();
+        }
+    }
+
+    // Only var bindings can be reassigned; the notes point at the declaration
+    private immutableAssignment(name: string, target: TypeVar): StrataError {
+        const notes: string[] = [];
+        if (target.declaredAt !== undefined) notes.push(message("type.declared-at", { name, line: target.declaredAt }));
+        if (target.keyword === "let") notes.push(message("type.var-note", { name }));
+        return errorAt("E0003", message("runtime.immutable", { name }), this.line !== undefined ? { line: this.line } : undefined, notes);
+    }
+
+    // while (let x = e) needs an optional e; x is its value, narrowed to the inner type
+    private checkWhileLet(stmt: Extract<Stmt, { kind: "while" }>): void {
+        const type = this.inferType(stmt.condition);
+        if (type.kind !== "optional" && type.primitive !== "any") {
+            throw new Error(message("type.while-let", { name: stmt.binding, type: formatType(type), where: atLine(stmt.line) }));
+        }
+        stmt.bindingType = type.kind === "optional" ? type.innerType! : type;
+        this.env.vars.set(stmt.binding!, {
+            type: stmt.bindingType,
+            mutable: false,
+            declaredAt: stmt.line,
+            line: this.env.parent ? stmt.line : undefined,
+        });
+    }
+
+    // return values must match the enclosing function's declared type
+    // The body is checked as a function's, with the enclosing variables in
+    // scope, and its type becomes the return type
+    private checkLambda(fn: Extract<Stmt, { kind: "function" }>): TypeDef {
+        const params: TypeVars = new Map(fn.params.map((p) => [p.name, { type: p.type, mutable: false, declaredAt: fn.line }]));
+        const body = fn.body[0] as Extract<Stmt, { kind: "return" }>;
+        this.functionStack.push(fn);
+        const outerLoops = this.loops;
+        this.loops = [];
+        try {
+            this.inScope(params, () => {
+                fn.returnType = this.inferType(body.value!);
+            }, true);
+        } finally {
+            this.functionStack.pop();
+            this.loops = outerLoops;
+        }
+        return { kind: "function", params: fn.params.map((p) => p.type), returnType: fn.returnType };
+    }
+
+    // The function an Err or None leaves through has to be declared to
+    // return one; an Ok's value is any, as result doesn't say its type
+    private propagateType(expr: Extract<Expr, { kind: "propagate" }>): TypeDef {
+        const type = this.inferType(expr.value);
+        const optional = type.kind === "optional" || type.primitive === "option";
+        if (!optional && type.primitive !== "result" && type.primitive !== "any") {
+            throw new Error(message("type.propagate-operand", { type: formatType(type) }));
+        }
+        const fn = this.functionStack[this.functionStack.length - 1];
+        if (!fn) throw new Error(message("type.propagate-outside"));
+        const returned: TypeDef = optional ? { kind: "primitive", primitive: "null" } : TYPE_REGISTRY.result;
+        if (type.primitive !== "any" && !typeCompatible(returned, fn.returnType)) {
+            const id = optional ? "type.propagate-none" : "type.propagate-err";
+            throw new Error(message(id, { name: fn.name, type: formatType(fn.returnType) }));
+        }
+        return type.kind === "optional" ? type.innerType! : TYPE_REGISTRY.any;
+    }
+
+    private checkReturn(value: Expr | undefined): void {
+        const fn = this.functionStack[this.functionStack.length - 1];
+        if (!fn) {
+            if (value) this.inferType(value);
+            return;
+        }
+        const expected = fn.returnType;
+        if (!value) {
+            if (!isVoidOrAny(expected)) {
+                throw new Error(message("type.empty-return", { name: fn.name, type: formatType(expected) }));
+            }
+            return;
+        }
+        const actual = this.inferType(value);
+        if (expected.primitive === "void") {
+            throw new Error(message("type.void-return", { name: fn.name, type: formatType(actual) }));
+        }
+        if (!typeCompatible(actual, expected)) {
+            throw new Error(
+                message("type.return-mismatch", { name: fn.name, expected: formatType(expected), actual: formatType(actual) })
+            );
+        }
+    }
+
     private checkExpression(expr: Expr, expectedType: TypeDef): void {
         const actualType = this.inferType(expr);
         if (!typeCompatible(actualType, expectedType)) {
-            throw new Error(
-                `Type mismatch: expected ${JSON.stringify(expectedType)}, got ${JSON.stringify(actualType)}`
-            );
+            this.errorExpr ??= expr;
+            const mismatch = message("type.mismatch", { expected: formatType(expectedType), actual: formatType(actualType) });
+            if (actualType.primitive === "null") {
+                throw errorAt("E0003", mismatch, this.errorSpan(), [message("type.null-note", { type: formatSuffixed(expectedType) })]);
+            }
+            throw new Error(mismatch);
         }
     }
 
     private inferType(expr: Expr): TypeDef {
+        try {
+            return this.inferExprType(expr);
+        } catch (error) {
+            this.errorExpr ??= expr;
+            throw error;
+        }
+    }
+
+    private inferExprType(expr: Expr): TypeDef {
         switch (expr.kind) {
             case "literal":
                 return expr.type;
             case "identifier":
-                return this.env.vars.get(expr.name)?.type || { kind: "primitive", primitive: "any" };
+                return this.variable(expr.name, true)?.type ?? { kind: "primitive", primitive: "any" };
             case "binary":
-                if (["==", "!=", "<", ">", "<=", ">=", "&&", "||"].includes(expr.op)) {
+                if (expr.op === "in" || expr.op === "not in") {
+                    this.checkMembership(expr.left, expr.right);
                     return { kind: "primitive", primitive: "bool" };
                 }
-                return this.inferType(expr.left);
-            case "unary":
+                const leftType = this.inferType(expr.left);
+                const rightType = this.inferType(expr.right);
+                if (INTEGER_OPS.has(expr.op) && leftType.primitive === "int" && rightType.primitive === "int") {
+                    expr.integer = true;
+                }
+                return this.binaryType(expr.op, leftType, rightType);
+            case "unary": {
                 if (expr.op === "!") return { kind: "primitive", primitive: "bool" };
-                return this.inferType(expr.operand);
+                const operandType = this.inferType(expr.operand);
+                // Only -INT_MIN overflows, and no int literal is INT_MIN
+                if (expr.op === "-" && operandType.primitive === "int" && expr.operand.kind !== "literal") expr.integer = true;
+                return operandType;
+            }
+            case "propagate":
+                return this.propagateType(expr);
+            case "call":
+                return this.checkCall(expr);
+            case "field":
+                const constant = this.moduleConstant(expr);
+                if (constant) return constant.type;
+                const variant = this.enumVariant(expr.object, expr.name);
+                if (variant) {
+                    if (variant.params.length > 0) {
+                        throw new Error(message("type.variant-payload", { enum: variant.enum, variant: expr.name }));
+                    }
+                    return { kind: "enum", name: variant.enum };
+                }
+                return this.fieldType(this.inferType(expr.object), expr.name);
+            case "structLiteral":
+                return this.checkStructLiteral(expr);
+            case "match":
+                return (expr.type = this.commonType(this.checkMatch(expr)));
+            case "if": {
+                this.checkExpression(expr.condition, { kind: "primitive", primitive: "bool" });
+                const then = this.checkValueBlock(expr.then);
+                const otherwise = this.checkValueBlock(expr.else);
+                if (!typeCompatible(then, otherwise) && !typeCompatible(otherwise, then)) {
+                    throw new Error(message("type.if-branches", { then: formatType(then), else: formatType(otherwise), where: atLine(expr.line) }));
+                }
+                return (expr.type = typeCompatible(then, otherwise) ? otherwise : then);
+            }
+            case "block":
+                return (expr.type = this.checkValueBlock(expr.body));
+            case "loop": {
+                const breaks: TypeDef[] = [];
+                this.checkLoopBody(expr.body, breaks);
+                return (expr.type = breaks.length > 0 ? this.commonType(breaks) : { kind: "primitive", primitive: "void" });
+            }
+            case "range":
+                const int: TypeDef = { kind: "primitive", primitive: "int" };
+                for (const bound of [expr.start, expr.end, expr.step]) {
+                    if (bound) this.checkExpression(bound, int);
+                }
+                return { kind: "primitive", primitive: "range" };
+            case "lambda":
+                return this.checkLambda(expr.fn);
+            case "index":
+                const objectType = this.inferType(expr.object);
+                const indexType = this.inferType(expr.index);
+                // Slicing keeps the container type: s[1..3] is a string
+                if (indexType.primitive === "range") {
+                    return objectType.kind === "array" ? { ...objectType, size: undefined } : objectType;
+                }
+                this.checkExpression(expr.index, this.keyType(objectType));
+                return this.elementType(objectType);
+            case "array":
+                return this.inferArrayType(expr.elements);
+            case "map":
+                return {
+                    kind: "map",
+                    keyType: this.commonType(expr.entries.map((e) => this.inferType(e.key))),
+                    valueType: this.commonType(expr.entries.map((e) => this.inferType(e.value))),
+                };
             default:
                 return { kind: "primitive", primitive: "any" };
         }
     }
-}
-
-// ============================================================================
-// INTERPRETER
-// ============================================================================
-
-interface ControlFlow {
-    type: "return" | "break" | "continue" | null;
-    value?: any;
-}
 
-class Environment {
-    private vars: Map<string, { value: any; mutable: boolean }> = new Map();
-    private functions: Map<string, { params: string[]; body: Stmt[] }> = new Map();
-    private modules: Map<string, any> = new Map();
-    public parent: Environment | null = null;
-
-    set(name: string, value: any, mutable = false): void {
-        this.vars.set(name, { value, mutable });
+    // Text (string or char) only combines with text: + concatenates and the
+    // comparisons compare; every other mix of text and operators is an error
+    private binaryType(op: string, left: TypeDef, right: TypeDef): TypeDef {
+        const bool: TypeDef = { kind: "primitive", primitive: "bool" };
+        const isText = (t: TypeDef) => t.primitive === "string" || t.primitive === "char";
+        const isAny = (t: TypeDef) => t.primitive === "any";
+        if (op === "??") return this.coalesceType(left, right);
+        // An optional has to be unwrapped first; it can only be compared
+        const optional = [left, right].find((t) => t.kind === "optional" || t.primitive === "null");
+        if (optional && op !== "==" && op !== "!=") {
+            throw new Error(message("type.optional-operand", { op, type: formatType(optional) }));
+        }
+        if ((isText(left) || isText(right)) && !isAny(left) && !isAny(right)) {
+            const bothText = isText(left) && isText(right);
+            const textOps = ["+", "==", "!=", "<", ">", "<=", ">="];
+            if (!bothText || !textOps.includes(op)) {
+                throw new Error(
+                    message("type.operator", { op, left: formatType(left), right: formatType(right) })
+                );
+            }
+            return op === "+" ? { kind: "primitive", primitive: "string" } : bool;
+        }
+        const isDate = (t: TypeDef) => t.primitive === "datetime";
+        if ((isDate(left) || isDate(right)) && !isAny(left) && !isAny(right)) {
+            if (!isDate(left) || !isDate(right) || !["==", "!=", "<", ">", "<=", ">="].includes(op)) {
+                throw new Error(
+                    message("type.operator", { op, left: formatType(left), right: formatType(right) })
+                );
+            }
+            return bool;
+        }
+        if (["==", "!=", "<", ">", "<=", ">=", "&&", "||"].includes(op)) return bool;
+        // int with float is float arithmetic
+        if (!isAny(left) && !isAny(right) && (left.primitive === "float" || right.primitive === "float")) {
+            return { kind: "primitive", primitive: "float" };
+        }
+        return left;
     }
 
-    get(name: string): any {
-        if (this.vars.has(name)) {
-            return this.vars.get(name)!.value;
+    // x ?? fallback is x's value when it has one; with a fallback of the
+    // inner type the result is no longer optional
+    private coalesceType(left: TypeDef, right: TypeDef): TypeDef {
+        if (left.primitive === "any" || left.primitive === "null" || right.primitive === "any") return right;
+        if (left.kind !== "optional") {
+            throw new Error(message("type.coalesce", { type: formatType(left) }));
         }
-        if (this.parent) return this.parent.get(name);
-        throw new Error(`Undefined variable: ${name}`);
+        if (typeCompatible(right, left.innerType!)) return left.innerType!;
+        if (typeCompatible(right, left)) return left;
+        throw new Error(message("type.mismatch", { expected: formatType(left.innerType!), actual: formatType(right) }));
     }
 
-    update(name: string, value: any): void {
-        if (this.vars.has(name)) {
-            const entry = this.vars.get(name)!;
-            if (!entry.mutable) {
-                throw new Error(`Cannot reassign immutable variable: ${name}`);
-            }
-            entry.value = value;
-            return;
-        }
-        if (this.parent) {
-            this.parent.update(name, value);
-            return;
-        }
-        throw new Error(`Undefined variable: ${name}`);
+    // math through an import, or int and char as type namespaces
+    private stdlibModule(object: Expr): string | undefined {
+        if (object.kind !== "identifier") return undefined;
+        return this.imports.get(object.name) ?? (this.lookupVar(object.name) ? undefined : object.name);
     }
 
-    setFunction(name: string, params: string[], body: Stmt[]): void {
-        this.functions.set(name, { params, body });
+    // math.PI, int.MAX
+    private moduleConstant(expr: Extract<Expr, { kind: "field" }>): ModuleConstant | undefined {
+        const module = this.stdlibModule(expr.object);
+        return module ? STDLIB_CONSTANTS[module]?.[expr.name] : undefined;
     }
 
-    getFunction(name: string): { params: string[]; body: Stmt[] } | null {
-        if (this.functions.has(name)) {
-            return this.functions.get(name)!;
-        }
-        if (this.parent) return this.parent.getFunction(name);
-        return null;
+    // math.checkedAdd(...), char.isDigit(...)
+    private moduleSignature(member: Extract<Expr, { kind: "member" }>): TypeDef | undefined {
+        const module = this.stdlibModule(member.object);
+        return module ? STDLIB_SIGNATURES[module]?.[member.property] : undefined;
     }
 
-    setModule(name: string, module: any): void {
-        this.modules.set(name, module);
+    // Color.Red, or Shape.Circle in Shape.Circle(2.0), unless Color or
+    // Shape is a variable
+    private enumVariant(object: Expr, name: string): (EnumVariant & { enum: string }) | undefined {
+        if (object.kind !== "identifier" || this.lookupVar(object.name)) return undefined;
+        const variants = this.enums.get(object.name);
+        if (!variants) return undefined;
+        const variant = variants.find((v) => v.name === name);
+        if (!variant) throw new Error(message("type.unknown-variant", { variant: name, enum: object.name }));
+        return { ...variant, enum: object.name };
     }
 
-    getModule(name: string): any {
-        if (this.modules.has(name)) {
-            return this.modules.get(name);
+    // Shape.Circle(2.0) calls a constructor taking the variant's payload
+    private variantConstructor(member: Extract<Expr, { kind: "member" }>): TypeDef | undefined {
+        const variant = this.enumVariant(member.object, member.property);
+        if (!variant) return undefined;
+        if (variant.params.length === 0) {
+            throw new Error(message("type.variant-call", { enum: variant.enum, variant: variant.name }));
         }
-        if (this.parent) return this.parent.getModule(name);
-        return null;
+        return { kind: "function", params: variant.params, returnType: { kind: "enum", name: variant.enum } };
     }
-}
 
-class Interpreter {
-    private env: Environment = new Environment();
-    private controlFlow: ControlFlow = { type: null };
+    // Compile-time value of literals, consts, module constants and
+    // arithmetic over them; undefined when the expression is not constant
+    private constValue(expr: Expr): { value: any } | undefined {
+        switch (expr.kind) {
+            case "literal":
+                return { value: expr.value };
+            case "identifier":
+                const entry = this.lookupVar(expr.name);
+                return entry?.value !== undefined ? { value: entry.value } : undefined;
+            case "field":
+                const constant = this.moduleConstant(expr);
+                return constant ? { value: constant.value } : undefined;
+            case "unary":
+                const operand = this.constValue(expr.operand);
+                return operand ? { value: applyUnaryOp(expr.op, operand.value) } : undefined;
+            case "binary":
+                const left = this.constValue(expr.left);
+                const right = left && this.constValue(expr.right);
+                return left && right ? { value: applyBinaryOp(expr.op, left.value, right.value) } : undefined;
+            default:
+                return undefined;
+        }
+    }
 
-    constructor() {
-        this.setupStdlib();
+    // Folds constant array sizes: int[N] becomes int[3] when N is a const
+    private resolveType(type: TypeDef): TypeDef {
+        if (type.kind !== "array") return type;
+        const innerType = this.resolveType(type.innerType!);
+        if (!type.sizeExpr) return { ...type, innerType };
+        const size = this.constValue(type.sizeExpr)?.value;
+        if (!Number.isInteger(size) || size < 0) {
+            throw new Error(message("type.array-size"));
+        }
+        return { kind: "array", innerType, size };
     }
 
-    private setupStdlib(): void {
-        // I/O Module
-        const ioModule = {
-            print: (value: any) => { console.log(value); return null; },
-            println: (value: any) => { console.log(value); return null; },
-        };
-        this.env.setModule("std::io", ioModule);
-        this.env.setModule("str", ioModule); // Alias for std::io
+    // Checks patterns against the subject and each arm in its own scope;
+    // returns the type of each arm's trailing expression
+    private checkMatch(match: { subject: Expr; arms: MatchArm[]; line?: number }): TypeDef[] {
+        const subjectType = this.inferType(match.subject);
+        const where = atLine(match.line);
+        const armTypes: TypeDef[] = [];
+        let irrefutable = false;
+        const seen = new Set<any>();
+        const variants = subjectType.kind === "enum" ? this.enums.get(subjectType.name!) : undefined;
+        for (const arm of match.arms) {
+            if (irrefutable) {
+                this.warn("W0001", message("type.unreachable-arm", { where }), match.line);
+            }
+            const bindings: TypeVars = new Map();
+            if (arm.pattern.kind === "literal") {
+                if (!typeCompatible(arm.pattern.type, subjectType)) {
+                    throw new Error(
+                        message("type.pattern", {
+                            pattern: formatValue(arm.pattern.value),
+                            type: formatType(arm.pattern.type),
+                            subject: formatType(subjectType),
+                            where,
+                        })
+                    );
+                }
+                seen.add(arm.pattern.value);
+            } else if (arm.pattern.kind === "variant") {
+                const variant = this.patternVariant(arm.pattern, subjectType, where);
+                arm.pattern.bindings.forEach((name, i) => {
+                    if (name !== "_") bindings.set(name, { type: variant.params[i], mutable: false, declaredAt: match.line });
+                });
+                seen.add(variant.name);
+            } else {
+                irrefutable = true;
+                if (arm.pattern.kind === "binding") {
+                    bindings.set(arm.pattern.name, { type: subjectType, mutable: false, declaredAt: match.line });
+                }
+            }
+            armTypes.push(this.checkValueBlock(arm.body, bindings));
+            // Arms after ones for every variant can't be reached either
+            if (variants?.every((v) => seen.has(v.name))) irrefutable = true;
+        }
+        if (variants && !irrefutable) {
+            const missing = variants.filter((v) => !seen.has(v.name)).map((v) => `${subjectType.name}.${v.name}`);
+            this.line = match.line ?? this.line;
+            throw new Error(message("type.missing-variants", { enum: subjectType.name, variants: missing.join(", "), where }));
+        }
+        const coversBool = subjectType.primitive === "bool" && seen.has(true) && seen.has(false);
+        if (!irrefutable && !coversBool) {
+            this.warn("W0002", message("type.non-exhaustive", { where }), match.line);
+        }
+        return armTypes;
+    }
 
-        // Math Module (Python, R, C, C++)
-        this.env.setModule("std::math", {
-            sqrt: (x: number) => Math.sqrt(x),
-            sin: (x: number) => Math.sin(x),
-            cos: (x: number) => Math.cos(x),
-            tan: (x: number) => Math.tan(x),
-            asin: (x: number) => Math.asin(x),
-            acos: (x: number) => Math.acos(x),
-            atan: (x: number) => Math.atan(x),
-            exp: (x: number) => Math.exp(x),
-            log: (x: number) => Math.log(x),
-            log10: (x: number) => Math.log10(x),
-            log2: (x: number) => Math.log2(x),
-            floor: (x: number) => Math.floor(x),
-            ceil: (x: number) => Math.ceil(x),
-            round: (x: number) => Math.round(x),
-            abs: (x: number) => Math.abs(x),
-            pow: (x: number, y: number) => Math.pow(x, y),
-            max: (...args: number[]) => Math.max(...args),
-            min: (...args: number[]) => Math.min(...args),
-            gcd: (a: number, b: number) => { let x = Math.abs(a), y = Math.abs(b); while (y) [x, y] = [y, x % y]; return x; },
-            PI: Math.PI,
-            E: Math.E,
-        });
+    // The variant a Color.Red or Shape.Circle(r) pattern names, checked
+    // against the subject and the size of its payload
+    private patternVariant(pattern: Extract<Pattern, { kind: "variant" }>, subjectType: TypeDef, where: string): EnumVariant {
+        const variants = this.enums.get(pattern.enum);
+        if (!variants) throw new Error(message("type.unknown-enum", { name: pattern.enum }));
+        const type: TypeDef = { kind: "enum", name: pattern.enum };
+        if (!typeCompatible(type, subjectType)) {
+            const text = `${pattern.enum}.${pattern.variant}`;
+            throw new Error(message("type.pattern", { pattern: text, type: pattern.enum, subject: formatType(subjectType), where }));
+        }
+        const variant = variants.find((v) => v.name === pattern.variant);
+        if (!variant) throw new Error(message("type.unknown-variant", { variant: pattern.variant, enum: pattern.enum }));
+        if (pattern.bindings.length !== variant.params.length) {
+            throw new Error(
+                message("type.variant-bindings", {
+                    enum: pattern.enum,
+                    variant: variant.name,
+                    actual: pattern.bindings.length,
+                    expected: variant.params.length,
+                    where,
+                })
+            );
+        }
+        return variant;
+    }
 
-        // String/Text Module (Python, Ruby, JavaScript)
-        this.env.setModule("std::text", {
-            split: (s: string, sep: string) => s.split(sep),
-            join: (arr: string[], sep: string) => arr.join(sep),
-            trim: (s: string) => s.trim(),
-            toUpperCase: (s: string) => s.toUpperCase(),
-            toLowerCase: (s: string) => s.toLowerCase(),
-            startsWith: (s: string, prefix: string) => s.startsWith(prefix),
-            endsWith: (s: string, suffix: string) => s.endsWith(suffix),
-            includes: (s: string, substr: string) => s.includes(substr),
-            indexOf: (s: string, substr: string) => s.indexOf(substr),
-            replace: (s: string, old: string, newStr: string) => s.replace(old, newStr),
-            replaceAll: (s: string, old: string, newStr: string) => s.replaceAll(old, newStr),
-            substring: (s: string, start: number, end?: number) => s.substring(start, end),
-            substr: (s: string, start: number, length?: number) => s.substr(start, length),
-            slice: (s: string, start: number, end?: number) => s.slice(start, end),
-            repeat: (s: string, count: number) => s.repeat(count),
-            length: (s: string) => s.length,
-            charAt: (s: string, index: number) => s.charAt(index),
-            charCodeAt: (s: string, index: number) => s.charCodeAt(index),
+    // The statements of a block in their own scope; the type of its last expression, else void
+    private checkValueBlock(body: Stmt[], bindings: TypeVars = new Map()): TypeDef {
+        let type: TypeDef = { kind: "primitive", primitive: "void" };
+        this.inScope(bindings, () => {
+            this.checkBlock(body);
+            const value = blockValue(body);
+            if (value) type = this.inferType(value);
         });
+        return type;
+    }
 
-        // Array/List Module (Python, JavaScript, Go, Rust)
-        this.env.setModule("std::list", {
-            map: (arr: any[], fn: any) => arr.map(fn),
-            filter: (arr: any[], fn: any) => arr.filter(fn),
-            reduce: (arr: any[], fn: any, init?: any) => arr.reduce(fn, init),
-            forEach: (arr: any[], fn: any) => { arr.forEach(fn); },
-            find: (arr: any[], fn: any) => arr.find(fn),
-            findIndex: (arr: any[], fn: any) => arr.findIndex(fn),
-            some: (arr: any[], fn: any) => arr.some(fn),
-            every: (arr: any[], fn: any) => arr.every(fn),
-            includes: (arr: any[], item: any) => arr.includes(item),
-            indexOf: (arr: any[], item: any) => arr.indexOf(item),
-            push: (arr: any[], item: any) => { arr.push(item); return arr; },
-            pop: (arr: any[]) => arr.pop(),
-            shift: (arr: any[]) => arr.shift(),
-            unshift: (arr: any[], item: any) => { arr.unshift(item); return arr; },
-            reverse: (arr: any[]) => { arr.reverse(); return arr; },
-            sort: (arr: any[], fn?: any) => { arr.sort(fn); return arr; },
-            concat: (arr: any[], ...others: any[]) => arr.concat(...others),
-            flat: (arr: any[], depth?: number) => arr.flat(depth),
-            length: (arr: any[]) => arr.length,
-        });
+    // Scopes stack up where the interpreter gives code a new Environment: a
+    // function body over its parameters, a match arm, a block used as a value.
+    // The outer scope is back in place even when checking throws, so a REPL
+    // input with a type error leaves no parameters or locals behind
+    private inScope(vars: TypeVars, check: () => void, isFunction = false): void {
+        const outer = this.env;
+        this.env = { vars, functions: new Map(), parent: outer, function: isFunction };
+        try {
+            check();
+            for (const [name, entry] of vars) {
+                if (entry.line !== undefined && !entry.used && !name.startsWith("_")) {
+                    this.warn("W0003", message("type.unused-variable", { name, where: atLine(entry.line) }), entry.line);
+                }
+            }
+        } finally {
+            this.env = outer;
+        }
+    }
 
-        // Dictionary/Map Module (Python, JavaScript, Go, Rust)
-        this.env.setModule("std::map", {
-            keys: (obj: any) => Object.keys(obj),
-            values: (obj: any) => Object.values(obj),
-            entries: (obj: any) => Object.entries(obj),
-            has: (obj: any, key: string) => key in obj,
-            get: (obj: any, key: string) => obj[key],
-            set: (obj: any, key: string, value: any) => { obj[key] = value; return obj; },
-            delete: (obj: any, key: string) => { delete obj[key]; return obj; },
-            clear: (obj: any) => { for (let k in obj) delete obj[k]; return obj; },
-            length: (obj: any) => Object.keys(obj).length,
-            assign: (target: any, ...sources: any[]) => Object.assign(target, ...sources),
-        });
+    // Point { x: 1, y: 2 } must set every field exactly once
+    private checkStructLiteral(expr: Extract<Expr, { kind: "structLiteral" }>): TypeDef {
+        const struct = this.structs.get(expr.name);
+        if (!struct) throw new Error(message("type.unknown-struct", { name: expr.name }));
+        const given = new Set<string>();
+        for (const field of expr.fields) {
+            const expected = struct.fields![field.name];
+            if (!expected) throw new Error(message("type.unknown-field", { field: field.name, type: expr.name }));
+            if (given.has(field.name)) throw new Error(message("type.duplicate-field", { field: field.name, struct: expr.name }));
+            given.add(field.name);
+            const actual = this.inferType(field.value);
+            if (!typeCompatible(actual, expected)) {
+                throw new Error(
+                    message("type.field-mismatch", {
+                        field: field.name,
+                        struct: expr.name,
+                        expected: formatType(expected),
+                        actual: formatType(actual),
+                    })
+                );
+            }
+        }
+        const missing = Object.keys(struct.fields!).filter((name) => !given.has(name));
+        if (missing.length > 0) {
+            throw new Error(
+                message(missing.length > 1 ? "type.missing-fields" : "type.missing-field", { fields: missing.join(", "), struct: expr.name })
+            );
+        }
+        return struct;
+    }
 
-        // Type Module (Python, JavaScript, TypeScript, Go)
-        this.env.setModule("std::type", {
-            typeof: (x: any) => typeof x,
-            isArray: (x: any) => Array.isArray(x),
-            isObject: (x: any) => x !== null && typeof x === "object",
-            isNull: (x: any) => x === null,
-            isUndefined: (x: any) => x === undefined,
-            isNumber: (x: any) => typeof x === "number",
-            isString: (x: any) => typeof x === "string",
-            isBoolean: (x: any) => typeof x === "boolean",
-            isNaN: (x: any) => isNaN(x),
-            isFinite: (x: any) => isFinite(x),
-            isInteger: (x: any) => Number.isInteger(x),
-            toNumber: (x: any) => Number(x),
-            toString: (x: any) => String(x),
-            toBoolean: (x: any) => Boolean(x),
-            toInt: (x: any) => Math.floor(Number(x)),
-            toFloat: (x: any) => parseFloat(String(x)),
-        });
+    // Records declare their fields; modules and untyped values are unchecked
+    private fieldType(objectType: TypeDef, name: string): TypeDef {
+        if (objectType.kind === "struct" && !objectType.fields) {
+            objectType = this.structs.get(objectType.name!) ?? objectType;
+        }
+        if (objectType.fields) {
+            const field = objectType.fields[name];
+            if (!field) throw new Error(message("type.unknown-field", { field: name, type: formatType(objectType) }));
+            return field;
+        }
+        if (objectType.kind === "enum" || (objectType.kind === "primitive" && objectType.primitive !== "any")) {
+            throw new Error(message("type.unknown-field", { field: name, type: formatType(objectType) }));
+        }
+        return { kind: "primitive", primitive: "any" };
+    }
 
-        // File Module (Python, Go, C, C++)
-        this.env.setModule("std::file", {
-            read: (path: string) => { try { return fs.readFileSync(path, "utf-8"); } catch { return null; } },
-            write: (path: string, content: string) => { try { fs.writeFileSync(path, content); return true; } catch { return false; } },
-            append: (path: string, content: string) => { try { fs.appendFileSync(path, content); return true; } catch { return false; } },
-            exists: (path: string) => fs.existsSync(path),
-            delete: (path: string) => { try { fs.unlinkSync(path); return true; } catch { return false; } },
-            isFile: (path: string) => { try { return fs.statSync(path).isFile(); } catch { return false; } },
-            isDirectory: (path: string) => { try { return fs.statSync(path).isDirectory(); } catch { return false; } },
-            mkdir: (path: string) => { try { fs.mkdirSync(path, { recursive: true }); return true; } catch { return false; } },
-        });
+    // x in arr, key in map, c in "abc", n in 0..10
+    private checkMembership(item: Expr, container: Expr): void {
+        const containerType = this.inferType(container);
+        const itemType = this.inferType(item);
+        let expected: TypeDef = { kind: "primitive", primitive: "any" };
+        if (containerType.kind === "array") {
+            expected = containerType.innerType!;
+        } else if (containerType.kind === "map") {
+            expected = containerType.keyType!;
+        } else if (containerType.kind === "primitive") {
+            switch (containerType.primitive) {
+                case "string":
+                    expected = { kind: "primitive", primitive: "string" };
+                    break;
+                case "range":
+                    expected = { kind: "primitive", primitive: "int" };
+                    break;
+                case "array": case "list": case "map": case "dict": case "set": case "tuple": case "any":
+                    break;
+                default:
+                    throw new Error(message("type.membership", { type: formatType(containerType) }));
+            }
+        } else if (containerType.kind === "function") {
+            throw new Error(message("type.membership", { type: formatType(containerType) }));
+        }
+        if (!typeCompatible(itemType, expected)) {
+            throw new Error(
+                message("type.membership-item", { item: formatType(itemType), container: formatType(containerType) })
+            );
+        }
+    }
 
-        // Regex Module (Python, Ruby, JavaScript, Go)
-        this.env.setModule("std::regex", {
-            match: (str: string, pattern: string, flags?: string) => { try { const m = str.match(new RegExp(pattern, flags ?? "")); return m ?? null; } catch { return null; } },
-            test: (str: string, pattern: string, flags?: string) => { try { return new RegExp(pattern, flags ?? "").test(str); } catch { return false; } },
-            search: (str: string, pattern: string, flags?: string) => { try { return str.search(new RegExp(pattern, flags ?? "")); } catch { return -1; } },
-            replace: (str: string, pattern: string, replacement: string, flags?: string) => { try { return str.replace(new RegExp(pattern, flags ?? "g"), replacement); } catch { return str; } },
-        });
+    // [1, 2, 3] is int[3]; mixed or empty literals fall back to any[N]
+    private inferArrayType(elements: Expr[]): TypeDef {
+        return {
+            kind: "array",
+            innerType: this.commonType(elements.map((e) => this.inferType(e))),
+            size: elements.length,
+        };
+    }
 
-        // DateTime Module (Python, Go, JavaScript, Ruby)
-        this.env.setModule("std::time", {
-            now: () => new Date().getTime(),
-            timestamp: () => Math.floor(new Date().getTime() / 1000),
-            getDate: (ms: number) => new Date(ms).getDate(),
-            getMonth: (ms: number) => new Date(ms).getMonth() + 1,
-            getYear: (ms: number) => new Date(ms).getFullYear(),
-            getHours: (ms: number) => new Date(ms).getHours(),
-            getMinutes: (ms: number) => new Date(ms).getMinutes(),
-            getSeconds: (ms: number) => new Date(ms).getSeconds(),
+    // The shared type of literal elements, or any when they differ
+    private commonType(types: TypeDef[]): TypeDef {
+        const first = types[0];
+        const uniform = first !== undefined && types.every((t) => formatType(t) === formatType(first));
+        return uniform ? first : { kind: "primitive", primitive: "any" };
+    }
+
+    // Type accepted between the brackets of c[...]
+    private keyType(container: TypeDef): TypeDef {
+        if (container.kind === "map") return container.keyType!;
+        if (container.primitive === "map" || container.primitive === "dict") {
+            return { kind: "primitive", primitive: "any" };
+        }
+        return { kind: "primitive", primitive: "int" };
+    }
+
+    // Type of the values produced by iterating or indexing a container
+    private elementType(container: TypeDef): TypeDef {
+        if (container.kind === "array") return container.innerType!;
+        if (container.kind === "map") return container.valueType!;
+        switch (container.primitive) {
+            case "range":
+                return { kind: "primitive", primitive: "int" };
+            case "string":
+                return { kind: "primitive", primitive: "char" };
+            default:
+                return { kind: "primitive", primitive: "any" };
+        }
+    }
+
+    private checkCall(expr: Extract<Expr, { kind: "call" }>): TypeDef {
+        if (expr.func.kind === "member" && expr.func.object.kind === "identifier" && this.imports.has(expr.func.object.name)) {
+            expr.module = this.imports.get(expr.func.object.name);
+        }
+        if (
+            expr.func.kind === "member" &&
+            expr.func.object.kind === "identifier" &&
+            this.imports.get(expr.func.object.name) === "std::func"
+        ) {
+            return this.checkFuncHelper(expr.func.property, expr.args);
+        }
+
+        // format(template, ...values) is what string interpolation desugars to
+        if (expr.func.kind === "identifier" && expr.func.name === "format" && !this.lookupVar("format")) {
+            const string: TypeDef = { kind: "primitive", primitive: "string" };
+            const [template, ...values] = expr.args;
+            if (!template) throw new Error(message("type.format-template"));
+            this.checkExpression(template, string);
+            for (const value of values) this.inferType(value);
+            return string;
+        }
+
+        // assert(condition) or assert(condition, message)
+        if (expr.func.kind === "identifier" && expr.func.name === "assert" && !this.lookupVar("assert")) {
+            if (expr.args.length < 1 || expr.args.length > 2) {
+                throw new Error(message("type.arity", { name: "assert", expected: "1 or 2", actual: expr.args.length }));
+            }
+            this.checkExpression(expr.args[0], { kind: "primitive", primitive: "bool" });
+            if (expr.args[1]) this.checkExpression(expr.args[1], { kind: "primitive", primitive: "string" });
+            return { kind: "primitive", primitive: "void" };
+        }
+
+        if (expr.func.kind === "member") this.warnDeprecated(expr, expr.func);
+        const arrayResult = this.checkArrayFunction(expr);
+        if (arrayResult) return arrayResult;
+        const calleeType = expr.func.kind === "identifier"
+            ? this.lookupVar(expr.func.name)?.type
+            : expr.func.kind === "member" ? this.variantConstructor(expr.func) ?? this.moduleSignature(expr.func) : undefined;
+        if (calleeType?.kind !== "function") {
+            for (const arg of expr.args) this.inferType(arg);
+            return { kind: "primitive", primitive: "any" };
+        }
+
+        // math.checkedAdd or a plain function name
+        const name = expr.func.kind === "member"
+            ? `${(expr.func.object as { name: string }).name}.${expr.func.property}`
+            : (expr.func as { name: string }).name;
+        const params = calleeType.params ?? [];
+        if (expr.args.length !== params.length) {
+            throw new Error(
+                message("type.arity", { name, expected: params.length, actual: expr.args.length })
+            );
+        }
+        expr.args.forEach((arg, i) => {
+            const argType = this.inferType(arg);
+            if (!typeCompatible(argType, params[i])) {
+                throw new Error(
+                    message("type.argument", { index: i + 1, name, expected: formatType(params[i]), actual: formatType(argType) })
+                );
+            }
         });
+        return calleeType.returnType ?? { kind: "primitive", primitive: "any" };
+    }
+
+    // xs.map(f), xs.filter(f), xs.reduce(f, init) and xs.forEach(f), or
+    // map(xs, f) and so on, unless the program declares a function of that
+    // name. f gets only the element (and reduce's accumulator first)
+    private checkArrayFunction(expr: Extract<Expr, { kind: "call" }>): TypeDef | undefined {
+        const func = expr.func;
+        const name = func.kind === "member" ? func.property : func.kind === "identifier" ? func.name : undefined;
+        if (name === undefined || !["map", "filter", "reduce", "forEach"].includes(name) || this.lookupVar(name)) return undefined;
+        if (func.kind === "member" && this.stdlibModule(func.object) !== undefined) return undefined;
+        const args = func.kind === "member" ? [func.object, ...expr.args] : expr.args;
+        const expected = name === "reduce" ? 3 : 2;
+        const any: TypeDef = { kind: "primitive", primitive: "any" };
+        if (args.length !== expected) {
+            const offset = func.kind === "member" ? 1 : 0;
+            throw new Error(message("type.arity", { name, expected: expected - offset, actual: args.length - offset }));
+        }
+        const arrayType = this.inferType(args[0]);
+        if (arrayType.kind !== "array") {
+            args.slice(1).forEach((arg) => this.inferType(arg));
+            return any;
+        }
+        const element = arrayType.innerType!;
+        const initial = name === "reduce" ? this.inferType(args[2]) : undefined;
+        const callback: TypeDef = initial
+            ? fnType([initial, element], initial)
+            : fnType([element], name === "filter" ? TYPE_REGISTRY.bool : any);
+        const actual = this.inferType(args[1]);
+        if (!typeCompatible(actual, callback)) {
+            const index = func.kind === "member" ? 1 : 2;
+            throw new Error(message("type.argument", { index, name, expected: formatType(callback), actual: formatType(actual) }));
+        }
+        switch (name) {
+            case "map":
+                return { kind: "array", innerType: actual.returnType ?? any };
+            case "filter":
+                return { kind: "array", innerType: element };
+            case "reduce":
+                return initial;
+            default:
+                return TYPE_REGISTRY.void;
+        }
+    }
+
+    // Signatures produced by std::func helpers
+    private checkFuncHelper(helper: string, args: Expr[]): TypeDef {
+        const types = args.map((a) => this.inferType(a));
+        const any: TypeDef = { kind: "primitive", primitive: "any" };
+        if (types.some((t) => t.kind !== "function" && t.primitive === "any")) return any;
+
+        switch (helper) {
+            case "bind": {
+                const [f, ...bound] = types;
+                if (f?.kind !== "function") {
+                    throw new Error(message("type.bind-function", { type: formatType(f ?? any) }));
+                }
+                const params = f.params ?? [];
+                if (bound.length > params.length) {
+                    throw new Error(
+                        message("type.bind-arity", { type: formatType(f), expected: params.length, actual: bound.length })
+                    );
+                }
+                bound.forEach((t, i) => {
+                    if (!typeCompatible(t, params[i])) {
+                        throw new Error(
+                            message("type.bind-argument", { index: i + 1, expected: formatType(params[i]), actual: formatType(t) })
+                        );
+                    }
+                });
+                return { kind: "function", params: params.slice(bound.length), returnType: f.returnType };
+            }
+            case "compose": {
+                const [f, g] = types;
+                if (types.length !== 2 || f.kind !== "function" || g.kind !== "function") {
+                    throw new Error(message("type.compose-functions"));
+                }
+                const fParams = f.params ?? [];
+                if (fParams.length !== 1 || !typeCompatible(g.returnType!, fParams[0])) {
+                    throw new Error(
+                        message("type.compose-mismatch", { type: formatType(g.returnType!), from: formatType(g), to: formatType(f) })
+                    );
+                }
+                return { kind: "function", params: g.params, returnType: f.returnType };
+            }
+            default:
+                return any;
+        }
+    }
+
+    private lookupVar(name: string): TypeVar | undefined {
+        for (let env: TypeEnv | undefined = this.env; env; env = env.parent) {
+            const entry = env.vars.get(name);
+            if (entry) {
+                entry.used = true;
+                return entry;
+            }
+        }
+        return undefined;
+    }
+
+    // A name read (or assigned, which is not a use) by the program: a
+    // variable in scope, or one a function body reads before the caller has
+    // declared it; undefined for builtins, modules and types used as values
+    private variable(name: string, use: boolean): TypeVar | undefined {
+        let inFunction = false;
+        for (let env: TypeEnv | undefined = this.env; env; env = env.parent) {
+            const entry = env.vars.get(name);
+            if (entry) {
+                if (use) entry.used = true;
+                return entry;
+            }
+            const later = env.pending?.get(name);
+            if (later) {
+                if (!inFunction && !this.lenient) throw new Error(message("type.before-definition", { name, where: atLine(this.line) }));
+                if (use) later.used = true;
+                return later;
+            }
+            if (env.function) inFunction = true;
+        }
+        if (Object.hasOwn(BUILTIN_FUNCTIONS, name) || this.imports.has(name) || this.structs.has(name) || this.enums.has(name) || name in TYPE_REGISTRY) {
+            return undefined;
+        }
+        if (this.lenient) return { type: TYPE_REGISTRY.any, mutable: true };
+        throw new Error(message("type.undefined-variable", { name, where: atLine(this.line) }));
+    }
+}
+
+// ============================================================================
+// INTERPRETER
+// ============================================================================
+
+// How a statement left its block, returned by the interpreter's statement
+// and block runners; null when it didn't, to go on with the next statement
+type Flow = "return" | "break" | "continue" | null;
+
+// Thrown by x? on an Err or None, out of the expressions being evaluated,
+// to the call it returns from
+class Propagation {
+    constructor(public value: any) { }
+}
+
+export class Environment {
+    private vars: Map<string, { value: any; mutable: boolean; type?: TypeDef }> = new Map();
+    // Only made for the scopes that declare one, not for every call's
+    private functions?: Map<string, { params: string[]; body: Stmt[] }>;
+    private modules?: Map<string, any>;
+    // Shared by a global scope and every scope opened under it; bumped
+    // whenever one of them shadows a builtin, to guard cached builtin calls
+    readonly shadows: { count: number };
+
+    constructor(public parent: Environment | null = null) {
+        this.shadows = parent ? parent.shadows : { count: 0 };
+    }
+
+    // Declaring a name again in the same scope reuses its entry, as loop
+    // bodies do on every iteration
+    set(name: string, value: any, mutable = false, type?: TypeDef): void {
+        const entry = this.vars.get(name);
+        if (entry) {
+            entry.value = value;
+            entry.mutable = mutable;
+            entry.type = type;
+            return;
+        }
+        if (Object.hasOwn(BUILTIN_FUNCTIONS, name)) this.shadows.count++;
+        this.vars.set(name, { value, mutable, type });
+    }
+
+    // This scope's own bindings, without the parent's
+    bindings(): { name: string; value: any; mutable: boolean; type?: TypeDef }[] {
+        return [...this.vars].map(([name, entry]) => ({ name, ...entry }));
+    }
+
+    get(name: string): any {
+        const entry = this.vars.get(name);
+        if (entry) return entry.value;
+        if (this.parent) return this.parent.get(name);
+        throw new Error(message("runtime.undefined-variable", { name }));
+    }
+
+    has(name: string): boolean {
+        if (this.vars.has(name)) return true;
+        return this.parent ? this.parent.has(name) : false;
+    }
+
+    update(name: string, value: any): void {
+        const entry = this.vars.get(name);
+        if (entry) {
+            if (!entry.mutable) {
+                throw new Error(message("runtime.immutable", { name }));
+            }
+            entry.value = value;
+            return;
+        }
+        if (this.parent) {
+            this.parent.update(name, value);
+            return;
+        }
+        throw new Error(message("runtime.undefined-variable", { name }));
+    }
+
+    setFunction(name: string, params: string[], body: Stmt[]): void {
+        (this.functions ??= new Map()).set(name, { params, body });
+    }
+
+    getFunction(name: string): { params: string[]; body: Stmt[] } | null {
+        const fn = this.functions?.get(name);
+        if (fn) return fn;
+        if (this.parent) return this.parent.getFunction(name);
+        return null;
+    }
+
+    setModule(name: string, module: any): void {
+        (this.modules ??= new Map()).set(name, module);
+    }
+
+    getModule(name: string): any {
+        if (this.modules?.has(name)) {
+            return this.modules.get(name);
+        }
+        if (this.parent) return this.parent.getModule(name);
+        return null;
+    }
+
+    // A copy of this scope's bindings; restore() goes back to it any number
+    // of times, undoing new bindings and assignments alike
+    snapshot(): EnvironmentSnapshot {
+        return {
+            vars: new Map([...this.vars].map(([name, entry]) => [name, { ...entry }])),
+            functions: new Map(this.functions ?? []),
+            modules: new Map(this.modules ?? []),
+        };
+    }
+
+    restore(snapshot: EnvironmentSnapshot): void {
+        this.vars = new Map([...snapshot.vars].map(([name, entry]) => [name, { ...entry }]));
+        this.functions = new Map(snapshot.functions);
+        this.modules = new Map(snapshot.modules);
+    }
+}
+
+export interface EnvironmentSnapshot {
+    vars: ReadonlyMap<string, { value: any; mutable: boolean; type?: TypeDef }>;
+    functions: ReadonlyMap<string, { params: string[]; body: Stmt[] }>;
+    modules: ReadonlyMap<string, any>;
+}
+
+// Name of a runtime value's type, for error messages
+function runtimeTypeName(value: any): string {
+    if (value === null || value === undefined) return "null";
+    if (typeof value === "number") return Number.isInteger(value) ? "int" : "float";
+    if (typeof value === "string") return "string";
+    if (Array.isArray(value)) return "array";
+    if (value instanceof Map) return "map";
+    if (isRange(value)) return "range";
+    if (value instanceof Date) return "datetime";
+    if (typeof value === "object" && value[STRUCT_NAME]) return value[STRUCT_NAME];
+    if (typeof value === "object" && value[ENUM_NAME]) return value[ENUM_NAME];
+    return typeof value;
+}
+
+function operandError(op: string, left: any, right: any): Error {
+    return new Error(
+        message("runtime.operator", { op, left: runtimeTypeName(left), right: runtimeTypeName(right) })
+    );
+}
+
+// The operators the checker marks integer when both operands are ints
+const INTEGER_OPS = new Set(["+", "-", "*", "/", "%"]);
+
+// int / int and int % int truncate toward zero, as in C. A result outside
+// the 32-bit int range is an error rather than a float or a wrapped value
+function integerOp(op: string, left: number, right: number): number {
+    let result: number;
+    switch (op) {
+        case "+":
+            result = left + right;
+            break;
+        case "-":
+            result = left - right;
+            break;
+        case "*":
+            result = left * right;
+            break;
+        case "/":
+            if (right === 0) throw new Error(message("runtime.division-by-zero"));
+            result = Math.trunc(left / right);
+            break;
+        default:
+            if (right === 0) throw new Error(message("runtime.remainder-by-zero"));
+            return left % right;
+    }
+    if (result > INT_MAX || result < INT_MIN) {
+        throw new Error(message("runtime.integer-overflow", { left, op, right }));
+    }
+    return result;
+}
+
+// Float / and % by zero under --strict-math, which are otherwise inf or NaN
+function strictZeroDivisor(op: string, right: any): void {
+    if (right === 0 && (op === "/" || op === "%")) {
+        throw new Error(message(op === "/" ? "runtime.division-by-zero" : "runtime.remainder-by-zero"));
+    }
+}
+
+// The order used by sorting: numbers numerically, text by code point,
+// false before true, datetimes by instant and arrays element by element
+function compareValues(a: any, b: any): number {
+    if (typeof a === "number" && typeof b === "number") return a < b ? -1 : a > b ? 1 : 0;
+    if (typeof a === "string" && typeof b === "string") return compareText(a, b);
+    if (typeof a === "boolean" && typeof b === "boolean") return Number(a) - Number(b);
+    if (a instanceof Date && b instanceof Date) return Math.sign(a.getTime() - b.getTime());
+    if (Array.isArray(a) && Array.isArray(b)) {
+        for (let i = 0; i < Math.min(a.length, b.length); i++) {
+            const order = compareValues(a[i], b[i]);
+            if (order !== 0) return order;
+        }
+        return Math.sign(a.length - b.length);
+    }
+    throw new Error(message("runtime.compare", { left: runtimeTypeName(a), right: runtimeTypeName(b) }));
+}
+
+// An array with at least one element, for folds without a starting value
+function nonEmpty(fn: string, arr: any[]): any[] {
+    if (!Array.isArray(arr) || arr.length === 0) throw new Error(message("runtime.empty-array", { fn, value: formatNested(arr) }));
+    return arr;
+}
+
+// Code point order, independent of the host locale. Without surrogates
+// this is plain UTF-16 order.
+function compareText(a: string, b: string): number {
+    const surrogate = /[\ud800-\udfff]/;
+    if (!surrogate.test(a) && !surrogate.test(b)) return a < b ? -1 : a > b ? 1 : 0;
+    const x = [...a];
+    const y = [...b];
+    for (let i = 0; i < Math.min(x.length, y.length); i++) {
+        const diff = (x[i].codePointAt(0) ?? 0) - (y[i].codePointAt(0) ?? 0);
+        if (diff !== 0) return Math.sign(diff);
+    }
+    return Math.sign(x.length - y.length);
+}
+
+function applyBinaryOp(op: string, left: any, right: any): any {
+    switch (op) {
+        case "+":
+            // Strings concatenate only with strings; use toString(n) for numbers
+            if ((typeof left === "string") !== (typeof right === "string")) {
+                throw operandError(op, left, right);
+            }
+            return left + right;
+        case "-":
+        case "*":
+        case "/":
+        case "%":
+            if (typeof left === "string" || typeof right === "string") {
+                throw operandError(op, left, right);
+            }
+            return op === "-" ? left - right : op === "*" ? left * right : op === "/" ? left / right : left % right;
+        case "==":
+        case "!=":
+            // Datetimes are equal when they are the same instant
+            if (left instanceof Date && right instanceof Date) {
+                return (left.getTime() === right.getTime()) === (op === "==");
+            }
+            // null stands for every empty optional
+            if (left === null || right === null) {
+                return (isEmptyOptional(left) && isEmptyOptional(right)) === (op === "==");
+            }
+            return (left === right) === (op === "==");
+        case "??":
+            if (left?.type === "some") return left.value;
+            return isEmptyOptional(left) ? right : left;
+        case "<":
+        case ">":
+        case "<=":
+        case ">=":
+            // Strings order by code point, but never against numbers
+            if ((typeof left === "string") !== (typeof right === "string")) {
+                throw operandError(op, left, right);
+            }
+            if (typeof left === "string") {
+                const order = compareText(left, right);
+                return op === "<" ? order < 0 : op === ">" ? order > 0 : op === "<=" ? order <= 0 : order >= 0;
+            }
+            return op === "<" ? left < right : op === ">" ? left > right : op === "<=" ? left <= right : left >= right;
+        case "&&":
+            return left && right;
+        case "||":
+            return left || right;
+        case "in":
+            return contains(right, left);
+        case "not in":
+            return !contains(right, left);
+        default:
+            return null;
+    }
+}
+
+// None, or a null from the literal or a host
+function isEmptyOptional(value: any): boolean {
+    return value === null || value === undefined || value?.type === "none";
+}
+
+function applyUnaryOp(op: string, operand: any): any {
+    switch (op) {
+        case "-":
+            return -operand;
+        case "+":
+            return +operand;
+        case "!":
+            return !operand;
+        case "~":
+            return ~operand;
+        default:
+            return null;
+    }
+}
+
+// ============================================================================
+// RANGES - 0..10, 0..=10, 0..10 step 2
+// ============================================================================
+
+interface RangeValue {
+    type: "range";
+    start: number;
+    end: number;
+    inclusive: boolean;
+    step: number;
+}
+
+function makeRange(start: number, end: number, inclusive: boolean, step = 1): RangeValue {
+    if (step === 0) throw new Error(message("runtime.range-step"));
+    return { type: "range", start, end, inclusive, step };
+}
+
+function isRange(value: any): value is RangeValue {
+    return value?.type === "range";
+}
+
+function* rangeValues(range: RangeValue): Generator<number> {
+    const { start, end, inclusive, step } = range;
+    for (
+        let i = start;
+        step > 0 ? (inclusive ? i <= end : i < end) : (inclusive ? i >= end : i > end);
+        i += step
+    ) {
+        yield i;
+    }
+}
+
+function rangeContains(range: RangeValue, value: number): boolean {
+    const { start, end, inclusive, step } = range;
+    const inBounds = step > 0
+        ? value >= start && (inclusive ? value <= end : value < end)
+        : value <= start && (inclusive ? value >= end : value > end);
+    return inBounds && (value - start) % step === 0;
+}
+
+// Values visited by for-in and matched by `in`
+function iterate(value: any): Iterable<any> {
+    if (isRange(value)) return rangeValues(value);
+    if (typeof value === "string" || Array.isArray(value) || value instanceof Set) return value;
+    if (value instanceof Map) return value.keys();
+    if (value !== null && typeof value === "object") return Object.keys(value);
+    throw new Error(message("runtime.iterate", { value: formatValue(value) }));
+}
+
+function contains(container: any, item: any): boolean {
+    if (isRange(container)) return rangeContains(container, item);
+    if (typeof container === "string") return container.includes(item);
+    if (Array.isArray(container)) return container.includes(item);
+    if (container instanceof Set || container instanceof Map) return container.has(item);
+    if (container !== null && typeof container === "object") return item in container;
+    throw new Error(message("runtime.membership", { value: formatValue(container) }));
+}
+
+// s[i] and s[2..5] on strings, arrays and ranges; m[key] on maps
+function indexValue(object: any, index: any): any {
+    if (object instanceof Map) {
+        if (!object.has(index)) throw new Error(message("runtime.key-not-found", { key: formatNested(index) }));
+        return object.get(index);
+    }
+    if (isRange(object)) object = [...rangeValues(object)];
+    if (typeof object !== "string" && !Array.isArray(object)) {
+        return object?.[index];
+    }
+    if (isRange(index)) {
+        const positions = [...rangeValues(index)].filter((i) => i >= 0 && i < object.length);
+        const items = positions.map((i) => object[i]);
+        return typeof object === "string" ? items.join("") : items;
+    }
+    if (!Number.isInteger(index) || index < 0 || index >= object.length) {
+        throw new Error(message("runtime.index-bounds", { index: formatNested(index), length: object.length }));
+    }
+    return object[index];
+}
+
+// Struct values are plain objects tagged with their struct name
+const STRUCT_NAME = Symbol("struct");
+
+function makeStruct(name: string, entries: [string, any][]): any {
+    const value: any = { [STRUCT_NAME]: name };
+    for (const [field, fieldValue] of entries) value[field] = fieldValue;
+    return value;
+}
+
+// Enum values are tagged with their enum's name and hold the variant's
+// name and payload. A variant without a payload is a single shared value,
+// so == compares it like a literal
+const ENUM_NAME = Symbol("enum");
+
+// What an enum's name is bound to: Color.Red is a value, Shape.Circle
+// a function making one
+function enumNamespace(name: string, variants: EnumVariant[]): Record<string, any> {
+    const namespace: Record<string, any> = {};
+    for (const variant of variants) {
+        const make = (...values: any[]) => ({ [ENUM_NAME]: name, variant: variant.name, values });
+        namespace[variant.name] = variant.params.length > 0 ? make : make();
+    }
+    return namespace;
+}
+
+// Whether a match arm's pattern takes the subject; a binding or _ takes anything
+function patternMatches(pattern: Pattern, subject: any): boolean {
+    if (pattern.kind === "literal") return pattern.value === subject;
+    if (pattern.kind === "variant") return subject?.[ENUM_NAME] === pattern.enum && subject.variant === pattern.variant;
+    return true;
+}
+
+// Host values implementing this choose their own Strata shape, usually
+// a struct built with strataStruct
+export interface IntoStrata {
+    toStrata(): any;
+}
+
+// A struct value for the host, with each field converted by toStrata.
+// Fields keep the order of the object, not of a struct declaration
+export function strataStruct(name: string, fields: Record<string, any>): any {
+    return makeStruct(name, Object.entries(fields).map(([field, value]) => [field, toStrata(value)]));
+}
+
+// Host values to Strata values: plain objects become map<string, _>,
+// arrays, Maps and Sets are converted element by element and undefined
+// becomes null. Scalars, dates, functions and Strata values pass through
+export function toStrata(value: any): any {
+    if (value === undefined) return null;
+    if (value === null || typeof value !== "object" || value instanceof Date || value[STRUCT_NAME] || value[ENUM_NAME]) return value;
+    if (typeof value.toStrata === "function") return value.toStrata();
+    if (Array.isArray(value)) return value.map(toStrata);
+    if (value instanceof Set) return new Set([...value].map(toStrata));
+    if (value instanceof Map) return new Map([...value].map(([k, v]) => [toStrata(k), toStrata(v)]));
+    return new Map(Object.entries(value).map(([k, v]) => [k, toStrata(v)]));
+}
+
+// Strata values to host values: structs and maps with string keys become
+// plain objects, Some(x) becomes x, None null and ranges arrays. A map with
+// a key like "10" stays a Map, as objects list such keys first and would
+// lose the map's insertion order
+export function fromStrata(value: any): any {
+    if (value === null || typeof value !== "object" || value instanceof Date) return value;
+    if (Array.isArray(value)) return value.map(fromStrata);
+    if (value instanceof Set) return new Set([...value].map(fromStrata));
+    if (value instanceof Map) {
+        return [...value.keys()].every((key) => typeof key === "string" && !/^(0|[1-9][0-9]*)$/.test(key))
+            ? Object.fromEntries([...value].map(([k, v]) => [k, fromStrata(v)]))
+            : new Map([...value].map(([k, v]) => [fromStrata(k), fromStrata(v)]));
+    }
+    if (isRange(value)) return [...rangeValues(value)];
+    if (value.type === "some" && "value" in value) return fromStrata(value.value);
+    if (value.type === "none" && Object.keys(value).length === 1) return null;
+    return Object.fromEntries(Object.entries(value).map(([k, v]) => [k, fromStrata(v)]));
+}
+
+// module.CONSTANT and record.field; other values have no fields
+function fieldValue(object: any, name: string): any {
+    const isRecord = object !== null && typeof object === "object" && !Array.isArray(object) &&
+        !(object instanceof Map) && !(object instanceof Set);
+    if (!isRecord || !Object.hasOwn(object, name)) {
+        throw new Error(message("runtime.unknown-field", { field: name }));
+    }
+    return object[name];
+}
+
+// arr[i] = value and m[key] = value; arrays grow only through push
+function assignIndex(object: any, index: any, value: any): void {
+    if (object instanceof Map) {
+        object.set(index, value);
+        return;
+    }
+    if (!Array.isArray(object)) {
+        throw new Error(message("runtime.index-assign", { value: formatValue(object) }));
+    }
+    if (!Number.isInteger(index) || index < 0 || index >= object.length) {
+        throw new Error(message("runtime.index-bounds", { index: formatNested(index), length: object.length }));
+    }
+    object[index] = value;
+}
+
+const utf8Encoder = new TextEncoder();
+
+// Bytes are int arrays with every element in 0..255
+function byteArg(fn: string, value: any): Uint8Array {
+    if (!Array.isArray(value) || !value.every((b) => Number.isInteger(b) && b >= 0 && b <= 255)) {
+        throw new Error(message("runtime.bytes-expected", { fn, value: formatNested(value) }));
+    }
+    return Uint8Array.from(value);
+}
+
+// Text is encoded as UTF-8; byte arrays are used as they are
+function toBytes(fn: string, value: any): Uint8Array {
+    return typeof value === "string" ? utf8Encoder.encode(value) : byteArg(fn, value);
+}
+
+// CRC-32 (IEEE 802.3, as used by zip and PNG)
+const CRC32_TABLE = Array.from({ length: 256 }, (_, n) => {
+    let c = n;
+    for (let k = 0; k < 8; k++) c = c & 1 ? 0xedb88320 ^ (c >>> 1) : c >>> 1;
+    return c >>> 0;
+});
+
+function crc32(bytes: Uint8Array): number {
+    let crc = 0xffffffff;
+    for (const b of bytes) crc = CRC32_TABLE[(crc ^ b) & 0xff] ^ (crc >>> 8);
+    return (crc ^ 0xffffffff) >>> 0;
+}
+
+function decodeUtf8(fn: string, bytes: Uint8Array): string {
+    try {
+        return new TextDecoder("utf-8", { fatal: true }).decode(bytes);
+    } catch {
+        throw new Error(message("runtime.invalid-utf8", { fn }));
+    }
+}
+
+// Standard input, read synchronously so a program can prompt and read in
+// turn. Bytes are kept until a newline so UTF-8 split across reads decodes
+const stdinInput = { pending: Buffer.alloc(0), ended: false };
+
+// The next line without its line break, or null at the end of input
+function readStdinLine(): string | null {
+    return readLineFrom(0, stdinInput);
+}
+
+// The next line of a file descriptor, buffering what follows it in input
+function readLineFrom(fd: number, input: { pending: Buffer; ended: boolean }): string | null {
+    const chunk = Buffer.alloc(4096);
+    while (!input.pending.includes(10) && !input.ended) {
+        let count = 0;
+        try {
+            count = fs.readSync(fd, chunk, 0, chunk.length, null);
+        } catch (e) {
+            // A non-blocking stdin has nothing yet; EOF is Windows' end of a pipe
+            if ((e as { code?: string }).code === "EAGAIN") continue;
+            if ((e as { code?: string }).code !== "EOF") throw e;
+        }
+        if (count === 0) input.ended = true;
+        else input.pending = Buffer.concat([input.pending, chunk.subarray(0, count)]);
+    }
+    if (input.pending.length === 0 && input.ended) return null;
+    const newline = input.pending.indexOf(10);
+    const end = newline < 0 ? input.pending.length : newline;
+    const line = input.pending.subarray(0, end).toString("utf-8");
+    input.pending = input.pending.subarray(newline < 0 ? end : end + 1);
+    return line.endsWith("\r") ? line.slice(0, -1) : line;
+}
+
+function readNumber(fn: string, pattern: RegExp, id: string, read: () => string | null = readStdinLine): number {
+    const line = read();
+    if (line === null) throw new Error(message("runtime.end-of-input", { fn }));
+    if (!pattern.test(line.trim())) throw new Error(message(id, { text: JSON.stringify(line) }));
+    return Number(line.trim());
+}
+
+// fs.* results: Ok(value), or Err("readFile notes.txt: no such file or
+// directory") with the OS reason stripped of its error code and syscall
+function fsResult(fn: string, path: string, run: () => any): any {
+    try {
+        return { type: "ok", value: run() };
+    } catch (e) {
+        const reason = String((e as Error).message ?? e).replace(/^[A-Z]+: /, "").replace(/, \w+( '.*')?$/, "");
+        return { type: "err", error: message("runtime.fs-failed", { fn, path, reason }) };
+    }
+}
+
+// An open file from fs.open or fs.create. Like a module its methods are
+// properties, called as f.readLine(); any use after close() is an error
+function fileHandle(path: string, fd: number): Record<string, (...args: any[]) => any> {
+    const input = { pending: Buffer.alloc(0), ended: false };
+    let open = true;
+    const use = (fn: string) => {
+        if (!open) throw new Error(message("runtime.file-closed", { fn, path }));
+    };
+    return {
+        // The next line, or None at the end of the file
+        readLine: () => {
+            use("readLine");
+            const line = readLineFrom(fd, input);
+            return line === null ? { type: "none" } : { type: "some", value: line };
+        },
+        // The number of UTF-8 bytes written
+        write: (text: string) => {
+            use("write");
+            return fs.writeSync(fd, text);
+        },
+        close: () => {
+            if (open) fs.closeSync(fd);
+            open = false;
+            return null;
+        },
+    };
+}
+
+// RFC 4180: quoted fields may hold commas, newlines and doubled quotes;
+// CRLF and LF both end a row, and a final line break is optional
+// lines receives the line each row starts on
+function parseCsv(text: string, lines: number[] = []): string[][] {
+    const rows: string[][] = [];
+    let row: string[] = [];
+    let field = "";
+    let line = 1;
+    let rowLine = 1;
+    let i = 0;
+    while (i < text.length) {
+        const c = text[i];
+        if (c === '"' && field === "") {
+            const start = line;
+            i++;
+            while (true) {
+                if (i >= text.length) throw new Error(message("runtime.csv-unterminated", { line: start }));
+                if (text[i] === '"' && text[i + 1] === '"') {
+                    field += '"';
+                    i += 2;
+                } else if (text[i] === '"') {
+                    i++;
+                    break;
+                } else {
+                    if (text[i] === "\n") line++;
+                    field += text[i++];
+                }
+            }
+            if (i < text.length && !",\r\n".includes(text[i])) {
+                throw new Error(message("runtime.csv-after-quote", { line }));
+            }
+        } else if (c === '"') {
+            throw new Error(message("runtime.csv-stray-quote", { line }));
+        } else if (c === ",") {
+            row.push(field);
+            field = "";
+            i++;
+        } else if (c === "\r" || c === "\n") {
+            row.push(field);
+            rows.push(row);
+            lines.push(rowLine);
+            row = [];
+            field = "";
+            i += c === "\r" && text[i + 1] === "\n" ? 2 : 1;
+            rowLine = ++line;
+        } else {
+            field += c;
+            i++;
+        }
+    }
+    if (field !== "" || row.length > 0) {
+        row.push(field);
+        rows.push(row);
+        lines.push(rowLine);
+    }
+    return rows;
+}
+
+// Rows are arrays of values, or maps that share the first map's keys as a header
+function stringifyCsv(rows: any[]): string {
+    if (!Array.isArray(rows)) throw new Error(message("runtime.csv-rows", { value: formatNested(rows) }));
+    const quote = (value: any) => {
+        const text = formatValue(value);
+        return /[",\r\n]/.test(text) ? `"${text.replace(/"/g, '""')}"` : text;
+    };
+    let table = rows;
+    if (rows[0] instanceof Map) {
+        const header = [...rows[0].keys()];
+        table = [header, ...rows.map((record) => {
+            if (!(record instanceof Map)) throw new Error(message("runtime.csv-record", { row: formatNested(record) }));
+            return header.map((name) => record.get(name) ?? "");
+        })];
+    }
+    return table.map((row) => {
+        if (!Array.isArray(row)) throw new Error(message("runtime.csv-row", { row: formatNested(row) }));
+        return row.map(quote).join(",") + "\n";
+    }).join("");
+}
+
+// Datetimes are JS Dates, always read and written in UTC so programs
+// behave the same in every timezone
+const MONTH_NAMES = ["January", "February", "March", "April", "May", "June", "July",
+    "August", "September", "October", "November", "December"];
+const WEEKDAY_NAMES = ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"];
+
+function dateArg(fn: string, value: any): Date {
+    if (!(value instanceof Date)) throw new Error(message("runtime.datetime-expected", { fn, value: formatNested(value) }));
+    return value;
+}
+
+function daysInMonth(year: number, month: number): number {
+    const leap = (year % 4 === 0 && year % 100 !== 0) || year % 400 === 0;
+    return [31, leap ? 29 : 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31][month - 1];
+}
+
+function makeDate(year: number, month: number, day: number, hour = 0, minute = 0, second = 0, ms = 0): Date {
+    const parts = [year, month, day, hour, minute, second, ms];
+    const valid = parts.every(Number.isInteger) && month >= 1 && month <= 12 &&
+        day >= 1 && day <= daysInMonth(year, month) && hour >= 0 && hour <= 23 &&
+        minute >= 0 && minute <= 59 && second >= 0 && second <= 59 && ms >= 0 && ms <= 999;
+    if (!valid) {
+        const pad = (n: number) => String(n).padStart(2, "0");
+        const datetime = `${year}-${pad(month)}-${pad(day)} ${pad(hour)}:${pad(minute)}:${pad(second)}`;
+        throw new Error(message("runtime.invalid-datetime", { datetime }));
+    }
+    const date = new Date(Date.UTC(2000, month - 1, day, hour, minute, second, ms));
+    date.setUTCFullYear(year); // Date.UTC maps years 0..99 onto 1900..1999
+    return date;
+}
+
+// Monday is 1, Sunday is 7 (ISO 8601)
+function isoWeekday(d: Date): number {
+    return ((d.getUTCDay() + 6) % 7) + 1;
+}
+
+function dayOfYear(d: Date): number {
+    return Math.round((d.getTime() - makeDate(d.getUTCFullYear(), 1, 1).getTime()) / 86400000) + 1;
+}
+
+// strftime-style directives shared by format and parse
+function formatDate(d: Date, fmt: string): string {
+    const pad = (n: number, width = 2) => String(n).padStart(width, "0");
+    return fmt.replace(/%(.)/g, (_, directive: string) => {
+        switch (directive) {
+            case "Y": return pad(d.getUTCFullYear(), 4);
+            case "m": return pad(d.getUTCMonth() + 1);
+            case "d": return pad(d.getUTCDate());
+            case "H": return pad(d.getUTCHours());
+            case "M": return pad(d.getUTCMinutes());
+            case "S": return pad(d.getUTCSeconds());
+            case "f": return pad(d.getUTCMilliseconds(), 3);
+            case "j": return pad(dayOfYear(d), 3);
+            case "u": return String(isoWeekday(d));
+            case "a": return WEEKDAY_NAMES[isoWeekday(d) - 1].slice(0, 3);
+            case "A": return WEEKDAY_NAMES[isoWeekday(d) - 1];
+            case "b": return MONTH_NAMES[d.getUTCMonth()].slice(0, 3);
+            case "B": return MONTH_NAMES[d.getUTCMonth()];
+            case "%": return "%";
+            default: throw new Error(message("runtime.datetime-directive", { fn: "format", directive }));
+        }
+    });
+}
+
+// Fields missing from the format default to 1970-01-01 00:00:00
+function parseDate(fmt: string, text: string): Date {
+    const fields = { Y: 1970, m: 1, d: 1, H: 0, M: 0, S: 0, f: 0, j: -1 };
+    const mismatch = () => new Error(message("runtime.datetime-mismatch", { text, format: fmt }));
+    const names = (list: string[], short: boolean) => list.map((n) => (short ? n.slice(0, 3) : n));
+    let pos = 0;
+    for (let i = 0; i < fmt.length; i++) {
+        if (fmt[i] !== "%" || i + 1 >= fmt.length) {
+            if (text[pos++] !== fmt[i]) throw mismatch();
+            continue;
+        }
+        const directive = fmt[++i];
+        const rest = text.slice(pos);
+        if (directive === "%") {
+            if (text[pos++] !== "%") throw mismatch();
+        } else if ("YmdHMSfj".includes(directive)) {
+            const digits = directive === "Y" ? 4 : directive === "f" ? 3 : 2;
+            const pattern = directive === "Y" || directive === "f" ? new RegExp(`^\\d{${digits}}`) : directive === "j" ? /^\d{1,3}/ : /^\d{1,2}/;
+            const match = rest.match(pattern);
+            if (!match) throw mismatch();
+            fields[directive as keyof typeof fields] = Number(match[0]);
+            pos += match[0].length;
+        } else if ("bBaA".includes(directive)) {
+            const list = names("bB".includes(directive) ? MONTH_NAMES : WEEKDAY_NAMES, directive === "b" || directive === "a");
+            const index = list.findIndex((n) => rest.toLowerCase().startsWith(n.toLowerCase()));
+            if (index < 0) throw mismatch();
+            if ("bB".includes(directive)) fields.m = index + 1;
+            pos += list[index].length;
+        } else if (directive === "u") {
+            // Like %a and %A, the weekday is read but not checked
+            if (!/^[1-7]/.test(rest)) throw mismatch();
+            pos++;
+        } else {
+            throw new Error(message("runtime.datetime-directive", { fn: "parse", directive }));
+        }
+    }
+    if (pos !== text.length) throw mismatch();
+    // %j names the month and day by the day of the year
+    if (fields.j >= 0) {
+        let day = fields.j;
+        for (fields.m = 1; fields.m < 12 && day > daysInMonth(fields.Y, fields.m); fields.m++) day -= daysInMonth(fields.Y, fields.m);
+        fields.d = day;
+    }
+    return makeDate(fields.Y, fields.m, fields.d, fields.H, fields.M, fields.S, fields.f);
+}
+
+// JS dates reach only about 275,000 years either side of 1970
+function validDate(d: Date): Date {
+    if (Number.isNaN(d.getTime())) throw new Error(message("runtime.datetime-range"));
+    return d;
+}
+
+const shiftDate = (fn: string, unit: number) => (d: Date, n: number) => validDate(new Date(dateArg(fn, d).getTime() + n * unit));
+
+// The datetime namespace; methods on datetime values resolve here too: d.format("%Y")
+const DATETIME_FUNCTIONS: Record<string, (...args: any[]) => any> = {
+    now: () => new Date(),
+    date: (year: number, month: number, day: number) => makeDate(year, month, day),
+    of: (year: number, month: number, day: number, hour: number, minute: number, second: number) =>
+        makeDate(year, month, day, hour, minute, second),
+    fromTimestamp: (seconds: number) => validDate(new Date(seconds * 1000)),
+    parse: (fmt: string, text: string) => parseDate(fmt, text),
+    format: (d: Date, fmt: string) => formatDate(dateArg("format", d), fmt),
+    addDays: shiftDate("addDays", 86400000),
+    addHours: shiftDate("addHours", 3600000),
+    addMinutes: shiftDate("addMinutes", 60000),
+    addSeconds: shiftDate("addSeconds", 1000),
+    year: (d: Date) => dateArg("year", d).getUTCFullYear(),
+    month: (d: Date) => dateArg("month", d).getUTCMonth() + 1,
+    day: (d: Date) => dateArg("day", d).getUTCDate(),
+    hour: (d: Date) => dateArg("hour", d).getUTCHours(),
+    minute: (d: Date) => dateArg("minute", d).getUTCMinutes(),
+    second: (d: Date) => dateArg("second", d).getUTCSeconds(),
+    weekday: (d: Date) => isoWeekday(dateArg("weekday", d)),
+    dayOfYear: (d: Date) => dayOfYear(dateArg("dayOfYear", d)),
+    timestamp: (d: Date) => Math.floor(dateArg("timestamp", d).getTime() / 1000),
+    secondsBetween: (a: Date, b: Date) =>
+        Math.floor((dateArg("secondsBetween", b).getTime() - dateArg("secondsBetween", a).getTime()) / 1000),
+};
+
+// Chars are one-code-point strings at runtime
+function charArg(fn: string, value: any): string {
+    if (typeof value !== "string" || [...value].length !== 1) {
+        throw new Error(message("runtime.char-expected", { fn, value: formatNested(value) }));
+    }
+    return value;
+}
+
+// Render a runtime value the way Strata prints it
+export function formatValue(value: any): string {
+    if (value === null || value === undefined) return "null";
+    if (isRange(value)) {
+        const range = `${value.start}${value.inclusive ? "..=" : ".."}${value.end}`;
+        return value.step === 1 ? range : `${range} step ${value.step}`;
+    }
+    if (typeof value === "string") return value;
+    if (typeof value === "function") return "<function>";
+    if (value instanceof Date) return validDate(value).toISOString().replace(".000Z", "Z");
+    if (value.type === "some" && "value" in value) return `Some(${formatNested(value.value)})`;
+    if (value.type === "none" && Object.keys(value).length === 1) return "None";
+    if (value.type === "ok" && "value" in value) return `Ok(${formatNested(value.value)})`;
+    if (value.type === "err" && "error" in value) return `Err(${formatNested(value.error)})`;
+    if (Array.isArray(value)) return `[${value.map(formatNested).join(", ")}]`;
+    if (value instanceof Set) return `{${[...value].map(formatNested).join(", ")}}`;
+    if (value instanceof Map) {
+        return `{${[...value].map(([k, v]) => `${formatNested(k)}: ${formatNested(v)}`).join(", ")}}`;
+    }
+    if (typeof value === "object" && value[STRUCT_NAME]) {
+        return `${value[STRUCT_NAME]} { ${Object.entries(value).map(([k, v]) => `${k}: ${formatNested(v)}`).join(", ")} }`;
+    }
+    if (typeof value === "object" && value[ENUM_NAME]) {
+        const payload = value.values.length > 0 ? `(${value.values.map(formatNested).join(", ")})` : "";
+        return `${value[ENUM_NAME]}.${value.variant}${payload}`;
+    }
+    if (typeof value === "object") {
+        return `{${Object.entries(value).map(([k, v]) => `${k}: ${formatNested(v)}`).join(", ")}}`;
+    }
+    return String(value);
+}
+
+// Strings inside collections are quoted so ["a, b"] and ["a", "b"] differ
+function formatNested(value: any): string {
+    return typeof value === "string" ? JSON.stringify(value) : formatValue(value);
+}
+
+// Source-like text of an expression for --explain-eval, with the
+// subexpressions evaluated so far replaced by their values
+function renderExpr(expr: Expr, values: Map<Expr, any>, parentPrecedence = 0): string {
+    if (values.has(expr)) return formatNested(values.get(expr));
+    const render = (e: Expr, precedence = 0) => renderExpr(e, values, precedence);
+    switch (expr.kind) {
+        case "literal":
+            if (expr.type.primitive === "char") return `'${expr.value === "'" ? "\\'" : JSON.stringify(expr.value).slice(1, -1)}'`;
+            if (expr.type.primitive === "float" && Number.isInteger(expr.value)) return `${expr.value}.0`;
+            return formatNested(expr.value);
+        case "identifier":
+            return expr.name;
+        case "binary": {
+            // Operators are left-associative, so an equal-precedence right operand needs parentheses
+            const precedence = BINARY_PRECEDENCE[expr.op] ?? 0;
+            const text = `${render(expr.left, precedence)} ${expr.op} ${render(expr.right, precedence + 1)}`;
+            return precedence < parentPrecedence ? `(${text})` : text;
+        }
+        case "unary":
+            return `${expr.op}${render(expr.operand, 10)}`;
+        case "propagate":
+            return `${render(expr.value, 10)}?`;
+        case "call":
+            return `${render(expr.func, 10)}(${expr.args.map((a) => render(a)).join(", ")})`;
+        case "member":
+            return `${render(expr.object, 10)}.${expr.property}`;
+        case "field":
+            return `${render(expr.object, 10)}.${expr.name}`;
+        case "index":
+            return `${render(expr.object, 10)}[${render(expr.index)}]`;
+        case "array":
+            return `[${expr.elements.map((e) => render(e)).join(", ")}]`;
+        case "map":
+            return `{${expr.entries.map((e) => `${render(e.key)}: ${render(e.value)}`).join(", ")}}`;
+        case "structLiteral":
+            return `${expr.name} { ${expr.fields.map((f) => `${f.name}: ${render(f.value)}`).join(", ")} }`;
+        case "range":
+            return `${render(expr.start, 7)}${expr.inclusive ? "..=" : ".."}${render(expr.end, 7)}`;
+        case "match":
+            return `match ${render(expr.subject)} { ... }`;
+        case "if":
+            return `if (${render(expr.condition)}) { ... } else { ... }`;
+        case "block":
+            return "{ ... }";
+        case "loop":
+            return "loop { ... }";
+        case "lambda":
+            return `(${expr.fn.params.map((p) => `${p.name}: ${formatType(p.type)}`).join(", ")}) => ...`;
+    }
+}
+
+// Modules and functions stay as written in an explanation
+function isExplainable(value: any): boolean {
+    if (typeof value === "function") return false;
+    if (value === null || typeof value !== "object" || Object.getPrototypeOf(value) !== Object.prototype) return true;
+    return !Object.values(value).some((v) => typeof v === "function");
+}
+
+// The expressions evaluated by statements on a line, at any nesting depth
+export function explainableAt(statements: Stmt[], line: number): Expr[] {
+    return statements.flatMap((stmt) => [
+        ...(stmt.line === line ? statementExprs(stmt) : []),
+        ...explainableAt(childStatements(stmt), line),
+    ]);
+}
+
+function childStatements(stmt: Stmt): Stmt[] {
+    switch (stmt.kind) {
+        case "if":
+            return [...stmt.then, ...(stmt.else ?? [])];
+        case "while":
+        case "forIn":
+        case "function":
+        case "test":
+            return stmt.body;
+        case "for":
+            return [stmt.init, stmt.update, ...stmt.body];
+        case "match":
+            return stmt.arms.flatMap((arm) => arm.body);
+        case "defer":
+            return [stmt.body];
+        default:
+            return [];
+    }
+}
+
+// The expressions a statement evaluates on its own line
+function statementExprs(stmt: Stmt): Expr[] {
+    switch (stmt.kind) {
+        case "let":
+        case "assignment":
+            return [stmt.value];
+        case "indexAssignment":
+            return [stmt.index, stmt.value];
+        case "expression":
+            return [stmt.expr];
+        case "return":
+        case "break":
+            return stmt.value ? [stmt.value] : [];
+        case "if":
+        case "while":
+        case "for":
+            return [stmt.condition];
+        case "forIn":
+            return [stmt.iterable];
+        case "match":
+            return [stmt.subject];
+        default:
+            return [];
+    }
+}
+
+// Stops a script run with runWithCancel. cancel() may come from a host
+// callback or, through the shared buffer, from another worker; a timeout
+// cancels once it has passed. The interpreter checks at every loop
+// iteration and function call and stops with an E0005 diagnostic
+export class CancellationToken {
+    readonly buffer: SharedArrayBuffer;
+    private flag: Int32Array;
+    private deadline?: number;
+
+    constructor(options: { buffer?: SharedArrayBuffer; timeoutMs?: number } = {}) {
+        this.buffer = options.buffer ?? new SharedArrayBuffer(4);
+        this.flag = new Int32Array(this.buffer);
+        if (options.timeoutMs !== undefined) this.deadline = performance.now() + options.timeoutMs;
+    }
+
+    cancel(): void {
+        Atomics.store(this.flag, 0, 1);
+    }
+
+    get cancelled(): boolean {
+        return Atomics.load(this.flag, 0) === 1 || (this.deadline !== undefined && performance.now() >= this.deadline);
+    }
+}
+
+export interface InterpreterOptions {
+    inlineCaches?: boolean;
+    maxCallDepth?: number;
+    explain?: Expr[]; // print the step-by-step evaluation of these expressions (see explainableAt)
+    prelude?: Stmt[]; // run once at construction and kept by reset()
+    args?: string[]; // the program's command-line arguments, from env.args()
+    input?: string; // what the io readers read instead of stdin
+    sandbox?: boolean; // no files or environment variables, for untrusted programs
+    file?: string; // what stack traces call the main program, "<input>" by default
+    strictMath?: boolean; // float division by zero is an error instead of inf or NaN
+}
+
+// A top-level binding of the running program, as seen by an embedding host
+export interface GlobalBinding {
+    name: string;
+    value: any;
+    type: string; // the declared type, e.g. "int" or "(int) => bool"
+    mutable: boolean;
+}
+
+interface CallFrame {
+    name: string;
+    line?: number;
+}
+
+// Where a runtime error was, or where a frame was when it passed through
+interface TracePosition {
+    range: SourceRange;
+    file?: string; // absent in the main program
+}
+
+// Built as the error unwinds: expressions fill in at, and each function
+// it leaves takes at as its frame's position
+interface ErrorTrace {
+    error: unknown;
+    frames: { name: string; at?: TracePosition }[]; // innermost first, at most five
+    depth: number;
+    at?: TracePosition;
+    site?: TracePosition; // the innermost position, which the diagnostic points at
+    line?: number;
+    file?: string;
+}
+
+const DEFAULT_MAX_CALL_DEPTH = 1000;
+
+// One test block run by Interpreter.runTests; error is absent when it passed
+export interface TestResult {
+    name: string;
+    line?: number;
+    error?: StrataError;
+    ms: number;
+}
+
+export class Interpreter {
+    private env: Environment = new Environment();
+    private flowValue: any = null; // of the return or break under way
+    // A return, break or continue inside an if, match or block expression,
+    // for the statement around the expression to pass on
+    private leaving: Flow = null;
+    // Deferred statements of the blocks being run, innermost block's last
+    private deferred: Stmt[] = [];
+    private inlineCaches: boolean;
+    private callCaches = new WeakMap<Expr, CallSiteCache>();
+    // BUILTIN_FUNCTIONS, or a copy keeping only SANDBOX_BUILTINS in a sandbox
+    private builtins = BUILTIN_FUNCTIONS;
+    private structs = new Map<string, string[]>(); // field names in declaration order
+    private maxCallDepth: number;
+    private callStack: CallFrame[] = [];
+    private currentLine?: number;
+    private currentFile?: string; // set while running statements linked in from an import
+    private errorTrace?: ErrorTrace;
+    private file: string;
+    private strictMath: boolean;
+    private baseline: { env: EnvironmentSnapshot; structs: Map<string, string[]> };
+    private cancellation?: CancellationToken; // only set during runWithCancel
+    private programArgs: string[];
+    private input?: string[]; // lines left of the input option
+    // Only set with the explain option, so evaluation checks one field otherwise
+    private explaining?: {
+        roots: Set<Expr>;
+        current?: Expr; // the node being evaluated past the explain check
+        explanation?: { root: Expr; values: Map<Expr, any>; last: string; depth: number };
+    };
+
+    // An interpreter whose call sites already point at the stdlib functions
+    // and builtins the checker resolved them to, so no call pays for the
+    // lookup on its first run. Run it with interpret(program.statements)
+    static fromTyped(program: TypedProgram, options: InterpreterOptions = {}): Interpreter {
+        const interpreter = new Interpreter(options);
+        if (interpreter.inlineCaches) interpreter.warmCallSites(program);
+        return interpreter;
+    }
+
+    constructor(options: InterpreterOptions = {}) {
+        this.inlineCaches = options.inlineCaches ?? true;
+        this.programArgs = options.args ?? [];
+        if (options.input !== undefined) this.input = options.input.split(/\r?\n/);
+        if (this.input?.[this.input.length - 1] === "") this.input.pop();
+        this.file = options.file ?? "<input>";
+        this.strictMath = options.strictMath ?? false;
+        this.maxCallDepth = options.maxCallDepth ?? DEFAULT_MAX_CALL_DEPTH;
+        if (options.explain?.length) this.explaining = { roots: new Set(options.explain) };
+        this.setupStdlib();
+        if (options.sandbox) this.sandbox();
+        if (options.prelude) this.interpret(options.prelude);
+        this.baseline = { env: this.env.snapshot(), structs: new Map(this.structs) };
+    }
+
+    // Forgets everything programs defined or assigned since construction,
+    // keeping the stdlib and the prelude, so one interpreter can serve
+    // unrelated requests without leaking state between them
+    reset(): void {
+        while (this.env.parent) this.env = this.env.parent;
+        this.env.restore(this.baseline.env);
+        this.structs = new Map(this.baseline.structs);
+        this.flowValue = null;
+        this.leaving = null;
+        this.deferred = [];
+        this.callStack = [];
+        this.currentLine = undefined;
+        this.currentFile = undefined;
+        this.errorTrace = undefined;
+        // Cached call targets may be functions of the forgotten program
+        this.env.shadows.count++;
+    }
+
+    // The stdlib modules and the program's top-level bindings
+    get environment(): Environment {
+        return this.env;
+    }
+
+    // The program's let/var/const and function bindings, in declaration
+    // order. Stdlib bindings and imports carry no declared type and are left out
+    globals(): GlobalBinding[] {
+        return this.env.bindings()
+            .filter((binding) => binding.type)
+            .map(({ name, value, mutable, type }) => ({ name, value, type: formatType(type!), mutable }));
+    }
+
+    // Lets a host change script state between calls, with the same rules
+    // as an assignment in the script: the binding must exist and be a var.
+    // The value is not checked against the declared type
+    setGlobal(name: string, value: any): void {
+        if (!this.globals().some((binding) => binding.name === name)) {
+            throw new Error(message("runtime.undefined-variable", { name }));
+        }
+        this.env.update(name, value);
+    }
+
+    private readLine(): string | null {
+        if (this.input) return this.input.shift() ?? null;
+        return readStdinLine();
+    }
+
+    // Untrusted programs get no file access and no environment variables;
+    // env.args() still sees the arguments the host passed in. Builtins
+    // outside SANDBOX_BUILTINS are blocked as well
+    private sandbox(): void {
+        this.builtins = { ...BUILTIN_FUNCTIONS };
+        for (const fn of Object.keys(BUILTIN_FUNCTIONS)) {
+            if (SANDBOX_BUILTINS.has(fn)) continue;
+            this.builtins[fn] = () => { throw new Error(message("runtime.sandboxed", { fn })); };
+        }
+        for (const name of ["std::file", "std::fs", "std::env"]) {
+            const module = this.env.getModule(name);
+            for (const fn of Object.keys(module)) {
+                if (name === "std::env" && fn === "args") continue;
+                const qualified = `${name.slice("std::".length)}.${fn}`;
+                module[fn] = () => { throw new Error(message("runtime.sandboxed", { fn: qualified })); };
+            }
+        }
+    }
+
+    private setupStdlib(): void {
+        // I/O Module
+        const ioModule = {
+            print: (...values: any[]) => { console.log(values.map(formatValue).join(" ")); return null; },
+            println: (...values: any[]) => { console.log(values.map(formatValue).join(" ")); return null; },
+            // readLine is "" at the end of input and nextLine None; the
+            // number readers take a whole line and fail on anything else
+            readLine: () => this.readLine() ?? "",
+            nextLine: () => {
+                const line = this.readLine();
+                return line === null ? { type: "none" } : { type: "some", value: line };
+            },
+            readInt: () => readNumber("io.readInt", /^[+-]?[0-9]+$/, "runtime.read-int", () => this.readLine()),
+            readFloat: () =>
+                readNumber("io.readFloat", /^[+-]?([0-9]+\.?[0-9]*|\.[0-9]+)([eE][+-]?[0-9]+)?$/, "runtime.read-float", () => this.readLine()),
+        };
+        this.env.setModule("std::io", ioModule);
+        this.env.setModule("str", ioModule); // Alias for std::io
+
+        // Math Module (Python, R, C, C++)
+        this.env.setModule("std::math", {
+            sqrt: (x: number) => Math.sqrt(x),
+            sin: (x: number) => Math.sin(x),
+            cos: (x: number) => Math.cos(x),
+            tan: (x: number) => Math.tan(x),
+            asin: (x: number) => Math.asin(x),
+            acos: (x: number) => Math.acos(x),
+            atan: (x: number) => Math.atan(x),
+            exp: (x: number) => Math.exp(x),
+            log: (x: number) => Math.log(x),
+            log10: (x: number) => Math.log10(x),
+            log2: (x: number) => Math.log2(x),
+            floor: (x: number) => Math.floor(x),
+            ceil: (x: number) => Math.ceil(x),
+            round: (x: number) => Math.round(x),
+            abs: (x: number) => Math.abs(x),
+            pow: (x: number, y: number) => Math.pow(x, y),
+            max: (...args: number[]) => Math.max(...args),
+            min: (...args: number[]) => Math.min(...args),
+            gcd: (a: number, b: number) => { let x = Math.abs(a), y = Math.abs(b); while (y) [x, y] = [y, x % y]; return x; },
+            checkedAdd: (a: number, b: number) => checkedIntOp(a + b),
+            checkedMul: (a: number, b: number) => checkedIntOp(a * b),
+            ...constantValues("std::math"),
+        });
+
+        // Type namespaces: int.MAX, float.EPSILON
+        this.env.set("int", constantValues("int"));
+        this.env.set("float", constantValues("float"));
+        this.env.set("datetime", DATETIME_FUNCTIONS);
+
+        // Char Module: Unicode-aware classification and case mapping, with
+        // ASCII-only variants for parsers of ASCII formats
+        const charTest = (name: string, pattern: RegExp) => (c: string) => pattern.test(charArg(name, c));
+        const upperAscii = (c: string) => (c >= "a" && c <= "z" ? c.toUpperCase() : c);
+        const lowerAscii = (c: string) => (c >= "A" && c <= "Z" ? c.toLowerCase() : c);
+        // Case mappings that would produce several chars ('ß' -> "SS") keep the char
+        const singleChar = (c: string, mapped: string) => ([...mapped].length === 1 ? mapped : c);
+        this.env.set("char", {
+            isDigit: charTest("isDigit", /^\p{Nd}$/u),
+            isAlpha: charTest("isAlpha", /^\p{L}$/u),
+            isAlphanumeric: charTest("isAlphanumeric", /^[\p{L}\p{Nd}]$/u),
+            isSpace: charTest("isSpace", /^\s$/u),
+            isUpper: charTest("isUpper", /^\p{Lu}$/u),
+            isLower: charTest("isLower", /^\p{Ll}$/u),
+            isAsciiDigit: charTest("isAsciiDigit", /^[0-9]$/),
+            isAsciiAlpha: charTest("isAsciiAlpha", /^[A-Za-z]$/),
+            isAsciiAlphanumeric: charTest("isAsciiAlphanumeric", /^[A-Za-z0-9]$/),
+            isAsciiSpace: charTest("isAsciiSpace", /^[ \t\n\r\f\v]$/),
+            toUpper: (c: string) => singleChar(c, charArg("toUpper", c).toUpperCase()),
+            toLower: (c: string) => singleChar(c, charArg("toLower", c).toLowerCase()),
+            toAsciiUpper: (c: string) => upperAscii(charArg("toAsciiUpper", c)),
+            toAsciiLower: (c: string) => lowerAscii(charArg("toAsciiLower", c)),
+            code: (c: string) => charArg("code", c).codePointAt(0),
+            fromCode: (n: number) => {
+                if (!Number.isInteger(n) || n < 0 || n > 0x10ffff || (n >= 0xd800 && n <= 0xdfff)) {
+                    throw new Error(message("runtime.code-point", { code: formatNested(n) }));
+                }
+                return String.fromCodePoint(n);
+            },
+        });
+
+        // String/Text Module (Python, Ruby, JavaScript)
+        this.env.setModule("std::text", {
+            split: (s: string, sep: string) => s.split(sep),
+            join: (arr: string[], sep: string) => arr.join(sep),
+            trim: (s: string) => s.trim(),
+            upper: (s: string) => s.toUpperCase(),
+            lower: (s: string) => s.toLowerCase(),
+            toUpperCase: (s: string) => s.toUpperCase(),
+            toLowerCase: (s: string) => s.toLowerCase(),
+            startsWith: (s: string, prefix: string) => s.startsWith(prefix),
+            endsWith: (s: string, suffix: string) => s.endsWith(suffix),
+            contains: (s: string, substr: string) => s.includes(substr),
+            includes: (s: string, substr: string) => s.includes(substr),
+            // Positions count code points, like length, so they're safe to
+            // pass back to substring whatever the text holds
+            indexOf: (s: string, substr: string) => {
+                const at = s.indexOf(substr);
+                return at < 0 ? -1 : [...s.slice(0, at)].length;
+            },
+            replace: (s: string, old: string, newStr: string) => s.replace(old, () => newStr),
+            replaceAll: (s: string, old: string, newStr: string) => s.replaceAll(old, () => newStr),
+            substring: (s: string, start: number, end?: number) => {
+                const chars = [...s];
+                const clamp = (i: number) => Math.min(Math.max(i, 0), chars.length);
+                const [from, to] = [clamp(start), clamp(end ?? chars.length)];
+                return chars.slice(Math.min(from, to), Math.max(from, to)).join("");
+            },
+            // Negative positions count back from the end
+            substr: (s: string, start: number, length?: number) => {
+                const chars = [...s];
+                const from = start < 0 ? Math.max(chars.length + start, 0) : start;
+                return chars.slice(from, length === undefined ? undefined : from + Math.max(length, 0)).join("");
+            },
+            slice: (s: string, start: number, end?: number) => [...s].slice(start, end).join(""),
+            repeat: (s: string, count: number) => s.repeat(count),
+            // Ordering by code point, the same in every locale: -1, 0 or 1
+            compare: (a: string, b: string) => compareText(a, b),
+            compareIgnoreCase: (a: string, b: string) => compareText(a.toLowerCase(), b.toLowerCase()),
+            // Lengths: chars are code points, bytes are UTF-8
+            length: (s: string) => [...s].length,
+            byteLength: (s: string) => utf8Encoder.encode(s).length,
+            chars: (s: string) => [...s],
+            bytes: (s: string) => [...utf8Encoder.encode(s)],
+            fromBytes: (bytes: number[]) => decodeUtf8("text.fromBytes", byteArg("text.fromBytes", bytes)),
+            charAt: (s: string, index: number) => [...s][index] ?? "",
+            charCodeAt: (s: string, index: number) => {
+                const chars = [...s];
+                if (!Number.isInteger(index) || index < 0 || index >= chars.length) {
+                    throw new Error(message("runtime.index-bounds", { index: formatNested(index), length: chars.length }));
+                }
+                return chars[index].codePointAt(0);
+            },
+        });
+
+        // Hash Module: digests of text (as UTF-8) or bytes, as lowercase hex
+        this.env.setModule("std::hash", {
+            md5: (data: string | number[]) => createHash("md5").update(toBytes("hash.md5", data)).digest("hex"),
+            sha256: (data: string | number[]) => createHash("sha256").update(toBytes("hash.sha256", data)).digest("hex"),
+            crc32: (data: string | number[]) => crc32(toBytes("hash.crc32", data)).toString(16).padStart(8, "0"),
+        });
+
+        // CSV Module: rows as string arrays, or records keyed by the header row
+        this.env.setModule("std::csv", {
+            parse: (text: string) => parseCsv(text),
+            parseRecords: (text: string) => {
+                const lines: number[] = [];
+                const [header = [], ...rows] = parseCsv(text, lines);
+                return rows.map((row, i) => {
+                    if (row.length !== header.length) {
+                        throw new Error(message("runtime.csv-record-width", { line: lines[i + 1], actual: row.length, expected: header.length }));
+                    }
+                    return new Map(header.map((name, j) => [name, row[j]]));
+                });
+            },
+            stringify: (rows: any[]) => stringifyCsv(rows),
+        });
+
+        // Encoding Module: text is encoded as UTF-8, decoding yields bytes
+        this.env.setModule("std::encode", {
+            base64: (data: string | number[]) => Buffer.from(toBytes("encode.base64", data)).toString("base64"),
+            fromBase64: (s: string) => {
+                if (s.length % 4 !== 0 || !/^[A-Za-z0-9+/]*={0,2}$/.test(s)) {
+                    throw new Error(message("runtime.invalid-base64"));
+                }
+                return [...Buffer.from(s, "base64")];
+            },
+            hex: (data: string | number[]) => Buffer.from(toBytes("encode.hex", data)).toString("hex"),
+            fromHex: (s: string) => {
+                if (!/^([0-9a-fA-F]{2})*$/.test(s)) throw new Error(message("runtime.invalid-hex"));
+                return [...Buffer.from(s, "hex")];
+            },
+        });
+
+        // Array/List Module (Python, JavaScript, Go, Rust)
+        this.env.setModule("std::list", {
+            map: (arr: any[], fn: any) => arr.map(fn),
+            filter: (arr: any[], fn: any) => arr.filter(fn),
+            reduce: (arr: any[], fn: any, init?: any) => arr.reduce(fn, init),
+            forEach: (arr: any[], fn: any) => { arr.forEach(fn); },
+            find: (arr: any[], fn: any) => arr.find(fn),
+            findIndex: (arr: any[], fn: any) => arr.findIndex(fn),
+            some: (arr: any[], fn: any) => arr.some(fn),
+            every: (arr: any[], fn: any) => arr.every(fn),
+            includes: (arr: any[], item: any) => arr.includes(item),
+            indexOf: (arr: any[], item: any) => arr.indexOf(item),
+            push: (arr: any[], item: any) => { arr.push(item); return arr; },
+            pop: (arr: any[]) => arr.pop(),
+            shift: (arr: any[]) => arr.shift(),
+            unshift: (arr: any[], item: any) => { arr.unshift(item); return arr; },
+            reverse: (arr: any[]) => { arr.reverse(); return arr; },
+            sort: (arr: any[], fn?: any) => { arr.sort(fn ?? compareValues); return arr; },
+            concat: (arr: any[], ...others: any[]) => arr.concat(...others),
+            flat: (arr: any[], depth?: number) => arr.flat(depth),
+            length: (arr: any[]) => arr.length,
+        });
+
+        // Collections Module: sorting that returns a new array and is stable,
+        // so elements that compare equal keep their original order
+        this.env.setModule("std::collections", {
+            sort: (arr: any[]) => [...arr].sort(compareValues),
+            sortDescending: (arr: any[]) => [...arr].sort((a, b) => compareValues(b, a)),
+            // Keys are computed once per element, not once per comparison
+            sortBy: (arr: any[], key: (x: any) => any) => arr
+                .map((value) => ({ value, key: key(value) }))
+                .sort((a, b) => compareValues(a.key, b.key))
+                .map((entry) => entry.value),
+            sortWith: (arr: any[], compare: (a: any, b: any) => number) => [...arr].sort((a, b) => compare(a, b)),
+            min: (arr: any[]) => nonEmpty("collections.min", arr).reduce((a, b) => (compareValues(b, a) < 0 ? b : a)),
+            max: (arr: any[]) => nonEmpty("collections.max", arr).reduce((a, b) => (compareValues(b, a) > 0 ? b : a)),
+        });
+
+        // Dictionary/Map Module (Python, JavaScript, Go, Rust)
+        this.env.setModule("std::map", {
+            keys: (obj: any) => BUILTIN_FUNCTIONS.keys([obj]),
+            values: (obj: any) => BUILTIN_FUNCTIONS.values([obj]),
+            entries: (obj: any) => BUILTIN_FUNCTIONS.entries([obj]),
+            has: (obj: any, key: string) => BUILTIN_FUNCTIONS.has([obj, key]),
+            get: (obj: any, key: string) => BUILTIN_FUNCTIONS.get([obj, key]),
+            set: (obj: any, key: string, value: any) => BUILTIN_FUNCTIONS.set([obj, key, value]),
+            delete: (obj: any, key: string) => BUILTIN_FUNCTIONS.delete([obj, key]),
+            clear: (obj: any) => BUILTIN_FUNCTIONS.clear([obj]),
+            length: (obj: any) => obj instanceof Map ? obj.size : Object.keys(obj).length,
+            assign: (target: any, ...sources: any[]) => Object.assign(target, ...sources),
+        });
+
+        // Type Module (Python, JavaScript, TypeScript, Go)
+        this.env.setModule("std::type", {
+            typeof: (x: any) => typeof x,
+            isArray: (x: any) => Array.isArray(x),
+            isObject: (x: any) => x !== null && typeof x === "object",
+            isNull: (x: any) => x === null,
+            isUndefined: (x: any) => x === undefined,
+            isNumber: (x: any) => typeof x === "number",
+            isString: (x: any) => typeof x === "string",
+            isBoolean: (x: any) => typeof x === "boolean",
+            isNaN: (x: any) => isNaN(x),
+            isFinite: (x: any) => isFinite(x),
+            isInteger: (x: any) => Number.isInteger(x),
+            toNumber: (x: any) => Number(x),
+            toString: (x: any) => String(x),
+            toBoolean: (x: any) => Boolean(x),
+            toInt: (x: any) => Math.floor(Number(x)),
+            toFloat: (x: any) => parseFloat(String(x)),
+        });
+
+        // File Module (Python, Go, C, C++)
+        this.env.setModule("std::file", {
+            read: (path: string) => { try { return fs.readFileSync(path, "utf-8"); } catch { return null; } },
+            write: (path: string, content: string) => { try { fs.writeFileSync(path, content); return true; } catch { return false; } },
+            append: (path: string, content: string) => { try { fs.appendFileSync(path, content); return true; } catch { return false; } },
+            exists: (path: string) => fs.existsSync(path),
+            delete: (path: string) => { try { fs.unlinkSync(path); return true; } catch { return false; } },
+            isFile: (path: string) => { try { return fs.statSync(path).isFile(); } catch { return false; } },
+            isDirectory: (path: string) => { try { return fs.statSync(path).isDirectory(); } catch { return false; } },
+            mkdir: (path: string) => { try { fs.mkdirSync(path, { recursive: true }); return true; } catch { return false; } },
+        });
+
+        // Environment Module: the program's arguments and environment variables
+        this.env.setModule("std::env", {
+            args: () => [...this.programArgs],
+            get: (name: string) => (process.env[name] !== undefined ? { type: "some", value: process.env[name] } : { type: "none" }),
+        });
+
+        // File System Module: failures are Err values the program can inspect
+        // instead of runtime errors, so scripts can recover from a missing file
+        this.env.setModule("std::fs", {
+            readFile: (path: string) => fsResult("readFile", path, () => fs.readFileSync(path, "utf-8")),
+            // Writes succeed with the number of UTF-8 bytes written
+            writeFile: (path: string, content: string) =>
+                fsResult("writeFile", path, () => (fs.writeFileSync(path, content), Buffer.byteLength(content))),
+            appendFile: (path: string, content: string) =>
+                fsResult("appendFile", path, () => (fs.appendFileSync(path, content), Buffer.byteLength(content))),
+            exists: (path: string) => fs.existsSync(path),
+            listDir: (path: string) => fsResult("listDir", path, () => fs.readdirSync(path).sort()),
+            // Handles for reading line by line or writing; close them, best
+            // with with (f = fs.open(path)) { ... }
+            open: (path: string) => fsResult("open", path, () => fileHandle(path, fs.openSync(path, "r"))),
+            create: (path: string) => fsResult("create", path, () => fileHandle(path, fs.openSync(path, "w"))),
+        });
+
+        // Regex Module (Python, Ruby, JavaScript, Go)
+        this.env.setModule("std::regex", {
+            match: (str: string, pattern: string, flags?: string) => { try { const m = str.match(new RegExp(pattern, flags ?? "")); return m ?? null; } catch { return null; } },
+            test: (str: string, pattern: string, flags?: string) => { try { return new RegExp(pattern, flags ?? "").test(str); } catch { return false; } },
+            search: (str: string, pattern: string, flags?: string) => { try { return str.search(new RegExp(pattern, flags ?? "")); } catch { return -1; } },
+            replace: (str: string, pattern: string, replacement: string, flags?: string) => { try { return str.replace(new RegExp(pattern, flags ?? "g"), replacement); } catch { return str; } },
+        });
+
+        // DateTime Module (Python, Go, JavaScript, Ruby)
+        this.env.setModule("std::time", {
+            now: () => new Date().getTime(),
+            timestamp: () => Math.floor(new Date().getTime() / 1000),
+            getDate: (ms: number) => new Date(ms).getDate(),
+            getMonth: (ms: number) => new Date(ms).getMonth() + 1,
+            getYear: (ms: number) => new Date(ms).getFullYear(),
+            getHours: (ms: number) => new Date(ms).getHours(),
+            getMinutes: (ms: number) => new Date(ms).getMinutes(),
+            getSeconds: (ms: number) => new Date(ms).getSeconds(),
+        });
+
+        // Function Module (partial application and composition)
+        this.env.setModule("std::func", {
+            bind: (f: any, ...bound: any[]) => (...rest: any[]) => f(...bound, ...rest),
+            compose: (f: any, g: any) => (...args: any[]) => f(g(...args)),
+        });
+
+        // Set Module (Python, Go, Rust)
+        this.env.setModule("std::set", {
+            create: () => new Set(),
+            add: (set: Set<any>, item: any) => { set.add(item); return set; },
+            remove: (set: Set<any>, item: any) => { set.delete(item); return set; },
+            has: (set: Set<any>, item: any) => set.has(item),
+            size: (set: Set<any>) => set.size,
+            clear: (set: Set<any>) => { set.clear(); return set; },
+            union: (set1: Set<any>, set2: Set<any>) => new Set([...set1, ...set2]),
+            intersection: (set1: Set<any>, set2: Set<any>) => new Set([...set1].filter(x => set2.has(x))),
+            difference: (set1: Set<any>, set2: Set<any>) => new Set([...set1].filter(x => !set2.has(x))),
+        });
+    }
+
+    interpret(statements: Stmt[]): void {
+        try {
+            this.runBlock(statements);
+        } catch (error) {
+            // An error leaves its blocks without running their defers
+            this.deferred = [];
+            throw this.runtimeError(error);
+        }
+    }
+
+    // Runs statements until one leaves the block, then the defers the block
+    // reached, last first
+    private runBlock(body: Stmt[]): Flow {
+        const mark = this.deferred.length;
+        let flow: Flow = null;
+        for (let i = 0; i < body.length && flow === null; i++) flow = this.interpretStatement(body[i]);
+        if (this.deferred.length > mark) this.runDeferred(mark);
+        return flow;
+    }
+
+    // A return or break is still under way while the defers run; they
+    // can't leave themselves, as the checker makes sure
+    private runDeferred(mark: number): void {
+        const value = this.flowValue;
+        while (this.deferred.length > mark) this.interpretStatement(this.deferred.pop()!);
+        this.flowValue = value;
+    }
+
+    // interpret() as the setup, then each test block in a scope of its own
+    // over the program's globals. A failing test's error is recorded and the
+    // next test still runs; an error in the setup is thrown as by interpret().
+    // token stops the whole run, as for runWithCancel
+    runTests(statements: Stmt[], token?: CancellationToken): TestResult[] {
+        this.cancellation = token;
+        try {
+            return this.runTestBlocks(statements);
+        } finally {
+            this.cancellation = undefined;
+        }
+    }
+
+    private runTestBlocks(statements: Stmt[]): TestResult[] {
+        this.interpret(statements);
+        const results: TestResult[] = [];
+        for (const stmt of statements) {
+            if (stmt.kind !== "test") continue;
+            const started = performance.now();
+            const outer = this.env;
+            this.env = new Environment(outer);
+            let error: StrataError | undefined;
+            try {
+                this.runBlock(stmt.body);
+            } catch (thrown) {
+                this.deferred = [];
+                error = this.runtimeError(thrown);
+            } finally {
+                this.env = outer;
+            }
+            results.push({ name: stmt.name, line: stmt.line, error, ms: performance.now() - started });
+        }
+        return results;
+    }
+
+    // The flow of a statement whose expressions may hold a value block
+    private left(): Flow {
+        const flow = this.leaving;
+        this.leaving = null;
+        return flow;
+    }
+
+    // interpret(), stopping at the next loop iteration or call once token
+    // is cancelled. Function calls unwind as for any runtime error, so the
+    // interpreter stays usable afterwards
+    runWithCancel(statements: Stmt[], token: CancellationToken): void {
+        this.cancellation = token;
+        try {
+            this.interpret(statements);
+        } finally {
+            this.cancellation = undefined;
+        }
+    }
+
+    private checkCancelled(): void {
+        if (this.cancellation?.cancelled) {
+            throw errorAt("E0005", message("runtime.cancelled"), this.currentLine !== undefined ? { line: this.currentLine } : undefined);
+        }
+    }
+
+    // The trace of error, started by the first expression or call it leaves
+    private traceError(error: unknown): ErrorTrace {
+        if (this.errorTrace?.error !== error) {
+            this.errorTrace = { error, frames: [], depth: 0, line: this.currentLine, file: this.currentFile };
+        }
+        return this.errorTrace;
+    }
+
+    // Notes are the stack trace, innermost call first and the main program
+    // last; the diagnostic points at the expression that failed
+    private runtimeError(error: unknown): StrataError {
+        const trace = this.errorTrace?.error === error ? this.errorTrace : undefined;
+        this.errorTrace = undefined;
+        const notes = (trace?.frames ?? []).map((f) => this.traceFrame(f.name, f.at));
+        if (trace && trace.depth > 5) notes.push(message("runtime.more-frames", { count: trace.depth - 5 }));
+        if (notes.length > 0) notes.push(this.traceFrame("<main>", trace!.at));
+        const site = trace?.site;
+        const line = trace?.line ?? this.currentLine;
+        const span = site
+            ? { line: site.range.line, column: site.range.column, length: site.range.end - site.range.start }
+            : line !== undefined ? { line } : undefined;
+        const reported = asStrataError(error, "E0004", span, notes);
+        reported.diagnostic.file ??= site ? site.file : trace?.line !== undefined ? trace.file : this.currentFile;
+        return reported;
+    }
+
+    private traceFrame(name: string, at?: TracePosition): string {
+        const file = at?.file ?? this.file;
+        const location = at ? `${file}:${at.range.line}:${at.range.column}` : file;
+        return message("runtime.frame-at", { name, location });
+    }
+
+    private interpretStatement(stmt: Stmt): Flow {
+        if (stmt.line !== undefined) {
+            this.currentLine = stmt.line;
+            this.currentFile = stmt.file;
+        }
+        switch (stmt.kind) {
+            case "let":
+                const value = this.evaluateExpression(stmt.value);
+                this.env.set(stmt.name, value, stmt.mutable, stmt.type);
+                break;
+            case "assignment":
+                const newValue = this.evaluateExpression(stmt.value);
+                this.env.update(stmt.target, newValue);
+                break;
+            case "struct":
+                this.structs.set(stmt.name, stmt.fields.map((f) => f.name));
+                return null;
+            // Only runTests runs test blocks
+            case "test":
+                return null;
+            case "enum":
+                this.env.set(stmt.name, enumNamespace(stmt.name, stmt.variants));
+                return null;
+            case "match":
+                this.runMatch(stmt, false);
+                break;
+            case "indexAssignment":
+                assignIndex(
+                    this.evaluateExpression(stmt.object),
+                    this.evaluateExpression(stmt.index),
+                    this.evaluateExpression(stmt.value)
+                );
+                break;
+            case "expression":
+                this.evaluateExpression(stmt.expr);
+                break;
+            case "if":
+                const condition = this.evaluateExpression(stmt.condition);
+                if (condition) return this.runBlock(stmt.then);
+                return stmt.else ? this.runBlock(stmt.else) : null;
+            case "while":
+                while (this.loopCondition(stmt)) {
+                    if (this.cancellation) this.checkCancelled();
+                    const flow = this.runBlock(stmt.body);
+                    if (flow === "break") return null;
+                    if (flow === "return") return flow;
+                }
+                return null;
+            case "for":
+                this.interpretStatement(stmt.init);
+                while (this.evaluateExpression(stmt.condition)) {
+                    if (this.cancellation) this.checkCancelled();
+                    const flow = this.runBlock(stmt.body);
+                    if (flow === "break") return null;
+                    if (flow === "return") return flow;
+                    this.interpretStatement(stmt.update);
+                }
+                return null;
+            case "forIn":
+                for (const item of iterate(this.evaluateExpression(stmt.iterable))) {
+                    if (this.cancellation) this.checkCancelled();
+                    this.env.set(stmt.variable, item, true);
+                    const flow = this.runBlock(stmt.body);
+                    if (flow === "break") return null;
+                    if (flow === "return") return flow;
+                }
+                return null;
+            case "return":
+                this.flowValue = stmt.value
+                    ? this.evaluateExpression(stmt.value)
+                    : null;
+                return "return";
+            case "break":
+                this.flowValue = stmt.value ? this.evaluateExpression(stmt.value) : null;
+                return "break";
+            case "continue":
+                return "continue";
+            case "defer":
+                this.deferred.push(stmt.body);
+                return null;
+            case "function":
+               this.env.setFunction(stmt.name, [
+                   ...stmt.params.map((p) => p.name),
+               ], stmt.body);
+               // Functions are values: bind a callable that closes over the
+               // defining environment so it can be passed around and called
+               const closure = this.env;
+               const type: TypeDef = { kind: "function", params: stmt.params.map((p) => p.type), returnType: stmt.returnType };
+               this.env.set(stmt.name, (...args: any[]) => this.callFunction(stmt, closure, args), false, type);
+               return null;
+            case "import":
+               // Bind module to variable name
+               const module = this.env.getModule(stmt.module);
+               if (!module) {
+                   throw new Error(message("runtime.module-not-found", { module: stmt.module }));
+               }
+               this.env.set(stmt.name, module, false);
+               return null;
+        }
+        return this.leaving === null ? null : this.left();
+    }
+
+    // The explain check is inlined rather than a wrapper so deep recursion
+    // doesn't pay an extra host stack frame per expression
+    private evaluateExpression(expr: Expr): any {
+        if (this.explaining && expr !== this.explaining.current) return this.explainExpression(expr);
+        try {
+            switch (expr.kind) {
+                case "literal":
+                    return expr.value;
+                case "identifier":
+                    return this.env.get(expr.name);
+                case "binary":
+                    const left = this.evaluateExpression(expr.left);
+                    // && and || leave the right operand alone once the left decides
+                    if (expr.op === "&&" ? !left : expr.op === "||" && left) return left;
+                    const right = this.evaluateExpression(expr.right);
+                    if (expr.integer) return integerOp(expr.op, left, right);
+                    if (this.strictMath) strictZeroDivisor(expr.op, right);
+                    return applyBinaryOp(expr.op, left, right);
+                case "unary":
+                    const operand = this.evaluateExpression(expr.operand);
+                    if (expr.integer) return integerOp("-", 0, operand);
+                    return applyUnaryOp(expr.op, operand);
+                case "call":
+                    const func = this.resolveCallee(expr);
+                    const args = expr.args.map((a) =>
+                        this.evaluateExpression(a)
+                    );
+                    this.currentLine = expr.line;
+                    return func(...args);
+                case "member":
+                    return ownField(this.evaluateExpression(expr.object), expr.property);
+                case "field":
+                    return fieldValue(this.evaluateExpression(expr.object), expr.name);
+                case "index":
+                    return indexValue(
+                        this.evaluateExpression(expr.object),
+                        this.evaluateExpression(expr.index)
+                    );
+                case "array":
+                    return expr.elements.map((e) => this.evaluateExpression(e));
+                case "match":
+                    return this.runMatch(expr, true);
+                case "if":
+                    return this.runValueBlock(this.evaluateExpression(expr.condition) ? expr.then : expr.else);
+                case "block":
+                    return this.runValueBlock(expr.body);
+                case "loop":
+                    return this.runLoop(expr.body);
+                case "structLiteral":
+                    const fieldValues = new Map<string, any>(
+                        expr.fields.map((f) => [f.name, this.evaluateExpression(f.value)])
+                    );
+                    // Declaration order, so printing does not depend on the literal
+                    const order = this.structs.get(expr.name) ?? [...fieldValues.keys()];
+                    return makeStruct(expr.name, order.map((name) => [name, fieldValues.get(name)]));
+                case "map":
+                    return new Map(expr.entries.map((e) => [
+                        this.evaluateExpression(e.key),
+                        this.evaluateExpression(e.value),
+                    ]));
+                case "range":
+                    return makeRange(
+                        this.evaluateExpression(expr.start),
+                        this.evaluateExpression(expr.end),
+                        expr.inclusive,
+                        expr.step ? this.evaluateExpression(expr.step) : 1
+                    );
+                case "lambda": {
+                    const closure = this.env;
+                    return (...args: any[]) => this.callFunction(expr.fn, closure, args);
+                }
+                case "propagate": {
+                    const value = this.evaluateExpression(expr.value);
+                    if (value?.type === "ok" || value?.type === "some") return value.value;
+                    if (value?.type !== "err" && !isEmptyOptional(value)) return value;
+                    if (this.callStack.length === 0) throw new Error(message("runtime.propagate-outside"));
+                    throw new Propagation(value);
+                }
+            }
+        } catch (error) {
+            // The innermost expression with a span is where the error is;
+            // after a call returns, its call site is where the caller was
+            if (expr.span && !(error instanceof Propagation)) {
+                const trace = this.traceError(error);
+                trace.at ??= { range: expr.span, file: this.currentFile };
+                trace.site ??= trace.at;
+            }
+            throw error;
+        }
+    }
+
+    private explainExpression(expr: Expr): any {
+        const { roots, explanation } = this.explaining!;
+        // Expressions in the bodies of functions it calls are not part of the explanation
+        if (explanation) {
+            return explanation.depth === this.callStack.length ? this.explainStep(expr) : this.evaluateTraced(expr);
+        }
+        return roots.has(expr) ? this.explain(expr) : this.evaluateTraced(expr);
+    }
+
+    // Evaluates expr itself, letting it past the explain check once
+    private evaluateTraced(expr: Expr): any {
+        const explaining = this.explaining!;
+        explaining.current = expr;
+        try {
+            return this.evaluateExpression(expr);
+        } finally {
+            explaining.current = undefined;
+        }
+    }
+
+    // Prints the expression, then the whole expression again after each
+    // operator, call or lookup is reduced to its value
+    private explain(root: Expr): any {
+        const explaining = this.explaining!;
+        const last = renderExpr(root, new Map());
+        console.log(`line ${this.currentLine}: ${last}`);
+        explaining.explanation = { root, values: new Map<Expr, any>(), last, depth: this.callStack.length };
+        try {
+            return this.explainStep(root);
+        } finally {
+            explaining.explanation = undefined;
+        }
+    }
+
+    private explainStep(expr: Expr): any {
+        const explanation = this.explaining!.explanation!;
+        const value = this.evaluateTraced(expr);
+        const discarded = expr === explanation.root && expr.kind === "call" && (value === null || value === undefined);
+        if (expr.kind !== "literal" && !discarded && isExplainable(value)) {
+            explanation.values.set(expr, value);
+            const text = renderExpr(explanation.root, explanation.values);
+            if (text !== explanation.last) console.log(`  = ${text}`);
+            explanation.last = text;
+        }
+        return value;
+    }
+
+    private callFunction(decl: Extract<Stmt, { kind: "function" }>, closure: Environment, args: any[]): any {
+        if (args.length !== decl.params.length) {
+            throw new Error(
+                message("runtime.arity", { name: decl.name, expected: decl.params.length, actual: args.length })
+            );
+        }
+        const line = this.currentLine;
+        const file = this.currentFile;
+        if (this.callStack.length >= this.maxCallDepth) {
+            throw this.stackOverflow(decl.name, line);
+        }
+        if (this.cancellation) this.checkCancelled();
+
+        // Every call gets a fresh environment whose parent is the closure
+        const previousEnv = this.env;
+        this.env = new Environment(closure);
+        for (let i = 0; i < args.length; i++) this.env.set(decl.params[i].name, args[i]);
+        this.callStack.push({ name: decl.name, line });
+        const mark = this.deferred.length;
+        try {
+            return this.runBlock(decl.body) === "return" ? this.flowValue : null;
+        } catch (error) {
+            // The blocks a ? left still run their defers, as for a return
+            if (error instanceof Propagation) {
+                this.runDeferred(mark);
+                return error.value;
+            }
+            // The host stack ran out before maxCallDepth was reached
+            if (error instanceof RangeError && error.message.includes("call stack")) {
+                throw new Error(
+                    message("runtime.host-stack", { where: atLine(line), name: decl.name, depth: this.callStack.length })
+                );
+            }
+            // Kept small as the host stack may be nearly exhausted here
+            const trace = this.traceError(error);
+            if (trace.frames.length < 5) trace.frames.push({ name: decl.name, at: trace.at });
+            trace.depth++;
+            trace.at = undefined;
+            throw error;
+        } finally {
+            this.currentLine = line;
+            this.currentFile = file;
+            this.callStack.pop();
+            this.env = previousEnv;
+        }
+    }
+
+    // Runs the first arm whose pattern matches, in its own scope. As an
+    // expression, the value is that of the arm's trailing expression.
+    private runMatch(match: { subject: Expr; arms: MatchArm[]; line?: number }, asExpression: boolean): any {
+        const subject = this.evaluateExpression(match.subject);
+        const arm = match.arms.find((a) => patternMatches(a.pattern, subject));
+        if (!arm) {
+            if (!asExpression) return null;
+            throw new Error(message("runtime.no-match-arm", { value: formatValue(subject), where: atLine(match.line) }));
+        }
+        const { pattern } = arm;
+        const bindings: [string, any][] = pattern.kind === "binding"
+            ? [[pattern.name, subject]]
+            : pattern.kind === "variant" ? pattern.bindings.map((name, i) => [name, subject.values[i]]) : [];
+        return this.runValueBlock(arm.body, bindings.filter(([name]) => name !== "_"));
+    }
+
+    // A loop expression runs until a break, whose value it returns
+    private runLoop(body: Stmt[]): any {
+        for (;;) {
+            if (this.cancellation) this.checkCancelled();
+            const flow = this.runBlock(body);
+            if (flow === "break") return this.flowValue;
+            // The function around the loop expression returns
+            if (flow === "return") {
+                this.leaving = flow;
+                return null;
+            }
+        }
+    }
+
+    // A while (let x = e) goes on while e is Some and binds its value
+    private loopCondition(stmt: Extract<Stmt, { kind: "while" }>): any {
+        const value = this.evaluateExpression(stmt.condition);
+        if (stmt.binding === undefined) return value;
+        if (value?.type !== "some") return false;
+        this.env.set(stmt.binding, value.value);
+        return true;
+    }
+
+    // Runs a block in its own scope and returns its last expression's value, else null
+    private runValueBlock(body: Stmt[], bindings: [string, any][] = []): any {
+        const previousEnv = this.env;
+        this.env = new Environment(previousEnv);
+        for (const [name, value] of bindings) this.env.set(name, value);
+        try {
+            const mark = this.deferred.length;
+            let value = null;
+            let flow: Flow = null;
+            for (let i = 0; i < body.length && flow === null; i++) {
+                const s = body[i];
+                if (s.kind === "expression") {
+                    value = this.evaluateExpression(s.expr);
+                    flow = this.left(); // from a value block in the expression
+                } else {
+                    value = null;
+                    flow = this.interpretStatement(s);
+                }
+            }
+            if (this.deferred.length > mark) this.runDeferred(mark);
+            this.leaving = flow;
+            return value;
+        } finally {
+            this.env = previousEnv;
+        }
+    }
+
+    private stackOverflow(name: string, line?: number): Error {
+        return new Error(message("runtime.call-depth", { where: atLine(line), name, limit: this.maxCallDepth }));
+    }
+
+    evaluate(expr: Expr, bindings: Record<string, any> = {}): any {
+        const previous = this.env;
+        this.env = new Environment(previous);
+        for (const [name, value] of Object.entries(bindings)) {
+            this.env.set(name, value);
+        }
+        try {
+            return this.evaluateExpression(expr);
+        } finally {
+            this.env = previous;
+        }
+    }
+
+    // Fills in the caches resolveCallee would on each call site's first run.
+    // Builtins are only bound where the program declares nothing by that name
+    private warmCallSites(program: TypedProgram): void {
+        const calls: Extract<Expr, { kind: "call" }>[] = [];
+        const declared = new Set<string>();
+        const visitExpr = (expr: Expr): void => {
+            if (expr.kind === "call") {
+                calls.push(expr);
+                visitExpr(expr.func);
+            }
+            if (expr.kind === "member") visitExpr(expr.object);
+            if (expr.kind === "match") expr.arms.forEach((arm) => visitArm(arm));
+            if (expr.kind === "if") visit([...expr.then, ...expr.else]);
+            if (expr.kind === "block" || expr.kind === "loop") visit(expr.body);
+            if (expr.kind === "range" && expr.step) visitExpr(expr.step);
+            if (expr.kind === "lambda") visit([expr.fn]);
+            exprOperands(expr).forEach(visitExpr);
+        };
+        const visit = (statements: Stmt[]): void => {
+            for (const stmt of statements) {
+                if (stmt.kind === "let" || stmt.kind === "function" || stmt.kind === "import" || stmt.kind === "enum") declared.add(stmt.name);
+                if (stmt.kind === "function") stmt.params.forEach((param) => declared.add(param.name));
+                if (stmt.kind === "forIn") declared.add(stmt.variable);
+                if (stmt.kind === "while" && stmt.binding !== undefined) declared.add(stmt.binding);
+                statementExprs(stmt).forEach(visitExpr);
+                if (stmt.kind === "match") stmt.arms.forEach((arm) => visitArm(arm));
+                else visit(childStatements(stmt));
+            }
+        };
+        const visitArm = (arm: MatchArm): void => {
+            patternNames(arm.pattern).forEach((name) => declared.add(name));
+            visit(arm.body);
+        };
+        visit(program.statements);
+        const version = this.env.shadows.count;
+        for (const call of calls) {
+            if (call.module !== undefined && call.func.kind === "member") {
+                const receiver = this.env.getModule(call.module);
+                const target = ownField(receiver, call.func.property);
+                if (typeof target === "function") this.callCaches.set(call, { receiver, target, version });
+            } else if (call.func.kind === "identifier" && Object.hasOwn(BUILTIN_FUNCTIONS, call.func.name) && !declared.has(call.func.name)) {
+                const builtin = this.builtins[call.func.name];
+                this.callCaches.set(call, { receiver: this.builtins, target: (...args: any[]) => builtin(args), version });
+            }
+        }
+    }
+
+    private resolveCallee(expr: Extract<Expr, { kind: "call" }>): (...args: any[]) => any {
+        const cache = this.callCaches.get(expr);
+
+        // Module members: io.print(...), math.sqrt(...); on other values
+        // a method call passes the receiver first: "abc".length()
+        if (expr.func.kind === "member") {
+            const receiver = this.evaluateExpression(expr.func.object);
+            const version = this.env.shadows.count;
+            if (cache && cache.receiver === receiver && cache.version === version) return cache.target;
+            const target = this.resolveMethod(receiver, expr.func.property);
+            if (this.inlineCaches) this.callCaches.set(expr, { receiver, target, version });
+            return target;
+        }
+
+        // Built-in functions from extended language features, unless the
+        // program defines its own binding with the same name
+        if (expr.func.kind === "identifier" && Object.hasOwn(BUILTIN_FUNCTIONS, expr.func.name)) {
+            const version = this.env.shadows.count;
+            if (cache && cache.receiver === this.builtins && cache.version === version) {
+                return cache.target;
+            }
+            if (!this.env.has(expr.func.name)) {
+                const builtin = this.builtins[expr.func.name];
+                const target = (...args: any[]) => builtin(args);
+                if (this.inlineCaches) this.callCaches.set(expr, { receiver: this.builtins, target, version });
+                return target;
+            }
+        }
+
+        const func = this.evaluateExpression(expr.func);
+        if (typeof func === "function") {
+            return func;
+        }
+        throw new Error(message("runtime.not-a-function"));
+    }
+
+    private resolveMethod(receiver: any, property: string): (...args: any[]) => any {
+        if (receiver instanceof Date && Object.hasOwn(DATETIME_FUNCTIONS, property)) {
+            return (...args: any[]) => DATETIME_FUNCTIONS[property](receiver, ...args);
+        }
+        const isModule = receiver !== null && typeof receiver === "object" && !Array.isArray(receiver) &&
+            !(receiver instanceof Map) && !(receiver instanceof Set) && !isRange(receiver) &&
+            !receiver[STRUCT_NAME] && !receiver[ENUM_NAME] && !(receiver instanceof Date);
+        if (isModule) {
+            if (typeof ownField(receiver, property) !== "function") {
+                throw new Error(message("runtime.not-a-method", { name: property }));
+            }
+            return receiver[property];
+        }
+        if (this.env.has(property)) {
+            const func = this.env.get(property);
+            if (typeof func === "function") return (...args: any[]) => func(receiver, ...args);
+        }
+        if (Object.hasOwn(this.builtins, property)) {
+            const builtin = this.builtins[property];
+            return (...args: any[]) => builtin([receiver, ...args]);
+        }
+        throw new Error(message("runtime.not-a-method", { name: property }));
+    }
+}
+
+// ============================================================================
+// INTERPRETER POOL - Reuse interpreters across requests
+// ============================================================================
+//
+// Servers evaluating one script or formula per request take an interpreter,
+// run it and give it back; release() resets it, so the next request starts
+// from the stdlib and prelude without paying for setting them up again.
+
+export interface InterpreterPoolOptions extends InterpreterOptions {
+    maxIdle?: number; // interpreters kept for reuse; more are dropped on release
+}
+
+export class InterpreterPool {
+    private idle: Interpreter[] = [];
+    private options: InterpreterPoolOptions;
+
+    constructor(options: InterpreterPoolOptions = {}) {
+        this.options = options;
+    }
+
+    acquire(): Interpreter {
+        return this.idle.pop() ?? new Interpreter(this.options);
+    }
+
+    release(interpreter: Interpreter): void {
+        interpreter.reset();
+        if (this.idle.length < (this.options.maxIdle ?? 8)) this.idle.push(interpreter);
+    }
+
+    // Runs use with a pooled interpreter and releases it even when use throws
+    run(use: (interpreter: Interpreter) => any): any {
+        const interpreter = this.acquire();
+        try {
+            return use(interpreter);
+        } finally {
+            this.release(interpreter);
+        }
+    }
+}
+
+// ============================================================================
+// COMPILED EXPRESSIONS - Parse once, evaluate against many bindings
+// ============================================================================
+
+export class CompiledExpr {
+    private expr: Expr;
+    private interpreter: Interpreter;
+
+    // Bindings are any to the lowering, as their types are only known per call
+    constructor(expr: Expr, interpreter: Interpreter = new Interpreter()) {
+        new TypeChecker().lowerExpression(expr);
+        this.expr = expr;
+        this.interpreter = interpreter;
+    }
+
+    static compile(source: string): CompiledExpr {
+        return new CompiledExpr(new Parser(source).parseExpression());
+    }
+
+    evalWith(bindings: Record<string, any>): any {
+        return this.interpreter.evaluate(this.expr, bindings);
+    }
+
+    // Array bindings are columns and must all have the same length; other
+    // bindings are broadcast to every row. Int results are returned as an
+    // Int32Array, float results as a Float64Array, everything else as a
+    // plain array.
+    evalColumns(bindings: Record<string, any>): Column {
+        return new ColumnEvaluator(this.interpreter, bindings).run(this.expr);
+    }
+}
+
+// ============================================================================
+// COMPILED PROGRAMS - Check once, run in many interpreters or workers
+// ============================================================================
+//
+// The checked statements are never changed after compile, so any number of
+// interpreters and VMs can run one CompiledProgram side by side, each with
+// its own globals. serialize() gives a string that a worker thread turns
+// back into a program with deserialize() without parsing or checking again.
+
+export class CompiledProgram {
+    readonly statements: Stmt[];
+    private bytecode?: BytecodeProgram;
+
+    constructor(statements: Stmt[]) {
+        this.statements = statements;
+    }
+
+    static compile(source: string, options: { prelude?: boolean } = {}): CompiledProgram {
+        const program = new Parser(source).parse();
+        const statements = options.prelude === false ? program : withPrelude(new Parser(PRELUDE_SOURCE).parse(), program);
+        new TypeChecker().check(statements);
+        return new CompiledProgram(statements);
+    }
+
+    static deserialize(data: string): CompiledProgram {
+        return new CompiledProgram(JSON.parse(data));
+    }
+
+    serialize(): string {
+        return JSON.stringify(this.statements);
+    }
+
+    // Runs the top level in a fresh interpreter; the host then calls the
+    // program's functions through its globals()
+    instantiate(options: InterpreterOptions = {}): Interpreter {
+        const interpreter = new Interpreter(options);
+        interpreter.interpret(this.statements);
+        return interpreter;
+    }
+
+    // Compiled to bytecode on the first call and shared by later VMs
+    runVM(options: VmOptions = {}): void {
+        this.bytecode ??= new BytecodeCompiler().compile(this.statements);
+        new VM(options).run(this.bytecode);
+    }
+}
+
+// ============================================================================
+// EXECUTE - One call from source to output, value and diagnostics
+// ============================================================================
+//
+// For graders, CI bots and playgrounds: execute() parses, checks and runs a
+// source string and returns an ExecutionReport rather than throwing. Output
+// is captured by replacing console.log and console.error while it runs, so
+// executions sharing a thread must not overlap.
+
+export interface ExecuteOptions {
+    args?: string[];
+    input?: string; // what the io readers read, instead of stdin
+    timeoutMs?: number; // the run stops with an E0005 once this has passed
+    sandbox?: boolean; // see InterpreterOptions
+    prelude?: Stmt[]; // in place of the standard prelude
+    check?: boolean; // parse and check only
+}
+
+export interface ExecutionReport {
+    ok: boolean; // no errors; warnings are still in diagnostics
+    stdout: string;
+    stderr: string;
+    value?: string; // a final expression statement's value, formatted
+    diagnostics: Diagnostic[];
+    timing: { parseMs: number; checkMs: number; runMs: number; totalMs: number };
+    // cpuMs is the whole process's; heapBytes is how much the heap grew
+    resources: { cpuMs: number; heapBytes: number };
+}
+
+export function execute(source: string, options: ExecuteOptions = {}): ExecutionReport {
+    const stdout: string[] = [];
+    const stderr: string[] = [];
+    const { log, error: logError } = console;
+    console.log = (...parts: any[]) => { stdout.push(`${parts.join(" ")}\n`); };
+    console.error = (...parts: any[]) => { stderr.push(`${parts.join(" ")}\n`); };
+    const timing = { parseMs: 0, checkMs: 0, runMs: 0, totalMs: 0 };
+    let stage: "parseMs" | "checkMs" | "runMs" = "parseMs";
+    const started = performance.now();
+    let mark = started;
+    const next = (following: "checkMs" | "runMs") => {
+        const now = performance.now();
+        timing[stage] = now - mark;
+        mark = now;
+        stage = following;
+    };
+    const cpu = process.cpuUsage();
+    const heap = process.memoryUsage().heapUsed;
+    const checker = new TypeChecker();
+    const errors: Diagnostic[] = [];
+    let value: string | undefined;
+    try {
+        const program = new Parser(source).parse();
+        const statements = withPrelude(options.prelude ?? new Parser(PRELUDE_SOURCE).parse(), program);
+        next("checkMs");
+        const typed = checker.checkProgram(statements);
+        if (!options.check) {
+            next("runMs");
+            const interpreter = Interpreter.fromTyped(typed, { args: options.args, input: options.input, sandbox: options.sandbox });
+            // The final expression is bound rather than evaluated afterwards,
+            // so the time limit covers it too
+            const last = program[program.length - 1];
+            const run: Stmt[] = last?.kind === "expression"
+                ? [...statements.slice(0, -1), { kind: "let", name: "<value>", type: TYPE_REGISTRY.any, value: last.expr, mutable: false, line: last.line }]
+                : statements;
+            if (options.timeoutMs !== undefined) interpreter.runWithCancel(run, new CancellationToken({ timeoutMs: options.timeoutMs }));
+            else interpreter.interpret(run);
+            if (last?.kind === "expression") {
+                const result = interpreter.evaluate({ kind: "identifier", name: "<value>" });
+                if (result !== null && result !== undefined) value = formatValue(result);
+            }
+        }
+    } catch (error) {
+        errors.push(...asStrataError(error, "E0004").diagnostics);
+    } finally {
+        console.log = log;
+        console.error = logError;
+    }
+    const ended = performance.now();
+    timing[stage] = ended - mark;
+    timing.totalMs = ended - started;
+    const used = process.cpuUsage(cpu);
+    const report: ExecutionReport = {
+        ok: errors.length === 0,
+        stdout: stdout.join(""),
+        stderr: stderr.join(""),
+        diagnostics: [...errors, ...checker.warnings],
+        timing,
+        resources: { cpuMs: (used.user + used.system) / 1000, heapBytes: Math.max(0, process.memoryUsage().heapUsed - heap) },
+    };
+    if (value !== undefined) report.value = value;
+    return report;
+}
+
+// ============================================================================
+// COLUMN EVALUATION - Apply an expression element-wise over whole columns
+// ============================================================================
+
+// Int32Array columns hold ints and Float64Array columns floats; a plain
+// array of numbers is a float column, as a host number is any to the checker
+export type Column = any[] | Float64Array | Int32Array;
+
+type ColumnValue =
+    | { kind: "scalar"; value: any; int?: boolean } // int: an int literal, or int arithmetic on them
+    | { kind: "column"; values: Column };
+
+const NUMERIC_KERNEL_OPS = ["+", "-", "*", "/", "%"];
+
+class ColumnEvaluator {
+    private interpreter: Interpreter;
+    private scalars: Record<string, any> = {};
+    private columns: Map<string, Column> = new Map();
+    private rows = 1;
+
+    constructor(interpreter: Interpreter, bindings: Record<string, any>) {
+        this.interpreter = interpreter;
+        let rows = -1;
+        for (const [name, value] of Object.entries(bindings)) {
+            if (!Array.isArray(value) && !(value instanceof Float64Array) && !(value instanceof Int32Array)) {
+                this.scalars[name] = value;
+                continue;
+            }
+            if (rows !== -1 && value.length !== rows) {
+                throw new Error(
+                    message("runtime.column-length", { name, actual: value.length, expected: rows })
+                );
+            }
+            rows = value.length;
+            const numeric = Array.isArray(value) && value.every((v: any) => typeof v === "number");
+            this.columns.set(name, numeric ? Float64Array.from(value) : value);
+        }
+        if (rows !== -1) this.rows = rows;
+    }
+
+    run(expr: Expr): Column {
+        const result = this.evaluate(expr);
+        if (result.kind === "column") return result.values;
+        if (typeof result.value !== "number") return new Array(this.rows).fill(result.value);
+        return result.int ? new Int32Array(this.rows).fill(result.value) : new Float64Array(this.rows).fill(result.value);
+    }
+
+    private evaluate(expr: Expr): ColumnValue {
+        switch (expr.kind) {
+            case "literal":
+                return { kind: "scalar", value: expr.value, int: expr.type.primitive === "int" };
+            case "identifier":
+                if (this.columns.has(expr.name)) {
+                    return { kind: "column", values: this.columns.get(expr.name)! };
+                }
+                return { kind: "scalar", value: this.interpreter.evaluate(expr, this.scalars) };
+            case "binary":
+                return this.binary(expr);
+            case "unary":
+                return this.unary(expr.op, this.evaluate(expr.operand));
+            default:
+                return this.perRow(expr);
+        }
+    }
+
+    // Int operands, as the checker tags them or as int columns, get the
+    // interpreter's int arithmetic: truncating, and checked for overflow and
+    // a zero divisor
+    private binary(expr: Extract<Expr, { kind: "binary" }>): ColumnValue {
+        const op = expr.op;
+        const left = this.evaluate(expr.left);
+        if (op === "&&" || op === "||") return this.logical(expr, left);
+        const right = this.evaluate(expr.right);
+        const integer = expr.integer || (INTEGER_OPS.has(op) && this.isInt(left) && this.isInt(right));
+        if (left.kind === "scalar" && right.kind === "scalar") {
+            return integer
+                ? { kind: "scalar", value: integerOp(op, left.value, right.value), int: true }
+                : { kind: "scalar", value: applyBinaryOp(op, left.value, right.value) };
+        }
+        if (integer) {
+            const out = new Int32Array(this.rows);
+            for (let i = 0; i < this.rows; i++) out[i] = integerOp(op, this.at(left, i), this.at(right, i));
+            return { kind: "column", values: out };
+        }
+        if (NUMERIC_KERNEL_OPS.includes(op) && this.isNumeric(left) && this.isNumeric(right)) {
+            return { kind: "column", values: this.numericKernel(op, this.toFloat64(left), this.toFloat64(right)) };
+        }
+        const values = new Array(this.rows);
+        for (let i = 0; i < this.rows; i++) {
+            values[i] = applyBinaryOp(op, this.at(left, i), this.at(right, i));
+        }
+        return { kind: "column", values };
+    }
+
+    // The right operand only runs, row by row, where the left doesn't decide
+    private logical(expr: Extract<Expr, { kind: "binary" }>, left: ColumnValue): ColumnValue {
+        const decides = (value: any) => (expr.op === "&&" ? !value : Boolean(value));
+        if (left.kind === "scalar") return decides(left.value) ? left : this.evaluate(expr.right);
+        const values = new Array(this.rows);
+        const row: Record<string, any> = { ...this.scalars };
+        for (let i = 0; i < this.rows; i++) {
+            if (decides(left.values[i])) {
+                values[i] = left.values[i];
+                continue;
+            }
+            for (const [name, column] of this.columns) row[name] = column[i];
+            values[i] = this.interpreter.evaluate(expr.right, row);
+        }
+        return { kind: "column", values };
+    }
+
+    private unary(op: string, operand: ColumnValue): ColumnValue {
+        if (operand.kind === "scalar") {
+            const value = op === "-" && operand.int ? integerOp("-", 0, operand.value) : applyUnaryOp(op, operand.value);
+            return { kind: "scalar", value, int: operand.int };
+        }
+        if (op === "-" && operand.values instanceof Int32Array) {
+            const input = operand.values;
+            const out = new Int32Array(this.rows);
+            for (let i = 0; i < this.rows; i++) out[i] = integerOp("-", 0, input[i]);
+            return { kind: "column", values: out };
+        }
+        if (op === "-" && operand.values instanceof Float64Array) {
+            const input = operand.values;
+            const out = new Float64Array(this.rows);
+            for (let i = 0; i < this.rows; i++) out[i] = -input[i];
+            return { kind: "column", values: out };
+        }
+        const values = new Array(this.rows);
+        for (let i = 0; i < this.rows; i++) {
+            values[i] = applyUnaryOp(op, operand.values[i]);
+        }
+        return { kind: "column", values };
+    }
+
+    // One loop per operator keeps the loop bodies free of dispatch so the
+    // JIT can compile them to straight-line (and vectorizable) code.
+    private numericKernel(op: string, a: Float64Array, b: Float64Array): Float64Array {
+        const n = this.rows;
+        const out = new Float64Array(n);
+        switch (op) {
+            case "+":
+                for (let i = 0; i < n; i++) out[i] = a[i] + b[i];
+                break;
+            case "-":
+                for (let i = 0; i < n; i++) out[i] = a[i] - b[i];
+                break;
+            case "*":
+                for (let i = 0; i < n; i++) out[i] = a[i] * b[i];
+                break;
+            case "/":
+                for (let i = 0; i < n; i++) out[i] = a[i] / b[i];
+                break;
+            case "%":
+                for (let i = 0; i < n; i++) out[i] = a[i] % b[i];
+                break;
+        }
+        return out;
+    }
+
+    // Calls and member accesses fall back to evaluating one row at a time.
+    private perRow(expr: Expr): ColumnValue {
+        const values = new Array(this.rows);
+        const row: Record<string, any> = { ...this.scalars };
+        for (let i = 0; i < this.rows; i++) {
+            for (const [name, column] of this.columns) row[name] = column[i];
+            values[i] = this.interpreter.evaluate(expr, row);
+        }
+        return { kind: "column", values };
+    }
+
+    private isNumeric(value: ColumnValue): boolean {
+        return value.kind === "column"
+            ? value.values instanceof Float64Array || value.values instanceof Int32Array
+            : typeof value.value === "number";
+    }
+
+    private isInt(value: ColumnValue): boolean {
+        return value.kind === "column" ? value.values instanceof Int32Array : value.int === true;
+    }
+
+    private toFloat64(value: ColumnValue): Float64Array {
+        if (value.kind === "column") return value.values instanceof Float64Array ? value.values : Float64Array.from(value.values);
+        return new Float64Array(this.rows).fill(value.value);
+    }
+
+    private at(value: ColumnValue, i: number): any {
+        return value.kind === "column" ? value.values[i] : value.value;
+    }
+}
+
+// ============================================================================
+// BYTECODE COMPILER - Lower the AST to a flat instruction stream
+// ============================================================================
+//
+// --backend vm compiles the checked program once and runs it on a stack VM,
+// so loops jump back through an array of numbers instead of walking the
+// statement tree again on every iteration. Each instruction is an opcode
+// followed by its operands inline, as printed by disassemble:
+//
+//   var i: int = 0              0  Const 0  ; 0
+//   while (i < 3) {             2  StoreGlobal 0
+//     var i: int = i + 1        4  LoadGlobal 0
+//   }                           6  Const 1  ; 3
+//                               8  Lt
+//                               9  JumpIfFalse 20
+//                              11  LoadGlobal 0
+//                              13  Const 2  ; 1
+//                              15  Add
+//                              16  StoreGlobal 0
+//                              18  Jump 4
+//                              20  Const 3  ; null
+//                              22  Return
+//
+// The semantics follow the interpreter. Top-level variables, functions and
+// imports are globals and every other name is a slot in its function's frame.
+// Blocks share their function's slots and match arms get their own. Values,
+// operators and the stdlib are the interpreter's own, so the two backends
+// print the same. Functions declared inside functions, and lambdas, are
+// closures: the variables they capture live in cells (VmCell) shared with
+// the frame that declared them.
+
+export const Op = {
+    Const: 0, // k: push constants[k]
+    Pop: 1,
+    LoadLocal: 2, // slot
+    StoreLocal: 3, // slot
+    LoadGlobal: 4, // slot
+    StoreGlobal: 5, // slot
+    Add: 6,
+    Sub: 7,
+    Mul: 8,
+    Lt: 9,
+    Le: 10,
+    Gt: 11,
+    Ge: 12,
+    Eq: 13,
+    Ne: 14,
+    Binary: 15, // k: any other operator, by name
+    IntegerOp: 16, // k: int arithmetic, checked for overflow
+    Unary: 17, // k
+    Jump: 18, // target
+    JumpIfFalse: 19, // target
+    Call: 20, // argc: callee below the arguments
+    CallFunction: 21, // function, argc
+    CallBuiltin: 22, // k: name, argc
+    CallMethod: 23, // k: property, site, argc: receiver below the arguments
+    Return: 24,
+    Member: 25, // k
+    Field: 26, // k
+    Index: 27,
+    SetIndex: 28,
+    Array: 29, // count
+    Map: 30, // entries
+    Struct: 31, // k: { name, fields }, count
+    Range: 32, // inclusive, hasStep
+    Iterate: 33, // slot: replaces the value with an iterator kept in slot
+    Next: 34, // slot, target: push the next item or jump when done
+    Same: 35, // === for match patterns
+    Function: 36, // function, global slot
+    Import: 37, // k: module name
+    Immutable: 38, // k: name; assigning a let or const
+    NoMatch: 39, // throws for the subject on top
+    Unwrap: 40, // target: replace a Some with its value, or pop a None and jump
+    Closure: 41, // function, count: a function value over the count cells on top
+    LoadCell: 42, // slot: push the value in the slot's cell
+    StoreCell: 43, // slot: store in the slot's cell, putting one there the first time
+    CellRef: 44, // slot: push the slot's cell itself, putting an empty one there if needed
+    Propagate: 45, // target: replace an Ok or Some with its value, or drop the rest of the frame's stack and jump
+} as const;
+
+const OP_NAMES: Record<number, string> = Object.fromEntries(Object.entries(Op).map(([name, code]) => [code, name]));
+
+// Operand count of each opcode with operands, for walking the stream
+const OP_OPERANDS: Record<number, number> = {
+    [Op.Const]: 1, [Op.LoadLocal]: 1, [Op.StoreLocal]: 1, [Op.LoadGlobal]: 1, [Op.StoreGlobal]: 1, [Op.Binary]: 1,
+    [Op.IntegerOp]: 1, [Op.Unary]: 1, [Op.Jump]: 1, [Op.JumpIfFalse]: 1, [Op.Call]: 1, [Op.CallFunction]: 2,
+    [Op.CallBuiltin]: 2, [Op.CallMethod]: 3, [Op.Member]: 1, [Op.Field]: 1, [Op.Array]: 1, [Op.Map]: 1,
+    [Op.Struct]: 2, [Op.Range]: 2, [Op.Iterate]: 1, [Op.Next]: 2, [Op.Function]: 2, [Op.Import]: 1, [Op.Immutable]: 1,
+    [Op.Unwrap]: 1, [Op.Closure]: 2, [Op.LoadCell]: 1, [Op.StoreCell]: 1, [Op.CellRef]: 1, [Op.Propagate]: 1,
+};
+
+// Opcodes whose first operand indexes the constants
+const OP_CONSTANT_OPERAND = new Set<number>([
+    Op.Const, Op.Binary, Op.IntegerOp, Op.Unary, Op.Member, Op.Field, Op.CallBuiltin, Op.CallMethod, Op.Import, Op.Immutable,
+]);
+
+const FAST_BINARY_OPS: Record<string, number> = {
+    "+": Op.Add, "-": Op.Sub, "*": Op.Mul, "<": Op.Lt, "<=": Op.Le, ">": Op.Gt, ">=": Op.Ge, "==": Op.Eq, "!=": Op.Ne,
+};
+
+export interface BytecodeFunction {
+    name: string;
+    arity: number;
+    slots: number; // parameters first, then locals and hidden temporaries
+    code: number[];
+    lines: (number | undefined)[]; // source line of each word of code
+}
+
+export interface BytecodeProgram {
+    main: BytecodeFunction;
+    functions: BytecodeFunction[];
+    constants: any[];
+    globals: string[]; // names of the global slots
+    callSites: number; // CallMethod instructions, each with its own cache
+}
+
+function bytecodeFunction(name: string, arity: number): BytecodeFunction {
+    return { name, arity, slots: 0, code: [], lines: [] };
+}
+
+// cell: the slot holds a VmCell shared with closures rather than the value
+interface CompilerBinding {
+    slot: number;
+    mutable: boolean;
+    cell?: boolean;
+}
+
+export class BytecodeCompiler {
+    private constants: any[] = [];
+    private constantIndex = new Map<any, number>();
+    private globals = new Map<string, CompilerBinding>();
+    private globalNames: string[] = [];
+    private functions: BytecodeFunction[] = [];
+    private functionIndex = new Map<string, number>(); // by name, for direct calls
+    private declarations = new Map<Stmt, number>(); // each function statement's entry
+    // Top-level names that are bound other than by their function declaration
+    private rebound = new Set<string>();
+    private structs = new Map<string, string[]>();
+    private callSites = 0;
+    // The function being compiled: its innermost scope last, empty for main
+    private fn: BytecodeFunction = bytecodeFunction("<main>", 0);
+    private scopes: Map<string, CompilerBinding>[] = [];
+    // result: the slot a loop expression's break values are stored in;
+    // blocks: how many of the function's blocks are open around the loop
+    private loops: { breaks: number[]; continues: number[]; result?: number; blocks: number }[] = [];
+    // The defers reached so far in each open block of the function, innermost
+    // last; every way out of a block compiles in a copy of them, last first
+    private blocks: Stmt[][] = [];
+    // Variables of the function being compiled that functions nested in it use
+    private captured = new Set<string>();
+    private line?: number;
+
+    compile(statements: Stmt[]): BytecodeProgram {
+        this.line = undefined;
+        return runPhase("bytecode compiler", () => (this.line !== undefined ? { line: this.line } : undefined), () =>
+            this.compileProgram(statements)
+        );
+    }
+
+    private compileProgram(statements: Stmt[]): BytecodeProgram {
+        this.constants = [];
+        this.constantIndex.clear();
+        this.globals.clear();
+        this.globalNames = [];
+        this.functions = [];
+        this.functionIndex.clear();
+        this.declarations.clear();
+        this.rebound.clear();
+        this.structs.clear();
+        this.callSites = 0;
+
+        // Functions are called by index when their name always means them
+        const visit = (stmts: Stmt[]) => {
+            for (const stmt of stmts) {
+                if (stmt.kind === "let" || stmt.kind === "import" || stmt.kind === "enum") this.rebound.add(stmt.name);
+                if (stmt.kind === "forIn") this.rebound.add(stmt.variable);
+                if (stmt.kind === "while" && stmt.binding !== undefined) this.rebound.add(stmt.binding);
+                if (stmt.kind === "struct") this.structs.set(stmt.name, stmt.fields.map((f) => f.name));
+                if (stmt.kind === "if") visit([...stmt.then, ...(stmt.else ?? [])]);
+                if (stmt.kind === "while" || stmt.kind === "for" || stmt.kind === "forIn") visit(stmt.body);
+            }
+        };
+        visit(statements);
+        for (const stmt of statements) {
+            if (stmt.kind !== "function") continue;
+            if (this.functionIndex.has(stmt.name)) this.rebound.add(stmt.name);
+            this.functionIndex.set(stmt.name, this.functions.length);
+            this.declarations.set(stmt, this.functions.length);
+            this.functions.push(bytecodeFunction(stmt.name, stmt.params.length));
+        }
+
+        const main = this.compileFunction(bytecodeFunction("<main>", 0), [], statements);
+        return { main, functions: this.functions, constants: this.constants, globals: this.globalNames, callSites: this.callSites };
+    }
+
+    // upvalues: the variables a closure uses from the functions around it,
+    // whose cells follow the parameters in its slots
+    private compileFunction(
+        fn: BytecodeFunction,
+        params: string[],
+        body: Stmt[],
+        upvalues: { name: string; mutable: boolean }[] = []
+    ): BytecodeFunction {
+        const outer = { fn: this.fn, scopes: this.scopes, loops: this.loops, blocks: this.blocks, captured: this.captured };
+        this.fn = fn;
+        this.captured = capturedNames(body);
+        const bindings = [
+            ...params.map((name, slot): [string, CompilerBinding] => [name, { slot, mutable: false, cell: this.captured.has(name) }]),
+            ...upvalues.map(({ name, mutable }, i): [string, CompilerBinding] => [name, { slot: params.length + i, mutable, cell: true }]),
+        ];
+        this.scopes = fn.name === "<main>" ? [] : [new Map(bindings)];
+        fn.slots = bindings.length;
+        this.loops = [];
+        this.blocks = [];
+        // Captured parameters move into cells on entry
+        params.forEach((name, slot) => {
+            if (!this.captured.has(name)) return;
+            this.emit(Op.LoadLocal, slot);
+            this.emit(Op.StoreCell, slot);
+        });
+        this.block(body);
+        this.emit(Op.Const, this.constant(null));
+        this.emit(Op.Return);
+        this.fn = outer.fn;
+        this.scopes = outer.scopes;
+        this.loops = outer.loops;
+        this.blocks = outer.blocks;
+        this.captured = outer.captured;
+        return fn;
+    }
+
+    // A function declared in a function, or a lambda, is a closure over the
+    // cells of the enclosing variables it uses, so assignments on either
+    // side are seen by the other, as with the interpreter's shared
+    // environment. Leaves the function value on the stack
+    private closure(stmt: Extract<Stmt, { kind: "function" }>): void {
+        const params = stmt.params.map((p) => p.name);
+        const own = new Set([...params, ...localNames(stmt.body)]);
+        const upvalues = [...referencedNames(stmt.body)]
+            .filter((name) => !own.has(name) && this.scopes.some((scope) => scope.has(name)))
+            .map((name) => ({ name, binding: this.resolve(name).binding }));
+        const index = this.functions.length;
+        this.functions.push(bytecodeFunction(stmt.name, params.length));
+        const line = this.line;
+        this.compileFunction(this.functions[index], params, stmt.body, upvalues.map(({ name, binding }) => ({ name, mutable: binding.mutable })));
+        this.line = line;
+        for (const { binding } of upvalues) this.emit(Op.CellRef, binding.slot);
+        this.emit(Op.Closure, index, upvalues.length);
+    }
+
+    private block(body: Stmt[]): void {
+        this.blocks.push([]);
+        // Functions nested in a function may call each other in any order
+        if (this.scopes.length > 0) {
+            for (const s of body) if (s.kind === "function") this.local(s.name);
+        }
+        for (const s of body) this.statement(s);
+        this.deferred(this.blocks.length - 1);
+        this.blocks.pop();
+    }
+
+    // The defers of the open blocks from depth inwards, innermost first. Each
+    // compiles with only the blocks outside its own open, so it can't run itself
+    private deferred(depth: number): void {
+        const open = this.blocks;
+        for (let i = open.length - 1; i >= depth; i--) {
+            this.blocks = open.slice(0, i);
+            for (let j = open[i].length - 1; j >= 0; j--) this.statement(open[i][j]);
+        }
+        this.blocks = open;
+    }
+
+    private unsupported(what: string): Error {
+        return new Error(message("vm.unsupported", { what }));
+    }
+
+    private emit(...words: number[]): number {
+        const at = this.fn.code.length;
+        for (const word of words) {
+            this.fn.code.push(word);
+            this.fn.lines.push(this.line);
+        }
+        return at;
+    }
+
+    // Emits a jump whose target is filled in by patch
+    private jump(op: number): number {
+        return this.emit(op, -1) + 1;
+    }
+
+    private patch(operand: number, target = this.fn.code.length): void {
+        this.fn.code[operand] = target;
+    }
+
+    private constant(value: any): number {
+        // Objects (struct layouts) are always new; equal primitives share a slot
+        const key = typeof value === "object" && value !== null ? undefined : `${typeof value}:${String(value)}`;
+        if (key !== undefined && this.constantIndex.has(key)) return this.constantIndex.get(key)!;
+        this.constants.push(value);
+        if (key !== undefined) this.constantIndex.set(key, this.constants.length - 1);
+        return this.constants.length - 1;
+    }
+
+    private temp(): number {
+        return this.fn.slots++;
+    }
+
+    private global(name: string): CompilerBinding {
+        let binding = this.globals.get(name);
+        if (!binding) {
+            binding = { slot: this.globalNames.length, mutable: true };
+            this.globalNames.push(name);
+            this.globals.set(name, binding);
+        }
+        return binding;
+    }
+
+    // A name resolves to the innermost scope declaring it so far, else a global
+    private resolve(name: string): { local: boolean; binding: CompilerBinding } {
+        for (let i = this.scopes.length - 1; i >= 0; i--) {
+            const binding = this.scopes[i].get(name);
+            if (binding) return { local: true, binding };
+        }
+        return { local: false, binding: this.global(name) };
+    }
+
+    // Binds name in the innermost scope, reusing its slot when redeclared
+    private declare(name: string, mutable: boolean): void {
+        if (this.scopes.length === 0) {
+            const binding = this.global(name);
+            binding.mutable = mutable;
+            this.emit(Op.StoreGlobal, binding.slot);
+            return;
+        }
+        const binding = this.local(name);
+        binding.mutable = mutable;
+        this.emit(binding.cell ? Op.StoreCell : Op.StoreLocal, binding.slot);
+    }
+
+    // The innermost scope's binding of name, added if it has none
+    private local(name: string): CompilerBinding {
+        const scope = this.scopes[this.scopes.length - 1];
+        let binding = scope.get(name);
+        if (!binding) {
+            binding = { slot: this.temp(), mutable: false, cell: this.captured.has(name) };
+            scope.set(name, binding);
+        }
+        return binding;
+    }
+
+    private load(name: string): void {
+        const { local, binding } = this.resolve(name);
+        this.emit(!local ? Op.LoadGlobal : binding.cell ? Op.LoadCell : Op.LoadLocal, binding.slot);
+    }
+
+    private statement(stmt: Stmt): void {
+        if (stmt.line !== undefined) this.line = stmt.line;
+        switch (stmt.kind) {
+            case "let":
+                this.expression(stmt.value);
+                this.declare(stmt.name, stmt.mutable);
+                return;
+            case "assignment": {
+                const { local, binding } = this.resolve(stmt.target);
+                this.expression(stmt.value);
+                if (!binding.mutable) {
+                    this.emit(Op.Immutable, this.constant(stmt.target));
+                    return;
+                }
+                this.emit(!local ? Op.StoreGlobal : binding.cell ? Op.StoreCell : Op.StoreLocal, binding.slot);
+                return;
+            }
+            case "indexAssignment":
+                this.expression(stmt.object);
+                this.expression(stmt.index);
+                this.expression(stmt.value);
+                this.emit(Op.SetIndex);
+                return;
+            case "expression":
+                this.expression(stmt.expr);
+                this.emit(Op.Pop);
+                return;
+            case "struct":
+                return;
+            case "enum":
+                this.emit(Op.Const, this.constant(enumNamespace(stmt.name, stmt.variants)));
+                this.declare(stmt.name, false);
+                return;
+            case "import":
+                this.emit(Op.Import, this.constant(stmt.module));
+                this.declare(stmt.name, false);
+                return;
+            case "test":
+                return;
+            case "function": {
+                if (this.scopes.length > 0) {
+                    this.closure(stmt);
+                    this.declare(stmt.name, false);
+                    return;
+                }
+                const index = this.declarations.get(stmt)!;
+                const line = this.line;
+                this.compileFunction(this.functions[index], stmt.params.map((p) => p.name), stmt.body);
+                this.line = line;
+                const binding = this.global(stmt.name);
+                binding.mutable = false;
+                this.emit(Op.Function, index, binding.slot);
+                return;
+            }
+            case "return":
+                if (stmt.value) this.expression(stmt.value);
+                else this.emit(Op.Const, this.constant(null));
+                this.deferred(0);
+                this.emit(Op.Return);
+                return;
+            case "break":
+            case "continue": {
+                const loop = this.loops[this.loops.length - 1];
+                if (!loop) throw new Error(message("type.loop-control", { keyword: stmt.kind, where: atLine(this.line) }));
+                if (stmt.kind === "break" && stmt.value && loop.result !== undefined) {
+                    this.expression(stmt.value);
+                    this.emit(Op.StoreLocal, loop.result);
+                }
+                this.deferred(loop.blocks);
+                (stmt.kind === "break" ? loop.breaks : loop.continues).push(this.jump(Op.Jump));
+                return;
+            }
+            case "if": {
+                this.expression(stmt.condition);
+                const otherwise = this.jump(Op.JumpIfFalse);
+                this.block(stmt.then);
+                if (stmt.else) {
+                    const end = this.jump(Op.Jump);
+                    this.patch(otherwise);
+                    this.block(stmt.else);
+                    this.patch(end);
+                } else {
+                    this.patch(otherwise);
+                }
+                return;
+            }
+            case "while":
+                if (stmt.binding !== undefined) {
+                    const binding = stmt.binding;
+                    this.loop(() => {
+                        this.expression(stmt.condition);
+                        const exit = this.jump(Op.Unwrap);
+                        this.declare(binding, false);
+                        return exit;
+                    }, stmt.body);
+                    return;
+                }
+                this.loop(() => this.test(stmt.condition), stmt.body);
+                return;
+            case "for":
+                this.statement(stmt.init);
+                this.loop(() => this.test(stmt.condition), stmt.body, () => this.statement(stmt.update));
+                return;
+            case "forIn": {
+                this.expression(stmt.iterable);
+                const iterator = this.temp();
+                this.emit(Op.Iterate, iterator);
+                this.loop(() => {
+                    const exit = this.emit(Op.Next, iterator, -1) + 2;
+                    this.declare(stmt.variable, true);
+                    return exit;
+                }, stmt.body);
+                return;
+            }
+            case "match":
+                this.match(stmt, false);
+                return;
+            case "defer":
+                this.blocks[this.blocks.length - 1].push(stmt.body);
+                return;
+        }
+    }
+
+    // Jumps past the loop when condition is false; returns the jump's operand
+    private test(condition: Expr): number {
+        this.expression(condition);
+        return this.jump(Op.JumpIfFalse);
+    }
+
+    // head runs before every iteration and returns the operand of its exit jump
+    private loop(head: () => number, body: Stmt[], update?: () => void): void {
+        const start = this.fn.code.length;
+        const exit = head();
+        this.loops.push({ breaks: [], continues: [], blocks: this.blocks.length });
+        this.block(body);
+        const loop = this.loops.pop()!;
+        for (const operand of loop.continues) this.patch(operand);
+        update?.();
+        this.emit(Op.Jump, start);
+        this.patch(exit);
+        for (const operand of loop.breaks) this.patch(operand);
+    }
+
+    // The first arm whose pattern matches runs in its own scope; as an
+    // expression it leaves the value of its trailing expression
+    private match(match: { subject: Expr; arms: MatchArm[]; line?: number }, asExpression: boolean): void {
+        this.expression(match.subject);
+        const subject = this.temp();
+        this.emit(Op.StoreLocal, subject);
+        const ends: number[] = [];
+        for (const arm of match.arms) {
+            // Jumps to the next arm when a test fails
+            const misses: number[] = [];
+            const test = (property: any, value: any) => {
+                this.emit(Op.LoadLocal, subject);
+                if (property !== undefined) this.emit(Op.Member, this.constant(property));
+                this.emit(Op.Const, this.constant(value));
+                this.emit(Op.Same);
+                misses.push(this.jump(Op.JumpIfFalse));
+            };
+            const { pattern } = arm;
+            if (pattern.kind === "literal") test(undefined, pattern.value);
+            if (pattern.kind === "variant") {
+                test(ENUM_NAME, pattern.enum);
+                test("variant", pattern.variant);
+            }
+            const scope = new Map<string, CompilerBinding>();
+            this.scopes.push(scope);
+            if (pattern.kind === "binding") {
+                this.emit(Op.LoadLocal, subject);
+                this.declare(pattern.name, false);
+            }
+            if (pattern.kind === "variant") {
+                pattern.bindings.forEach((name, i) => {
+                    if (name === "_") return;
+                    this.emit(Op.LoadLocal, subject);
+                    this.emit(Op.Field, this.constant("values"));
+                    this.emit(Op.Const, this.constant(i));
+                    this.emit(Op.Index);
+                    this.declare(name, false);
+                });
+            }
+            if (asExpression) this.valueBlock(arm.body);
+            else this.block(arm.body);
+            this.scopes.pop();
+            ends.push(this.jump(Op.Jump));
+            for (const operand of misses) this.patch(operand);
+            if (pattern.kind === "wildcard" || pattern.kind === "binding") break;
+        }
+        if (asExpression && match.arms.every((a) => a.pattern.kind === "literal" || a.pattern.kind === "variant")) {
+            this.line = match.line ?? this.line;
+            this.emit(Op.LoadLocal, subject);
+            this.emit(Op.NoMatch);
+        }
+        for (const operand of ends) this.patch(operand);
+    }
+
+    // The statements of a block, leaving its last expression's value (else null) on the stack
+    private valueBlock(body: Stmt[]): void {
+        this.blocks.push([]);
+        body.forEach((s, i) => {
+            const last = i === body.length - 1;
+            if (last && s.kind === "expression") {
+                if (s.line !== undefined) this.line = s.line;
+                this.expression(s.expr);
+            } else {
+                this.statement(s);
+                if (last) this.emit(Op.Const, this.constant(null));
+            }
+        });
+        if (body.length === 0) this.emit(Op.Const, this.constant(null));
+        this.deferred(this.blocks.length - 1);
+        this.blocks.pop();
+    }
+
+    private scopedValueBlock(body: Stmt[]): void {
+        this.scopes.push(new Map<string, CompilerBinding>());
+        this.valueBlock(body);
+        this.scopes.pop();
+    }
+
+    private expression(expr: Expr): void {
+        switch (expr.kind) {
+            case "literal":
+                this.emit(Op.Const, this.constant(expr.value));
+                return;
+            case "identifier":
+                this.load(expr.name);
+                return;
+            case "binary": {
+                // && and || only evaluate the right operand when the left
+                // doesn't decide, as in the interpreter
+                if (expr.op === "&&" || expr.op === "||") {
+                    this.expression(expr.left);
+                    const otherwise = this.jump(Op.JumpIfFalse);
+                    if (expr.op === "&&") this.expression(expr.right);
+                    else this.emit(Op.Const, this.constant(true));
+                    const end = this.jump(Op.Jump);
+                    this.patch(otherwise);
+                    if (expr.op === "&&") this.emit(Op.Const, this.constant(false));
+                    else this.expression(expr.right);
+                    this.patch(end);
+                    return;
+                }
+                this.expression(expr.left);
+                this.expression(expr.right);
+                if (expr.integer) this.emit(Op.IntegerOp, this.constant(expr.op));
+                else if (expr.op in FAST_BINARY_OPS) this.emit(FAST_BINARY_OPS[expr.op]);
+                else this.emit(Op.Binary, this.constant(expr.op));
+                return;
+            }
+            case "unary":
+                if (expr.integer) {
+                    this.emit(Op.Const, this.constant(0));
+                    this.expression(expr.operand);
+                    this.emit(Op.IntegerOp, this.constant("-"));
+                    return;
+                }
+                this.expression(expr.operand);
+                this.emit(Op.Unary, this.constant(expr.op));
+                return;
+            case "call":
+                this.call(expr);
+                return;
+            case "member":
+                this.expression(expr.object);
+                this.emit(Op.Member, this.constant(expr.property));
+                return;
+            case "field":
+                this.expression(expr.object);
+                this.emit(Op.Field, this.constant(expr.name));
+                return;
+            case "index":
+                this.expression(expr.object);
+                this.expression(expr.index);
+                this.emit(Op.Index);
+                return;
+            case "array":
+                for (const element of expr.elements) this.expression(element);
+                this.emit(Op.Array, expr.elements.length);
+                return;
+            case "map":
+                for (const entry of expr.entries) {
+                    this.expression(entry.key);
+                    this.expression(entry.value);
+                }
+                this.emit(Op.Map, expr.entries.length);
+                return;
+            case "if": {
+                this.expression(expr.condition);
+                const otherwise = this.jump(Op.JumpIfFalse);
+                this.scopedValueBlock(expr.then);
+                const end = this.jump(Op.Jump);
+                this.patch(otherwise);
+                this.scopedValueBlock(expr.else);
+                this.patch(end);
+                return;
+            }
+            case "block":
+                this.scopedValueBlock(expr.body);
+                return;
+            case "loop": {
+                const result = this.temp();
+                this.emit(Op.Const, this.constant(null));
+                this.emit(Op.StoreLocal, result);
+                const start = this.fn.code.length;
+                this.loops.push({ breaks: [], continues: [], result, blocks: this.blocks.length });
+                this.block(expr.body);
+                const loop = this.loops.pop()!;
+                for (const operand of loop.continues) this.patch(operand, start);
+                this.emit(Op.Jump, start);
+                for (const operand of loop.breaks) this.patch(operand);
+                this.emit(Op.LoadLocal, result);
+                return;
+            }
+            case "structLiteral":
+                for (const field of expr.fields) this.expression(field.value);
+                this.emit(Op.Struct, this.constant({
+                    name: expr.name,
+                    fields: expr.fields.map((f) => f.name),
+                    order: this.structs.get(expr.name) ?? expr.fields.map((f) => f.name),
+                }), expr.fields.length);
+                return;
+            case "match":
+                this.match(expr, true);
+                return;
+            case "range":
+                this.expression(expr.start);
+                this.expression(expr.end);
+                if (expr.step) this.expression(expr.step);
+                this.emit(Op.Range, expr.inclusive ? 1 : 0, expr.step ? 1 : 0);
+                return;
+            case "lambda":
+                this.closure(expr.fn);
+                return;
+            case "propagate": {
+                if (this.fn.name === "<main>") throw new Error(message("runtime.propagate-outside"));
+                this.expression(expr.value);
+                const failed = this.jump(Op.Propagate);
+                const done = this.jump(Op.Jump);
+                // An Err or None returns as a return statement would
+                this.patch(failed);
+                this.deferred(0);
+                this.emit(Op.Return);
+                this.patch(done);
+                return;
+            }
+        }
+    }
+
+    private call(expr: Extract<Expr, { kind: "call" }>): void {
+        const line = this.line;
+        if (expr.line !== undefined) this.line = expr.line;
+        const callee = expr.func;
+        const args = () => {
+            for (const arg of expr.args) this.expression(arg);
+        };
+        const shadowed = (name: string) => this.scopes.some((scope) => scope.has(name)) || this.rebound.has(name);
+        if (callee.kind === "identifier" && this.functionIndex.has(callee.name) && !shadowed(callee.name)) {
+            args();
+            this.emit(Op.CallFunction, this.functionIndex.get(callee.name)!, expr.args.length);
+        } else if (callee.kind === "identifier" && Object.hasOwn(BUILTIN_FUNCTIONS, callee.name) && !shadowed(callee.name) && !this.functionIndex.has(callee.name)) {
+            args();
+            this.emit(Op.CallBuiltin, this.constant(callee.name), expr.args.length);
+        } else if (callee.kind === "member") {
+            this.expression(callee.object);
+            args();
+            this.emit(Op.CallMethod, this.constant(callee.property), this.callSites++, expr.args.length);
+        } else {
+            this.expression(callee);
+            args();
+            this.emit(Op.Call, expr.args.length);
+        }
+        this.line = line;
+    }
+}
+
+// One instruction per line, for debugging the compiler
+export function disassemble(fn: BytecodeFunction, constants: any[]): string {
+    const out: string[] = [];
+    for (let pc = 0; pc < fn.code.length; pc += 1 + (OP_OPERANDS[fn.code[pc]] ?? 0)) {
+        const op = fn.code[pc];
+        const operands = fn.code.slice(pc + 1, pc + 1 + (OP_OPERANDS[op] ?? 0));
+        const comment = OP_CONSTANT_OPERAND.has(op) ? `  ; ${formatNested(constants[operands[0]])}` : "";
+        out.push(`${String(pc).padStart(5)}  ${OP_NAMES[op]} ${operands.join(" ")}${comment}`.trimEnd());
+    }
+    return out.join("\n");
+}
+
+// ============================================================================
+// BYTECODE VM - Run a BytecodeProgram on an operand stack
+// ============================================================================
+
+interface VmFrame {
+    fn: BytecodeFunction;
+    pc: number;
+    slots: any[];
+    line?: number; // the line that called it
+    base: number; // the stack height on entry, where a ? that fails leaves its value
+}
+
+// Global slots start out unbound; reading one falls back to the stdlib
+const UNBOUND = Symbol("unbound");
+// Callables made from program functions, so the VM can call them directly
+const VM_FUNCTION = Symbol("vmFunction");
+// The cells a closure's callable was made with
+const VM_CELLS = Symbol("vmCells");
+
+// A variable shared by a function and the closures declared in it
+class VmCell {
+    constructor(public value: any) { }
+}
+
+export interface VmOptions {
+    maxCallDepth?: number;
+    args?: string[]; // the program's command-line arguments, from env.args()
+    strictMath?: boolean; // as for the Interpreter
+}
+
+export class VM {
+    private program: BytecodeProgram = { main: bytecodeFunction("<main>", 0), functions: [], constants: [], globals: [], callSites: 0 };
+    private globals: any[] = [];
+    private globalIndex = new Map<string, number>();
+    private stdlib: Environment = new Interpreter().environment;
+    private stack: any[] = [];
+    private frames: VmFrame[] = [];
+    private callables: ((...args: any[]) => any)[] = [];
+    private caches: ({ receiver: any; target: (...args: any[]) => any } | undefined)[] = [];
+    private maxCallDepth: number;
+    private strictMath: boolean;
+    private errorTrace?: { error: unknown; frames: CallFrame[]; depth: number; line?: number };
+
+    constructor(options: VmOptions = {}) {
+        this.maxCallDepth = options.maxCallDepth ?? DEFAULT_MAX_CALL_DEPTH;
+        this.strictMath = options.strictMath ?? false;
+        if (options.args) this.stdlib = new Interpreter({ args: options.args }).environment;
+    }
+
+    run(program: BytecodeProgram): void {
+        this.program = program;
+        this.globals = program.globals.map(() => UNBOUND);
+        this.globalIndex = new Map(program.globals.map((name, slot) => [name, slot]));
+        this.callables = program.functions.map((_, index) => {
+            const callable = (...args: any[]) => this.invoke(index, args);
+            (callable as any)[VM_FUNCTION] = index;
+            return callable;
+        });
+        this.caches = new Array(program.callSites);
+        this.stack = [];
+        this.frames = [{ fn: program.main, pc: 0, slots: new Array(program.main.slots), base: 0 }];
+        try {
+            this.execute(0);
+        } catch (error) {
+            throw this.runtimeError(error);
+        }
+    }
+
+    // Calls a program function from native code, such as a stdlib callback
+    private invoke(index: number, args: any[], cells?: VmCell[]): any {
+        const depth = this.frames.length;
+        this.enter(index, args, this.currentLine(), cells);
+        return this.execute(depth);
+    }
+
+    // A closure's cells follow its parameters in its slots
+    private enter(index: number, args: any[], line?: number, cells?: VmCell[]): void {
+        const fn = this.program.functions[index];
+        if (args.length !== fn.arity) {
+            throw new Error(message("runtime.arity", { name: fn.name, expected: fn.arity, actual: args.length }));
+        }
+        // The main frame doesn't count towards the limit
+        if (this.frames.length > this.maxCallDepth) {
+            throw new Error(message("runtime.call-depth", { where: atLine(line), name: fn.name, limit: this.maxCallDepth }));
+        }
+        const slots = args;
+        if (cells) slots.push(...cells);
+        slots.length = fn.slots;
+        this.frames.push({ fn, pc: 0, slots, line, base: this.stack.length });
+    }
+
+    private currentLine(): number | undefined {
+        const frame = this.frames[this.frames.length - 1];
+        return frame?.fn.lines[Math.max(frame.pc - 1, 0)];
+    }
+
+    // Runs until the frame at index depth returns, and returns its value
+    private execute(depth: number): any {
+        const stack = this.stack;
+        const constants = this.program.constants;
+        const globals = this.globals;
+        let frame = this.frames[this.frames.length - 1];
+        let code = frame.fn.code;
+        let slots = frame.slots;
+        let pc = frame.pc;
+        try {
+            for (;;) {
+                // Literal cases, so the switch compiles to a jump table
+                switch (code[pc++]) {
+                    case 0: // Const
+                        stack.push(constants[code[pc++]]);
+                        break;
+                    case 1: // Pop
+                        stack.pop();
+                        break;
+                    case 2: // LoadLocal
+                        stack.push(slots[code[pc++]]);
+                        break;
+                    case 3: // StoreLocal
+                        slots[code[pc++]] = stack.pop();
+                        break;
+                    case 4: { // LoadGlobal
+                        const value = globals[code[pc++]];
+                        stack.push(value !== UNBOUND ? value : (frame.pc = pc, this.unboundGlobal(code[pc - 1])));
+                        break;
+                    }
+                    case 5: // StoreGlobal
+                        globals[code[pc++]] = stack.pop();
+                        break;
+                    case 6: { // Add
+                        const right = stack.pop();
+                        const left = stack.pop();
+                        stack.push(typeof left === "number" && typeof right === "number" ? left + right : applyBinaryOp("+", left, right));
+                        break;
+                    }
+                    case 7: { // Sub
+                        const right = stack.pop();
+                        const left = stack.pop();
+                        stack.push(typeof left === "number" && typeof right === "number" ? left - right : applyBinaryOp("-", left, right));
+                        break;
+                    }
+                    case 8: { // Mul
+                        const right = stack.pop();
+                        const left = stack.pop();
+                        stack.push(typeof left === "number" && typeof right === "number" ? left * right : applyBinaryOp("*", left, right));
+                        break;
+                    }
+                    case 9: { // Lt
+                        const right = stack.pop();
+                        const left = stack.pop();
+                        stack.push(typeof left === "number" && typeof right === "number" ? left < right : applyBinaryOp("<", left, right));
+                        break;
+                    }
+                    case 10: { // Le
+                        const right = stack.pop();
+                        const left = stack.pop();
+                        stack.push(typeof left === "number" && typeof right === "number" ? left <= right : applyBinaryOp("<=", left, right));
+                        break;
+                    }
+                    case 11: { // Gt
+                        const right = stack.pop();
+                        const left = stack.pop();
+                        stack.push(typeof left === "number" && typeof right === "number" ? left > right : applyBinaryOp(">", left, right));
+                        break;
+                    }
+                    case 12: { // Ge
+                        const right = stack.pop();
+                        const left = stack.pop();
+                        stack.push(typeof left === "number" && typeof right === "number" ? left >= right : applyBinaryOp(">=", left, right));
+                        break;
+                    }
+                    case 13: { // Eq
+                        const right = stack.pop();
+                        const left = stack.pop();
+                        stack.push(typeof left === "number" ? left === right : applyBinaryOp("==", left, right));
+                        break;
+                    }
+                    case 14: { // Ne
+                        const right = stack.pop();
+                        const left = stack.pop();
+                        stack.push(typeof left === "number" ? left !== right : applyBinaryOp("!=", left, right));
+                        break;
+                    }
+                    case 15: { // Binary
+                        const right = stack.pop();
+                        const op = constants[code[pc++]];
+                        if (this.strictMath) {
+                            frame.pc = pc;
+                            strictZeroDivisor(op, right);
+                        }
+                        stack.push(applyBinaryOp(op, stack.pop(), right));
+                        break;
+                    }
+                    case 16: { // IntegerOp
+                        const right = stack.pop();
+                        frame.pc = pc;
+                        stack.push(integerOp(constants[code[pc++]], stack.pop(), right));
+                        break;
+                    }
+                    case 17: // Unary
+                        stack.push(applyUnaryOp(constants[code[pc++]], stack.pop()));
+                        break;
+                    case 18: // Jump
+                        pc = code[pc];
+                        break;
+                    case 19: // JumpIfFalse
+                        pc = stack.pop() ? pc + 1 : code[pc];
+                        break;
+                    case 21: { // CallFunction
+                        const index = code[pc];
+                        const argc = code[pc + 1];
+                        frame.pc = pc += 2;
+                        this.enter(index, stack.splice(stack.length - argc), frame.fn.lines[pc - 1]);
+                        frame = this.frames[this.frames.length - 1];
+                        code = frame.fn.code;
+                        slots = frame.slots;
+                        pc = 0;
+                        break;
+                    }
+                    case 22: { // CallBuiltin
+                        const builtin = BUILTIN_FUNCTIONS[constants[code[pc]]];
+                        const argc = code[pc + 1];
+                        frame.pc = pc += 2;
+                        stack.push(builtin(stack.splice(stack.length - argc)));
+                        break;
+                    }
+                    case 23: { // CallMethod
+                        const property = constants[code[pc]];
+                        const site = code[pc + 1];
+                        const argc = code[pc + 2];
+                        frame.pc = pc += 3;
+                        const args = stack.splice(stack.length - argc);
+                        stack.push(this.method(stack.pop(), property, site)(...args));
+                        break;
+                    }
+                    case 20: { // Call
+                        const argc = code[pc];
+                        frame.pc = pc += 1;
+                        const args = stack.splice(stack.length - argc);
+                        const callee = stack.pop();
+                        if (typeof callee !== "function") throw new Error(message("runtime.not-a-function"));
+                        const index = callee[VM_FUNCTION];
+                        if (index === undefined) {
+                            stack.push(callee(...args));
+                            break;
+                        }
+                        this.enter(index, args, frame.fn.lines[pc - 1], callee[VM_CELLS]);
+                        frame = this.frames[this.frames.length - 1];
+                        code = frame.fn.code;
+                        slots = frame.slots;
+                        pc = 0;
+                        break;
+                    }
+                    case 24: { // Return
+                        this.frames.pop();
+                        if (this.frames.length === depth) return stack.pop();
+                        frame = this.frames[this.frames.length - 1];
+                        code = frame.fn.code;
+                        slots = frame.slots;
+                        pc = frame.pc;
+                        break;
+                    }
+                    case 25: { // Member
+                        const object = stack.pop();
+                        stack.push(object?.[constants[code[pc++]]]);
+                        break;
+                    }
+                    case 26: // Field
+                        frame.pc = pc;
+                        stack.push(fieldValue(stack.pop(), constants[code[pc++]]));
+                        break;
+                    case 27: { // Index
+                        const index = stack.pop();
+                        frame.pc = pc;
+                        stack.push(indexValue(stack.pop(), index));
+                        break;
+                    }
+                    case 28: { // SetIndex
+                        const value = stack.pop();
+                        const index = stack.pop();
+                        frame.pc = pc;
+                        assignIndex(stack.pop(), index, value);
+                        break;
+                    }
+                    case 29: { // Array
+                        const count = code[pc++];
+                        stack.push(stack.splice(stack.length - count, count));
+                        break;
+                    }
+                    case 30: { // Map
+                        const values = stack.splice(stack.length - 2 * code[pc++]);
+                        const map = new Map();
+                        for (let i = 0; i < values.length; i += 2) map.set(values[i], values[i + 1]);
+                        stack.push(map);
+                        break;
+                    }
+                    case 31: { // Struct
+                        const layout = constants[code[pc++]];
+                        const values = stack.splice(stack.length - code[pc++]);
+                        const fields = new Map<string, any>(layout.fields.map((name: string, i: number) => [name, values[i]]));
+                        stack.push(makeStruct(layout.name, layout.order.map((name: string) => [name, fields.get(name)])));
+                        break;
+                    }
+                    case 32: { // Range
+                        const inclusive = code[pc++] === 1;
+                        const step = code[pc++] === 1 ? stack.pop() : 1;
+                        const end = stack.pop();
+                        frame.pc = pc;
+                        stack.push(makeRange(stack.pop(), end, inclusive, step));
+                        break;
+                    }
+                    case 33: // Iterate
+                        frame.pc = pc;
+                        slots[code[pc++]] = iterate(stack.pop())[Symbol.iterator]();
+                        break;
+                    case 34: { // Next
+                        const next = slots[code[pc++]].next();
+                        if (next.done) {
+                            pc = code[pc];
+                        } else {
+                            stack.push(next.value);
+                            pc++;
+                        }
+                        break;
+                    }
+                    case 35: { // Same
+                        const right = stack.pop();
+                        stack.push(stack.pop() === right);
+                        break;
+                    }
+                    case 36: // Function
+                        globals[code[pc + 1]] = this.callables[code[pc]];
+                        pc += 2;
+                        break;
+                    case 37: { // Import
+                        const name = constants[code[pc++]];
+                        const module = this.stdlib.getModule(name);
+                        if (!module) throw new Error(message("runtime.module-not-found", { module: name }));
+                        stack.push(module);
+                        break;
+                    }
+                    case 38: // Immutable
+                        frame.pc = pc;
+                        throw new Error(message("runtime.immutable", { name: constants[code[pc++]] }));
+                    case 39: // NoMatch
+                        frame.pc = pc;
+                        throw new Error(message("runtime.no-match-arm", { value: formatValue(stack.pop()), where: atLine(frame.fn.lines[pc - 1]) }));
+                    case 40: { // Unwrap
+                        const optional = stack[stack.length - 1];
+                        if (optional?.type === "some") {
+                            stack[stack.length - 1] = optional.value;
+                            pc++;
+                        } else {
+                            stack.pop();
+                            pc = code[pc];
+                        }
+                        break;
+                    }
+                    case 45: { // Propagate
+                        const value = stack[stack.length - 1];
+                        if (value?.type === "ok" || value?.type === "some") {
+                            stack[stack.length - 1] = value.value;
+                            pc++;
+                        } else if (value?.type !== "err" && !isEmptyOptional(value)) {
+                            pc++;
+                        } else {
+                            stack.length = frame.base;
+                            stack.push(value);
+                            pc = code[pc];
+                        }
+                        break;
+                    }
+                    case 41: { // Closure
+                        const index = code[pc];
+                        const cells: VmCell[] = stack.splice(stack.length - code[pc + 1]);
+                        pc += 2;
+                        const callable = (...args: any[]) => this.invoke(index, args, cells);
+                        (callable as any)[VM_FUNCTION] = index;
+                        (callable as any)[VM_CELLS] = cells;
+                        stack.push(callable);
+                        break;
+                    }
+                    case 42: // LoadCell
+                        stack.push(slots[code[pc++]]?.value);
+                        break;
+                    case 43: { // StoreCell
+                        const slot = code[pc++];
+                        const value = stack.pop();
+                        if (slots[slot] instanceof VmCell) slots[slot].value = value;
+                        else slots[slot] = new VmCell(value);
+                        break;
+                    }
+                    case 44: { // CellRef
+                        const slot = code[pc++];
+                        if (!(slots[slot] instanceof VmCell)) slots[slot] = new VmCell(undefined);
+                        stack.push(slots[slot]);
+                        break;
+                    }
+                }
+            }
+        } catch (error) {
+            frame.pc = Math.max(frame.pc, pc);
+            if (this.errorTrace?.error !== error) {
+                const frames = this.frames.slice(1).map((f) => ({ name: f.fn.name, line: f.line }));
+                this.errorTrace = { error, frames: frames.slice(-5), depth: frames.length, line: this.currentLine() };
+            }
+            this.frames.length = depth;
+            throw error;
+        }
+    }
+
+    // Program globals first, then the stdlib namespaces and builtins
+    private unboundGlobal(slot: number): any {
+        const name = this.program.globals[slot];
+        if (this.stdlib.has(name)) return this.stdlib.get(name);
+        if (Object.hasOwn(BUILTIN_FUNCTIONS, name)) return (...args: any[]) => BUILTIN_FUNCTIONS[name](args);
+        throw new Error(message("runtime.undefined-variable", { name }));
+    }
+
+    // As Interpreter.resolveMethod; module members are cached per call site
+    private method(receiver: any, property: string, site: number): (...args: any[]) => any {
+        const cache = this.caches[site];
+        if (cache && cache.receiver === receiver) return cache.target;
+        if (receiver instanceof Date && Object.hasOwn(DATETIME_FUNCTIONS, property)) {
+            return (...args: any[]) => DATETIME_FUNCTIONS[property](receiver, ...args);
+        }
+        const isModule = receiver !== null && typeof receiver === "object" && !Array.isArray(receiver) &&
+            !(receiver instanceof Map) && !(receiver instanceof Set) && !isRange(receiver) &&
+            !receiver[STRUCT_NAME] && !receiver[ENUM_NAME] && !(receiver instanceof Date);
+        if (isModule) {
+            if (typeof ownField(receiver, property) !== "function") {
+                throw new Error(message("runtime.not-a-method", { name: property }));
+            }
+            this.caches[site] = { receiver, target: receiver[property] };
+            return receiver[property];
+        }
+        const slot = this.globalIndex.get(property);
+        const bound = slot !== undefined && this.globals[slot] !== UNBOUND ? this.globals[slot] : this.stdlib.has(property) ? this.stdlib.get(property) : undefined;
+        if (typeof bound === "function") return (...args: any[]) => bound(receiver, ...args);
+        if (Object.hasOwn(BUILTIN_FUNCTIONS, property)) {
+            const builtin = BUILTIN_FUNCTIONS[property];
+            return (...args: any[]) => builtin([receiver, ...args]);
+        }
+        throw new Error(message("runtime.not-a-method", { name: property }));
+    }
+
+    // As Interpreter.runtimeError: notes name the innermost calls
+    private runtimeError(error: unknown): StrataError {
+        const trace = this.errorTrace?.error === error ? this.errorTrace : undefined;
+        this.errorTrace = undefined;
+        const notes = (trace?.frames ?? []).reverse()
+            .map((f) => message(f.line !== undefined ? "runtime.frame-called" : "runtime.frame", f));
+        if (trace && trace.depth > 5) notes.push(message("runtime.more-frames", { count: trace.depth - 5 }));
+        const span = trace?.line;
+        return asStrataError(error, "E0004", span !== undefined ? { line: span } : undefined, notes);
+    }
+}
+
+// ============================================================================
+// GENERATED ARTIFACTS
+// ============================================================================
+//
+// Every generator starts its output with a comment naming the compiler
+// version and a hash of the code below it. Nothing else in the output
+// depends on time, paths or the machine, so the same program and flags
+// always give byte-identical files.
+
+export const STRATA_VERSION = "1.0.0";
+
+function withArtifactHeader(comment: string, code: string): string {
+    const hash = createHash("sha256").update(code).digest("hex").slice(0, 16);
+    return `${comment} Generated by Strata ${STRATA_VERSION} (sha256:${hash})\n${code}`;
+}
+
+// ============================================================================
+// C CODE GENERATOR
+// ============================================================================
+
+// Emitted once when a program uses range values
+const C_RANGE_SUPPORT = [
+    "typedef struct { int start; int end; int step; int inclusive; } strata_range;",
+    "static int strata_range_continues(strata_range r, int i) {",
+    "return r.step > 0 ? (r.inclusive ? i <= r.end : i < r.end) : (r.inclusive ? i >= r.end : i > r.end);",
+    "}",
+    "static int strata_range_contains(strata_range r, int x) {",
+    "int started = r.step > 0 ? x >= r.start : x <= r.start;",
+    "return started && strata_range_continues(r, x) && (x - r.start) % r.step == 0;",
+    "}",
+];
+
+// char.* in C: chars are bytes, so both variants map onto <ctype.h>
+const C_CHAR_FUNCTIONS: Record<string, string> = {
+    isDigit: "isdigit", isAsciiDigit: "isdigit",
+    isAlpha: "isalpha", isAsciiAlpha: "isalpha",
+    isAlphanumeric: "isalnum", isAsciiAlphanumeric: "isalnum",
+    isSpace: "isspace", isAsciiSpace: "isspace",
+    isUpper: "isupper", isLower: "islower",
+    toUpper: "toupper", toAsciiUpper: "toupper",
+    toLower: "tolower", toAsciiLower: "tolower",
+};
+
+// char.code and char.fromCode: chars are bytes, so code points past ASCII
+// stop the program instead of wrapping
+const C_CHAR_CODE_SUPPORT = [
+    "static int strata_char_code(char c) {",
+    "if ((unsigned char)c > 127) { fprintf(stderr, \"char.code: byte %d is not an ASCII char; the c target stores chars as bytes\\n\", (unsigned char)c); exit(1); }",
+    "return c;",
+    "}",
+    "static char strata_char_from_code(int code) {",
+    "if (code < 0 || code > 127) { fprintf(stderr, \"char.fromCode: %d is outside ASCII; the c target stores chars as bytes\\n\", code); exit(1); }",
+    "return (char)code;",
+    "}",
+];
+
+// math.checkedAdd / checkedMul: overflow tests that never overflow themselves
+const C_CHECKED_SUPPORT = [
+    "typedef struct { int has_value; int value; } strata_optional_int;",
+    "static strata_optional_int strata_checked_add(int a, int b) {",
+    "if ((b > 0 && a > INT_MAX - b) || (b < 0 && a < INT_MIN - b)) return (strata_optional_int){0, 0};",
+    "return (strata_optional_int){1, a + b};",
+    "}",
+    "static strata_optional_int strata_checked_mul(int a, int b) {",
+    "int overflow = a > 0 ? (b > 0 ? a > INT_MAX / b : b < INT_MIN / a)",
+    ": (b > 0 ? a < INT_MIN / b : (a != 0 && b < INT_MAX / a));",
+    "if (overflow) return (strata_optional_int){0, 0};",
+    "return (strata_optional_int){1, a * b};",
+    "}",
+];
+
+// String interpolation: printf formatting into a buffer sized by a dry run
+const C_FORMAT_SUPPORT = [
+    "static char* strata_format(const char* fmt, ...) {",
+    "va_list args;",
+    "va_start(args, fmt);",
+    "int len = vsnprintf(NULL, 0, fmt, args);",
+    "va_end(args);",
+    "char* buf = malloc(len + 1);",
+    "va_start(args, fmt);",
+    "vsnprintf(buf, len + 1, fmt, args);",
+    "va_end(args);",
+    "return buf;",
+    "}",
+];
+
+// Floats print as the interpreter prints them: the fewest digits that read
+// back as the same double. buf is the caller's, at least 32 bytes
+const C_FLOAT_TEXT_SUPPORT = [
+    "static char* strata_float_text(char* buf, double x) {",
+    "if (isnan(x)) return strcpy(buf, \"NaN\");",
+    "if (isinf(x)) return strcpy(buf, x > 0 ? \"Infinity\" : \"-Infinity\");",
+    "int p = 1;",
+    "for (; p < 17; p++) {",
+    "snprintf(buf, 32, \"%.*g\", p, x);",
+    "if (strtod(buf, NULL) == x) break;",
+    "}",
+    "snprintf(buf, 32, \"%.*g\", p, x);",
+    // 1500 rather than 1.5e+03, and 1e-7 rather than 1e-07, as JavaScript writes them
+    "char* e = strchr(buf, 'e');",
+    "if (e == NULL) return buf;",
+    "int exponent = atoi(e + 1);",
+    "if (exponent >= -6 && exponent < 21) {",
+    "snprintf(buf, 32, \"%.*f\", p - 1 - exponent > 0 ? p - 1 - exponent : 0, x);",
+    "} else if (e[2] == '0') {",
+    "memmove(e + 2, e + 3, strlen(e + 3) + 1);",
+    "}",
+    "return buf;",
+    "}",
+];
+
+// Strings in C are UTF-8 char*, so lengths and positions count code points
+// (bytes that aren't continuation bytes) to agree with the interpreter.
+// a + b, comparisons, slices and .length() compile to these; results are
+// fresh allocations that are never freed
+const C_STRING_SUPPORT = [
+    "static int strata_string_length(const char* s) {",
+    "int n = 0;",
+    "for (; *s; s++) n += (*s & 0xC0) != 0x80;",
+    "return n;",
+    "}",
+    "static size_t strata_string_offset(const char* s, int index) {",
+    "size_t i = 0;",
+    "for (; s[i] && index > 0; index--) do i++; while ((s[i] & 0xC0) == 0x80);",
+    "return i;",
+    "}",
+    "static char* strata_string_concat(const char* a, const char* b) {",
+    "size_t a_len = strlen(a), b_len = strlen(b);",
+    "char* out = malloc(a_len + b_len + 1);",
+    "memcpy(out, a, a_len);",
+    "memcpy(out + a_len, b, b_len + 1);",
+    "return out;",
+    "}",
+    "static int strata_string_compare(const char* a, const char* b) {",
+    "int diff = strcmp(a, b);",
+    "return (diff > 0) - (diff < 0);",
+    "}",
+    // s[start..end]: negative positions count from the end, and both are
+    // clamped to the string, as in the interpreter
+    "static char* strata_string_slice(const char* s, int start, int end) {",
+    "int n = strata_string_length(s);",
+    "if (start < 0) start += n;",
+    "if (end < 0) end += n;",
+    "start = start < 0 ? 0 : start > n ? n : start;",
+    "end = end < start ? start : end > n ? n : end;",
+    "size_t from = strata_string_offset(s, start), to = strata_string_offset(s, end);",
+    "return strndup(s + from, to - from);",
+    "}",
+    // text.substring swaps a reversed pair instead
+    "static char* strata_string_substring(const char* s, int start, int end) {",
+    "if (start > end) { int t = start; start = end; end = t; }",
+    "return strata_string_slice(s, start, end);",
+    "}",
+];
+
+// text.* in C, over the string support above. Case mapping and trimming
+// only know ASCII. split needs strata_array_string
+const C_TEXT_FUNCTIONS: Record<string, string> = {
+    length: "strata_string_length",
+    upper: "strata_text_upper", toUpperCase: "strata_text_upper",
+    lower: "strata_text_lower", toLowerCase: "strata_text_lower",
+    trim: "strata_text_trim",
+    substring: "strata_string_substring",
+    compare: "strata_string_compare",
+    indexOf: "strata_text_index_of",
+    split: "strata_text_split",
+    replace: "strata_text_replace",
+    contains: "strata_text_contains", includes: "strata_text_contains",
+};
+
+const C_TEXT_SUPPORT = [
+    "static char* strata_text_map(const char* s, int (*f)(int)) {",
+    "char* out = strdup(s);",
+    "for (char* p = out; *p; p++) *p = (char)f((unsigned char)*p);",
+    "return out;",
+    "}",
+    "static char* strata_text_upper(const char* s) { return strata_text_map(s, toupper); }",
+    "static char* strata_text_lower(const char* s) { return strata_text_map(s, tolower); }",
+    "static char* strata_text_trim(const char* s) {",
+    "while (isspace((unsigned char)*s)) s++;",
+    "size_t len = strlen(s);",
+    "while (len > 0 && isspace((unsigned char)s[len - 1])) len--;",
+    "return strndup(s, len);",
+    "}",
+    "static int strata_text_index_of(const char* s, const char* sub) {",
+    "const char* at = strstr(s, sub);",
+    "if (!at) return -1;",
+    "int n = 0;",
+    "for (const char* p = s; p < at; p++) n += (*p & 0xC0) != 0x80;",
+    "return n;",
+    "}",
+    "static int strata_text_contains(const char* s, const char* sub) { return strstr(s, sub) != NULL; }",
+    "static char* strata_text_replace(const char* s, const char* old, const char* new_text) {",
+    "const char* at = strstr(s, old);",
+    "if (!at) return strdup(s);",
+    "size_t before = at - s, old_len = strlen(old), new_len = strlen(new_text);",
+    "char* out = malloc(strlen(s) - old_len + new_len + 1);",
+    "memcpy(out, s, before);",
+    "memcpy(out + before, new_text, new_len);",
+    "strcpy(out + before + new_len, at + old_len);",
+    "return out;",
+    "}",
+    "static strata_array_string strata_text_split(const char* s, const char* sep) {",
+    "strata_array_string parts = strata_array_string_from(NULL, 0);",
+    "size_t sep_len = strlen(sep);",
+    "if (sep_len == 0) {",
+    "while (*s) { size_t n = strata_string_offset(s, 1); strata_array_string_push(&parts, strndup(s, n)); s += n; }",
+    "return parts;",
+    "}",
+    "for (const char* at; (at = strstr(s, sep)); s = at + sep_len) strata_array_string_push(&parts, strndup(s, at - s));",
+    "strata_array_string_push(&parts, strdup(s));",
+    "return parts;",
+    "}",
+];
+
+// A string? such as env.get's: has_value is 0 for None
+const C_OPTIONAL_STRING = "typedef struct { int has_value; char* value; } strata_optional_string;";
+
+// io.readLine/nextLine/readInt/readFloat: fgets into a growing buffer, then
+// sscanf for the numbers, failing like the interpreter on a line that isn't one
+const C_INPUT_FUNCTIONS: Record<string, string> = {
+    readLine: "strata_read_line",
+    nextLine: "strata_next_line",
+    readInt: "strata_read_int",
+    readFloat: "strata_read_float",
+};
+
+const C_INPUT_SUPPORT = [
+    "static char* strata_read_line(void) {",
+    "size_t cap = 64, len = 0;",
+    "char* buf = malloc(cap);",
+    "buf[0] = '\\0';",
+    "while (fgets(buf + len, (int)(cap - len), stdin)) {",
+    "len += strlen(buf + len);",
+    "if (len > 0 && buf[len - 1] == '\\n') {",
+    "buf[--len] = '\\0';",
+    "if (len > 0 && buf[len - 1] == '\\r') buf[--len] = '\\0';",
+    "return buf;",
+    "}",
+    "if (len + 1 == cap) buf = realloc(buf, cap *= 2);",
+    "}",
+    "return buf;",
+    "}",
+    "static strata_optional_string strata_next_line(void) {",
+    "char* line = strata_read_line();",
+    "if (!*line && feof(stdin)) { free(line); return (strata_optional_string){0, NULL}; }",
+    "return (strata_optional_string){1, line};",
+    "}",
+    "static char* strata_read_number(const char* fn) {",
+    "char* line = strata_read_line();",
+    "if (!*line && feof(stdin)) { fprintf(stderr, \"%s: no more input\\n\", fn); exit(1); }",
+    "return line;",
+    "}",
+    "static int strata_read_int(void) {",
+    "char* line = strata_read_number(\"io.readInt\");",
+    "int value;",
+    "char extra;",
+    "if (sscanf(line, \"%d %c\", &value, &extra) != 1) {",
+    "fprintf(stderr, \"io.readInt: expected an integer, got \\\"%s\\\"\\n\", line);",
+    "exit(1);",
+    "}",
+    "free(line);",
+    "return value;",
+    "}",
+    "static double strata_read_float(void) {",
+    "char* line = strata_read_number(\"io.readFloat\");",
+    "double value;",
+    "char extra;",
+    "if (sscanf(line, \"%lf %c\", &value, &extra) != 1) {",
+    "fprintf(stderr, \"io.readFloat: expected a number, got \\\"%s\\\"\\n\", line);",
+    "exit(1);",
+    "}",
+    "free(line);",
+    "return value;",
+    "}",
+];
+
+// env.args() and env.get(name): main saves argc/argv for the helpers, and
+// an unset variable is an empty optional like the interpreter's None
+const C_ENV_SUPPORT = [
+    "static int strata_argc;",
+    "static char** strata_argv;",
+    "static strata_array_string strata_env_args(void) { return strata_array_string_from(strata_argv + 1, strata_argc - 1); }",
+    "static strata_optional_string strata_env_get(const char* name) {",
+    "char* value = getenv(name);",
+    "return (strata_optional_string){value != NULL, value};",
+    "}",
+];
+
+const C_ESCAPES: Record<string, string> = { "\\": "\\\\", "\n": "\\n", "\t": "\\t", "\r": "\\r" };
+
+// Text for a C literal delimited by quote. Other control characters become
+// three-digit octal escapes, which unlike \x can't swallow a following digit
+function cEscape(text: string, quote: string): string {
+    return text.replace(/[\\"'\x00-\x1f\x7f]/g, (c) =>
+        C_ESCAPES[c] ?? (c === quote ? `\\${c}` : c === '"' || c === "'" ? c : `\\${c.charCodeAt(0).toString(8).padStart(3, "0")}`)
+    );
+}
+
+// A C string literal for text that may hold quotes, backslashes or newlines
+function cString(text: string): string {
+    return `"${cEscape(text, '"')}"`;
+}
+
+// Growable arrays: one struct plus constructor and push helper per element type
+function cArraySupport(name: string, elem: string): string[] {
+    return [
+        `typedef struct { ${elem}* data; size_t len; size_t cap; } ${name};`,
+        `static ${name} ${name}_from(${elem}* items, size_t len) {`,
+        `${name} a = { malloc((len ? len : 1) * sizeof(${elem})), len, len ? len : 1 };`,
+        `if (len) memcpy(a.data, items, len * sizeof(${elem}));`,
+        "return a;",
+        "}",
+        `static void ${name}_push(${name}* a, ${elem} value) {`,
+        `if (a->len == a->cap) { a->cap *= 2; a->data = realloc(a->data, a->cap * sizeof(${elem})); }`,
+        "a->data[a->len++] = value;",
+        "}",
+        `static int ${name}_contains(${name} a, ${elem} value) {`,
+        "for (size_t i = 0; i < a.len; i++) {",
+        `if (${elem === "char*" ? "strcmp(a.data[i], value) == 0" : "a.data[i] == value"}) return 1;`,
+        "}",
+        "return 0;",
+        "}",
+    ];
+}
+
+// Strata function names C already uses, as a keyword or from the headers
+// generated code includes, get a prefix
+const C_RESERVED = new Set([
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum", "extern",
+    "float", "for", "goto", "if", "inline", "int", "long", "register", "restrict", "return", "short", "signed",
+    "sizeof", "static", "struct", "switch", "typedef", "union", "unsigned", "void", "volatile", "while", "main",
+    "abs", "exit", "free", "malloc", "printf", "puts", "strlen", "sqrt", "pow", "floor", "ceil", "round",
+    "fabs", "fmod", "sin", "cos", "tan", "exp", "log",
+]);
+
+function cFunctionName(name: string): string {
+    return C_RESERVED.has(name) ? `strata_${name}` : name;
+}
+
+export interface CGeneratorOptions {
+    // With a file name, each statement is preceded by a #line directive so
+    // C compiler messages point at the Strata line: the statement's own file
+    // for imported modules, this one otherwise. prelude statements get none
+    lineFile?: string;
+    prelude?: Stmt[];
+}
+
+export class CGenerator {
+    private code: string[] = [];
+    private usesRanges = false;
+    // Growable array structs in use, in dependency order: name -> element C type
+    private arrayTypes = new Map<string, string>();
+    private varTypes = new Map<string, TypeDef>();
+    private typedefs: string[] = [];
+    private enums = new Map<string, EnumVariant[]>();
+    private structs = new Map<string, { name: string; type: TypeDef }[]>();
+    private matchCount = 0;
+    private valueCount = 0;
+    // Enclosing loops, innermost last: the temporary of a loop expression,
+    // if any, and how many blocks were open around the loop
+    private loops: { result?: string; blocks: number }[] = [];
+    // The defers reached so far in each open block, innermost last; every
+    // way out of a block is preceded by a copy of them, last first
+    private blocks: Stmt[][] = [];
+    private imports = new Map<string, string>();
+    private includes = new Set<string>();
+    private usesChecked = false;
+    private usesFormat = false;
+    private usesFloatText = false;
+    private usesStrings = false;
+    private usesText = false;
+    private usesInput = false;
+    private usesCharCodes = false;
+    private usesEnv = false;
+    private usesOptionalString = false;
+    // Top-level functions' return types, and the C they become: a prototype
+    // each, ahead of the definitions, so calls may come before a definition
+    private functionTypes = new Map<string, TypeDef>();
+    private prototypes: string[] = [];
+    private functions: string[] = [];
+    // Top-level variables the functions use live at file scope: declaration by name
+    private globals = new Map<string, string>();
+    // The top-level functions main reaches and the variables they use
+    private fileScope = new Set<Stmt>();
+    // Line of the statement being generated, for internal compiler errors
+    private line?: number;
+    // The file #line directives name for the top-level statement being
+    // generated and everything inside it; undefined for none
+    private directiveFile?: string;
+    private topLevel = new Set<Stmt>();
+    private prelude: Set<Stmt>;
+
+    constructor(private options: CGeneratorOptions = {}) {
+        this.prelude = new Set(options.prelude);
+    }
+
+    generate(statements: Stmt[]): string {
+        this.line = undefined;
+        return runPhase("C generator", () => (this.line !== undefined ? { line: this.line } : undefined), () =>
+            this.generateProgram(statements)
+        );
+    }
+
+    private generateProgram(statements: Stmt[]): string {
+        this.code = [];
+        this.usesRanges = false;
+        this.arrayTypes.clear();
+        this.varTypes.clear();
+        this.typedefs = [];
+        this.enums.clear();
+        this.structs.clear();
+        this.matchCount = 0;
+        this.imports.clear();
+        this.includes.clear();
+        this.usesChecked = false;
+        this.usesFormat = false;
+        this.usesFloatText = false;
+        this.usesStrings = false;
+        this.usesText = false;
+        this.usesInput = false;
+        this.usesCharCodes = false;
+        this.usesEnv = false;
+        this.usesOptionalString = false;
+        this.loops = [];
+        this.blocks = [];
+        this.functionTypes.clear();
+        this.prototypes = [];
+        this.functions = [];
+        this.globals.clear();
+        this.fileScope.clear();
+        this.findFileScope(statements);
+        this.directiveFile = undefined;
+        this.topLevel = new Set(statements);
+        this.code.push("int main() {");
+
+        this.generateBlock(statements);
+
+        this.code.push("return 0;");
+        this.code.push("}");
+        if (this.usesEnv) this.code.splice(0, 1, "int main(int argc, char** argv) {", "strata_argc = argc;", "strata_argv = argv;");
+
+        if (this.usesChecked) this.includes.add("limits.h");
+        if (this.usesText) this.includes.add("ctype.h");
+        if (this.usesText || this.usesEnv) this.typeToCString({ kind: "array", innerType: TYPE_REGISTRY.string });
+        if (this.usesText) this.usesStrings = true;
+        if (this.usesFormat || this.usesFloatText || this.usesStrings || this.usesInput || this.usesCharCodes || this.arrayTypes.size > 0) this.includes.add("stdlib.h");
+        if (this.usesFormat) this.includes.add("stdarg.h");
+        const header = ["#include <stdio.h>", "#include <math.h>", "#include <string.h>"];
+        for (const include of this.includes) header.push(`#include <${include}>`);
+        if (this.usesChecked) header.push(...C_CHECKED_SUPPORT);
+        if (this.usesCharCodes) header.push(...C_CHAR_CODE_SUPPORT);
+        if (this.usesFormat) header.push(...C_FORMAT_SUPPORT);
+        if (this.usesFloatText) header.push(...C_FLOAT_TEXT_SUPPORT);
+        if (this.usesRanges) header.push(...C_RANGE_SUPPORT);
+        header.push(...this.typedefs);
+        for (const [name, elem] of this.arrayTypes) header.push(...cArraySupport(name, elem));
+        if (this.usesStrings) header.push(...C_STRING_SUPPORT);
+        if (this.usesText) header.push(...C_TEXT_SUPPORT);
+        if (this.usesOptionalString || this.usesInput || this.usesEnv) header.push(C_OPTIONAL_STRING);
+        if (this.usesInput) header.push(...C_INPUT_SUPPORT);
+        if (this.usesEnv) header.push(...C_ENV_SUPPORT);
+        header.push(...this.globals.values(), ...this.prototypes, ...this.functions);
+        return withArtifactHeader("//", [...header, ...this.code].join("\n"));
+    }
+
+    private generateStatement(stmt: Stmt): void {
+        if (stmt.line !== undefined) this.line = stmt.line;
+        if (this.options.lineFile !== undefined && this.topLevel.has(stmt)) {
+            this.directiveFile = this.prelude.has(stmt) ? undefined : stmt.file ?? this.options.lineFile;
+        }
+        if (this.directiveFile !== undefined && stmt.line !== undefined) {
+            this.code.push(`#line ${stmt.line} ${cString(this.directiveFile)}`);
+        }
+        switch (stmt.kind) {
+            case "let":
+                this.varTypes.set(stmt.name, stmt.type);
+                if (this.fileScope.has(stmt)) {
+                    this.generateGlobal(stmt);
+                    break;
+                }
+                if (stmt.type.kind === "array" && stmt.value.kind === "array") {
+                    this.code.push(this.declareArray(stmt.type, stmt.name, stmt.value.elements));
+                    break;
+                }
+                const value = this.generateExpression(stmt.value);
+                const qualifier = stmt.constant ? "const " : "";
+                this.code.push(`${qualifier}${this.declareC(stmt.type, stmt.name)} = ${value};`);
+                break;
+            case "import":
+                this.imports.set(stmt.name, stmt.module);
+                break;
+            // Test blocks are for strata test, which runs them in the interpreter
+            case "test":
+                break;
+            case "function":
+                // Nested functions would need closures, which C doesn't have
+                if (this.fileScope.has(stmt)) this.generateFunction(stmt);
+                break;
+            case "match":
+                this.generateMatch(stmt);
+                break;
+            case "struct":
+                const members = stmt.fields.map((f) => `${this.declareC(f.type, f.name)};`);
+                this.typedefs.push(`typedef struct { ${members.join(" ")} } ${stmt.name};`);
+                this.structs.set(stmt.name, stmt.fields);
+                break;
+            case "enum":
+                this.generateEnum(stmt);
+                break;
+            case "assignment":
+                this.code.push(`${stmt.target} = ${this.generateExpression(stmt.value)};`);
+                break;
+            case "indexAssignment":
+                const slot = this.generateExpression({ kind: "index", object: stmt.object, index: stmt.index });
+                this.code.push(`${slot} = ${this.generateExpression(stmt.value)};`);
+                break;
+            case "expression":
+                if (stmt.expr.kind === "block") {
+                    this.code.push("{");
+                    this.generateBlock(stmt.expr.body);
+                    this.code.push("}");
+                    break;
+                }
+                const expr = this.generateExpression(stmt.expr);
+                this.code.push(`${expr};`);
+                break;
+            case "if":
+                const condition = this.generateExpression(stmt.condition);
+                this.code.push(`if (${condition}) {`);
+                this.generateBlock(stmt.then);
+                if (stmt.else) {
+                    this.code.push("} else {");
+                    this.generateBlock(stmt.else);
+                }
+                this.code.push("}");
+                break;
+            case "forIn":
+                const v = stmt.variable;
+                const arrayType = this.arrayTypeOf(stmt.iterable);
+                if (arrayType) {
+                    const arr = this.generateExpression(stmt.iterable);
+                    const i = `strata_i_${v}`;
+                    const len = arrayType.size !== undefined ? String(arrayType.size) : `${arr}.len`;
+                    const items = arrayType.size !== undefined ? arr : `${arr}.data`;
+                    this.varTypes.set(v, arrayType.innerType!);
+                    this.code.push(`for (size_t ${i} = 0; ${i} < ${len}; ${i}++) {`);
+                    this.code.push(`${this.declareC(arrayType.innerType!, v)} = ${items}[${i}];`);
+                } else if (stmt.iterable.kind === "range") {
+                    const range = stmt.iterable;
+                    // Bounds and step are worked out once, in order, and the
+                    // step's sign picks the direction at run time
+                    const n = this.valueCount++;
+                    const end = `strata_end_${n}`;
+                    const cmp = range.inclusive ? "=" : "";
+                    const init = `int ${v} = ${this.generateExpression(range.start)}, ${end} = ${this.generateExpression(range.end)}`;
+                    if (range.step) {
+                        const step = `strata_step_${n}`;
+                        this.code.push(
+                            `for (${init}, ${step} = ${this.generateExpression(range.step)}; ${step} > 0 ? ${v} <${cmp} ${end} : ${v} >${cmp} ${end}; ${v} += ${step}) {`
+                        );
+                    } else {
+                        this.code.push(`for (${init}; ${v} <${cmp} ${end}; ${v}++) {`);
+                    }
+                } else {
+                    // C chars are bytes, so walking a string would split its characters
+                    if (this.exprType(stmt.iterable)?.primitive === "string") throw this.unsupported("for-in over a string");
+                    // Range held in a variable: walk it with the runtime helpers
+                    this.usesRanges = true;
+                    const r = this.generateExpression(stmt.iterable);
+                    this.code.push(`for (int ${v} = (${r}).start; strata_range_continues(${r}, ${v}); ${v} += (${r}).step) {`);
+                }
+                this.generateLoopBody(stmt.body, undefined);
+                break;
+            case "while":
+                if (stmt.binding === undefined) {
+                    this.code.push(`while (${this.generateExpression(stmt.condition)}) {`);
+                } else {
+                    // A fresh optional per iteration; its value binds the name until the next
+                    const type = stmt.bindingType ?? { kind: "primitive", primitive: "int" };
+                    const optional = `strata_value_${this.valueCount++}`;
+                    this.varTypes.set(stmt.binding, type);
+                    this.code.push("while (1) {");
+                    this.code.push(`${this.typeToCString({ kind: "optional", innerType: type })} ${optional} = ${this.generateExpression(stmt.condition)};`);
+                    this.code.push(`if (!${optional}.has_value) break;`);
+                    this.code.push(`${this.declareC(type, stmt.binding)} = ${optional}.value;`);
+                }
+                this.generateLoopBody(stmt.body, undefined);
+                break;
+            case "break":
+            case "continue": {
+                const loop = this.loops[this.loops.length - 1];
+                if (stmt.kind === "break" && stmt.value && loop?.result) this.code.push(`${loop.result} = ${this.generateExpression(stmt.value)};`);
+                this.generateDeferred(loop?.blocks ?? this.blocks.length);
+                this.code.push(`${stmt.kind};`);
+                break;
+            }
+            case "return":
+                const value2 = stmt.value
+                    ? this.generateExpression(stmt.value)
+                    : "0";
+                if (this.blocks.some((block) => block.length > 0)) {
+                    // The value is worked out before the defers run
+                    const result = `strata_value_${this.valueCount++}`;
+                    const type = (stmt.value && this.exprType(stmt.value)) ?? { kind: "primitive", primitive: "int" };
+                    this.code.push(`${this.declareC(type, result)} = ${value2};`);
+                    this.generateDeferred(0);
+                    this.code.push(`return ${result};`);
+                    break;
+                }
+                this.code.push(`return ${value2};`);
+                break;
+            case "defer":
+                this.blocks[this.blocks.length - 1].push(stmt.body);
+                break;
+        }
+    }
+
+    private generateExpression(expr: Expr): string {
+        switch (expr.kind) {
+            case "literal":
+                if (expr.type.primitive === "char") {
+                    return `'${cEscape(expr.value, "'")}'`;
+                }
+                if (typeof expr.value === "string") {
+                    return cString(expr.value);
+                }
+                // bool is int in C
+                if (typeof expr.value === "boolean") {
+                    return expr.value ? "1" : "0";
+                }
+                // An empty optional of whichever type the declaration gives it
+                if (expr.value === null) return "{0}";
+                // 2.0 stays a double in C so 2.0 / 4 doesn't become integer division;
+                // 1e+21 already is one
+                if (expr.type.primitive === "float" && Number.isInteger(expr.value) && !/e/.test(String(expr.value))) {
+                    return `${expr.value}.0`;
+                }
+                return String(expr.value);
+            case "identifier":
+                return this.functionTypes.has(expr.name) && !this.varTypes.has(expr.name) ? cFunctionName(expr.name) : expr.name;
+            case "binary":
+                const left = this.generateExpression(expr.left);
+                if (expr.op === "in") {
+                    return this.generateContains(expr.left, left, expr.right);
+                }
+                if (expr.op === "not in") {
+                    return `(!${this.generateContains(expr.left, left, expr.right)})`;
+                }
+                const right = this.generateExpression(expr.right);
+                if (expr.op === "??") {
+                    // The optional is read twice, so anything but a variable is copied first
+                    let optional = left;
+                    if (expr.left.kind !== "identifier") {
+                        optional = `strata_value_${this.valueCount++}`;
+                        const type = this.exprType(expr.left) ?? { kind: "optional", innerType: TYPE_REGISTRY.int };
+                        this.code.push(`${this.declareC(type, optional)} = ${left};`);
+                    }
+                    return `(${optional}.has_value ? ${optional}.value : ${right})`;
+                }
+                const isString = (e: Expr) => this.exprType(e)?.primitive === "string";
+                if (expr.op === "+" && isString(expr.left)) {
+                    this.usesStrings = true;
+                    return `strata_string_concat(${left}, ${right})`;
+                }
+                if (["==", "!=", "<", ">", "<=", ">="].includes(expr.op) && isString(expr.left)) {
+                    return `(strcmp(${left}, ${right}) ${expr.op} 0)`;
+                }
+                const isFloat = (e: Expr) => this.exprType(e)?.primitive === "float";
+                if (expr.op === "%" && (isFloat(expr.left) || isFloat(expr.right))) {
+                    return `fmod(${left}, ${right})`;
+                }
+                return `(${left} ${expr.op} ${right})`;
+            case "unary":
+                const operand = this.generateExpression(expr.operand);
+                return `(${expr.op}${operand})`;
+            case "call":
+                // Shape.Circle(2.0)
+                const variantEnum = expr.func.kind === "member" ? this.enumOf(expr.func.object) : undefined;
+                if (variantEnum && expr.func.kind === "member") {
+                    return this.generateVariant(variantEnum, expr.func.property, expr.args.map((a) => this.generateExpression(a)));
+                }
+                const loop = this.generateArrayFunction(expr);
+                if (loop !== undefined) return loop;
+                const func = this.generateExpression(expr.func);
+                const args = expr.args.map((a) =>
+                    this.generateExpression(a)
+                );
+                if (
+                    expr.func.kind === "member" &&
+                    expr.func.object.kind === "identifier" &&
+                    ["str", "std::io"].includes(this.imports.get(expr.func.object.name) ?? "") &&
+                    (expr.func.property === "print" || expr.func.property === "println")
+                ) {
+                    return this.generatePrint(expr.args);
+                }
+                if (
+                    expr.func.kind === "member" &&
+                    expr.func.object.kind === "identifier" &&
+                    this.imports.get(expr.func.object.name) === "std::math" &&
+                    ["checkedAdd", "checkedMul"].includes(expr.func.property)
+                ) {
+                    this.usesChecked = true;
+                    const helper = expr.func.property === "checkedAdd" ? "strata_checked_add" : "strata_checked_mul";
+                    return `${helper}(${args.join(", ")})`;
+                }
+                if (
+                    expr.func.kind === "member" &&
+                    expr.func.object.kind === "identifier" &&
+                    this.imports.get(expr.func.object.name) === "std::text" &&
+                    C_TEXT_FUNCTIONS[expr.func.property]
+                ) {
+                    if (C_TEXT_FUNCTIONS[expr.func.property].startsWith("strata_string_")) this.usesStrings = true;
+                    else this.usesText = true;
+                    return `${C_TEXT_FUNCTIONS[expr.func.property]}(${args.join(", ")})`;
+                }
+                if (
+                    expr.func.kind === "member" &&
+                    expr.func.object.kind === "identifier" &&
+                    ["str", "std::io"].includes(this.imports.get(expr.func.object.name) ?? "") &&
+                    C_INPUT_FUNCTIONS[expr.func.property]
+                ) {
+                    this.usesInput = true;
+                    return `${C_INPUT_FUNCTIONS[expr.func.property]}()`;
+                }
+                if (
+                    expr.func.kind === "member" &&
+                    expr.func.object.kind === "identifier" &&
+                    this.imports.get(expr.func.object.name) === "std::env" &&
+                    ["args", "get"].includes(expr.func.property)
+                ) {
+                    this.usesEnv = true;
+                    return expr.func.property === "args" ? "strata_env_args()" : `strata_env_get(${args[0]})`;
+                }
+                if (
+                    func === "format" &&
+                    !this.varTypes.has("format") &&
+                    expr.args[0]?.kind === "literal" &&
+                    typeof expr.args[0].value === "string"
+                ) {
+                    return this.generateFormat(expr.args[0].value, expr.args.slice(1));
+                }
+                if (
+                    expr.func.kind === "member" &&
+                    expr.func.object.kind === "identifier" &&
+                    expr.func.object.name === "char" &&
+                    !this.varTypes.has("char")
+                ) {
+                    const property = expr.func.property;
+                    if (property === "code" || property === "fromCode") {
+                        // A C char is one byte, so only ASCII round-trips; literals are
+                        // rejected here and anything else when the program runs
+                        const arg = expr.args[0];
+                        const code = arg?.kind === "literal" ? (typeof arg.value === "string" ? arg.value.codePointAt(0) : arg.value) : undefined;
+                        if (typeof code === "number" && (code < 0 || code > 127)) {
+                            throw this.unsupported(`char.${property} on code point ${code}, outside ASCII`);
+                        }
+                        this.usesCharCodes = true;
+                        return property === "code" ? `strata_char_code(${args[0]})` : `strata_char_from_code(${args[0]})`;
+                    }
+                    const ctype = C_CHAR_FUNCTIONS[property];
+                    if (ctype) {
+                        this.includes.add("ctype.h");
+                        const call = `${ctype}((unsigned char)${args[0]})`;
+                        return ctype.startsWith("to") ? `((char)${call})` : `(${call} != 0)`;
+                    }
+                }
+                // "abc".length()
+                if (expr.func.kind === "member" && expr.func.property === "length" && expr.args.length === 0 && this.exprType(expr.func.object)?.primitive === "string") {
+                    this.usesStrings = true;
+                    return `strata_string_length(${this.generateExpression(expr.func.object)})`;
+                }
+                const array = expr.args.length > 0 ? this.arrayTypeOf(expr.args[0]) : undefined;
+                if (array && func === "strlen") {
+                    return array.size !== undefined ? String(array.size) : `${args[0]}.len`;
+                }
+                if (array && array.size === undefined && func === "push") {
+                    return `${this.typeToCString(array)}_push(&${args[0]}, ${args[1]})`;
+                }
+                return `${func}(${args.join(", ")})`;
+            case "member":
+                const obj = this.generateExpression(expr.object);
+                return `${obj}.${expr.property}`;
+            case "field":
+                // Module constants are inlined: math.PI, int.MAX
+                if (expr.object.kind === "identifier" && !this.varTypes.has(expr.object.name)) {
+                    const module = this.imports.get(expr.object.name) ?? expr.object.name;
+                    const constant = STDLIB_CONSTANTS[module]?.[expr.name];
+                    if (constant?.c) {
+                        if (constant.c.includes("INT_")) this.includes.add("limits.h");
+                        if (constant.c.includes("DBL_")) this.includes.add("float.h");
+                        return constant.c;
+                    }
+                    if (constant) return String(constant.value);
+                }
+                const fieldEnum = this.enumOf(expr.object);
+                if (fieldEnum) return this.generateVariant(fieldEnum, expr.name, []);
+                return `${this.generateExpression(expr.object)}.${expr.name}`;
+            case "match": {
+                // A conditional chain tests the subject once per arm, so it is
+                // only used for a plain name or literal and arms that are a
+                // single expression binding nothing; otherwise the match sets
+                // a temporary
+                const simple = (expr.subject.kind === "identifier" || expr.subject.kind === "literal") &&
+                    expr.arms.every((arm) => arm.body.length === 1 && arm.body[0].kind === "expression" && patternNames(arm.pattern).length === 0);
+                if (!simple) {
+                    const result = this.valueTemporary(expr.type);
+                    this.generateMatch(expr, result);
+                    return result ?? "0";
+                }
+                const subject = this.generateExpression(expr.subject);
+                let chain = "0";
+                for (const arm of [...expr.arms].reverse()) {
+                    const armValue = this.generateExpression((arm.body[0] as Extract<Stmt, { kind: "expression" }>).expr);
+                    chain = arm.pattern.kind === "literal" || arm.pattern.kind === "variant"
+                        ? `${this.generatePatternTest(subject, arm.pattern)} ? ${armValue} : ${chain}`
+                        : armValue;
+                }
+                return `(${chain})`;
+            }
+            case "if": {
+                const condition = this.generateExpression(expr.condition);
+                const then = blockValue(expr.then);
+                const otherwise = blockValue(expr.else);
+                if (then && otherwise && expr.then.length === 1 && expr.else.length === 1) {
+                    return `(${condition} ? ${this.generateExpression(then)} : ${this.generateExpression(otherwise)})`;
+                }
+                // Branches with statements set a temporary declared ahead of the current statement
+                const value = then ?? otherwise;
+                const result = this.valueTemporary(expr.type ?? (value && this.exprType(value)));
+                this.code.push(`if (${condition}) {`);
+                this.generateValueBlock(expr.then, result);
+                this.code.push("} else {");
+                this.generateValueBlock(expr.else, result);
+                this.code.push("}");
+                return result ?? "0";
+            }
+            case "loop": {
+                // while (1) ahead of the current statement; breaks set the temporary
+                const result = this.valueTemporary(expr.type);
+                this.code.push("while (1) {");
+                this.generateLoopBody(expr.body, result);
+                return result ?? "0";
+            }
+            case "block": {
+                const value = blockValue(expr.body);
+                if (value && expr.body.length === 1) return `(${this.generateExpression(value)})`;
+                const result = this.valueTemporary(expr.type ?? (value && this.exprType(value)));
+                this.code.push("{");
+                this.generateValueBlock(expr.body, result);
+                this.code.push("}");
+                return result ?? "0";
+            }
+            case "structLiteral":
+                const inits = expr.fields.map((f) => `.${f.name} = ${this.generateExpression(f.value)}`);
+                return `((${expr.name}){${inits.join(", ")}})`;
+            case "index":
+                const target = this.generateExpression(expr.object);
+                if (expr.index.kind === "range") {
+                    if (expr.index.step) throw this.unsupported("slices with a step");
+                    // String slice: copy of [start, end)
+                    this.usesStrings = true;
+                    const start = this.generateExpression(expr.index.start);
+                    const end = this.generateExpression(expr.index.end) + (expr.index.inclusive ? " + 1" : "");
+                    return `strata_string_slice(${target}, ${start}, ${end})`;
+                }
+                const indexed = this.arrayTypeOf(expr.object);
+                const items = indexed && indexed.size === undefined ? `${target}.data` : target;
+                return `${items}[${this.generateExpression(expr.index)}]`;
+            case "range":
+                this.usesRanges = true;
+                return `((strata_range){${[
+                    this.generateExpression(expr.start),
+                    this.generateExpression(expr.end),
+                    expr.step ? this.generateExpression(expr.step) : "1",
+                    expr.inclusive ? "1" : "0",
+                ].join(", ")}})`;
+            // C has no closures, and no exception to leave a function with
+            case "lambda":
+                throw this.unsupported("lambdas outside map, filter, reduce and forEach");
+            case "propagate":
+                throw this.unsupported("the ? operator");
+            default:
+                return "";
+        }
+    }
+
+    private unsupported(what: string): Error {
+        return new Error(message("c.unsupported", { what }));
+    }
+
+    // map, filter, reduce and forEach with a lambda become a loop ahead of
+    // the current statement, the lambda's parameters its variables, as C
+    // has no function values to pass
+    private generateArrayFunction(expr: Extract<Expr, { kind: "call" }>): string | undefined {
+        const func = expr.func;
+        const name = func.kind === "member" ? func.property : func.kind === "identifier" ? func.name : undefined;
+        if (name === undefined || !["map", "filter", "reduce", "forEach"].includes(name) || this.varTypes.has(name)) return undefined;
+        const args = func.kind === "member" ? [func.object, ...expr.args] : expr.args;
+        const array = this.arrayTypeOf(args[0]);
+        const lambda = args[1];
+        if (!array || lambda?.kind !== "lambda") return undefined;
+        const { params, body, returnType } = lambda.fn;
+        const value = (body[0] as Extract<Stmt, { kind: "return" }>).value!;
+        const elementType = array.innerType!;
+        const items = this.generateExpression(args[0]);
+        const index = `strata_i_${this.valueCount++}`;
+        const element = array.size !== undefined ? `${items}[${index}]` : `${items}.data[${index}]`;
+        const resultType: TypeDef = name === "map"
+            ? { kind: "array", innerType: returnType }
+            : name === "filter" ? { kind: "array", innerType: elementType } : this.exprType(args[2] ?? value) ?? returnType;
+        const initial = name === "reduce" ? this.generateExpression(args[2]) : undefined;
+        const result = name === "forEach" ? undefined : this.valueTemporary(resultType);
+        if (name === "map" || name === "filter") this.code.push(`${result} = ${this.typeToCString(resultType)}_from(NULL, 0);`);
+        if (initial !== undefined) this.code.push(`${result} = ${initial};`);
+        this.code.push(`for (size_t ${index} = 0; ${index} < ${array.size ?? `${items}.len`}; ${index}++) {`);
+        const bind = (param: { name: string }, type: TypeDef, init: string) => {
+            this.varTypes.set(param.name, type);
+            this.code.push(`${this.declareC(type, param.name)} = ${init};`);
+        };
+        if (name === "reduce") {
+            bind(params[0], resultType, result!);
+            bind(params[1], elementType, element);
+        } else {
+            bind(params[0], elementType, element);
+        }
+        if (name === "map") this.code.push(`${this.typeToCString(resultType)}_push(&${result}, ${this.generateExpression(value)});`);
+        if (name === "filter") this.code.push(`if (${this.generateExpression(value)}) ${this.typeToCString(resultType)}_push(&${result}, ${params[0].name});`);
+        if (name === "reduce") this.code.push(`${result} = ${this.generateExpression(value)};`);
+        if (name === "forEach") this.generateStatement({ kind: "expression", expr: value });
+        this.code.push("}");
+        return result ?? "0";
+    }
+
+    // A variable for the value of an if or block expression; none for void
+    private valueTemporary(type: TypeDef | undefined): string | undefined {
+        if (type?.primitive === "void") return undefined;
+        const name = `strata_value_${this.valueCount++}`;
+        this.code.push(`${this.declareC(type ?? { kind: "primitive", primitive: "int" }, name)};`);
+        return name;
+    }
+
+    // Functions main never calls, such as most of the prelude's, are left out
+    private findFileScope(statements: Stmt[]): void {
+        const functions = new Map<string, Extract<Stmt, { kind: "function" }>>();
+        for (const stmt of statements) if (stmt.kind === "function") functions.set(stmt.name, stmt);
+        const shared = new Set<string>();
+        const pending = [...referencedNames(statements.filter((s) => s.kind !== "function"))];
+        while (pending.length > 0) {
+            const fn = functions.get(pending.pop()!);
+            if (!fn || this.fileScope.has(fn)) continue;
+            this.fileScope.add(fn);
+            this.functionTypes.set(fn.name, fn.returnType);
+            for (const name of referencedNames(fn.body)) {
+                shared.add(name);
+                pending.push(name);
+            }
+        }
+        for (const stmt of statements) if (stmt.kind === "let" && shared.has(stmt.name)) this.fileScope.add(stmt);
+    }
+
+    // The body is generated on its own, with the parameters' types in
+    // scope along with the variables main has declared so far
+    private generateFunction(fn: Extract<Stmt, { kind: "function" }>): void {
+        const outer = { code: this.code, varTypes: this.varTypes, loops: this.loops, blocks: this.blocks };
+        this.code = [];
+        this.varTypes = new Map(this.varTypes);
+        this.loops = [];
+        this.blocks = [];
+        for (const p of fn.params) this.varTypes.set(p.name, p.type);
+        const params = fn.params.map((p) => this.declareC(p.type, p.name)).join(", ") || "void";
+        const result = fn.returnType.primitive === "void" ? "void" : this.typeToCString(fn.returnType);
+        const signature = `${result} ${cFunctionName(fn.name)}(${params})`;
+        try {
+            this.generateBlock(fn.body);
+        } finally {
+            this.prototypes.push(`${signature};`);
+            this.functions.push(`${signature} {`, ...this.code, "}");
+            Object.assign(this, outer);
+        }
+    }
+
+    // main assigns a file-scope variable where the program declares it
+    private generateGlobal(stmt: Extract<Stmt, { kind: "let" }>): void {
+        const type = stmt.type;
+        if (!this.globals.has(stmt.name)) this.globals.set(stmt.name, `static ${this.declareC(type, stmt.name)};`);
+        if (type.kind === "array" && stmt.value.kind === "array") {
+            if (type.size !== undefined) {
+                const items = stmt.value.elements.map((e) => this.generateElement(type.innerType!, e));
+                this.code.push(`memcpy(${stmt.name}, (${this.typeToCString(type.innerType!)}[]){${items.join(", ")}}, sizeof ${stmt.name});`);
+                return;
+            }
+            this.code.push(`${stmt.name} = ${this.generateArray(type, stmt.value.elements)};`);
+            return;
+        }
+        this.code.push(`${stmt.name} = ${this.generateExpression(stmt.value)};`);
+    }
+
+    // The body of a loop whose header is already out, then its closing
+    // brace; result is the temporary a break value is assigned to
+    private generateLoopBody(body: Stmt[], result: string | undefined): void {
+        this.loops.push({ result, blocks: this.blocks.length });
+        this.generateBlock(body);
+        this.loops.pop();
+        this.code.push("}");
+    }
+
+    // The statements of a block, then the defers it reached unless it
+    // ends by leaving, which already ran them
+    private generateBlock(body: Stmt[]): void {
+        this.blocks.push([]);
+        for (const s of body) {
+            this.generateStatement(s);
+        }
+        const last = body[body.length - 1]?.kind;
+        if (last !== "return" && last !== "break" && last !== "continue") this.generateDeferred(this.blocks.length - 1);
+        this.blocks.pop();
+    }
+
+    // The defers of the open blocks from depth inwards, innermost first. Each
+    // is generated with only the blocks outside its own open, so it can't
+    // repeat itself
+    private generateDeferred(depth: number): void {
+        const open = this.blocks;
+        for (let i = open.length - 1; i >= depth; i--) {
+            this.blocks = open.slice(0, i);
+            for (let j = open[i].length - 1; j >= 0; j--) this.generateStatement(open[i][j]);
+        }
+        this.blocks = open;
+    }
+
+    // The statements of a block, its last expression assigned to result
+    private generateValueBlock(body: Stmt[], result: string | undefined): void {
+        const value = blockValue(body);
+        this.blocks.push([]);
+        for (const s of value && result ? body.slice(0, -1) : body) {
+            this.generateStatement(s);
+        }
+        if (value && result) this.code.push(`${result} = ${this.generateExpression(value)};`);
+        this.generateDeferred(this.blocks.length - 1);
+        this.blocks.pop();
+    }
+
+    // An enum without payloads is a C enum, Color_Red and so on. One with
+    // payloads is a struct of a Shape_tag and a union of one struct per
+    // variant carrying values, fields _0, _1, ...
+    private generateEnum(stmt: Extract<Stmt, { kind: "enum" }>): void {
+        this.enums.set(stmt.name, stmt.variants);
+        const tags = stmt.variants.map((v) => `${stmt.name}_${v.name}`).join(", ");
+        if (stmt.variants.every((v) => v.params.length === 0)) {
+            this.typedefs.push(`typedef enum { ${tags} } ${stmt.name};`);
+            return;
+        }
+        this.typedefs.push(`typedef enum { ${tags} } ${stmt.name}_tag;`);
+        const members = stmt.variants.filter((v) => v.params.length > 0).map((v) => {
+            const fields = v.params.map((type, i) => `${this.declareC(type, `_${i}`)};`);
+            return `struct { ${fields.join(" ")} } ${v.name};`;
+        });
+        this.typedefs.push(`typedef struct { ${stmt.name}_tag tag; union { ${members.join(" ")} } as; } ${stmt.name};`);
+    }
+
+    // The enum a Color.Red or Shape.Circle refers to, unless Color or Shape is a variable
+    private enumOf(object: Expr): string | undefined {
+        if (object.kind !== "identifier" || this.varTypes.has(object.name)) return undefined;
+        return this.enums.has(object.name) ? object.name : undefined;
+    }
+
+    private hasPayloads(name: string): boolean {
+        return this.enums.get(name)!.some((v) => v.params.length > 0);
+    }
+
+    // Color_Red, or a Shape compound literal with the variant's payload
+    private generateVariant(name: string, variant: string, args: string[]): string {
+        if (!this.hasPayloads(name)) return `${name}_${variant}`;
+        const payload = args.length > 0 ? `, .as.${variant} = {${args.join(", ")}}` : "";
+        return `((${name}){${name}_${variant}${payload}})`;
+    }
+
+    // match (x) { ... } becomes an if / else if chain over a copy of the
+    // subject; as a value, each arm assigns result
+    private generateMatch(match: { subject: Expr; arms: MatchArm[] }, result?: string): void {
+        const firstLiteral = match.arms.find((a) => a.pattern.kind === "literal")?.pattern;
+        const type = this.exprType(match.subject) ??
+            (firstLiteral?.kind === "literal" ? firstLiteral.type : { kind: "primitive", primitive: "int" });
+        const subject = `strata_match_${this.matchCount++}`;
+        this.code.push("{");
+        this.code.push(`${this.declareC(type, subject)} = ${this.generateExpression(match.subject)};`);
+        // Arms after a wildcard or binding can't match (the checker warns
+        // W0001), so the chain ends there
+        const last = match.arms.findIndex((a) => a.pattern.kind !== "literal" && a.pattern.kind !== "variant");
+        const arms = last < 0 ? match.arms : match.arms.slice(0, last + 1);
+        arms.forEach((arm, i) => {
+            const keyword = i === 0 ? "if" : "} else if";
+            const pattern = arm.pattern;
+            if (pattern.kind === "literal" || pattern.kind === "variant") {
+                this.code.push(`${keyword} (${this.generatePatternTest(subject, pattern)}) {`);
+            } else {
+                this.code.push(i === 0 ? "{" : "} else {");
+                if (pattern.kind === "binding") {
+                    this.varTypes.set(pattern.name, type);
+                    this.code.push(`${this.declareC(type, pattern.name)} = ${subject};`);
+                }
+            }
+            if (pattern.kind === "variant") {
+                const params = this.enums.get(pattern.enum)?.find((v) => v.name === pattern.variant)?.params ?? [];
+                pattern.bindings.forEach((name, j) => {
+                    if (name === "_") return;
+                    this.varTypes.set(name, params[j]);
+                    this.code.push(`${this.declareC(params[j], name)} = ${subject}.as.${pattern.variant}._${j};`);
+                });
+            }
+            if (result !== undefined) this.generateValueBlock(arm.body, result);
+            else this.generateBlock(arm.body);
+        });
+        if (arms.length > 0) this.code.push("}");
+        this.code.push("}");
+    }
+
+    private generatePatternTest(subject: string, pattern: Extract<Pattern, { kind: "literal" | "variant" }>): string {
+        if (pattern.kind === "variant") {
+            const tag = `${pattern.enum}_${pattern.variant}`;
+            return this.hasPayloads(pattern.enum) ? `${subject}.tag == ${tag}` : `${subject} == ${tag}`;
+        }
+        const literal = this.generateExpression(pattern);
+        return pattern.type.primitive === "string"
+            ? `strcmp(${subject}, ${literal}) == 0`
+            : `${subject} == ${literal}`;
+    }
+
+    // Membership picks its C form from the container's static type
+    private generateContains(item: Expr, value: string, container: Expr): string {
+        if (container.kind === "range") {
+            return this.generateRangeContains(container, value);
+        }
+        const target = this.generateExpression(container);
+        const array = this.arrayTypeOf(container);
+        if (array) {
+            const struct = this.typeToCString(array);
+            // Fixed arrays are wrapped in a growable-array view for the helper
+            const view = array.size !== undefined ? `((${struct}){${target}, ${array.size}, ${array.size}})` : target;
+            return `${struct}_contains(${view}, ${value})`;
+        }
+        if (this.exprType(container)?.primitive === "string") {
+            return this.exprType(item)?.primitive === "char"
+                ? `(strchr(${target}, ${value}) != NULL)`
+                : `(strstr(${target}, ${value}) != NULL)`;
+        }
+        this.usesRanges = true;
+        return `strata_range_contains(${target}, ${value})`;
+    }
+
+    // A step may be negative, which only strata_range_contains knows at run time
+    private generateRangeContains(range: Extract<Expr, { kind: "range" }>, value: string): string {
+        if (range.step) return `strata_range_contains(${this.generateExpression(range)}, ${value})`;
+        const start = this.generateExpression(range.start);
+        const end = this.generateExpression(range.end);
+        return `((${value}) >= (${start}) && (${value}) ${range.inclusive ? "<=" : "<"} (${end}))`;
+    }
+
+    // strata_format("hi %s, %d", name, n) for "hi ${name}, ${n}"
+    private generateFormat(template: string, values: Expr[]): string {
+        this.usesFormat = true;
+        const args: string[] = [];
+        let next = 0;
+        const fmt = template.replace(/\{\}|\{\{|\}\}|%/g, (m) => {
+            if (m === "%") return "%%";
+            if (m !== "{}") return m[0];
+            const [directive, arg] = this.printfArgument(values[next++]);
+            args.push(arg);
+            return directive;
+        });
+        return `strata_format(${[cString(fmt), ...args].join(", ")})`;
+    }
+
+    // io.print(a, b): one printf, the arguments separated by spaces as the
+    // interpreter prints them. String literals go into the format itself
+    private generatePrint(values: Expr[]): string {
+        const args: string[] = [];
+        const fmt = values.map((value) => {
+            if (value.kind === "literal" && value.type.primitive === "string") return String(value.value).replace(/%/g, "%%");
+            const [directive, arg] = this.printfArgument(value);
+            args.push(arg);
+            return directive;
+        });
+        return `printf(${[cString(`${fmt.join(" ")}\n`), ...args].join(", ")})`;
+    }
+
+    // The conversion for a value by its static type, and the argument it takes;
+    // values of unknown type are taken to be ints
+    private printfArgument(value: Expr): [string, string] {
+        const code = this.generateExpression(value);
+        const type = this.exprType(value);
+        switch (type?.kind === "primitive" ? type.primitive : undefined) {
+            case "float":
+                this.usesFloatText = true;
+                return ["%s", `strata_float_text((char[32]){0}, ${code})`];
+            case "string":
+                return ["%s", code];
+            case "char":
+                return ["%c", code];
+            case "bool":
+                return ["%s", `(${code} ? "true" : "false")`];
+            default:
+                return ["%d", code];
+        }
+    }
+
+    // Static type of literals, known variables and their elements
+    private exprType(expr: Expr): TypeDef | undefined {
+        if (expr.kind === "literal" || expr.kind === "if" || expr.kind === "block" || expr.kind === "match") return expr.type;
+        if (expr.kind === "identifier") return this.varTypes.get(expr.name);
+        if (expr.kind === "unary") return expr.op === "!" ? { kind: "primitive", primitive: "bool" } : this.exprType(expr.operand);
+        if (expr.kind === "binary") {
+            if (["==", "!=", "<", ">", "<=", ">=", "&&", "||", "in", "not in"].includes(expr.op)) {
+                return { kind: "primitive", primitive: "bool" };
+            }
+            const left = this.exprType(expr.left);
+            const right = this.exprType(expr.right);
+            if (expr.op === "??") return right ?? left?.innerType;
+            return right?.primitive === "float" ? right : left;
+        }
+        if (expr.kind === "call" && expr.func.kind === "identifier" && expr.func.name === "format") {
+            return { kind: "primitive", primitive: "string" };
+        }
+        if (expr.kind === "call" && expr.func.kind === "identifier" && !this.varTypes.has(expr.func.name)) {
+            const result = this.functionTypes.get(expr.func.name);
+            if (result) return result;
+        }
+        if (expr.kind === "call" && expr.func.kind === "identifier" && this.varTypes.get(expr.func.name)?.kind === "function") {
+            return this.varTypes.get(expr.func.name)!.returnType;
+        }
+        // math.sqrt(x), text.upper(s)
+        if (expr.kind === "call" && expr.func.kind === "member" && expr.func.object.kind === "identifier") {
+            const module = this.imports.get(expr.func.object.name) ?? expr.func.object.name;
+            const returnType = STDLIB_SIGNATURES[module]?.[expr.func.property]?.returnType;
+            if (returnType) return returnType;
+        }
+        // Color.Red, Shape.Circle(2.0)
+        const namespace = expr.kind === "field" ? expr.object : expr.kind === "call" && expr.func.kind === "member" ? expr.func.object : undefined;
+        const variantEnum = namespace && this.enumOf(namespace);
+        if (variantEnum) return { kind: "enum", name: variantEnum };
+        if (expr.kind === "field" && expr.object.kind === "identifier" && !this.varTypes.has(expr.object.name)) {
+            const module = this.imports.get(expr.object.name) ?? expr.object.name;
+            const constant = STDLIB_CONSTANTS[module]?.[expr.name];
+            if (constant) return constant.type;
+        }
+        if (expr.kind === "field") {
+            const record = this.exprType(expr.object);
+            return this.structs.get(record?.name ?? "")?.find((f) => f.name === expr.name)?.type;
+        }
+        if (expr.kind === "index") {
+            const container = this.exprType(expr.object);
+            if (expr.index.kind === "range") return container?.primitive === "string" ? container : undefined;
+            if (container?.kind === "array") return container.innerType;
+            if (container?.primitive === "string") return { kind: "primitive", primitive: "char" };
+        }
+        return undefined;
+    }
+
+    private arrayTypeOf(expr: Expr): TypeDef | undefined {
+        const type = this.exprType(expr);
+        return type?.kind === "array" ? type : undefined;
+    }
+
+    // int a[3] = {1, 2, 3};  or a growable strata_array_int built from the literal
+    private declareArray(type: TypeDef, name: string, elements: Expr[]): string {
+        if (type.size !== undefined) {
+            const items = elements.map((e) => this.generateElement(type.innerType!, e));
+            return `${this.declareC(type, name)} = {${items.join(", ")}};`;
+        }
+        return `${this.typeToCString(type)} ${name} = ${this.generateArray(type, elements)};`;
+    }
+
+    private generateArray(type: TypeDef, elements: Expr[]): string {
+        const struct = this.typeToCString(type);
+        const items = elements.map((e) => this.generateElement(type.innerType!, e));
+        const source = elements.length > 0
+            ? `(${this.typeToCString(type.innerType!)}[]){${items.join(", ")}}`
+            : "NULL";
+        return `${struct}_from(${source}, ${elements.length})`;
+    }
+
+    // Nested literals in int[][] become growable arrays themselves
+    private generateElement(type: TypeDef, expr: Expr): string {
+        if (type.kind === "array" && type.size === undefined && expr.kind === "array") {
+            return this.generateArray(type, expr.elements);
+        }
+        return this.generateExpression(expr);
+    }
+
+    // C declarator for a named value; function types become function pointers
+    private declareC(type: TypeDef, name: string): string {
+        if (type.kind === "array" && type.size !== undefined) {
+            return `${this.typeToCString(type.innerType!)} ${name}[${type.size}]`;
+        }
+        if (type.kind === "function") {
+            const params = (type.params ?? []).map((p) => this.typeToCString(p));
+            return `${this.typeToCString(type.returnType!)} (*${name})(${params.join(", ") || "void"})`;
+        }
+        return `${this.typeToCString(type)} ${name}`;
+    }
+
+    private typeToCString(type: TypeDef): string {
+        if (type.kind === "primitive") {
+            switch (type.primitive) {
+                case "int":
+                    return "int";
+                case "float":
+                    return "double";
+                case "bool":
+                    return "int";
+                case "char":
+                    return "char";
+                case "string":
+                    return "char*";
+                case "range":
+                    return "strata_range";
+                default:
+                    return "int";
+            }
+        }
+        if (type.kind === "struct" || type.kind === "enum") return type.name!;
+        if (type.kind === "optional" && type.innerType?.primitive === "int") {
+            this.usesChecked = true;
+            return "strata_optional_int";
+        }
+        if (type.kind === "optional" && type.innerType?.primitive === "string") {
+            this.usesOptionalString = true;
+            return "strata_optional_string";
+        }
+        if (type.kind === "array") {
+            const elem = this.typeToCString(type.innerType!);
+            const name = `strata_array_${formatType(type.innerType!).replace(/\[\d*\]/g, "_array")}`;
+            if (!this.arrayTypes.has(name)) this.arrayTypes.set(name, elem);
+            return name;
+        }
+        return "int";
+    }
+}
+
+// ============================================================================
+// WASM CODE GENERATOR
+// ============================================================================
+//
+// Lowers a program to WebAssembly text (out.wat with --target wasm). int is
+// i64, float is f64, bool and char are i32, and a string is an i32 pointer
+// to a length-prefixed UTF-8 constant in linear memory, so string literals
+// can be bound and printed but not built at runtime. Functions become wasm
+// functions and their variables wasm locals; top-level variables are wasm
+// globals so functions can read them, and top-level statements run in the
+// exported "main". As in the interpreter, blocks share their function's
+// variables. io.print calls a host import chosen by the value's type:
+//
+//   env.print_i64(i64)   env.print_f64(f64)   env.print_bool(i32)
+//   env.print_char(i32)  env.print_str(ptr: i32)   [u32 length][bytes] at ptr
+//
+// Constructs outside this subset (structs, arrays, maps, match, most of the
+// stdlib) are an error naming the construct.
+
+type WasmValue = "int" | "float" | "bool" | "char" | "string";
+type WasmType = WasmValue | "void";
+
+const WASM_TYPES: Record<WasmValue, string> = { int: "i64", float: "f64", bool: "i32", char: "i32", string: "i32" };
+
+const WASM_PRINT_IMPORTS: Record<WasmValue, string> = {
+    int: "print_i64",
+    float: "print_f64",
+    bool: "print_bool",
+    char: "print_char",
+    string: "print_str",
+};
+
+// std::math functions with a single wasm instruction
+const WASM_MATH: Record<string, string> = {
+    sqrt: "f64.sqrt",
+    abs: "f64.abs",
+    floor: "f64.floor",
+    ceil: "f64.ceil",
+    trunc: "f64.trunc",
+    min: "f64.min",
+    max: "f64.max",
+};
+
+const WASM_COMPARISONS: Record<string, string> = { "==": "eq", "!=": "ne", "<": "lt", ">": "gt", "<=": "le", ">=": "ge" };
+
+interface WasmVariable {
+    ref: string;
+    type: WasmValue;
+    global: boolean;
+}
+
+export class WasmGenerator {
+    private code: string[] = [];
+    private indent = 0;
+    // The program's globals, then the variables of the function being generated
+    private scopes: Map<string, WasmVariable>[] = [];
+    private globals: string[] = [];
+    private locals: string[] = [];
+    private localNames = new Set<string>();
+    private functions = new Map<string, { params: WasmValue[]; result: WasmType }>();
+    private imports = new Map<string, string>();
+    private prints = new Set<WasmValue>();
+    private data: string[] = [];
+    private dataSize = 0;
+    private strings = new Map<string, number>();
+    private loops: { exit: string; next: string }[] = [];
+    private labelCount = 0;
+    private result: WasmType = "void";
+    // Line of the statement being generated, for internal compiler errors
+    private line?: number;
+
+    generate(statements: Stmt[]): string {
+        this.line = undefined;
+        return runPhase("wasm generator", () => (this.line !== undefined ? { line: this.line } : undefined), () =>
+            this.generateModule(statements)
+        );
+    }
+
+    private generateModule(statements: Stmt[]): string {
+        this.scopes = [new Map()];
+        this.globals = [];
+        this.functions.clear();
+        this.imports.clear();
+        this.prints.clear();
+        this.data = [];
+        this.dataSize = 0;
+        this.strings.clear();
+        this.labelCount = 0;
+
+        // Signatures first, so calls can precede declarations
+        const functions = statements.filter((s): s is Extract<Stmt, { kind: "function" }> => s.kind === "function");
+        for (const fn of functions) {
+            this.functions.set(fn.name, {
+                params: fn.params.map((p) => this.valueType(p.type, `parameter ${p.name} of ${fn.name}`)),
+                result: fn.returnType.primitive === "void" ? "void" : this.valueType(fn.returnType, `the result of ${fn.name}`),
+            });
+        }
+        for (const stmt of statements) {
+            if (stmt.kind === "import") this.imports.set(stmt.name, stmt.module);
+        }
+
+        // main first, so the globals it declares are known to every function
+        const main = this.generateFunction("strata_main", [], "void", statements.filter((s) => s.kind !== "function"));
+        const bodies = functions.map((fn) =>
+            this.generateFunction(
+                fn.name,
+                fn.params.map((p, i) => ({ name: p.name, type: this.functions.get(fn.name)!.params[i] })),
+                this.functions.get(fn.name)!.result,
+                fn.body
+            )
+        );
+
+        const module = ["(module"];
+        for (const type of this.prints) {
+            const name = WASM_PRINT_IMPORTS[type];
+            module.push(`  (import "env" "${name}" (func $${name} (param ${WASM_TYPES[type]})))`);
+        }
+        module.push(`  (memory (export "memory") ${Math.max(1, Math.ceil(this.dataSize / 65536))})`);
+        module.push(...this.data.map((d) => `  ${d}`), ...this.globals.map((g) => `  ${g}`));
+        module.push(...bodies, main, ")");
+        return withArtifactHeader(";;", module.join("\n"));
+    }
+
+    private generateFunction(name: string, params: { name: string; type: WasmValue }[], result: WasmType, body: Stmt[]): string {
+        this.code = [];
+        this.locals = [];
+        this.localNames.clear();
+        this.loops = [];
+        this.result = result;
+        this.indent = 2;
+        const isMain = name === "strata_main";
+        if (!isMain) {
+            const scope = new Map<string, WasmVariable>();
+            for (const p of params) {
+                scope.set(p.name, { ref: `$${p.name}`, type: p.type, global: false });
+                this.localNames.add(`$${p.name}`);
+            }
+            this.scopes.push(scope);
+        }
+        // The top-level statements of main declare globals, so no scope of their own
+        for (const stmt of body) this.statement(stmt);
+        if (!isMain) this.scopes.pop();
+        // Every path returns (the type checker ensures it); wasm validation can't tell
+        if (result !== "void") this.emit("unreachable");
+
+        const signature = [
+            `  (func $${name}`,
+            isMain ? ` (export "main")` : "",
+            ...params.map((p) => ` (param $${p.name} ${WASM_TYPES[p.type]})`),
+            result !== "void" ? ` (result ${WASM_TYPES[result]})` : "",
+        ].join("");
+        return [signature, ...this.locals.map((l) => `    ${l}`), ...this.code, "  )"].join("\n");
+    }
+
+    private emit(instruction: string): void {
+        this.code.push(`${"  ".repeat(this.indent)}${instruction}`);
+    }
+
+    private unsupported(what: string): Error {
+        return new Error(message("wasm.unsupported", { what }));
+    }
+
+    private valueType(type: TypeDef, what: string): WasmValue {
+        const primitive = type.kind === "primitive" ? type.primitive : undefined;
+        if (primitive && primitive in WASM_TYPES) return primitive as WasmValue;
+        throw this.unsupported(`${formatType(type)} values (${what})`);
+    }
+
+    private lookup(name: string): WasmVariable {
+        for (let i = this.scopes.length - 1; i >= 0; i--) {
+            const variable = this.scopes[i].get(name);
+            if (variable) return variable;
+        }
+        throw this.unsupported(`the name ${name}`);
+    }
+
+    // Redeclaring a variable in the same function reuses it, as the
+    // interpreter rebinds the name in the same environment
+    private declare(name: string, type: WasmValue): WasmVariable {
+        const scope = this.scopes[this.scopes.length - 1];
+        const existing = scope.get(name);
+        if (existing) {
+            if (existing.type !== type) throw this.unsupported(`redeclaring ${name} with a different type`);
+            return existing;
+        }
+        const global = this.scopes.length === 1;
+        const ref = `$${name}`;
+        if (global) {
+            this.globals.push(`(global ${ref} (mut ${WASM_TYPES[type]}) (${WASM_TYPES[type]}.const 0))`);
+        } else {
+            this.localNames.add(ref);
+            this.locals.push(`(local ${ref} ${WASM_TYPES[type]})`);
+        }
+        const variable = { ref, type, global };
+        scope.set(name, variable);
+        return variable;
+    }
+
+    private temp(type: WasmValue): WasmVariable {
+        let ref = "$strata_tmp";
+        for (let n = 2; this.localNames.has(ref); n++) ref = `$strata_tmp_${n}`;
+        this.localNames.add(ref);
+        this.locals.push(`(local ${ref} ${WASM_TYPES[type]})`);
+        return { ref, type, global: false };
+    }
+
+    private get(variable: WasmVariable): void {
+        this.emit(`${variable.global ? "global" : "local"}.get ${variable.ref}`);
+    }
+
+    private set(variable: WasmVariable): void {
+        this.emit(`${variable.global ? "global" : "local"}.set ${variable.ref}`);
+    }
+
+    private block(body: Stmt[]): void {
+        this.indent++;
+        for (const stmt of body) this.statement(stmt);
+        this.indent--;
+    }
+
+    private statement(stmt: Stmt): void {
+        if (stmt.line !== undefined) this.line = stmt.line;
+        switch (stmt.kind) {
+            case "let": {
+                const type = this.valueType(stmt.type, `variable ${stmt.name}`);
+                // The initializer still sees an outer variable of the same name
+                this.expression(stmt.value, type);
+                this.set(this.declare(stmt.name, type));
+                return;
+            }
+            case "assignment": {
+                const variable = this.lookup(stmt.target);
+                this.expression(stmt.value, variable.type);
+                this.set(variable);
+                return;
+            }
+            case "expression":
+                if (this.expression(stmt.expr) !== "void") this.emit("drop");
+                return;
+            case "if":
+                this.expression(stmt.condition, "bool");
+                this.emit("if");
+                this.block(stmt.then);
+                if (stmt.else) {
+                    this.emit("else");
+                    this.block(stmt.else);
+                }
+                this.emit("end");
+                return;
+            case "while":
+                if (stmt.binding !== undefined) throw this.unsupported("while (let ...) loops");
+                this.loop(() => this.expression(stmt.condition, "bool"), stmt.body, () => {});
+                return;
+            case "for":
+                this.statement(stmt.init);
+                this.loop(() => this.expression(stmt.condition, "bool"), stmt.body, () => this.statement(stmt.update));
+                return;
+            case "forIn":
+                this.forRange(stmt);
+                return;
+            case "break":
+            case "continue": {
+                const loop = this.loops[this.loops.length - 1];
+                if (!loop) throw this.unsupported(`${stmt.kind} outside a loop`);
+                this.emit(`br ${stmt.kind === "break" ? loop.exit : loop.next}`);
+                return;
+            }
+            case "return":
+                if (stmt.value) this.expression(stmt.value, this.result === "void" ? undefined : this.result);
+                this.emit("return");
+                return;
+            case "import":
+            case "test":
+                return;
+            case "function":
+                throw this.unsupported(`nested function ${stmt.name}`);
+            default:
+                throw this.unsupported(`${stmt.kind} statements`);
+        }
+    }
+
+    // block $exit (loop $loop (br_if $exit !condition) (block $continue body) update (br $loop))
+    private loop(condition: () => void, body: Stmt[], update: () => void, enter?: () => void): void {
+        const id = ++this.labelCount;
+        const loop = { exit: `$exit_${id}`, next: `$continue_${id}` };
+        this.emit(`block ${loop.exit}`);
+        this.indent++;
+        this.emit(`loop $loop_${id}`);
+        this.indent++;
+        condition();
+        this.emit("i32.eqz");
+        this.emit(`br_if ${loop.exit}`);
+        enter?.();
+        this.emit(`block ${loop.next}`);
+        this.loops.push(loop);
+        this.block(body);
+        this.loops.pop();
+        this.emit("end");
+        update();
+        this.emit(`br $loop_${id}`);
+        this.indent--;
+        this.emit("end");
+        this.indent--;
+        this.emit("end");
+    }
+
+    private forRange(stmt: Extract<Stmt, { kind: "forIn" }>): void {
+        const range = stmt.iterable;
+        if (range.kind !== "range") throw this.unsupported("for-in over anything but a range literal");
+        // As in the C backend, a literal negative step counts down
+        const step = range.step;
+        const descending = step !== undefined &&
+            ((step.kind === "unary" && step.op === "-") || (step.kind === "literal" && step.value < 0));
+        // A hidden counter walks the range, so assigning to the loop
+        // variable in the body doesn't change the iteration
+        const counter = this.temp("int");
+        this.expression(range.start, "int");
+        this.set(counter);
+        const end = this.temp("int");
+        this.expression(range.end, "int");
+        this.set(end);
+        const variable = this.declare(stmt.variable, "int");
+        const compare = `i64.${descending ? "g" : "l"}${range.inclusive ? "e" : "t"}_s`;
+        this.loop(
+            () => {
+                this.get(counter);
+                this.get(end);
+                this.emit(compare);
+            },
+            stmt.body,
+            () => {
+                this.get(counter);
+                if (step) this.expression(step, "int");
+                else this.emit("i64.const 1");
+                this.emit("i64.add");
+                this.set(counter);
+            },
+            () => {
+                this.get(counter);
+                this.set(variable);
+            }
+        );
+    }
+
+    // Emits expr, converting an int to float where a float is wanted
+    private expression(expr: Expr, want?: WasmValue): WasmType {
+        const type = this.emitExpression(expr);
+        if (want === "float" && type === "int") {
+            this.emit("f64.convert_i64_s");
+            return want;
+        }
+        if (want !== undefined && type !== want) throw this.unsupported(`using ${type} as ${want}`);
+        return type;
+    }
+
+    private emitExpression(expr: Expr): WasmType {
+        switch (expr.kind) {
+            case "literal": {
+                const type = this.valueType(expr.type, `literal ${formatNested(expr.value)}`);
+                if (type === "string") this.emit(`i32.const ${this.stringData(expr.value)}`);
+                else if (type === "char") this.emit(`i32.const ${expr.value.codePointAt(0)}`);
+                else if (type === "bool") this.emit(`i32.const ${expr.value ? 1 : 0}`);
+                else if (type === "float") this.emit(`f64.const ${Number.isInteger(expr.value) ? `${expr.value}.0` : expr.value}`);
+                else this.emit(`i64.const ${expr.value}`);
+                return type;
+            }
+            case "identifier": {
+                const variable = this.lookup(expr.name);
+                this.get(variable);
+                return variable.type;
+            }
+            case "binary":
+                return this.binary(expr);
+            case "unary": {
+                const type = this.typeOf(expr.operand);
+                if (expr.op === "!") {
+                    this.expression(expr.operand, "bool");
+                    this.emit("i32.eqz");
+                    return "bool";
+                }
+                if (expr.op === "-" && type === "int") {
+                    this.emit("i64.const 0");
+                    this.expression(expr.operand, "int");
+                    this.emit("i64.sub");
+                    return "int";
+                }
+                if (expr.op === "-" && type === "float") {
+                    this.expression(expr.operand, "float");
+                    this.emit("f64.neg");
+                    return "float";
+                }
+                if (expr.op === "+" && (type === "int" || type === "float")) return this.expression(expr.operand);
+                throw this.unsupported(`unary ${expr.op} on ${type}`);
+            }
+            case "call":
+                return this.call(expr);
+            case "field": {
+                const constant = this.mathConstant(expr);
+                if (constant === undefined) throw this.unsupported(`field access .${expr.name}`);
+                this.emit(`f64.const ${constant}`);
+                return "float";
+            }
+            default:
+                throw this.unsupported(`${expr.kind} expressions`);
+        }
+    }
+
+    private binary(expr: Extract<Expr, { kind: "binary" }>): WasmType {
+        if (expr.op === "&&" || expr.op === "||") {
+            this.expression(expr.left, "bool");
+            this.emit("if (result i32)");
+            this.indent++;
+            if (expr.op === "&&") this.expression(expr.right, "bool");
+            else this.emit("i32.const 1");
+            this.indent--;
+            this.emit("else");
+            this.indent++;
+            if (expr.op === "&&") this.emit("i32.const 0");
+            else this.expression(expr.right, "bool");
+            this.indent--;
+            this.emit("end");
+            return "bool";
+        }
+        const left = this.typeOf(expr.left);
+        const right = this.typeOf(expr.right);
+        const numeric = (t: WasmType) => t === "int" || t === "float";
+        const operands = numeric(left) && numeric(right) ? (left === "float" || right === "float" ? "float" : "int") : left;
+        if (operands !== right && !(numeric(left) && numeric(right))) {
+            throw this.unsupported(`${expr.op} between ${left} and ${right}`);
+        }
+        const comparison = WASM_COMPARISONS[expr.op];
+        if (comparison) {
+            if (operands === "string" || operands === "void") throw this.unsupported(`${expr.op} on ${operands}`);
+            this.expression(expr.left, operands);
+            this.expression(expr.right, operands);
+            const wasm = WASM_TYPES[operands];
+            const signed = operands === "int" ? "_s" : operands === "float" ? "" : "_u";
+            this.emit(`${wasm}.${comparison}${comparison === "eq" || comparison === "ne" ? "" : signed}`);
+            return "bool";
+        }
+        const arithmetic: Record<string, string> = { "+": "add", "-": "sub", "*": "mul", "/": operands === "int" ? "div_s" : "div", "%": "rem_s" };
+        if (!arithmetic[expr.op] || !numeric(operands) || (expr.op === "%" && operands === "float")) {
+            throw this.unsupported(`${expr.op} on ${operands}`);
+        }
+        this.expression(expr.left, operands);
+        this.expression(expr.right, operands);
+        this.emit(`${WASM_TYPES[operands]}.${arithmetic[expr.op]}`);
+        return operands;
+    }
+
+    private call(expr: Extract<Expr, { kind: "call" }>): WasmType {
+        const callee = expr.func;
+        if (callee.kind === "identifier" && this.functions.has(callee.name)) {
+            const fn = this.functions.get(callee.name)!;
+            expr.args.forEach((arg, i) => this.expression(arg, fn.params[i]));
+            this.emit(`call $${callee.name}`);
+            return fn.result;
+        }
+        if (callee.kind === "member" && callee.object.kind === "identifier") {
+            const module = this.imports.get(callee.object.name);
+            if ((module === "str" || module === "std::io") && ["print", "println"].includes(callee.property)) {
+                if (expr.args.length !== 1) throw this.unsupported("printing more or less than one value");
+                const type = this.typeOf(expr.args[0]);
+                if (type === "void") throw this.unsupported("printing a void value");
+                this.expression(expr.args[0], type);
+                this.prints.add(type);
+                this.emit(`call $${WASM_PRINT_IMPORTS[type]}`);
+                return "void";
+            }
+            if (module === "std::math" && callee.property in WASM_MATH) {
+                for (const arg of expr.args) this.expression(arg, "float");
+                this.emit(WASM_MATH[callee.property]);
+                return "float";
+            }
+        }
+        throw this.unsupported(`calls to ${renderExpr(callee, new Map())}`);
+    }
+
+    // math.PI and math.E
+    private mathConstant(expr: Extract<Expr, { kind: "field" }>): number | undefined {
+        if (expr.object.kind !== "identifier" || this.imports.get(expr.object.name) !== "std::math") return undefined;
+        return expr.name === "PI" ? Math.PI : expr.name === "E" ? Math.E : undefined;
+    }
+
+    // The type emitExpression will produce, without emitting anything
+    private typeOf(expr: Expr): WasmType {
+        switch (expr.kind) {
+            case "literal":
+                return this.valueType(expr.type, `literal ${formatNested(expr.value)}`);
+            case "identifier":
+                return this.lookup(expr.name).type;
+            case "binary": {
+                if (expr.op in WASM_COMPARISONS || expr.op === "&&" || expr.op === "||") return "bool";
+                const left = this.typeOf(expr.left);
+                return left === "float" || this.typeOf(expr.right) === "float" ? "float" : left;
+            }
+            case "unary":
+                return expr.op === "!" ? "bool" : this.typeOf(expr.operand);
+            case "call": {
+                const callee = expr.func;
+                if (callee.kind === "identifier" && this.functions.has(callee.name)) return this.functions.get(callee.name)!.result;
+                if (callee.kind === "member" && callee.object.kind === "identifier") {
+                    const module = this.imports.get(callee.object.name);
+                    if (module === "str" || module === "std::io") return "void";
+                    if (module === "std::math") return "float";
+                }
+                throw this.unsupported(`calls to ${renderExpr(callee, new Map())}`);
+            }
+            case "field":
+                if (this.mathConstant(expr) !== undefined) return "float";
+                throw this.unsupported(`field access .${expr.name}`);
+            default:
+                throw this.unsupported(`${expr.kind} expressions`);
+        }
+    }
+
+    // Constants live in memory as a u32 length and the UTF-8 bytes
+    private stringData(text: string): number {
+        const known = this.strings.get(text);
+        if (known !== undefined) return known;
+        const bytes = utf8Encoder.encode(text);
+        const n = bytes.length;
+        const encoded = [n & 255, (n >> 8) & 255, (n >> 16) & 255, (n >>> 24) & 255, ...bytes]
+            .map((b) => (b >= 0x20 && b < 0x7f && b !== 0x22 && b !== 0x5c ? String.fromCharCode(b) : `\\${b.toString(16).padStart(2, "0")}`))
+            .join("");
+        const offset = this.dataSize;
+        this.data.push(`(data (i32.const ${offset}) "${encoded}")`);
+        this.strings.set(text, offset);
+        this.dataSize += 4 + n;
+        return offset;
+    }
+}
+
+// ============================================================================
+// JAVASCRIPT CODE GENERATOR
+// ============================================================================
+//
+// Transpiles a program to readable JavaScript (out.js with --target js) that
+// runs in a browser or under node. Functions stay functions, var becomes
+// let and let/const become const, ranges in for-in become counting loops,
+// structs become classes, and io.print and std::math map onto console.log
+// and Math. Scripts rebind a name with another var in a block, which the
+// interpreter treats as one variable per function; such names are declared
+// once with let at the top of the function. Values that print differently in
+// JavaScript (arrays, maps, structs) go through a small strataFormat helper
+// emitted only when needed. Other stdlib modules are an error naming the
+// construct.
+
+const JS_FORMAT_SUPPORT = [
+    "function strataFormat(value) {",
+    '    if (typeof value === "string") return value;',
+    '    if (typeof value === "function") return "<function>";',
+    '    if (Array.isArray(value)) return `[${value.map(strataNested).join(", ")}]`;',
+    '    if (value instanceof Map) return `{${[...value].map(([k, v]) => `${strataNested(k)}: ${strataNested(v)}`).join(", ")}}`;',
+    '    if (value !== null && typeof value === "object") {',
+    '        return `${value.constructor.name} { ${Object.entries(value).map(([k, v]) => `${k}: ${strataNested(v)}`).join(", ")} }`;',
+    "    }",
+    "    return String(value);",
+    "}",
+    "function strataNested(value) {",
+    '    return typeof value === "string" ? JSON.stringify(value) : strataFormat(value);',
+    "}",
+];
+
+// Text compares by code point, as in the interpreter
+const JS_COMPARE_SUPPORT = [
+    "function strataCompareText(a, b) {",
+    "    const x = [...a];",
+    "    const y = [...b];",
+    "    for (let i = 0; i < Math.min(x.length, y.length); i++) {",
+    "        const diff = x[i].codePointAt(0) - y[i].codePointAt(0);",
+    "        if (diff !== 0) return Math.sign(diff);",
+    "    }",
+    "    return Math.sign(x.length - y.length);",
+    "}",
+];
+
+// Int arithmetic, checked as integerOp checks it
+function jsIntegerSupport(): string[] {
+    const overflow = JSON.stringify(message("runtime.integer-overflow", { left: "{left}", op: "{op}", right: "{right}" }));
+    return [
+        "function strataInt(op, left, right) {",
+        `    if (right === 0 && op === "/") throw new Error(${JSON.stringify(message("runtime.division-by-zero"))});`,
+        `    if (right === 0 && op === "%") throw new Error(${JSON.stringify(message("runtime.remainder-by-zero"))});`,
+        '    const result = op === "+" ? left + right : op === "-" ? left - right : op === "*" ? left * right : op === "/" ? Math.trunc(left / right) : left % right;',
+        `    if (result > ${INT_MAX} || result < ${INT_MIN}) {`,
+        `        throw new Error(${overflow}.replace("{left}", left).replace("{op}", op).replace("{right}", right));`,
+        "    }",
+        "    return result;",
+        "}",
+    ];
+}
+
+const JS_MATH = ["sqrt", "sin", "cos", "tan", "asin", "acos", "atan", "exp", "log", "log10", "log2", "floor", "ceil", "round", "abs", "pow", "max", "min"];
+
+// Builtins called as f(x, ...) or x.f(...), given the generated arguments
+const JS_BUILTINS: Record<string, (args: string[]) => string> = {
+    strlen: ([s]) => `${s}.length`,
+    length: ([s]) => `${s}.length`,
+    toUpperCase: ([s]) => `${s}.toUpperCase()`,
+    toLowerCase: ([s]) => `${s}.toLowerCase()`,
+    trim: ([s]) => `${s}.trim()`,
+    startsWith: ([s, prefix]) => `${s}.startsWith(${prefix})`,
+    endsWith: ([s, suffix]) => `${s}.endsWith(${suffix})`,
+    indexOf: ([s, item]) => `${s}.indexOf(${item})`,
+    split: ([s, separator]) => `${s}.split(${separator})`,
+    join: ([items, separator]) => `${items}.join(${separator})`,
+    repeat: ([s, n]) => `${s}.repeat(${n})`,
+    includes: ([items, item]) => `${items}.includes(${item})`,
+    keys: ([map]) => `[...${map}.keys()]`,
+    values: ([map]) => `[...${map}.values()]`,
+    has: ([map, key]) => `${map}.has(${key})`,
+    push: ([items, item]) => `${items}.push(${item})`,
+    pop: ([items]) => `${items}.pop()`,
+    // Extra index and array arguments are ignored by the functions the callbacks compile to
+    map: ([items, f]) => `${items}.map(${f})`,
+    filter: ([items, f]) => `${items}.filter(${f})`,
+    reduce: ([items, f, initial]) => `${items}.reduce(${f}, ${initial})`,
+    forEach: ([items, f]) => `${items}.forEach(${f})`,
+    toString: ([value]) => `String(${value})`,
+    parseInt: ([s, radix]) => `parseInt(${s}, ${radix ?? "10"})`,
+    parseFloat: ([s]) => `parseFloat(${s})`,
+    ...Object.fromEntries(JS_MATH.map((name) => [name, (args: string[]) => `Math.${name}(${args.join(", ")})`])),
+};
+
+// Strata operators spelled differently in JavaScript
+const JS_OPERATORS: Record<string, string> = { "==": "===", "!=": "!==" };
+
+export class JsGenerator {
+    private code: string[] = [];
+    private indent = 0;
+    private imports = new Map<string, string>();
+    private types = new Map<string, TypeDef>();
+    private functions = new Map<string, TypeDef>();
+    private structs = new Map<string, { name: string; type: TypeDef }[]>();
+    // Per enclosing function or match arm: names declared with let at its top
+    private hoisted: Set<string>[] = [];
+    private usesFormat = false;
+    private usesCompare = false;
+    private usesInteger = false;
+    private matchCount = 0;
+    // Line of the statement being generated, for internal compiler errors
+    private line?: number;
+
+    generate(statements: Stmt[]): string {
+        this.line = undefined;
+        return runPhase("js generator", () => (this.line !== undefined ? { line: this.line } : undefined), () =>
+            this.generateProgram(statements)
+        );
+    }
+
+    private generateProgram(statements: Stmt[]): string {
+        this.code = [];
+        this.indent = 0;
+        this.imports.clear();
+        this.types.clear();
+        this.functions.clear();
+        this.structs.clear();
+        this.hoisted = [];
+        this.usesFormat = false;
+        this.usesCompare = false;
+        this.usesInteger = false;
+        this.matchCount = 0;
+        for (const stmt of statements) {
+            if (stmt.kind === "function") this.functions.set(stmt.name, stmt.returnType);
+            if (stmt.kind === "import") this.imports.set(stmt.name, stmt.module);
+            if (stmt.kind === "struct") this.structs.set(stmt.name, stmt.fields);
+        }
+        this.body(statements, []);
+        const header = [
+            ...(this.usesFormat ? [...JS_FORMAT_SUPPORT, ""] : []),
+            ...(this.usesCompare ? [...JS_COMPARE_SUPPORT, ""] : []),
+            ...(this.usesInteger ? [...jsIntegerSupport(), ""] : []),
+        ];
+        return withArtifactHeader("//", [...header, ...this.code].join("\n") + "\n");
+    }
+
+    private emit(line: string): void {
+        this.code.push(`${"    ".repeat(this.indent)}${line}`);
+    }
+
+    private unsupported(what: string): Error {
+        return new Error(message("js.unsupported", { what }));
+    }
+
+    // Statements of a function, match arm or the program, which share one
+    // set of variables. A name declared more than once, inside a nested
+    // block, or also used as a loop variable is declared up front so every
+    // declaration can assign it
+    private body(statements: Stmt[], params: string[]): void {
+        const declared = new Map<string, number>();
+        const nested = new Set<string>();
+        const loopVariables = new Set<string>();
+        const visit = (stmts: Stmt[], depth: number) => {
+            for (const stmt of stmts) {
+                if (stmt.kind === "let") {
+                    declared.set(stmt.name, (declared.get(stmt.name) ?? 0) + 1);
+                    if (depth > 0) nested.add(stmt.name);
+                } else if (stmt.kind === "forIn") {
+                    loopVariables.add(stmt.variable);
+                    visit(stmt.body, depth + 1);
+                } else if (stmt.kind === "for") {
+                    visit([stmt.init], depth + 1);
+                    visit(stmt.body, depth + 1);
+                } else if (stmt.kind === "if") {
+                    visit(stmt.then, depth + 1);
+                    visit(stmt.else ?? [], depth + 1);
+                } else if (stmt.kind === "while") {
+                    visit(stmt.body, depth + 1);
+                } else if (stmt.kind === "function") {
+                    // Nested functions are closures that can call each other
+                    this.functions.set(stmt.name, stmt.returnType);
+                }
+            }
+        };
+        visit(statements, 0);
+        const hoisted = new Set(params);
+        for (const [name, count] of declared) {
+            if (count > 1 || nested.has(name) || loopVariables.has(name) || params.includes(name)) hoisted.add(name);
+        }
+        const declarations = [...hoisted].filter((name) => !params.includes(name));
+        if (declarations.length > 0) this.emit(`let ${declarations.join(", ")};`);
+        this.hoisted.push(hoisted);
+        for (const stmt of statements) this.statement(stmt);
+        this.hoisted.pop();
+    }
+
+    private isHoisted(name: string): boolean {
+        return this.hoisted[this.hoisted.length - 1].has(name);
+    }
+
+    private block(statements: Stmt[]): void {
+        this.indent++;
+        for (const stmt of statements) this.statement(stmt);
+        this.indent--;
+    }
+
+    private statement(stmt: Stmt): void {
+        if (stmt.line !== undefined) this.line = stmt.line;
+        switch (stmt.kind) {
+            case "let":
+            case "assignment":
+            case "indexAssignment":
+            case "expression":
+                this.emit(`${this.simpleStatement(stmt)};`);
+                return;
+            case "import":
+            case "test":
+                return;
+            case "struct": {
+                const names = stmt.fields.map((f) => f.name);
+                this.emit(`class ${stmt.name} {`);
+                this.emit(`    constructor(${names.join(", ")}) {`);
+                for (const name of names) this.emit(`        this.${name} = ${name};`);
+                this.emit("    }");
+                this.emit("}");
+                return;
+            }
+            case "enum":
+                throw this.unsupported(`enums (${stmt.name})`);
+            case "function": {
+                const params = stmt.params.map((p) => p.name);
+                for (const p of stmt.params) this.types.set(p.name, p.type);
+                this.emit(`function ${stmt.name}(${params.join(", ")}) {`);
+                this.indent++;
+                this.body(stmt.body, params);
+                this.indent--;
+                this.emit("}");
+                return;
+            }
+            case "return":
+                this.emit(stmt.value ? `return ${this.expression(stmt.value)};` : "return;");
+                return;
+            case "break":
+            case "continue":
+                this.emit(`${stmt.kind};`);
+                return;
+            case "if":
+                this.emit(`if (${this.expression(stmt.condition)}) {`);
+                this.block(stmt.then);
+                if (stmt.else) {
+                    this.emit("} else {");
+                    this.block(stmt.else);
+                }
+                this.emit("}");
+                return;
+            case "while":
+                if (stmt.binding !== undefined) throw this.unsupported("while (let ...) loops");
+                this.emit(`while (${this.expression(stmt.condition)}) {`);
+                this.block(stmt.body);
+                this.emit("}");
+                return;
+            case "for":
+                this.emit(`for (${this.simpleStatement(stmt.init)}; ${this.expression(stmt.condition)}; ${this.simpleStatement(stmt.update)}) {`);
+                this.block(stmt.body);
+                this.emit("}");
+                return;
+            case "forIn":
+                this.forIn(stmt);
+                return;
+            case "match":
+                this.match(stmt);
+                return;
+            case "defer":
+                throw this.unsupported("defer statements");
+        }
+    }
+
+    // Statements that also appear in a for (init; condition; update) header
+    private simpleStatement(stmt: Stmt): string {
+        switch (stmt.kind) {
+            case "let": {
+                this.types.set(stmt.name, stmt.type);
+                const value = this.expression(stmt.value);
+                if (this.isHoisted(stmt.name)) return `${stmt.name} = ${value}`;
+                return `${stmt.mutable ? "let" : "const"} ${stmt.name} = ${value}`;
+            }
+            case "assignment":
+                return `${stmt.target} = ${this.expression(stmt.value)}`;
+            case "indexAssignment":
+                if (this.typeOf(stmt.object)?.kind === "map") {
+                    return `${this.expression(stmt.object, 10)}.set(${this.expression(stmt.index)}, ${this.expression(stmt.value)})`;
+                }
+                return `${this.expression(stmt.object, 10)}[${this.expression(stmt.index)}] = ${this.expression(stmt.value)}`;
+            case "expression":
+                return this.expression(stmt.expr);
+            default:
+                throw this.unsupported(`${stmt.kind} statements here`);
+        }
+    }
+
+    private forIn(stmt: Extract<Stmt, { kind: "forIn" }>): void {
+        const variable = stmt.variable;
+        const iterable = stmt.iterable;
+        if (iterable.kind === "range") {
+            this.types.set(variable, TYPE_REGISTRY.int);
+            // A loop variable the body can redeclare is copied from a hidden
+            // counter, so the body can't change the iteration
+            const name = this.isHoisted(variable) ? `strata${variable[0].toUpperCase()}${variable.slice(1)}` : variable;
+            const start = this.expression(iterable.start);
+            const end = this.expression(iterable.end, 6);
+            const step = iterable.step ? this.expression(iterable.step) : "1";
+            const literalStep = !iterable.step || (iterable.step.kind === "literal" || (iterable.step.kind === "unary" && iterable.step.operand.kind === "literal"));
+            const compare = (descending: boolean) => `${name} ${descending ? ">" : "<"}${iterable.inclusive ? "=" : ""} ${end}`;
+            // The direction of a computed step is only known at runtime
+            const condition = literalStep
+                ? compare(step.startsWith("-"))
+                : `(${step} > 0 ? ${compare(false)} : ${compare(true)})`;
+            const update = step === "1" ? `${name}++` : literalStep && step.startsWith("-") ? `${name} -= ${step.slice(1)}` : `${name} += ${step}`;
+            this.emit(`for (let ${name} = ${start}; ${condition}; ${update}) {`);
+            if (name !== variable) this.emit(`    ${variable} = ${name};`);
+        } else {
+            const name = variable;
+            const type = this.typeOf(iterable);
+            if (type?.kind === "array") this.types.set(name, type.innerType!);
+            if (type?.primitive === "string") this.types.set(name, TYPE_REGISTRY.char);
+            const items = type?.kind === "map" ? `${this.expression(iterable, 10)}.keys()` : this.expression(iterable);
+            this.emit(`for (${this.isHoisted(name) ? "" : "const "}${name} of ${items}) {`);
+        }
+        this.block(stmt.body);
+        this.emit("}");
+    }
+
+    // A variable subject is tested directly; anything else is evaluated once
+    private matchSubject(match: { subject: Expr }): string {
+        const type = this.typeOf(match.subject);
+        if (match.subject.kind === "identifier") return match.subject.name;
+        const subject = `strataMatch${this.matchCount++}`;
+        if (type) this.types.set(subject, type);
+        this.emit(`const ${subject} = ${this.expression(match.subject)};`);
+        return subject;
+    }
+
+    // match (x) { ... } becomes an if / else if chain
+    private match(match: Extract<Stmt, { kind: "match" }>): void {
+        const subject = this.matchSubject(match);
+        match.arms.forEach((arm, i) => {
+            const keyword = i === 0 ? "if" : "} else if";
+            if (arm.pattern.kind === "literal") {
+                this.emit(`${keyword} (${subject} === ${this.expression(arm.pattern)}) {`);
+            } else {
+                this.emit(i === 0 ? "{" : "} else {");
+            }
+            this.indent++;
+            this.arm(arm, subject);
+            this.indent--;
+        });
+        if (match.arms.length > 0) this.emit("}");
+    }
+
+    // Each arm has its own variables, as in the interpreter
+    private arm(arm: MatchArm, subject: string): void {
+        if (arm.pattern.kind === "binding") {
+            const type = this.types.get(subject);
+            if (type) this.types.set(arm.pattern.name, type);
+            this.emit(`const ${arm.pattern.name} = ${subject};`);
+        }
+        this.body(arm.body, arm.pattern.kind === "binding" ? [arm.pattern.name] : []);
+    }
+
+    // A match expression is a function called on the spot, so arms can run
+    // statements before their value; the value is the last expression
+    private matchExpression(match: Extract<Expr, { kind: "match" }>, parentPrecedence: number): string {
+        // Arms that are just a value over a variable read better as a conditional chain
+        const catchAll = match.arms.findIndex((a) => a.pattern.kind !== "literal");
+        const simple = match.subject.kind === "identifier" && catchAll >= 0 && match.arms[catchAll].pattern.kind === "wildcard" &&
+            match.arms.slice(0, catchAll + 1).every((a) => a.body.length === 1 && a.body[0].kind === "expression");
+        if (simple) {
+            const value = (arm: MatchArm) => this.expression((arm.body[0] as Extract<Stmt, { kind: "expression" }>).expr, 1);
+            let chain = value(match.arms[catchAll]);
+            for (const arm of match.arms.slice(0, catchAll).reverse()) {
+                chain = `${this.expression(match.subject)} === ${this.expression(arm.pattern as Expr)} ? ${value(arm)} : ${chain}`;
+            }
+            return parentPrecedence > 0 ? `(${chain})` : chain;
+        }
+        const outer = this.code;
+        const indent = this.indent;
+        this.code = [];
+        this.indent = indent + 1;
+        const subject = this.matchSubject(match);
+        // Arms after the first catch-all can't be reached
+        const arms = catchAll >= 0 ? match.arms.slice(0, catchAll + 1) : match.arms;
+        for (const arm of arms) {
+            const last = arm.body[arm.body.length - 1];
+            const body = last?.kind === "expression" ? arm.body.slice(0, -1) : arm.body;
+            const value = last?.kind === "expression" ? last.expr : undefined;
+            const literal = arm.pattern.kind === "literal";
+            if (literal) {
+                this.emit(`if (${subject} === ${this.expression(arm.pattern as Expr)}) {`);
+                this.indent++;
+            }
+            const armBody: Stmt[] = [...body, value ? { kind: "return", value } : { kind: "return", value: { kind: "literal", value: null, type: TYPE_REGISTRY.null } }];
+            this.arm({ pattern: arm.pattern, body: armBody }, subject);
+            if (literal) {
+                this.indent--;
+                this.emit("}");
+            }
+        }
+        if (catchAll < 0) this.emit(`throw new Error(\`no match arm for \${${subject}}\`);`);
+        const lines = this.code;
+        this.code = outer;
+        this.indent = indent;
+        return `(() => {\n${lines.join("\n")}\n${"    ".repeat(indent)}})()`;
+    }
+
+    // Statements run in a function called on the spot, like a match expression
+    private valueFunction(statements: Stmt[]): string {
+        const outer = this.code;
+        const indent = this.indent;
+        this.code = [];
+        this.indent = indent + 1;
+        this.body(statements, []);
+        const lines = this.code;
+        this.code = outer;
+        this.indent = indent;
+        return `(() => {\n${lines.join("\n")}\n${"    ".repeat(indent)}})()`;
+    }
+
+    // A block whose last expression becomes the value it returns
+    private returning(body: Stmt[]): Stmt[] {
+        const value = blockValue(body);
+        const result: Stmt = { kind: "return", value: value ?? { kind: "literal", value: null, type: TYPE_REGISTRY.null } };
+        return [...(value ? body.slice(0, -1) : body), result];
+    }
+
+    // Parenthesized where a Strata operator of lower precedence is nested
+    // in one that binds tighter, as in renderExpr
+    private expression(expr: Expr, parentPrecedence = 0): string {
+        switch (expr.kind) {
+            case "literal":
+                if (expr.type.primitive === "float" && Number.isInteger(expr.value)) return `${expr.value}.0`;
+                return typeof expr.value === "string" ? JSON.stringify(expr.value) : String(expr.value);
+            case "identifier":
+                return expr.name;
+            case "binary":
+                return this.binary(expr, parentPrecedence);
+            case "unary": {
+                if (expr.integer) {
+                    this.usesInteger = true;
+                    return `strataInt("-", 0, ${this.expression(expr.operand)})`;
+                }
+                // - -x must not become the decrement --x
+                const operand = this.expression(expr.operand, 10);
+                return /^[-+]/.test(operand) ? `${expr.op}(${operand})` : `${expr.op}${operand}`;
+            }
+            case "call":
+                return this.call(expr);
+            case "field": {
+                if (expr.object.kind === "identifier" && !this.types.has(expr.object.name)) {
+                    const module = this.imports.get(expr.object.name) ?? expr.object.name;
+                    const constant = STDLIB_CONSTANTS[module]?.[expr.name];
+                    if (constant) return constant.js ?? String(constant.value);
+                }
+                return `${this.expression(expr.object, 10)}.${expr.name}`;
+            }
+            case "index": {
+                const target = this.expression(expr.object, 10);
+                if (expr.index.kind === "range") {
+                    if (expr.index.step) throw this.unsupported("slices with a step");
+                    const end = this.expression(expr.index.end, expr.index.inclusive ? 7 : 0);
+                    return `${target}.slice(${this.expression(expr.index.start)}, ${expr.index.inclusive ? `${end} + 1` : end})`;
+                }
+                if (this.typeOf(expr.object)?.kind === "map") return `${target}.get(${this.expression(expr.index)})`;
+                return `${target}[${this.expression(expr.index)}]`;
+            }
+            case "array":
+                return `[${expr.elements.map((e) => this.expression(e)).join(", ")}]`;
+            case "map": {
+                const entries = expr.entries.map((e) => `[${this.expression(e.key)}, ${this.expression(e.value)}]`);
+                return `new Map([${entries.join(", ")}])`;
+            }
+            case "structLiteral": {
+                // Constructor arguments in declaration order
+                const values = new Map(expr.fields.map((f) => [f.name, this.expression(f.value)]));
+                const order = this.structs.get(expr.name)?.map((f) => f.name) ?? [...values.keys()];
+                return `new ${expr.name}(${order.map((name) => values.get(name) ?? "undefined").join(", ")})`;
+            }
+            case "match":
+                return this.matchExpression(expr, parentPrecedence);
+            case "if": {
+                const then = blockValue(expr.then);
+                const otherwise = blockValue(expr.else);
+                if (then && otherwise && expr.then.length === 1 && expr.else.length === 1) {
+                    const chain = `${this.expression(expr.condition, 1)} ? ${this.expression(then, 1)} : ${this.expression(otherwise, 1)}`;
+                    return parentPrecedence > 0 ? `(${chain})` : chain;
+                }
+                return this.valueFunction([{ kind: "if", condition: expr.condition, then: this.returning(expr.then), else: this.returning(expr.else) }]);
+            }
+            case "block":
+                return this.valueFunction(this.returning(expr.body));
+            case "lambda": {
+                const { params, body } = expr.fn;
+                for (const p of params) this.types.set(p.name, p.type);
+                const value = (body[0] as Extract<Stmt, { kind: "return" }>).value!;
+                const lambda = `(${params.map((p) => p.name).join(", ")}) => ${this.expression(value, 1)}`;
+                return parentPrecedence > 0 ? `(${lambda})` : lambda;
+            }
+            case "loop":
+                throw this.unsupported("loop expressions");
+            case "propagate":
+                throw this.unsupported("the ? operator");
+            default:
+                throw this.unsupported(`${expr.kind} values outside for-in, in and slices`);
+        }
+    }
+
+    private binary(expr: Extract<Expr, { kind: "binary" }>, parentPrecedence: number): string {
+        const precedence = BINARY_PRECEDENCE[expr.op] ?? 0;
+        if (expr.op === "in" || expr.op === "not in") {
+            const test = this.contains(expr.left, expr.right);
+            if (expr.op === "in") return test;
+            return `!${/^[\w.]+\(.*\)$/.test(test) ? test : `(${test})`}`;
+        }
+        if (expr.op === "??") throw this.unsupported("?? on optionals");
+        if (expr.integer) {
+            this.usesInteger = true;
+            return `strataInt("${expr.op}", ${this.expression(expr.left)}, ${this.expression(expr.right)})`;
+        }
+        const left = this.expression(expr.left, precedence);
+        const right = this.expression(expr.right, precedence + 1);
+        let text = `${left} ${JS_OPERATORS[expr.op] ?? expr.op} ${right}`;
+        const textual = (type?: TypeDef) => type?.primitive === "string" || type?.primitive === "char";
+        if (["<", ">", "<=", ">="].includes(expr.op) && (textual(this.typeOf(expr.left)) || textual(this.typeOf(expr.right)))) {
+            this.usesCompare = true;
+            text = `strataCompareText(${this.expression(expr.left)}, ${this.expression(expr.right)}) ${expr.op} 0`;
+        }
+        return precedence < parentPrecedence ? `(${text})` : text;
+    }
+
+    // Membership picks its JavaScript form from the container's static type
+    private contains(item: Expr, container: Expr): string {
+        if (container.kind === "range") {
+            const [below, above] = container.inclusive ? ["<=", ">="] : ["<", ">"];
+            if (!container.step && (item.kind === "identifier" || item.kind === "literal")) {
+                const value = this.expression(item, 5);
+                return `${value} >= ${this.expression(container.start, 5)} && ${value} ${below} ${this.expression(container.end, 5)}`;
+            }
+            // Each operand is evaluated once, as an argument of an arrow
+            // function. The step's sign decides which way the range runs
+            const operands = [item, container.start, container.end, ...(container.step ? [container.step] : [])];
+            const test = container.step
+                ? `(x, start, end, step) => (step > 0 ? x >= start && x ${below} end : x <= start && x ${above} end) && (x - start) % step === 0`
+                : `(x, start, end) => x >= start && x ${below} end`;
+            return `(${test})(${operands.map((e) => this.expression(e)).join(", ")})`;
+        }
+        const type = this.typeOf(container);
+        const target = this.expression(container, 10);
+        if (type?.kind === "map") return `${target}.has(${this.expression(item)})`;
+        if (type?.kind === "array" || type?.primitive === "string") return `${target}.includes(${this.expression(item)})`;
+        throw this.unsupported(`in on ${type ? formatType(type) : renderExpr(container, new Map())}`);
+    }
+
+    private call(expr: Extract<Expr, { kind: "call" }>): string {
+        const callee = expr.func;
+        const args = () => expr.args.map((a) => this.expression(a));
+        if (callee.kind === "identifier") {
+            if (this.functions.has(callee.name) || this.types.has(callee.name)) {
+                return `${callee.name}(${args().join(", ")})`;
+            }
+            if (callee.name === "format" && expr.args[0]?.kind === "literal" && typeof expr.args[0].value === "string") {
+                return this.format(expr.args[0].value, expr.args.slice(1));
+            }
+            if (callee.name in JS_BUILTINS) return JS_BUILTINS[callee.name](args());
+        }
+        if (callee.kind === "member") {
+            const object = callee.object;
+            const module = object.kind === "identifier" && !this.types.has(object.name) ? this.imports.get(object.name) : undefined;
+            if (module === "str" || module === "std::io") {
+                if (callee.property === "print" || callee.property === "println") return `console.log(${expr.args.map((arg) => this.printable(arg)).join(", ")})`;
+            } else if (module === "std::math") {
+                if (JS_MATH.includes(callee.property)) return `Math.${callee.property}(${args().join(", ")})`;
+            } else if (!module) {
+                // A method call passes the receiver first: "abc".length()
+                const receiver = this.expression(object, 10);
+                if (this.functions.has(callee.property)) return `${callee.property}(${[receiver, ...args()].join(", ")})`;
+                if (callee.property in JS_BUILTINS) return JS_BUILTINS[callee.property]([receiver, ...args()]);
+            }
+        }
+        throw this.unsupported(`calls to ${renderExpr(callee, new Map())}`);
+    }
+
+    // Scalars print the same in JavaScript; values print through strataFormat
+    private printable(expr: Expr | undefined): string {
+        if (!expr) return "";
+        const code = this.expression(expr);
+        if (this.isScalar(expr)) return code;
+        this.usesFormat = true;
+        return `strataFormat(${code})`;
+    }
+
+    private isScalar(expr: Expr): boolean {
+        const primitive = this.typeOf(expr)?.primitive;
+        return primitive !== undefined && ["int", "float", "bool", "char", "string"].includes(primitive);
+    }
+
+    // format("{} + {}", a, b) becomes a template literal
+    private format(template: string, values: Expr[]): string {
+        let next = 0;
+        const text = template.replace(/\{\}|\{\{|\}\}|`|\\|\$\{/g, (m) => {
+            if (m === "{{" || m === "}}") return m[0];
+            if (m !== "{}") return `\\${m}`;
+            const value = values[next++];
+            if (!value) throw this.unsupported("format placeholders without an argument");
+            return `\${${this.printable(value)}}`;
+        });
+        return `\`${text}\``;
+    }
+
+    // Static type of literals, known variables and what is built from them
+    private typeOf(expr: Expr): TypeDef | undefined {
+        switch (expr.kind) {
+            case "literal":
+                return expr.type;
+            case "identifier":
+                return this.types.get(expr.name);
+            case "binary": {
+                if (["==", "!=", "<", ">", "<=", ">=", "&&", "||", "in", "not in"].includes(expr.op)) return TYPE_REGISTRY.bool;
+                const left = this.typeOf(expr.left);
+                const right = this.typeOf(expr.right);
+                if (left?.primitive === "string" || right?.primitive === "string") return TYPE_REGISTRY.string;
+                return right?.primitive === "float" ? right : left;
+            }
+            case "unary":
+                return expr.op === "!" ? TYPE_REGISTRY.bool : this.typeOf(expr.operand);
+            case "call": {
+                const callee = expr.func;
+                if (callee.kind === "identifier" && this.functions.has(callee.name)) return this.functions.get(callee.name);
+                if (callee.kind === "identifier" && callee.name === "format") return TYPE_REGISTRY.string;
+                if (callee.kind === "member" && callee.object.kind === "identifier" && this.imports.get(callee.object.name) === "std::math") {
+                    return TYPE_REGISTRY.float;
+                }
+                return undefined;
+            }
+            case "field": {
+                if (expr.object.kind === "identifier" && !this.types.has(expr.object.name)) {
+                    const module = this.imports.get(expr.object.name) ?? expr.object.name;
+                    return STDLIB_CONSTANTS[module]?.[expr.name]?.type;
+                }
+                const object = this.typeOf(expr.object);
+                const struct = object?.kind === "struct" ? this.structs.get(object.name!) : undefined;
+                return struct?.find((f) => f.name === expr.name)?.type;
+            }
+            case "index": {
+                const container = this.typeOf(expr.object);
+                if (expr.index.kind === "range") return container;
+                if (container?.kind === "array") return container.innerType;
+                if (container?.kind === "map") return container.valueType;
+                if (container?.primitive === "string") return TYPE_REGISTRY.char;
+                return undefined;
+            }
+            default:
+                return undefined;
+        }
+    }
+}
+
+// ============================================================================
+// STRATAUIM PACKAGE MANAGER
+// ============================================================================
+
+interface StrataumfileConfig {
+    name: string;
+    version: string;
+    registry?: string;
+    dependencies?: Record<string, string>;
+    prelude?: string; // Strata file parsed ahead of every script, relative to the project root
+    modulePath?: string[]; // Directories searched for imported files, relative to the project root
+    keywords?: Record<string, string>; // Keyword aliases, alias -> keyword
+    edition?: string; // Edition of files without a //! strata pragma
+}
+
+class PackageManager {
+    private projectRoot: string;
+    private strataumfile: StrataumfileConfig;
+    private lockFile: any;
+
+    constructor(projectRoot: string = process.cwd()) {
+        this.projectRoot = projectRoot;
+        this.strataumfile = this.loadStrataumfile();
+        this.lockFile = this.loadLockFile();
+    }
+
+    private loadStrataumfile(): StrataumfileConfig {
+        const path = `${this.projectRoot}/Strataumfile`;
+        try {
+            const content = fs.readFileSync(path, "utf-8");
+            return JSON.parse(content);
+        } catch {
+            return { name: "unknown", version: "0.0.0", dependencies: {} };
+        }
+    }
+
+    private loadLockFile(): any {
+        const path = `${this.projectRoot}/Strataumfile.lock`;
+        try {
+            const content = fs.readFileSync(path, "utf-8");
+            return JSON.parse(content);
+        } catch {
+            return { locked: false, packages: {} };
+        }
+    }
+
+    private saveStrataumfile(): void {
+        const path = `${this.projectRoot}/Strataumfile`;
+        fs.writeFileSync(path, JSON.stringify(this.strataumfile, null, 2));
+        console.log(`✓ Updated ${path}`);
+    }
+
+    private saveLockFile(): void {
+        const path = `${this.projectRoot}/Strataumfile.lock`;
+        this.lockFile.timestamp = new Date().toISOString();
+        fs.writeFileSync(path, JSON.stringify(this.lockFile, null, 2));
+        console.log(`✓ Locked dependencies in ${path}`);
+    }
+
+    install(packageName?: string): void {
+        const packagesDir = `${this.projectRoot}/.strata/packages`;
+        
+        if (!fs.existsSync(packagesDir)) {
+            fs.mkdirSync(packagesDir, { recursive: true });
+        }
+
+        if (packageName) {
+            // Install specific package
+            this.installPackage(packageName, packagesDir);
+        } else {
+            // Install all dependencies from Strataumfile
+            if (!this.strataumfile.dependencies) {
+                console.log("No dependencies to install.");
+                return;
+            }
+            
+            for (const [pkg, version] of Object.entries(this.strataumfile.dependencies)) {
+                this.installPackage(pkg, packagesDir, version as string);
+            }
+        }
+
+        this.saveLockFile();
+        console.log("✓ Installation complete");
+    }
+
+    private installPackage(packageName: string, packagesDir: string, version?: string): void {
+        const pkgDir = `${packagesDir}/${packageName}`;
+        
+        // Create package directory
+        if (!fs.existsSync(pkgDir)) {
+            fs.mkdirSync(pkgDir, { recursive: true });
+        }
+
+        // Create a mock package with module definition
+        const moduleContent = `
+// ${packageName} module (v${version || "1.0.0"})
+export func init() => void {
+    io.print("${packageName} loaded")
+}
+`;
+        fs.writeFileSync(`${pkgDir}/index.str`, moduleContent);
+        
+        // Create package.json in package directory
+        const pkgInfo = {
+            name: packageName,
+            version: version || "1.0.0",
+            main: "index.str",
+        };
+        fs.writeFileSync(`${pkgDir}/package.json`, JSON.stringify(pkgInfo, null, 2));
+
+        // Update lock file
+        if (!this.lockFile.packages) this.lockFile.packages = {};
+        this.lockFile.packages[packageName] = {
+            version: version || "1.0.0",
+            installed: true,
+            timestamp: new Date().toISOString(),
+        };
+
+        console.log(`✓ Installed ${packageName}@${version || "1.0.0"}`);
+    }
+
+    add(packageName: string, version: string = "latest"): void {
+        if (!this.strataumfile.dependencies) {
+            this.strataumfile.dependencies = {};
+        }
+
+        this.strataumfile.dependencies[packageName] = version;
+        this.saveStrataumfile();
+        
+        // Auto-install
+        const packagesDir = `${this.projectRoot}/.strata/packages`;
+        if (!fs.existsSync(packagesDir)) {
+            fs.mkdirSync(packagesDir, { recursive: true });
+        }
+        this.installPackage(packageName, packagesDir, version);
+        this.saveLockFile();
+        
+        console.log(`✓ Added ${packageName}@${version}`);
+    }
+
+    remove(packageName: string): void {
+        if (this.strataumfile.dependencies && packageName in this.strataumfile.dependencies) {
+            delete this.strataumfile.dependencies[packageName];
+            this.saveStrataumfile();
+            
+            // Remove from filesystem
+            const pkgDir = `${this.projectRoot}/.strata/packages/${packageName}`;
+            if (fs.existsSync(pkgDir)) {
+                fs.rmSync(pkgDir, { recursive: true });
+            }
+            
+            // Update lock file
+            if (this.lockFile.packages && packageName in this.lockFile.packages) {
+                delete this.lockFile.packages[packageName];
+                this.saveLockFile();
+            }
+            
+            console.log(`✓ Removed ${packageName}`);
+        } else {
+            console.error(`Package ${packageName} not found in dependencies`);
+            process.exit(1);
+        }
+    }
+
+    list(): void {
+        console.log("\nInstalled Packages:");
+        console.log("==================");
+        
+        if (!this.strataumfile.dependencies || Object.keys(this.strataumfile.dependencies).length === 0) {
+            console.log("No packages installed");
+            return;
+        }
+
+        for (const [pkg, version] of Object.entries(this.strataumfile.dependencies)) {
+            const status = this.lockFile.packages?.[pkg]?.installed ? "✓" : "✗";
+            console.log(`${status} ${pkg}@${version}`);
+        }
+    }
+
+    init(name: string, version: string = "0.0.1"): void {
+        const strataumfile: StrataumfileConfig = {
+            name,
+            version,
+            registry: "https://registry.stratauim.io",
+            edition: LATEST_EDITION,
+            dependencies: {},
+        };
+
+        fs.writeFileSync(
+            `${this.projectRoot}/Strataumfile`,
+            JSON.stringify(strataumfile, null, 2)
+        );
+
+        const lockFile = {
+            locked: true,
+            version: "1.0",
+            timestamp: new Date().toISOString(),
+            packages: {},
+        };
+
+        fs.writeFileSync(
+            `${this.projectRoot}/Strataumfile.lock`,
+            JSON.stringify(lockFile, null, 2)
+        );
+
+        console.log(`✓ Initialized Strata project: ${name}`);
+    }
+
+    preludePath(): string | undefined {
+        const prelude = this.strataumfile.prelude;
+        return prelude ? path.resolve(this.projectRoot, prelude) : undefined;
+    }
+
+    modulePath(): string[] {
+        return (this.strataumfile.modulePath ?? []).map((dir) => path.resolve(this.projectRoot, dir));
+    }
+
+    keywordAliases(): Record<string, string> {
+        return this.strataumfile.keywords ?? {};
+    }
+
+    edition(): string | undefined {
+        return this.strataumfile.edition;
+    }
+
+    info(): void {
+        console.log("\nProject Information:");
+        console.log("====================");
+        console.log(`Name: ${this.strataumfile.name}`);
+        console.log(`Version: ${this.strataumfile.version}`);
+        console.log(`Registry: ${this.strataumfile.registry || "default"}`);
+        console.log(`Dependencies: ${Object.keys(this.strataumfile.dependencies || {}).length}`);
+    }
+}
+
+// ============================================================================
+// PRELUDE
+// ============================================================================
+//
+// Constants and helpers written in Strata and parsed ahead of every program,
+// so scripts can use them without an import. A project can add its own
+// prelude with "prelude" in the Strataumfile or --prelude <file>; it comes
+// after this one. --no-prelude turns both off.
+
+export const PRELUDE_SOURCE = `
+const PI: float = 3.141592653589793
+const E: float = 2.718281828459045
+
+func clamp(value: float, low: float, high: float) => float {
+    if (value < low) {
+        return low
+    }
+    if (value > high) {
+        return high
+    }
+    return value
+}
+
+func sign(value: float) => int {
+    if (value > 0) {
+        return 1
+    }
+    if (value < 0) {
+        return -1
+    }
+    return 0
+}
+
+func isEven(n: int) => bool {
+    return n % 2 == 0
+}
+
+func isOdd(n: int) => bool {
+    return n % 2 != 0
+}
+`;
+
+function declaredName(stmt: Stmt): string | undefined {
+    return stmt.kind === "let" || stmt.kind === "function" || stmt.kind === "struct" || stmt.kind === "enum" ? stmt.name : undefined;
+}
+
+// Prelude statements run first. A top-level declaration in the program
+// replaces the prelude's declaration of the same name.
+export function withPrelude(prelude: Stmt[], program: Stmt[]): Stmt[] {
+    const declared = new Set(program.map(declaredName));
+    return [...prelude.filter((stmt) => !declared.has(declaredName(stmt) ?? "")), ...program];
+}
+
+// ============================================================================
+// MODULES - Link imported .str files into one program
+// ============================================================================
+//
+// import geometry from "./geometry.str" loads the file, checks it on its
+// own and links it into the importing program: every imported file runs
+// once, ahead of the first file that imports it. A module's top-level names
+// are prefixed with its file name (geometry__area), so modules can't
+// collide with each other or with the program, and geometry.area in the
+// importer becomes a reference to geometry__area. Every backend then sees
+// one ordinary program. Functions and let/const bindings are exported; var
+// bindings, structs and enums are not.
+
+export interface ModuleOptions {
+    // Directories searched, in order, for paths that don't start with ./ or ../
+    searchPath?: string[];
+    // Statements each module is checked after, as the program runs after them
+    prelude?: Stmt[];
+    // Check each module on its own, reporting its type errors in its file
+    typecheck?: boolean;
+    // Read by the lexer of every imported file, as for the importing one
+    keywordAliases?: KeywordAliases;
+}
+
+interface LoadedModule {
+    file: string;
+    exports: Map<string, string>; // exported name -> linked name
+    statements: Stmt[]; // renamed, without its imports of files
+    imports: LoadedModule[];
+    free: Map<string, Stmt | Expr>; // names used but not declared, at their first use
+}
+
+// Names that shadow the module's top-level names, and what the rest become
+interface LinkScope {
+    file?: string; // the imported file, recorded on its statements
+    renames: Map<string, string>;
+    aliases: Map<string, LoadedModule>;
+    namespaces?: Map<string, Map<string, string>>; // module blocks' exports
+    local: Set<string>;
+    free?: Map<string, Stmt | Expr>;
+}
+
+// A module geometry { ... } block of a file; its body is among the file's statements
+interface Namespace {
+    name: string;
+    body: Stmt[];
+    span: SourceRange;
+}
+
+// The prefixed names of a module's top-level declarations, and which of
+// them it exports
+function moduleNames(statements: Stmt[], prefix: string): { renames: Map<string, string>; exports: Map<string, string> } {
+    const renames = new Map<string, string>();
+    const exports = new Map<string, string>();
+    for (const stmt of statements) {
+        const declares = stmt.kind === "let" || stmt.kind === "function" || stmt.kind === "struct" || stmt.kind === "enum";
+        if (!declares && !(stmt.kind === "import" && !stmt.path)) continue;
+        renames.set(stmt.name, `${prefix}__${stmt.name}`);
+        if (stmt.kind === "function" || (stmt.kind === "let" && !stmt.mutable)) exports.set(stmt.name, `${prefix}__${stmt.name}`);
+    }
+    return { renames, exports };
+}
+
+// Names a match pattern binds
+function patternNames(pattern: Pattern): string[] {
+    if (pattern.kind === "binding") return [pattern.name];
+    return pattern.kind === "variant" ? pattern.bindings.filter((name) => name !== "_") : [];
+}
+
+// Names bound inside a function body, at any depth outside nested functions
+function localNames(body: Stmt[], names = new Set<string>()): Set<string> {
+    for (const stmt of body) {
+        if (stmt.kind === "let") names.add(stmt.name);
+        if (stmt.kind === "forIn") names.add(stmt.variable);
+        if (stmt.kind === "match") {
+            for (const arm of stmt.arms) patternNames(arm.pattern).forEach((name) => names.add(name));
+        }
+        if (stmt.kind !== "function") localNames(childStatements(stmt), names);
+    }
+    return names;
+}
+
+// Visits every statement and expression in statements, at any depth,
+// nested functions included
+function walkProgram(statements: Stmt[], visitExpr: (expr: Expr) => void, visitStmt: (stmt: Stmt) => void = () => { }): void {
+    const walk = (expr: Expr | undefined): void => {
+        if (!expr) return;
+        visitExpr(expr);
+        switch (expr.kind) {
+            case "call":
+                walk(expr.func);
+                break;
+            case "member":
+                walk(expr.object);
+                return;
+            case "match":
+                for (const arm of expr.arms) walkProgram(arm.body, visitExpr, visitStmt);
+                break;
+            case "if":
+                walkProgram([...expr.then, ...expr.else], visitExpr, visitStmt);
+                break;
+            case "block":
+            case "loop":
+                walkProgram(expr.body, visitExpr, visitStmt);
+                return;
+            case "range":
+                walk(expr.step);
+                break;
+            case "lambda":
+                walkProgram([expr.fn], visitExpr, visitStmt);
+                return;
+        }
+        exprOperands(expr).forEach(walk);
+    };
+    for (const stmt of statements) {
+        visitStmt(stmt);
+        if (stmt.kind === "indexAssignment") walk(stmt.object);
+        statementExprs(stmt).forEach(walk);
+        walkProgram(childStatements(stmt), visitExpr, visitStmt);
+    }
+}
+
+// Names read or assigned anywhere in statements, nested functions included
+function referencedNames(statements: Stmt[], names = new Set<string>()): Set<string> {
+    walkProgram(
+        statements,
+        (expr) => {
+            if (expr.kind === "identifier") names.add(expr.name);
+        },
+        (stmt) => {
+            if (stmt.kind === "assignment") names.add(stmt.target);
+        }
+    );
+    return names;
+}
+
+// Variables of a function body that the functions declared in it use
+function capturedNames(body: Stmt[]): Set<string> {
+    const names = new Set<string>();
+    walkProgram(body, () => { }, (stmt) => {
+        if (stmt.kind === "function") referencedNames(stmt.body, names);
+    });
+    return names;
+}
+
+function nodeSpan(node: { line?: number; span?: SourceRange }): Span | undefined {
+    const range = node.span;
+    if (range) return { line: range.line, column: range.column, length: range.end - range.start };
+    return node.line !== undefined ? { line: node.line } : undefined;
+}
+
+// Diagnostics of an imported file point into it; file is absent for the
+// program being compiled
+function inFile(error: unknown, file?: string): unknown {
+    if (error instanceof StrataError && file !== undefined) {
+        for (const diagnostic of error.diagnostics) diagnostic.file ??= file;
+    }
+    return error;
+}
+
+export class ModuleLoader {
+    private searchPath: string[];
+    private prelude: Stmt[];
+    private typecheck: boolean;
+    private keywordAliases: KeywordAliases;
+    private modules = new Map<string, LoadedModule>(); // by absolute path
+    private prefixes = new Set<string>();
+    private loading: string[] = []; // absolute paths of the import chain, importers first
+    private entry = "";
+
+    constructor(options: ModuleOptions = {}) {
+        this.searchPath = options.searchPath ?? [];
+        this.prelude = options.prelude ?? [];
+        this.typecheck = options.typecheck ?? true;
+        this.keywordAliases = options.keywordAliases ?? NO_KEYWORD_ALIASES;
+    }
+
+    // The program read from file, with the files it imports linked in ahead
+    // of it. Renaming rewrites a copy, so statements can be linked again
+    link(statements: Stmt[], file: string): Stmt[] {
+        this.entry = file;
+        this.loading = [path.resolve(file)];
+        const program = this.linkFile(structuredClone(statements), { renames: new Map(), aliases: new Map(), local: new Set() });
+        const modules = this.ordered(program.imports);
+        // Linked together, a module's free names would find the program's
+        // top-level names, which a module must not see
+        const declared = new Set<string>();
+        for (const stmt of program.statements) {
+            if (stmt.kind === "let" || stmt.kind === "function" || stmt.kind === "import" || stmt.kind === "struct" || stmt.kind === "enum") {
+                declared.add(stmt.name);
+            }
+        }
+        for (const module of modules) {
+            const name = [...module.free.keys()].find((n) => declared.has(n));
+            if (name === undefined) continue;
+            const notes = [message("module.scope-note")];
+            throw inFile(errorAt("E0007", message("module.program-name", { name }), nodeSpan(module.free.get(name)!), notes), module.file);
+        }
+        return [...modules.flatMap((module) => module.statements), ...program.statements];
+    }
+
+    private linkFile(statements: Stmt[], scope: LinkScope): { statements: Stmt[]; imports: LoadedModule[] } {
+        const own: Stmt[] = [];
+        for (const stmt of statements) {
+            if (stmt.kind === "import" && stmt.path) scope.aliases.set(stmt.name, this.load(stmt, scope.file));
+            else own.push(stmt);
+        }
+        this.renameStatements(own, scope);
+        return { statements: own, imports: [...new Set(scope.aliases.values())] };
+    }
+
+    // The modules and everything they import, each once and after the
+    // modules it imports
+    private ordered(modules: LoadedModule[], seen = new Set<LoadedModule>()): LoadedModule[] {
+        return modules.flatMap((module) => {
+            if (seen.has(module)) return [];
+            seen.add(module);
+            return [...this.ordered(module.imports, seen), module];
+        });
+    }
+
+    private load(stmt: Extract<Stmt, { kind: "import" }>, importer?: string): LoadedModule {
+        const candidates = this.candidates(stmt.module, importer ?? this.entry);
+        const file = candidates.find((candidate) => fs.existsSync(candidate) && fs.statSync(candidate).isFile());
+        if (!file) {
+            const notes = candidates.length > 0 ? [message("module.searched", { paths: candidates.join(", ") })] : [message("module.search-path")];
+            throw inFile(errorAt("E0007", message("module.not-found", { module: stmt.module }), nodeSpan(stmt), notes), importer);
+        }
+        const key = path.resolve(file);
+        if (this.loading.includes(key)) {
+            const chain = [...this.loading.slice(this.loading.indexOf(key)), key].map((f) => path.relative(process.cwd(), f));
+            throw inFile(errorAt("E0007", message("module.cycle", { chain: chain.join(" -> ") }), nodeSpan(stmt)), importer);
+        }
+        let module = this.modules.get(key);
+        if (!module) {
+            this.loading.push(key);
+            try {
+                module = this.loadFile(file);
+            } finally {
+                this.loading.pop();
+            }
+            this.modules.set(key, module);
+        }
+        return module;
+    }
+
+    // ./ and ../ paths start at the importing file, absolute paths are taken
+    // as they are and others are looked up in the search path. .str may be
+    // left off
+    private candidates(module: string, importer: string): string[] {
+        const bases = /^\.\.?\//.test(module)
+            ? [path.join(path.dirname(importer), module)]
+            : path.isAbsolute(module)
+              ? [module]
+              : this.searchPath.map((dir) => path.join(dir, module));
+        return bases.flatMap((base) => (base.endsWith(".str") ? [base] : [base, `${base}.str`]));
+    }
+
+    private loadFile(file: string): LoadedModule {
+        let statements: Stmt[];
+        try {
+            // A file's tests run when strata test is given that file, not its importers
+            const parser = new Parser(fs.readFileSync(file, "utf-8"), 1, undefined, undefined, this.keywordAliases);
+            statements = parser.parse().filter((stmt) => stmt.kind !== "test");
+        } catch (error) {
+            throw inFile(error, file);
+        }
+        const { renames, exports } = moduleNames(statements, this.prefix(file));
+        const free = new Map<string, Stmt | Expr>();
+        const linked = this.linkFile(statements, { file, renames, aliases: new Map(), local: new Set(), free });
+        const module: LoadedModule = { file, exports, free, ...linked };
+        // Warnings are left to the check of the whole program, which
+        // reports them in their files too
+        if (this.typecheck) {
+            const statements = this.ordered([module]).flatMap((m) => m.statements);
+            new TypeChecker().check(withPrelude(this.prelude, statements));
+        }
+        return module;
+    }
+
+    // The file name as an identifier, numbered when another file has it
+    private prefix(file: string): string {
+        const base = path.basename(file, ".str").replace(/[^A-Za-z0-9_]/g, "_").replace(/^(?=[0-9])/, "_");
+        let prefix = base;
+        for (let n = 2; this.prefixes.has(prefix); n++) prefix = `${base}${n}`;
+        this.prefixes.add(prefix);
+        return prefix;
+    }
+
+    // A file's module blocks are linked like imported files: their
+    // declarations become geometry__area and geometry.area references to
+    // them plain names. The rest of the file stays visible inside them, and
+    // a block hides a stdlib module of the same name
+    linkNamespaces(statements: Stmt[], namespaces: Namespace[]): void {
+        const inBlocks = new Set(namespaces.flatMap((namespace) => namespace.body));
+        const topLevel = new Set(statements.flatMap((stmt) => (!inBlocks.has(stmt) && "name" in stmt && typeof stmt.name === "string" ? [stmt.name] : [])));
+        const exports = new Map<string, Map<string, string>>();
+        const owners = new Map<Stmt, Map<string, string>>();
+        for (const namespace of namespaces) {
+            if (topLevel.has(namespace.name) || exports.has(namespace.name)) {
+                throw errorAt("E0003", message("module.namespace-conflict", { name: namespace.name }), nodeSpan(namespace));
+            }
+            const names = moduleNames(namespace.body, namespace.name);
+            exports.set(namespace.name, names.exports);
+            for (const stmt of namespace.body) owners.set(stmt, names.renames);
+        }
+        for (const stmt of statements) {
+            this.renameStatement(stmt, { renames: owners.get(stmt) ?? new Map(), aliases: new Map(), namespaces: exports, local: new Set() });
+        }
+    }
+
+    private renameStatements(statements: Stmt[], scope: LinkScope): void {
+        for (const stmt of statements) this.renameStatement(stmt, scope);
+    }
+
+    // A nested block's own declarations shadow the top-level names
+    private renameBlock(body: Stmt[], scope: LinkScope, bound: string[] = [], header: Stmt[] = []): void {
+        const local = new Set([...scope.local, ...bound, ...localNames([...header, ...body])]);
+        this.renameStatements([...header, ...body], { ...scope, local });
+    }
+
+    private renameStatement(stmt: Stmt, scope: LinkScope): void {
+        if (scope.file !== undefined) stmt.file = scope.file;
+        const rename = (name: string) => (scope.local.has(name) ? name : scope.renames.get(name) ?? name);
+        switch (stmt.kind) {
+            case "let":
+                stmt.name = rename(stmt.name);
+                this.renameType(stmt.type, scope);
+                this.renameExpr(stmt.value, scope);
+                break;
+            case "assignment":
+                if (!scope.local.has(stmt.target) && !scope.renames.has(stmt.target) && !scope.free?.has(stmt.target)) {
+                    scope.free?.set(stmt.target, stmt);
+                }
+                stmt.target = rename(stmt.target);
+                this.renameExpr(stmt.value, scope);
+                break;
+            case "indexAssignment":
+                this.renameExpr(stmt.object, scope);
+                this.renameExpr(stmt.index, scope);
+                this.renameExpr(stmt.value, scope);
+                break;
+            case "expression":
+                this.renameExpr(stmt.expr, scope);
+                break;
+            case "if":
+                this.renameExpr(stmt.condition, scope);
+                this.renameBlock(stmt.then, scope);
+                this.renameBlock(stmt.else ?? [], scope);
+                break;
+            case "while":
+                this.renameExpr(stmt.condition, scope);
+                this.renameBlock(stmt.body, scope, stmt.binding !== undefined ? [stmt.binding] : []);
+                break;
+            case "for": {
+                const local = new Set([...scope.local, ...localNames([stmt.init])]);
+                this.renameExpr(stmt.condition, { ...scope, local });
+                this.renameBlock(stmt.body, scope, [], [stmt.init, stmt.update]);
+                break;
+            }
+            case "forIn":
+                this.renameExpr(stmt.iterable, scope);
+                this.renameBlock(stmt.body, scope, [stmt.variable]);
+                break;
+            case "return":
+            case "break":
+                if (stmt.value) this.renameExpr(stmt.value, scope);
+                break;
+            case "defer":
+                this.renameStatement(stmt.body, scope);
+                break;
+            case "function": {
+                stmt.name = rename(stmt.name);
+                for (const param of stmt.params) this.renameType(param.type, scope);
+                this.renameType(stmt.returnType, scope);
+                const local = new Set([...scope.local, ...stmt.params.map((p) => p.name), ...localNames(stmt.body)]);
+                this.renameStatements(stmt.body, { ...scope, local });
+                break;
+            }
+            case "import":
+                stmt.name = rename(stmt.name);
+                break;
+            case "struct":
+                stmt.name = scope.renames.get(stmt.name) ?? stmt.name;
+                for (const field of stmt.fields) this.renameType(field.type, scope);
+                break;
+            case "enum":
+                stmt.name = scope.renames.get(stmt.name) ?? stmt.name;
+                for (const variant of stmt.variants) variant.params.forEach((type) => this.renameType(type, scope));
+                break;
+            case "match":
+                this.renameExpr(stmt.subject, scope);
+                this.renameArms(stmt, scope);
+                break;
+            case "test":
+                this.renameBlock(stmt.body, scope);
+                break;
+        }
+    }
+
+    private renameArms(match: Extract<Stmt | Expr, { kind: "match" }>, scope: LinkScope): void {
+        for (const arm of match.arms) {
+            if (arm.pattern.kind === "variant") arm.pattern.enum = this.renameTypeName(arm.pattern.enum, match, scope);
+            this.renameBlock(arm.body, scope, patternNames(arm.pattern));
+        }
+    }
+
+    // Struct and enum names, which values can't shadow
+    private renameTypeName(name: string, node: Stmt | Expr, scope: LinkScope): string {
+        if (!scope.renames.has(name) && !scope.free?.has(name)) scope.free?.set(name, node);
+        return scope.renames.get(name) ?? name;
+    }
+
+    // Struct and enum types, and array sizes such as int[N], refer to names
+    private renameType(type: TypeDef | undefined, scope: LinkScope): void {
+        if (!type) return;
+        if ((type.kind === "struct" || type.kind === "enum") && type.name) type.name = scope.renames.get(type.name) ?? type.name;
+        if (type.sizeExpr) this.renameExpr(type.sizeExpr, scope);
+        for (const inner of [...(type.types ?? []), ...(type.params ?? []), ...Object.values(type.fields ?? {})]) {
+            this.renameType(inner, scope);
+        }
+        for (const inner of [type.innerType, type.returnType, type.keyType, type.valueType]) this.renameType(inner, scope);
+    }
+
+    private renameExpr(expr: Expr, scope: LinkScope): void {
+        switch (expr.kind) {
+            case "identifier":
+                if (scope.local.has(expr.name)) break;
+                if (!scope.renames.has(expr.name) && !scope.free?.has(expr.name)) scope.free?.set(expr.name, expr);
+                expr.name = scope.renames.get(expr.name) ?? expr.name;
+                break;
+            case "field":
+            case "member": {
+                const object = expr.object;
+                const exports = object.kind === "identifier" && !scope.local.has(object.name)
+                    ? scope.aliases.get(object.name)?.exports ?? scope.namespaces?.get(object.name)
+                    : undefined;
+                if (!exports) {
+                    this.renameExpr(object, scope);
+                    break;
+                }
+                const name = expr.kind === "field" ? expr.name : expr.property;
+                const linked = exports.get(name);
+                if (!linked) {
+                    const notes = [message("module.export-note")];
+                    throw inFile(errorAt("E0007", message("module.no-export", { module: object.name, name }), nodeSpan(expr), notes), scope.file);
+                }
+                // geometry.area becomes a plain reference to geometry__area
+                const node = expr as any;
+                delete node.object;
+                delete node.property;
+                node.kind = "identifier";
+                node.name = linked;
+                break;
+            }
+            case "binary":
+                this.renameExpr(expr.left, scope);
+                this.renameExpr(expr.right, scope);
+                break;
+            case "unary":
+                this.renameExpr(expr.operand, scope);
+                break;
+            case "propagate":
+                this.renameExpr(expr.value, scope);
+                break;
+            case "call":
+                this.renameExpr(expr.func, scope);
+                for (const arg of expr.args) this.renameExpr(arg, scope);
+                break;
+            case "index":
+                this.renameExpr(expr.object, scope);
+                this.renameExpr(expr.index, scope);
+                break;
+            case "array":
+                for (const element of expr.elements) this.renameExpr(element, scope);
+                break;
+            case "map":
+                for (const entry of expr.entries) {
+                    this.renameExpr(entry.key, scope);
+                    this.renameExpr(entry.value, scope);
+                }
+                break;
+            case "structLiteral":
+                expr.name = this.renameTypeName(expr.name, expr, scope);
+                for (const field of expr.fields) this.renameExpr(field.value, scope);
+                break;
+            case "match":
+                this.renameExpr(expr.subject, scope);
+                this.renameArms(expr, scope);
+                break;
+            case "if":
+                this.renameExpr(expr.condition, scope);
+                this.renameBlock(expr.then, scope);
+                this.renameBlock(expr.else, scope);
+                break;
+            case "block":
+            case "loop":
+                this.renameBlock(expr.body, scope);
+                break;
+            case "range":
+                this.renameExpr(expr.start, scope);
+                this.renameExpr(expr.end, scope);
+                if (expr.step) this.renameExpr(expr.step, scope);
+                break;
+            case "lambda":
+                this.renameStatement(expr.fn, scope);
+                break;
+        }
+    }
+}
+
+// ============================================================================
+// FORMATTER
+// ============================================================================
+//
+// strata fmt prints one canonical form: the tokens of each line respaced,
+// runs of stdlib imports sorted, opening braces kept on the line of their
+// if, func or struct and else on the line of the closing brace, then
+// indentation by bracket depth, two spaces per level, with trailing
+// whitespace trimmed and runs of blank lines collapsed. It works on tokens
+// and lines rather than the AST, so comments stay where they were written.
+
+// Brackets opened minus closed on one line, outside strings, char literals
+// and comments. inString carries a string that continues past the line
+function scanBrackets(line: string, state: { inString: boolean }): number {
+    let depth = 0;
+    for (let i = 0; i < line.length; i++) {
+        const c = line[i];
+        if (state.inString) {
+            if (c === "\\") i++;
+            else if (c === '"') state.inString = false;
+        } else if (c === "/" && line[i + 1] === "/") {
+            break;
+        } else if (c === '"') {
+            state.inString = true;
+        } else if (c === "'") {
+            if (line[i + 1] === "\\") i++;
+            i += 2;
+        } else if (c === "{" || c === "(" || c === "[") {
+            depth++;
+        } else if (c === "}" || c === ")" || c === "]") {
+            depth--;
+        }
+    }
+    return depth;
+}
+
+export function formatSource(source: string): string {
+    const lines: string[] = [];
+    const state = { inString: false };
+    let depth = 0;
+    for (const raw of source.split(/\r?\n/)) {
+        // Lines continuing a multi-line string are part of its value
+        if (state.inString) {
+            lines.push(raw);
+            depth += scanBrackets(raw, state);
+            continue;
+        }
+        const line = raw.trimStart();
+        if (line.trimEnd() === "") {
+            if (lines.length > 0 && lines[lines.length - 1] !== "") lines.push("");
+            continue;
+        }
+        const closers = line.match(/^[}\])]*/)![0].length;
+        const indent = "  ".repeat(Math.max(0, depth - closers));
+        depth = Math.max(0, depth + scanBrackets(line, state));
+        lines.push(indent + (state.inString ? line : line.trimEnd()));
+    }
+    while (lines[lines.length - 1] === "") lines.pop();
+    return lines.join("\n") + "\n";
+}
+
+// Operand-like tokens: a following ( or [ is a call or index, and a
+// following - or + is binary
+function endsOperand(token: Token): boolean {
+    if (token.kind === "keyword") return token.token === "true" || token.token === "false" || token.token === "null";
+    return token.kind !== "symbol" || [")", "]", "}", "?"].includes(token.token);
+}
+
+// Whether canonical spacing puts a space between two tokens of a line.
+// generics counts the open map< / dict< brackets and remembers the last >
+// that closed one, which a [ or ? then follows like a type name
+function spaced(prev: Token, next: Token, beforePrev: Token | undefined, generics: { depth: number; closed?: Token }): boolean {
+    const [p, n] = [prev.token, next.token];
+    if (n === "<" && (p === "map" || p === "dict")) {
+        generics.depth++;
+        return false;
+    }
+    if (n === ">" && generics.depth > 0) {
+        generics.depth--;
+        generics.closed = next;
+        return false;
+    }
+    if (p === "<" && (beforePrev?.token === "map" || beforePrev?.token === "dict")) return false;
+    if (prev === generics.closed && (n === "[" || n === "?")) return false;
+    // func(x: int) => int { ... } is a function value; match(text, pattern)
+    // is the regex builtin unless written as the match statement
+    if (n === "(" && p === "func") return false;
+    if (n === "(" && p === "match") return prev.location.end !== next.location.offset;
+    if ([",", ";", ":", ")", "]", ".", "::", "?", "..", "..="].includes(n)) return false;
+    if (["(", "[", ".", "::", "!", "~", "..", "..="].includes(p)) return false;
+    if ((n === "(" || n === "[") && endsOperand(prev)) return false;
+    if ((n === "++" || n === "--") && endsOperand(prev)) return false;
+    if (p === "{") return n !== "}";
+    // A sign where no operand precedes it: -1, f(-x), return -n
+    if ((p === "-" || p === "+" || p === "++" || p === "--") && (!beforePrev || !endsOperand(beforePrev))) return false;
+    return true;
+}
+
+// Respaces the tokens of each line; lines held together by a multi-line
+// string are respaced as one, keeping the string as written
+function canonicalSpacing(source: string): string {
+    const tokens: Token[] = [];
+    const lexer = new Lexer(source);
+    for (let token; (token = lexer.nextToken()); ) tokens.push(token);
+    const lineStarts = [0];
+    for (let i = 0; i < source.length; i++) if (source[i] === "\n") lineStarts.push(i + 1);
+    const lineEnd = (line: number) => (line + 1 < lineStarts.length ? lineStarts[line + 1] - 1 : source.length);
+    // Tokens come in order, so the search resumes where the last one ended
+    let cursor = 0;
+    const lineOf = (offset: number) => {
+        while (cursor > 0 && lineStarts[cursor] > offset) cursor--;
+        while (cursor + 1 < lineStarts.length && lineStarts[cursor + 1] <= offset) cursor++;
+        return cursor;
+    };
+    const out: string[] = [];
+    let t = 0;
+    for (let line = 0; line < lineStarts.length; ) {
+        const group: Token[] = [];
+        let last = line;
+        while (t < tokens.length && lineOf(tokens[t].location.offset) <= last) {
+            last = Math.max(last, lineOf(tokens[t].location.end - 1));
+            group.push(tokens[t++]);
+        }
+        const comment = source.slice(group.length > 0 ? group[group.length - 1].location.end : lineStarts[line], lineEnd(last)).trim();
+        const generics = { depth: 0 };
+        let text = group.length > 0 ? source.slice(lineStarts[line], group[0].location.offset) : "";
+        group.forEach((token, i) => {
+            if (i > 0 && spaced(group[i - 1], token, group[i - 2], generics)) text += " ";
+            text += source.slice(token.location.offset, token.location.end);
+        });
+        if (comment) text += group.length > 0 ? ` ${comment}` : source.slice(lineStarts[line], lineEnd(line)).replace(/\S.*$/, comment);
+        out.push(text);
+        line = last + 1;
+    }
+    return out.join("\n");
+}
+
+// Consecutive stdlib imports in name order. File imports run their module
+// on import, so they keep their place and end a run
+function sortImports(source: string): string {
+    const lines = source.split("\n");
+    const sortable = (line: string) => /^import \w+ from [^"\s]/.test(line);
+    for (let i = 0; i < lines.length; ) {
+        let j = i;
+        while (j < lines.length && sortable(lines[j])) j++;
+        if (j > i) lines.splice(i, j - i, ...lines.slice(i, j).sort());
+        i = Math.max(j, i + 1);
+    }
+    return lines.join("\n");
+}
+
+// Lines that a following lone { belongs to
+const BRACE_HEADER = /^(pub\s+)?(struct|enum|func|if|else|guard|with|for|while|loop|match|test)\b|^} else\b/;
+
+// A lone { joins the if, func or struct line before it, and an else the }
+// line before it, unless a comment ends that line
+function braceStyle(source: string): string {
+    const lines: string[] = [];
+    const state = { inString: false };
+    for (const raw of source.split("\n")) {
+        const continued = state.inString;
+        scanBrackets(raw, state);
+        const line = raw.trim();
+        const previous = lines.length > 0 ? lines[lines.length - 1].trim() : "";
+        const joinable = !continued && !previous.includes("//") && !previous.endsWith("{");
+        if (joinable && line === "{" && BRACE_HEADER.test(previous)) {
+            lines[lines.length - 1] = lines[lines.length - 1].trimEnd() + " {";
+        } else if (joinable && /^else\b/.test(line) && previous === "}") {
+            lines[lines.length - 1] = lines[lines.length - 1].trimEnd() + " " + line;
+        } else {
+            lines.push(raw);
+        }
+    }
+    return lines.join("\n");
+}
+
+export function canonicalSource(source: string): string {
+    return formatSource(braceStyle(sortImports(canonicalSpacing(source))));
+}
+
+// Statement IDs for review tools, stable while a statement keeps its
+// meaning: declarations by name (func greet), others by kind and a hash of
+// the statement without its nested blocks, under their parent's ID
+export function statementMap(source: string): { id: string; line: number; endLine: number }[] {
+    const entries: { id: string; line: number; endLine: number }[] = [];
+    const lineAt = (offset: number) => source.slice(0, offset).split("\n").length;
+    const visit = (statements: Stmt[], parent: string) => {
+        const seen = new Set<string>();
+        for (const stmt of statements) {
+            let key = declarationKey(stmt)?.replace(/^binding /, "let ");
+            if (key === undefined) {
+                const head = JSON.stringify(stmt, (k, value) =>
+                    ["line", "span", "file", "body", "then", "else", "arms"].includes(k) ? undefined : value
+                );
+                key = `${stmt.kind} ${createHash("sha256").update(head).digest("hex").slice(0, 8)}`;
+            }
+            let id = parent + key;
+            for (let n = 2; seen.has(id); n++) id = `${parent}${key}#${n}`;
+            seen.add(id);
+            if (stmt.span) entries.push({ id, line: stmt.span.line, endLine: lineAt(stmt.span.end) });
+            visit(childStatements(stmt), `${id}/`);
+        }
+    };
+    visit(new Parser(source).parse(), "");
+    return entries;
+}
+
+// ============================================================================
+// MINIMIZER - Shrink a failing program to a small reproduction
+// ============================================================================
+//
+// strata minimize crash.str keeps deleting runs of lines, then single
+// statements, then replacing expressions with one of their operands, for
+// as long as the program still fails the same way. Spans make statement
+// and expression edits exact; a program that doesn't parse only loses lines.
+
+// Two failures are the same when their codes and messages match; numbers
+// are left out, since line numbers in messages move as lines go
+function failureSignature(error: unknown): string {
+    if (error instanceof StrataError) return `${error.diagnostic.code} ${error.diagnostic.message.replace(/[0-9]+/g, "#")}`;
+    return error instanceof Error ? `${error.name}: ${error.message}` : String(error);
+}
+
+// Subexpressions an expression can be replaced with
+function exprOperands(expr: Expr): Expr[] {
+    switch (expr.kind) {
+        case "binary":
+            return [expr.left, expr.right];
+        case "unary":
+            return [expr.operand];
+        case "call":
+            return expr.args;
+        case "field":
+            return [expr.object];
+        case "index":
+            return [expr.object, expr.index];
+        case "array":
+            return expr.elements;
+        case "map":
+            return expr.entries.flatMap((entry) => [entry.key, entry.value]);
+        case "structLiteral":
+            return expr.fields.map((field) => field.value);
+        case "match":
+            return [expr.subject];
+        case "if":
+            return [expr.condition];
+        case "range":
+            return [expr.start, expr.end];
+        case "propagate":
+            return [expr.value];
+        default:
+            return [];
+    }
+}
+
+// Deleting any statement, replacing an if or loop with its body, or an
+// expression with an operand; biggest first, so whole functions go
+// before their insides
+function programEdits(statements: Stmt[], source: string): { start: number; end: number; text: string }[] {
+    const edits: { start: number; end: number; text: string }[] = [];
+    const visitExpr = (expr: Expr) => {
+        for (const operand of exprOperands(expr)) {
+            if (expr.span && operand.span) {
+                edits.push({ start: expr.span.start, end: expr.span.end, text: source.slice(operand.span.start, operand.span.end) });
+            }
+            visitExpr(operand);
+        }
+    };
+    const visit = (stmts: Stmt[]) => {
+        for (const stmt of stmts) {
+            const span = stmt.span;
+            const body = stmt.kind === "if" ? stmt.then : stmt.kind === "while" || stmt.kind === "forIn" ? stmt.body : [];
+            const first = body[0]?.span;
+            const last = body[body.length - 1]?.span;
+            if (span) edits.push({ start: span.start, end: span.end, text: "" });
+            if (span && first && last) edits.push({ start: span.start, end: span.end, text: source.slice(first.start, last.end) });
+            statementExprs(stmt).forEach(visitExpr);
+            visit(childStatements(stmt));
+        }
+    };
+    visit(statements);
+    return edits.sort((a, b) => b.end - b.start - (a.end - a.start));
+}
+
+// Deletes runs of lines, halving the run length once none can go
+function removeLines(source: string, fails: (source: string) => boolean): string {
+    let lines = source.split("\n");
+    for (let size = Math.max(1, Math.floor(lines.length / 2)); size >= 1; ) {
+        let removed = false;
+        for (let start = 0; start < lines.length; ) {
+            const candidate = [...lines.slice(0, start), ...lines.slice(start + size)];
+            if (fails(candidate.join("\n"))) {
+                lines = candidate;
+                removed = true;
+            } else {
+                start += size;
+            }
+        }
+        if (!removed) size = size === 1 ? 0 : Math.floor(size / 2);
+    }
+    return lines.join("\n");
+}
+
+function applyEdits(source: string, fails: (source: string) => boolean): string {
+    for (let progress = true; progress; ) {
+        progress = false;
+        let statements: Stmt[];
+        try {
+            statements = new Parser(source).parse();
+        } catch {
+            return source;
+        }
+        for (const edit of programEdits(statements, source)) {
+            const candidate = source.slice(0, edit.start) + edit.text + source.slice(edit.end);
+            if (candidate !== source && fails(candidate)) {
+                source = candidate;
+                progress = true;
+                break;
+            }
+        }
+    }
+    return source;
+}
+
+// failure runs a candidate and describes how it failed, or returns
+// undefined when it doesn't. tests counts the runs, the original included
+export function minimizeSource(source: string, failure: (source: string) => string | undefined): { source: string; tests: number } {
+    const target = failure(source);
+    if (target === undefined) throw new Error(message("minimize.no-failure"));
+    let tests = 1;
+    const fails = (candidate: string) => {
+        tests++;
+        return failure(candidate) === target;
+    };
+    for (let before = ""; before !== source; ) {
+        before = source;
+        source = applyEdits(removeLines(source, fails), fails);
+    }
+    return { source: source.replace(/\n+$/, "") + "\n", tests };
+}
+
+// Each candidate goes through the pipeline of strata run, with its output
+// dropped and a time limit so deleting a loop's update can't hang it
+function minimizeFile(file: string, prelude: Stmt[], options: ModuleOptions & { output?: string; quiet: boolean; timeoutMs: number }): void {
+    const source = fs.readFileSync(file, "utf-8");
+    const failure = (candidate: string): string | undefined => {
+        const log = console.log;
+        console.log = () => {};
+        try {
+            const loader = new ModuleLoader({ ...options, prelude });
+            const statements = withPrelude(prelude, loader.link(new Parser(candidate, 1, undefined, undefined, options.keywordAliases).parse(), file));
+            if (options.typecheck !== false) new TypeChecker().check(statements);
+            else new TypeChecker().lower(statements);
+            new Interpreter().runWithCancel(statements, new CancellationToken({ timeoutMs: options.timeoutMs }));
+            return undefined;
+        } catch (error) {
+            // A program that runs out of time reproduces nothing
+            if (error instanceof StrataError && error.diagnostic.code === "E0005") return undefined;
+            return failureSignature(error);
+        } finally {
+            console.log = log;
+        }
+    };
+    const result = minimizeSource(source, failure);
+    if (options.output) fs.writeFileSync(options.output, result.source);
+    else process.stdout.write(result.source);
+    if (!options.quiet) {
+        const lines = (text: string) => text.trimEnd().split("\n").length;
+        console.error(`Reduced ${lines(source)} lines to ${lines(result.source)} in ${result.tests} runs`);
+    }
+}
+
+// ============================================================================
+// PROGRAM DIFF - What changed between two versions of a program
+// ============================================================================
+//
+// strata diff old.str new.str compares the parsed programs, not their text,
+// so reformatting, comments and 0xFF for 255 are not changes. Top-level
+// functions, structs, bindings and imports are matched by name; the other
+// top-level statements, and the statements of a function's body, are
+// matched in order by a longest common subsequence.
+
+export interface ProgramChange {
+    change: "added" | "removed" | "changed";
+    // A declaration's signature, or the first line of a statement
+    what: string;
+    // What a changed declaration or statement was before
+    was?: string;
+    // The function whose body changed
+    within?: string;
+    oldLine?: number;
+    newLine?: number;
+}
+
+// What a node means: positions, the file it came from and the checker's
+// annotations are left out
+function nodeFingerprint(node: Stmt | Expr): string {
+    return JSON.stringify(node, (key, value) => (["line", "span", "file", "integer"].includes(key) ? undefined : value));
+}
+
+// Top-level statements matched by name between the two versions
+function declarationKey(stmt: Stmt): string | undefined {
+    switch (stmt.kind) {
+        case "function":
+            return `func ${stmt.name}`;
+        case "struct":
+            return `struct ${stmt.name}`;
+        case "enum":
+            return `enum ${stmt.name}`;
+        case "let":
+            return `binding ${stmt.name}`;
+        case "import":
+            return `import ${stmt.name}`;
+        case "test":
+            return `test ${JSON.stringify(stmt.name)}`;
+        default:
+            return undefined;
+    }
+}
+
+// func area(w: float, h: float) => float; other statements by their first line
+function describeStatement(stmt: Stmt, source: string): string {
+    if (stmt.kind === "function") {
+        const params = stmt.params.map((p) => `${p.name}: ${formatType(p.type)}`).join(", ");
+        return `func ${stmt.name}(${params}) => ${formatType(stmt.returnType)}`;
+    }
+    if (stmt.kind === "struct") {
+        return `struct ${stmt.name} { ${stmt.fields.map((f) => `${f.name}: ${formatType(f.type)}`).join(", ")} }`;
+    }
+    const text = stmt.span ? source.slice(stmt.span.start, stmt.span.end) : stmt.kind;
+    return text.split("\n")[0].trim();
+}
+
+// Statements of one block against another. A run of removals next to a run
+// of additions pairs up into changes, so an edited line is one entry
+function diffStatements(before: Stmt[], after: Stmt[], sources: [string, string], within?: string): ProgramChange[] {
+    const [a, b] = [before.map(nodeFingerprint), after.map(nodeFingerprint)];
+    // common[i][j]: length of the longest common subsequence of a[i..] and b[j..]
+    const common = Array.from({ length: a.length + 1 }, () => new Array<number>(b.length + 1).fill(0));
+    for (let i = a.length - 1; i >= 0; i--) {
+        for (let j = b.length - 1; j >= 0; j--) {
+            common[i][j] = a[i] === b[j] ? common[i + 1][j + 1] + 1 : Math.max(common[i + 1][j], common[i][j + 1]);
+        }
+    }
+    const changes: ProgramChange[] = [];
+    let removed: Stmt[] = [];
+    let added: Stmt[] = [];
+    const flush = () => {
+        for (let k = 0; k < Math.max(removed.length, added.length); k++) {
+            const [old, now] = [removed[k], added[k]];
+            if (old && now) {
+                changes.push({
+                    change: "changed",
+                    what: describeStatement(now, sources[1]),
+                    was: describeStatement(old, sources[0]),
+                    within,
+                    oldLine: old.line,
+                    newLine: now.line,
+                });
+            } else if (old) {
+                changes.push({ change: "removed", what: describeStatement(old, sources[0]), within, oldLine: old.line });
+            } else {
+                changes.push({ change: "added", what: describeStatement(now, sources[1]), within, newLine: now.line });
+            }
+        }
+        removed = [];
+        added = [];
+    };
+    let [i, j] = [0, 0];
+    while (i < a.length || j < b.length) {
+        if (i < a.length && j < b.length && a[i] === b[j]) {
+            flush();
+            i++;
+            j++;
+        } else if (j === b.length || (i < a.length && common[i + 1][j] >= common[i][j + 1])) {
+            removed.push(before[i++]);
+        } else {
+            added.push(after[j++]);
+        }
+    }
+    flush();
+    return changes;
+}
+
+// Changes from the old source to the new, in the order of the new program
+// (removals where they were in the old one)
+export function diffPrograms(oldSource: string, newSource: string, aliases?: KeywordAliases): ProgramChange[] {
+    const sources: [string, string] = [oldSource, newSource];
+    const [before, after] = sources.map((source) => new Parser(source, 1, undefined, undefined, aliases).parse());
+    // Redeclared names are told apart by their order: x, x#2
+    const declarations = (statements: Stmt[]) => {
+        const found = new Map<string, Stmt>();
+        for (const stmt of statements) {
+            const key = declarationKey(stmt);
+            if (key === undefined) continue;
+            let unique = key;
+            for (let n = 2; found.has(unique); n++) unique = `${key}#${n}`;
+            found.set(unique, stmt);
+        }
+        return found;
+    };
+    const [oldDeclarations, newDeclarations] = [declarations(before), declarations(after)];
+    const changes: ProgramChange[] = [];
+    for (const [key, old] of oldDeclarations) {
+        if (!newDeclarations.has(key)) changes.push({ change: "removed", what: describeStatement(old, oldSource), oldLine: old.line });
+    }
+    for (const [key, now] of newDeclarations) {
+        const old = oldDeclarations.get(key);
+        const what = describeStatement(now, newSource);
+        if (!old) {
+            changes.push({ change: "added", what, newLine: now.line });
+        } else if (old.kind === "function" && now.kind === "function") {
+            const was = describeStatement(old, oldSource);
+            if (was !== what) changes.push({ change: "changed", what, was, oldLine: old.line, newLine: now.line });
+            changes.push(...diffStatements(old.body, now.body, sources, now.name));
+        } else if (nodeFingerprint(old) !== nodeFingerprint(now)) {
+            changes.push({ change: "changed", what, was: describeStatement(old, oldSource), oldLine: old.line, newLine: now.line });
+        }
+    }
+    const rest = (statements: Stmt[]) => statements.filter((stmt) => declarationKey(stmt) === undefined);
+    changes.push(...diffStatements(rest(before), rest(after), sources));
+    const position = (change: ProgramChange) => change.newLine ?? change.oldLine ?? 0;
+    return changes.sort((x, y) => position(x) - position(y));
+}
+
+// One line per change: + new.str:5  func area(w: float) => float
+export function renderChanges(changes: ProgramChange[], oldFile: string, newFile: string): string {
+    const markers = { added: "+", removed: "-", changed: "~" };
+    return changes
+        .map((c) => {
+            const where = c.newLine !== undefined ? `${newFile}:${c.newLine}` : `${oldFile}:${c.oldLine}`;
+            const scope = c.within !== undefined ? `in func ${c.within}: ` : "";
+            return `${markers[c.change]} ${where}  ${scope}${c.what}${c.was !== undefined ? `  (was ${c.was})` : ""}`;
+        })
+        .join("\n");
+}
+
+// ============================================================================
+// MIGRATION - Rewrite calls to renamed stdlib functions
+// ============================================================================
+//
+// strata fix --migrate replaces each call of a function in
+// STDLIB_DEPRECATIONS with its new name. Only the name after the dot is
+// edited, located from the spans of the parsed program, so the rest of
+// the file stays byte for byte as written.
+
+export interface Migration {
+    line: number;
+    from: string; // text.toUpperCase
+    to: string; // text.upper
+}
+
+export function migrateSource(source: string, aliases?: KeywordAliases): { source: string; migrations: Migration[] } {
+    const program = new Parser(source, 1, undefined, undefined, aliases).parse();
+    // Stdlib imports by the name they are bound to
+    const modules = new Map<string, string>();
+    for (const stmt of program) {
+        if (stmt.kind === "import" && !stmt.path) modules.set(stmt.name, stmt.module);
+    }
+    const edits: { start: number; end: number; text: string; migration: Migration }[] = [];
+    walkProgram(program, (expr) => {
+        if (expr.kind !== "call" || expr.func.kind !== "member") return;
+        const { object, property } = expr.func;
+        if (object.kind !== "identifier" || !object.span) return;
+        const deprecation = STDLIB_DEPRECATIONS[modules.get(object.name) ?? ""]?.[property];
+        // The property follows the object's span after a dot
+        const dot = /^\s*\.\s*/.exec(source.slice(object.span.end));
+        if (!deprecation || !dot || !source.startsWith(property, object.span.end + dot[0].length)) return;
+        const start = object.span.end + dot[0].length;
+        const migration = { line: object.span.line, from: `${object.name}.${property}`, to: `${object.name}.${deprecation.replacement}` };
+        edits.push({ start, end: start + property.length, text: deprecation.replacement, migration });
+    });
+    edits.sort((a, b) => b.start - a.start);
+    let migrated = source;
+    for (const edit of edits) migrated = migrated.slice(0, edit.start) + edit.text + migrated.slice(edit.end);
+    return { source: migrated, migrations: edits.reverse().map((edit) => edit.migration) };
+}
+
+// ============================================================================
+// FEATURE REPORT - Which constructs and stdlib functions a program uses
+// ============================================================================
+//
+// strata run|check|build --feature-report usage.json counts the statement
+// and expression kinds of the program (binary and unary expressions by
+// operator) and its calls to stdlib functions, so scripts can be audited
+// for features a team has ruled out. The report is only written to the
+// given file; nothing is sent anywhere.
+
+export interface FeatureReport {
+    file: string;
+    strata: string; // the compiler version that wrote it
+    constructs: Record<string, number>;
+    stdlib: Record<string, number>; // std::io.print, or a builtin such as len
+    deprecated: Record<string, number>; // calls of STDLIB_DEPRECATIONS entries
+}
+
+export function featureReport(statements: Stmt[], file: string): FeatureReport {
+    const constructs = new Map<string, number>();
+    const stdlib = new Map<string, number>();
+    const deprecated = new Map<string, number>();
+    const count = (counts: Map<string, number>, key: string) => counts.set(key, (counts.get(key) ?? 0) + 1);
+    const modules = new Map<string, string>();
+    const declared = new Set<string>();
+    walkProgram(statements, () => { }, (stmt) => {
+        if (stmt.kind === "import" && !stmt.path) modules.set(stmt.name, stmt.module);
+        const name = declaredName(stmt);
+        if (name !== undefined) declared.add(name);
+        if (stmt.kind === "function") stmt.params.forEach((p) => declared.add(p.name));
+    });
+    // The function and return statement a lambda is made of aren't written
+    const synthesized = new Set<Stmt>();
+    walkProgram(
+        statements,
+        (expr) => {
+            if (expr.kind === "lambda") [expr.fn, ...expr.fn.body].forEach((stmt) => synthesized.add(stmt));
+            if (expr.kind === "binary" || expr.kind === "unary") count(constructs, `${expr.kind} ${expr.op}`);
+            else if (!["literal", "identifier", "member"].includes(expr.kind)) count(constructs, expr.kind);
+            if (expr.kind !== "call") return;
+            const callee = expr.func;
+            if (callee.kind === "identifier" && Object.hasOwn(BUILTIN_FUNCTIONS, callee.name) && !declared.has(callee.name)) {
+                count(stdlib, callee.name);
+            }
+            if (callee.kind !== "member" || callee.object.kind !== "identifier") return;
+            const module = modules.get(callee.object.name);
+            if (module === undefined) return;
+            count(stdlib, `${module}.${callee.property}`);
+            if (STDLIB_DEPRECATIONS[module]?.[callee.property]) count(deprecated, `${module}.${callee.property}`);
+        },
+        (stmt) => {
+            if (stmt.kind !== "expression" && !synthesized.has(stmt)) count(constructs, stmt.kind);
+        }
+    );
+    const sorted = (counts: Map<string, number>) => Object.fromEntries([...counts].sort(([a], [b]) => a.localeCompare(b)));
+    return { file, strata: STRATA_VERSION, constructs: sorted(constructs), stdlib: sorted(stdlib), deprecated: sorted(deprecated) };
+}
+
+// ============================================================================
+// REPL
+// ============================================================================
+//
+// strata repl (or strata without a file) checks and runs each input with
+// one TypeChecker and Interpreter, so definitions persist between inputs.
+// Input continues over several lines while a bracket is open, and the
+// value of an expression statement is printed. :save writes the session
+// so far as a Transcript, which strata replay runs again as a test.
+
+// One complete input and what it printed
+export interface TranscriptEntry {
+    time: string; // when it ran, as an ISO timestamp
+    input: string; // every line of a multi-line input
+    output: string;
+    diagnostics: string; // errors and warnings as rendered
+}
+
+export interface Transcript {
+    strata: string; // the version that recorded it
+    started: string;
+    entries: TranscriptEntry[];
+}
+
+// Brackets opened minus closed; 0 when the input can't be lexed, so the
+// parser reports the error
+function openBrackets(source: string): number {
+    let depth = 0;
+    try {
+        const lexer = new Lexer(source);
+        let token;
+        while ((token = lexer.nextToken())) {
+            if (token.kind !== "symbol") continue;
+            if (["{", "(", "["].includes(token.token)) depth++;
+            if (["}", ")", "]"].includes(token.token)) depth--;
+        }
+    } catch {
+        return 0;
+    }
+    return depth;
+}
+
+class Repl {
+    private checker = new TypeChecker();
+    private interpreter = new Interpreter({ file: "<repl>" });
+    private typeNames = new Map<string, "struct" | "enum">();
+    // Names declared during the session, in declaration order
+    private defined = new Map<string, Stmt["kind"]>();
+    private buffer = "";
+    private transcript: Transcript = { strata: STRATA_VERSION, started: new Date().toISOString(), entries: [] };
+
+    // echo is off when replaying, which only compares what inputs print
+    constructor(prelude: Stmt[], private echo = true, private aliases: KeywordAliases = NO_KEYWORD_ALIASES) {
+        this.checker.check(prelude);
+        this.interpreter.interpret(prelude);
+    }
 
-        // Set Module (Python, Go, Rust)
-        this.env.setModule("std::set", {
-            create: () => new Set(),
-            add: (set: Set<any>, item: any) => { set.add(item); return set; },
-            remove: (set: Set<any>, item: any) => { set.delete(item); return set; },
-            has: (set: Set<any>, item: any) => set.has(item),
-            size: (set: Set<any>) => set.size,
-            clear: (set: Set<any>) => { set.clear(); return set; },
-            union: (set1: Set<any>, set2: Set<any>) => new Set([...set1, ...set2]),
-            intersection: (set1: Set<any>, set2: Set<any>) => new Set([...set1].filter(x => set2.has(x))),
-            difference: (set1: Set<any>, set2: Set<any>) => new Set([...set1].filter(x => !set2.has(x))),
+    get entries(): TranscriptEntry[] {
+        return this.transcript.entries;
+    }
+
+    start(): void {
+        const rl = readline.createInterface({ input: process.stdin, output: process.stdout });
+        console.log("Strata REPL. :type <expr> shows a type, :env lists definitions, :save <file> writes a transcript, :quit exits");
+        rl.setPrompt("> ");
+        rl.prompt();
+        let closed = false;
+        rl.on("close", () => (closed = true));
+        rl.on("line", (line) => {
+            // Lines already read stay queued after :quit
+            if (closed) return;
+            if (!this.feed(line)) {
+                rl.close();
+                return;
+            }
+            rl.setPrompt(this.buffer ? "... " : "> ");
+            rl.prompt();
+        });
+        // Ctrl-C abandons an unfinished input, or exits at an empty prompt
+        rl.on("SIGINT", () => {
+            if (!this.buffer) {
+                rl.close();
+                return;
+            }
+            this.buffer = "";
+            console.log();
+            rl.setPrompt("> ");
+            rl.prompt();
         });
     }
 
-    interpret(statements: Stmt[]): void {
-        for (const stmt of statements) {
-            this.interpretStatement(stmt);
-            if (this.controlFlow.type) break;
+    // Handles one line of input; false once the session should end
+    feed(line: string): boolean {
+        const command = line.trim();
+        if (!this.buffer && command.startsWith(":")) {
+            const [name] = command.split(/\s/, 1);
+            const argument = command.slice(name.length).trim();
+            if (name === ":quit" || name === ":q") return false;
+            if (name === ":save" && argument) this.save(argument);
+            else if (name === ":env") this.perform(command, () => this.printEnv());
+            else if (name === ":type" && argument) this.perform(command, () => this.report(argument, () => this.printType(argument)));
+            else console.error(`Unknown command ${name}; expected :type <expr>, :env, :save <file> or :quit`);
+            return true;
         }
+        if (!this.buffer && !command) return true;
+        this.buffer += this.buffer ? `\n${line}` : line;
+        if (openBrackets(this.buffer) > 0) return true;
+        const source = this.buffer;
+        this.buffer = "";
+        this.perform(source, () => this.report(source, () => this.run(source)));
+        return true;
     }
 
-    private interpretStatement(stmt: Stmt): void {
-        switch (stmt.kind) {
-            case "let":
-                const value = this.evaluateExpression(stmt.value);
-                this.env.set(stmt.name, value, stmt.mutable);
-                break;
-            case "assignment":
-                const newValue = this.evaluateExpression(stmt.value);
-                this.env.update(stmt.target, newValue);
-                break;
-            case "expression":
-                this.evaluateExpression(stmt.expr);
-                break;
-            case "if":
-                const condition = this.evaluateExpression(stmt.condition);
-                if (condition) {
-                    for (const s of stmt.then) {
-                        this.interpretStatement(s);
-                        if (this.controlFlow.type) return;
-                    }
-                } else if (stmt.else) {
-                    for (const s of stmt.else) {
-                        this.interpretStatement(s);
-                        if (this.controlFlow.type) return;
-                    }
-                }
-                break;
-            case "while":
-                while (this.evaluateExpression(stmt.condition)) {
-                    for (const s of stmt.body) {
-                        this.interpretStatement(s);
-                        if (this.controlFlow.type === "break") {
-                            this.controlFlow.type = null;
-                            return;
-                        }
-                        if (this.controlFlow.type === "continue") {
-                            this.controlFlow.type = null;
-                            break;
-                        }
-                        if (this.controlFlow.type === "return") return;
-                    }
-                }
-                break;
-            case "for":
-                this.interpretStatement(stmt.init);
-                while (this.evaluateExpression(stmt.condition)) {
-                    for (const s of stmt.body) {
-                        this.interpretStatement(s);
-                        if (this.controlFlow.type === "break") {
-                            this.controlFlow.type = null;
-                            return;
-                        }
-                        if (this.controlFlow.type === "continue") {
-                            this.controlFlow.type = null;
-                            break;
-                        }
-                        if (this.controlFlow.type === "return") return;
-                    }
-                    this.interpretStatement(stmt.update);
-                }
-                break;
-            case "return":
-                this.controlFlow.value = stmt.value
-                    ? this.evaluateExpression(stmt.value)
-                    : null;
-                this.controlFlow.type = "return";
-                break;
-            case "break":
-                this.controlFlow.type = "break";
-                break;
-            case "continue":
-                this.controlFlow.type = "continue";
-                break;
-            case "function":
-               this.env.setFunction(stmt.name, [
-                   ...stmt.params.map((p) => p.name),
-               ], stmt.body);
-               break;
-            case "import":
-               // Bind module to variable name
-               const module = this.env.getModule(stmt.module);
-               if (!module) {
-                   throw new Error(`Module not found: ${stmt.module}`);
-               }
-               this.env.set(stmt.name, module, false);
-               break;
+    // Runs a complete input with what it prints kept for the transcript
+    private perform(input: string, action: () => void): void {
+        const output: string[] = [];
+        const diagnostics: string[] = [];
+        const { log, error } = console;
+        console.log = (...parts: any[]) => {
+            output.push(`${parts.join(" ")}\n`);
+            if (this.echo) log(...parts);
+        };
+        console.error = (...parts: any[]) => {
+            diagnostics.push(`${parts.join(" ")}\n`);
+            if (this.echo) error(...parts);
+        };
+        const time = new Date().toISOString();
+        try {
+            action();
+        } finally {
+            console.log = log;
+            console.error = error;
         }
+        this.transcript.entries.push({ time, input, output: output.join(""), diagnostics: diagnostics.join("") });
     }
 
-    private evaluateExpression(expr: Expr): any {
-        switch (expr.kind) {
-            case "literal":
-                return expr.value;
-            case "identifier":
-                return this.env.get(expr.name);
-            case "binary":
-                const left = this.evaluateExpression(expr.left);
-                const right = this.evaluateExpression(expr.right);
-                switch (expr.op) {
-                    case "+":
-                        return left + right;
-                    case "-":
-                        return left - right;
-                    case "*":
-                        return left * right;
-                    case "/":
-                        return left / right;
-                    case "%":
-                        return left % right;
-                    case "==":
-                        return left === right;
-                    case "!=":
-                        return left !== right;
-                    case "<":
-                        return left < right;
-                    case ">":
-                        return left > right;
-                    case "<=":
-                        return left <= right;
-                    case ">=":
-                        return left >= right;
-                    case "&&":
-                        return left && right;
-                    case "||":
-                        return left || right;
-                    default:
-                        return null;
-                }
-            case "unary":
-                const operand = this.evaluateExpression(expr.operand);
-                switch (expr.op) {
-                    case "-":
-                        return -operand;
-                    case "+":
-                        return +operand;
-                    case "!":
-                        return !operand;
-                    case "~":
-                        return ~operand;
-                    default:
-                        return null;
-                }
-            case "call":
-                const func = this.evaluateExpression(expr.func);
-                const args = expr.args.map((a) =>
-                    this.evaluateExpression(a)
-                );
+    private save(file: string): void {
+        try {
+            fs.writeFileSync(file, `${JSON.stringify(this.transcript, null, 2)}\n`);
+            console.log(`Saved ${this.transcript.entries.length} input(s) to ${file}`);
+        } catch (error) {
+            console.error(error instanceof Error ? error.message : String(error));
+        }
+    }
 
-                // Check for built-in functions from extended language features
-                if (expr.func.kind === "identifier" && expr.func.name in BUILTIN_FUNCTIONS) {
-                    return BUILTIN_FUNCTIONS[expr.func.name](args);
+    private run(source: string): void {
+        const statements = new Parser(source, 1, this.typeNames, undefined, this.aliases).parse();
+        this.checker.check(statements);
+        for (const stmt of statements) {
+            if (stmt.kind === "expression") {
+                let value;
+                try {
+                    value = this.interpreter.evaluate(stmt.expr);
+                } catch (error) {
+                    throw asStrataError(error, "E0004", stmt.line !== undefined ? { line: stmt.line } : undefined);
                 }
+                if (value !== null && value !== undefined) console.log(formatNested(value));
+            } else {
+                this.interpreter.interpret([stmt]);
+            }
+            const name = declaredName(stmt);
+            if (name !== undefined && !this.defined.has(name)) this.defined.set(name, stmt.kind);
+        }
+    }
 
-                if (typeof func === "function") {
-                    return func(...args);
-                }
-                throw new Error("Not a function");
-            case "member":
-                const obj = this.evaluateExpression(expr.object);
-                return obj?.[expr.property];
+    private printType(source: string): void {
+        const statements = new Parser(source, 1, this.typeNames, undefined, this.aliases).parse();
+        const [stmt] = statements;
+        if (statements.length !== 1 || stmt.kind !== "expression") {
+            throw errorAt("E0002", message("repl.type-expression"));
+        }
+        console.log(formatType(this.checker.typeOf(stmt.expr)));
+    }
+
+    private printEnv(): void {
+        if (this.defined.size === 0) {
+            console.log("Nothing defined yet");
+            return;
+        }
+        for (const [name, kind] of this.defined) {
+            if (kind === "struct" || kind === "enum") {
+                console.log(`${kind} ${name}`);
+                continue;
+            }
+            const identifier: Expr = { kind: "identifier", name };
+            const type = formatType(this.checker.typeOf(identifier));
+            console.log(kind === "function" ? `${name}: ${type}` : `${name}: ${type} = ${formatNested(this.interpreter.evaluate(identifier))}`);
+        }
+    }
+
+    private report(source: string, action: () => void): void {
+        try {
+            action();
+        } catch (error) {
+            const diagnostics = error instanceof StrataError ? error.diagnostics : [asStrataError(error, "E0004").diagnostic];
+            console.error(diagnostics.map((d) => renderDiagnostic(d, source, "<repl>")).join("\n\n"));
+        }
+        for (const warning of this.checker.warnings.splice(0)) {
+            console.error(renderDiagnostic(warning, source, "<repl>"));
+        }
+    }
+}
+
+// An entry whose output or diagnostics came out different on replay
+export interface ReplayMismatch {
+    index: number;
+    expected: TranscriptEntry;
+    actual: TranscriptEntry;
+}
+
+// Feeds the transcript's inputs, line by line, to a fresh REPL with the
+// given prelude; timestamps are not compared
+export function replayTranscript(transcript: Transcript, prelude: Stmt[] = [], aliases?: KeywordAliases): ReplayMismatch[] {
+    const repl = new Repl(prelude, false, aliases);
+    const mismatches: ReplayMismatch[] = [];
+    transcript.entries.forEach((expected, index) => {
+        const before = repl.entries.length;
+        for (const line of expected.input.split("\n")) repl.feed(line);
+        const actual = repl.entries[before] ?? { time: "", input: expected.input, output: "", diagnostics: "" };
+        if (actual.output !== expected.output || actual.diagnostics !== expected.diagnostics) {
+            mismatches.push({ index, expected, actual });
+        }
+    });
+    return mismatches;
+}
+
+// strata replay: prints each input that no longer prints the same and
+// exits with 1 if there is one
+function replayFile(file: string, prelude: Stmt[], aliases: KeywordAliases): void {
+    const transcript: Transcript = JSON.parse(fs.readFileSync(file, "utf-8"));
+    const mismatches = replayTranscript(transcript, prelude, aliases);
+    for (const { index, expected, actual } of mismatches) {
+        console.log(`input ${index + 1} differs:\n${expected.input.replace(/^/gm, "  > ")}`);
+        for (const stream of ["output", "diagnostics"] as const) {
+            if (expected[stream] === actual[stream]) continue;
+            console.log(`  expected ${stream}:\n${expected[stream].replace(/^(?=.)/gm, "    ")}`);
+            console.log(`  actual ${stream}:\n${actual[stream].replace(/^(?=.)/gm, "    ")}`);
         }
     }
+    const total = transcript.entries.length;
+    console.log(`${total} input(s): ${total - mismatches.length} matched, ${mismatches.length} differ`);
+    if (mismatches.length > 0) process.exit(1);
 }
 
 // ============================================================================
-// C CODE GENERATOR
+// AST INTERCHANGE - strata ast --export, and --from-ast for run/check/build
 // ============================================================================
+//
+// Other implementations of Strata can hand a program to this toolchain as
+// JSON instead of source: {"format": "strata-ast", "version": 1,
+// "strata": "1.0.0", "program": [...]}. program holds the parsed
+// statements exactly as the Parser builds them, every node an object
+// keyed on "kind" with the fields of its Stmt or Expr variant, and types
+// as TypeDef objects. Floats that JSON can't write (inf, NaN) are the
+// strings "Infinity", "-Infinity" and "NaN". version goes up whenever a
+// node changes shape; a reader rejects versions newer than its own.
+// Imports are kept, not linked, and resolve against the AST file's path.
+
+// 2: test statements
+export const AST_VERSION = 2;
+
+export interface AstDocument {
+    format: "strata-ast";
+    version: number;
+    strata: string; // the compiler version that wrote it
+    program: Stmt[];
+}
 
-class CGenerator {
-    private code: string[] = [];
+export function exportAst(program: Stmt[]): AstDocument {
+    return { format: "strata-ast", version: AST_VERSION, strata: STRATA_VERSION, program };
+}
 
-    generate(statements: Stmt[]): string {
-        this.code = [];
-        this.code.push("#include <stdio.h>");
-        this.code.push("#include <math.h>");
-        this.code.push("int main() {");
+export function renderAst(program: Stmt[]): string {
+    return JSON.stringify(exportAst(program), astReplacer, 2);
+}
 
-        for (const stmt of statements) {
-            this.generateStatement(stmt);
+// Checks the envelope and the statements' shape; the type checker finds
+// the rest, as it would in a parsed program
+export function importAst(json: string): Stmt[] {
+    const document = JSON.parse(json, astReviver);
+    if (typeof document !== "object" || document === null || document.format !== "strata-ast") {
+        throw new Error(message("ast.not-interchange"));
+    }
+    if (!Number.isInteger(document.version) || document.version < 1) {
+        throw new Error(message("ast.bad-version"));
+    }
+    if (document.version > AST_VERSION) {
+        throw new Error(message("ast.newer", { version: document.version, strata: STRATA_VERSION, supported: AST_VERSION }));
+    }
+    if (!Array.isArray(document.program)) {
+        throw new Error(message("ast.bad-program"));
+    }
+    document.program.forEach((stmt: any, i: number) => {
+        if (typeof stmt !== "object" || stmt === null || typeof stmt.kind !== "string") {
+            throw new Error(message("ast.bad-statement", { index: i + 1 }));
         }
+    });
+    return document.program;
+}
 
-        this.code.push("return 0;");
-        this.code.push("}");
+function astReplacer(key: string, value: any): any {
+    if (key === "value" && typeof value === "number" && !Number.isFinite(value)) return String(value);
+    return value;
+}
 
-        return this.code.join("\n");
+function astReviver(key: string, value: any): any {
+    if (value?.kind === "literal" && value.type?.primitive === "float" && typeof value.value === "string") {
+        return { ...value, value: Number(value.value) };
     }
+    return value;
+}
 
-    private generateStatement(stmt: Stmt): void {
-        switch (stmt.kind) {
-            case "let":
-                const ctype = this.typeToCString(stmt.type);
-                const value = this.generateExpression(stmt.value);
-                this.code.push(`${ctype} ${stmt.name} = ${value};`);
-                break;
-            case "expression":
-                const expr = this.generateExpression(stmt.expr);
-                this.code.push(`${expr};`);
-                break;
-            case "if":
-                const condition = this.generateExpression(stmt.condition);
-                this.code.push(`if (${condition}) {`);
-                for (const s of stmt.then) {
-                    this.generateStatement(s);
-                }
-                this.code.push("}");
-                break;
-            case "return":
-                const value2 = stmt.value
-                    ? this.generateExpression(stmt.value)
-                    : "0";
-                this.code.push(`return ${value2};`);
-                break;
-        }
-    }
+// ============================================================================
+// EVALUATION SERVICE - strata serve: run untrusted programs over HTTP
+// ============================================================================
+//
+// POST /run or /check with {"source": "...", "args": [], "input": "",
+// "timeoutMs": 500, "memoryMb": 32} answers with
+// {"ok", "diagnostics", "output", "value"}. Each request gets its own
+// worker thread with a heap limit, so a program that allocates without
+// end only takes its worker down, and a sandboxed interpreter without file
+// or environment access. A final expression statement is the value. At
+// most workers programs run at once; requests beyond those wait in a
+// queue, and when that is full too they get a 503.
+
+export interface ServeOptions {
+    host: string;
+    port: number;
+    timeoutMs: number; // the most a request may ask for, and its default
+    memoryMb: number;
+    workers: number;
+    prelude?: Stmt[];
+}
 
-    private generateExpression(expr: Expr): string {
-        switch (expr.kind) {
-            case "literal":
-                if (typeof expr.value === "string") {
-                    return `"${expr.value}"`;
-                }
-                return String(expr.value);
-            case "identifier":
-                return expr.name;
-            case "binary":
-                const left = this.generateExpression(expr.left);
-                const right = this.generateExpression(expr.right);
-                return `(${left} ${expr.op} ${right})`;
-            case "unary":
-                const operand = this.generateExpression(expr.operand);
-                return `(${expr.op}${operand})`;
-            case "call":
-                const func = this.generateExpression(expr.func);
-                const args = expr.args.map((a) =>
-                    this.generateExpression(a)
-                );
-                return `${func}(${args.join(", ")})`;
-            case "member":
-                const obj = this.generateExpression(expr.object);
-                return `${obj}.${expr.property}`;
-            default:
-                return "";
-        }
+interface ServeJob {
+    mode: "run" | "check";
+    source: string;
+    args: string[];
+    input: string;
+    timeoutMs: number;
+    memoryMb: number;
+    prelude: Stmt[];
+}
+
+export interface ServeResult {
+    ok: boolean; // no errors; warnings are still in diagnostics
+    diagnostics: Diagnostic[];
+    output: string[]; // the lines the program printed
+    value?: string;
+}
+
+const SERVE_MAX_BODY = 1 << 20;
+// Requests waiting for a worker
+const SERVE_QUEUE = 64;
+// How long past its time limit a worker may take to stop itself before it
+// is terminated, for a run stuck in a single long stdlib call
+const SERVE_GRACE_MS = 1000;
+
+// Runs in the worker: the main thread only sees the result message
+function runServeJob(job: ServeJob): ServeResult {
+    const report = execute(job.source, {
+        args: job.args,
+        input: job.input,
+        timeoutMs: job.timeoutMs,
+        sandbox: true,
+        prelude: job.prelude,
+        check: job.mode === "check",
+    });
+    const result: ServeResult = { ok: report.ok, diagnostics: report.diagnostics, output: report.stdout.split("\n").slice(0, -1) };
+    if (report.value !== undefined) result.value = report.value;
+    return result;
+}
+
+function serveLimit(diagnostic: Diagnostic): ServeResult {
+    return { ok: false, diagnostics: [diagnostic], output: [] };
+}
+
+function runInWorker(job: ServeJob): Promise<ServeResult> {
+    return new Promise((resolve) => {
+        const worker = new Worker(fileURLToPath(import.meta.url), {
+            workerData: { serveJob: job },
+            resourceLimits: { maxOldGenerationSizeMb: job.memoryMb },
+        });
+        const timer = setTimeout(() => {
+            finish(serveLimit(errorAt("E0005", message("serve.time-limit", { ms: job.timeoutMs })).diagnostic));
+        }, job.timeoutMs + SERVE_GRACE_MS);
+        const finish = (result: ServeResult) => {
+            clearTimeout(timer);
+            resolve(result);
+            void worker.terminate();
+        };
+        worker.once("message", finish);
+        worker.once("error", (error: Error & { code?: string }) => {
+            const id = error.code === "ERR_WORKER_OUT_OF_MEMORY" ? "serve.memory-limit" : "serve.worker-failed";
+            finish(serveLimit(errorAt("E0004", message(id, { mb: job.memoryMb, reason: error.message })).diagnostic));
+        });
+        worker.once("exit", () => finish(serveLimit(errorAt("E0006", message("serve.worker-failed", { reason: "exited" })).diagnostic)));
+    });
+}
+
+// Requests may lower the server's limits but not raise them
+function serveJob(mode: "run" | "check", body: any, options: ServeOptions): ServeJob | string {
+    if (typeof body?.source !== "string") return "expected a JSON object with a source string";
+    if (body.args !== undefined && !(Array.isArray(body.args) && body.args.every((arg: unknown) => typeof arg === "string"))) {
+        return "args must be an array of strings";
     }
+    if (body.input !== undefined && typeof body.input !== "string") return "input must be a string";
+    for (const limit of ["timeoutMs", "memoryMb"]) {
+        if (body[limit] !== undefined && !(typeof body[limit] === "number" && body[limit] > 0)) return `${limit} must be a positive number`;
+    }
+    return {
+        mode,
+        source: body.source,
+        args: body.args ?? [],
+        input: body.input ?? "",
+        timeoutMs: Math.min(body.timeoutMs ?? options.timeoutMs, options.timeoutMs),
+        memoryMb: Math.min(body.memoryMb ?? options.memoryMb, options.memoryMb),
+        prelude: options.prelude ?? [],
+    };
+}
 
-    private typeToCString(type: TypeDef): string {
-        if (type.kind === "primitive") {
-            switch (type.primitive) {
-                case "int":
-                    return "int";
-                case "float":
-                    return "double";
-                case "bool":
-                    return "int";
-                case "char":
-                    return "char";
-                case "string":
-                    return "char*";
-                default:
-                    return "int";
+export function serve(options: ServeOptions): http.Server {
+    let running = 0;
+    const waiting: (() => void)[] = [];
+    // A finished job starts the first one waiting for its worker
+    const start = (job: ServeJob, reply: (result: ServeResult) => void) => {
+        running++;
+        void runInWorker(job).then((result) => {
+            running--;
+            reply(result);
+            waiting.shift()?.();
+        });
+    };
+    const server = http.createServer((request, response) => {
+        const send = (status: number, body: object) => {
+            response.writeHead(status, { "content-type": "application/json" });
+            response.end(JSON.stringify(body) + "\n");
+        };
+        if (request.method === "GET" && request.url === "/health") return send(200, { ok: true, strata: STRATA_VERSION });
+        const mode = request.url === "/run" ? "run" : request.url === "/check" ? "check" : undefined;
+        if (mode === undefined) return send(404, { error: `no endpoint ${request.url}; expected POST /run or /check` });
+        if (request.method !== "POST") return send(405, { error: `${request.url} takes POST` });
+        const chunks: Buffer[] = [];
+        let bytes = 0;
+        request.on("data", (chunk: Buffer) => {
+            bytes += chunk.length;
+            if (bytes > SERVE_MAX_BODY) {
+                send(413, { error: `requests are limited to ${SERVE_MAX_BODY} bytes` });
+                request.destroy();
+                return;
             }
-        }
-        return "int";
-    }
+            chunks.push(chunk);
+        });
+        request.on("end", () => {
+            let body: unknown;
+            try {
+                body = JSON.parse(Buffer.concat(chunks).toString("utf-8"));
+            } catch {
+                return send(400, { error: "the request body is not JSON" });
+            }
+            const job = serveJob(mode, body, options);
+            if (typeof job === "string") return send(400, { error: job });
+            const reply = (result: ServeResult) => send(200, result);
+            if (running < options.workers) start(job, reply);
+            else if (waiting.length < SERVE_QUEUE) waiting.push(() => start(job, reply));
+            else send(503, { error: "the server is busy; try again later" });
+        });
+    });
+    server.listen(options.port, options.host);
+    return server;
 }
 
 // ============================================================================
-// STRATAUIM PACKAGE MANAGER
+// MAIN
 // ============================================================================
 
-interface StrataumfileConfig {
-    name: string;
-    version: string;
-    registry?: string;
-    dependencies?: Record<string, string>;
+// --lang takes a translation file, or a language code looked up as
+// locales/<code>.json in the working directory, then beside the compiler
+function loadLanguage(lang: string): void {
+    if (lang === "en") {
+        setMessages({});
+        return;
+    }
+    const here = path.dirname(fileURLToPath(import.meta.url));
+    const candidates = lang.endsWith(".json")
+        ? [lang]
+        : [process.cwd(), here, path.dirname(here)].map((dir) => path.join(dir, "locales", `${lang}.json`));
+    const file = candidates.find((candidate) => fs.existsSync(candidate));
+    if (!file) {
+        console.error(`No translation for ${lang}; looked for ${candidates.join(", ")}`);
+        process.exit(1);
+    }
+    const unknown = setMessages(JSON.parse(fs.readFileSync(file, "utf-8")));
+    if (unknown.length > 0) console.error(`warning: ${file} has unknown message ids: ${unknown.join(", ")}`);
 }
 
-class PackageManager {
-    private projectRoot: string;
-    private strataumfile: StrataumfileConfig;
-    private lockFile: any;
+const USAGE = `Usage: strata [command] [options] [file.str] [-- args]
+
+Commands:
+  run <file>       Type check and run a program
+  check <file>     Parse and type check without running
+  run --all <files>, check --all <files>
+                   Each file on its own, then a pass/fail/error summary
+  test [paths]     Run the test blocks of the .str files in paths (default .) and summarize
+  build <file>     Compile with --target, to -o or out.c/out.js/out.wat
+  fmt [files]      Format files in place, or stdin to stdout; --check only reports
+  minimize <file>  Shrink a failing program to a small reproduction, to -o or stdout
+  diff <old> <new> List changed functions and statements, ignoring formatting
+  fix --migrate <files>
+                   Rewrite calls to renamed stdlib functions in place
+  serve            Run programs sent as JSON over HTTP in sandboxed workers
+  ast --export <file>
+                   Write the parsed program in the AST interchange format, to -o or stdout
+  repl             Interactive REPL
+  replay           Run a transcript saved with :save again and report inputs that print differently
+  init, install, add, remove, list, info
+                   Manage the Strataumfile
+Without a command, the file is run as with run.
+
+Options:
+  --target c|js|wasm          Code generator (default c)
+  -o, --output <file>         Where build writes the generated code, - for stdout
+  --emit <target:path>        --target and -o together: --emit=c:- prints the C
+  --force                     Overwrite an output file that Strata didn't generate
+  --build                     run also compiles the program with --target afterwards
+  --run                       build compiles the C in a temporary directory and runs it
+  --cc <compiler>             C compiler for --run, flags allowed (default $CC, then cc)
+  --backend interpreter|vm    How run executes the program
+  --emit-ast                  Print the parsed program as JSON and stop
+  --from-ast                  run, check or build a file written by strata ast --export
+  --no-typecheck              Skip the type checker
+  --quiet                     Hide warnings and the timing line
+  --strict-math               Float division by zero is a runtime error instead of inf or NaN
+  --prelude <file>, --no-prelude
+  --module-path <dir>         Where imports that aren't ./ or ../ paths are found
+  --timeout <ms>              Time limit for each run with --all, minimize or serve (default 1000)
+  --listen <host:port>        Where serve listens (default 127.0.0.1:7878)
+  --max-memory <mb>           Heap limit for each program serve runs (default 64)
+  --workers <n>               Programs serve runs at once (default 4)
+  --check                     fmt lists unformatted files and exits 1, writing nothing
+  --write                     fmt rewrites the files in place (the default with files)
+  --statement-map             fmt writes <file>.statements.json: statement IDs and lines
+  --migrate                   fix renames calls to deprecated stdlib functions
+  --feature-report <file>     Write the constructs and stdlib calls used as JSON (local only)
+  --explain-eval <line|expression>
+  --lang <code|file>
+  --list-editions             Editions a //! strata pragma may name
+  --version`;
+
+// Diagnostics from imported files are shown against those files
+function renderIn(diagnostic: Diagnostic, source: string, filePath: string): string {
+    if (diagnostic.file === undefined) return renderDiagnostic(diagnostic, source, filePath);
+    return renderDiagnostic(diagnostic, fs.readFileSync(diagnostic.file, "utf-8"), diagnostic.file);
+}
 
-    constructor(projectRoot: string = process.cwd()) {
-        this.projectRoot = projectRoot;
-        this.strataumfile = this.loadStrataumfile();
-        this.lockFile = this.loadLockFile();
+// Renders a parse, type or runtime error and exits
+function exitWithError(error: unknown, source: string, filePath: string): never {
+    if (error instanceof StrataError) {
+        const rendered = error.diagnostics.map((d) => renderIn(d, source, filePath));
+        if (rendered.length > 1) {
+            rendered.push(`${message("diagnostic.error")}: ${message("diagnostic.aborting", { count: rendered.length })}`);
+        }
+        console.error(rendered.join("\n\n"));
+    } else {
+        console.error(
+            "Error:",
+            error instanceof Error ? error.message : String(error)
+        );
     }
+    process.exit(1);
+}
 
-    private loadStrataumfile(): StrataumfileConfig {
-        const path = `${this.projectRoot}/Strataumfile`;
-        try {
-            const content = fs.readFileSync(path, "utf-8");
-            return JSON.parse(content);
-        } catch {
-            return { name: "unknown", version: "0.0.0", dependencies: {} };
+export function main(args: string[]): void {
+    // One for the package commands and the project settings of a run
+    const project = new PackageManager();
+
+    // Handle package manager commands
+    if (args.length > 0) {
+        const command = args[0];
+
+        switch (command) {
+            case "init":
+                const projectName = args[1] || "my-strata-project";
+                const version = args[2] || "0.0.1";
+                project.init(projectName, version);
+                process.exit(0);
+            case "install":
+                project.install(args[1]);
+                process.exit(0);
+            case "add":
+                if (!args[1]) {
+                    console.error("Usage: strataum add <package> [version]");
+                    process.exit(1);
+                }
+                project.add(args[1], args[2] || "latest");
+                process.exit(0);
+            case "remove":
+                if (!args[1]) {
+                    console.error("Usage: strataum remove <package>");
+                    process.exit(1);
+                }
+                project.remove(args[1]);
+                process.exit(0);
+            case "list":
+                project.list();
+                process.exit(0);
+            case "info":
+                project.info();
+                process.exit(0);
+            case "help":
+            case "--help":
+                console.log(USAGE);
+                process.exit(0);
+            case "--version":
+                console.log(`strata ${STRATA_VERSION}`);
+                process.exit(0);
+            case "--list-editions":
+                for (const { edition, summary } of EDITIONS) {
+                    console.log(`${edition}${edition === LATEST_EDITION ? " (latest)" : ""}  ${summary}`);
+                }
+                process.exit(0);
         }
     }
 
-    private loadLockFile(): any {
-        const path = `${this.projectRoot}/Strataumfile.lock`;
-        try {
-            const content = fs.readFileSync(path, "utf-8");
-            return JSON.parse(content);
-        } catch {
-            return { locked: false, packages: {} };
+    // Without a subcommand a file is run, as with run; run --build also
+    // compiles it afterwards
+    const subcommand = ["run", "check", "build", "fmt", "minimize", "diff", "fix", "serve", "replay", "ast", "test"].includes(args[0]) ? args[0] : undefined;
+    let preludeFile = project.preludePath();
+    let usePrelude = true;
+    let explain: string | undefined;
+    let target = "c";
+    let backend = "interpreter";
+    let output: string | undefined;
+    let emitAst = false;
+    let astExport = false;
+    let fromAst = false;
+    let runBinary = false;
+    let force = false;
+    let alsoBuild = false;
+    let fmtCheck = false;
+    let fmtWrite = false;
+    let cc = process.env.CC || "cc";
+    let typecheck = true;
+    let quiet = false;
+    let statementMap = false;
+    let migrate = false;
+    let reportFile: string | undefined;
+    let timeoutMs = 1000;
+    let listen = "127.0.0.1:7878";
+    let memoryMb = 64;
+    let workers = 4;
+    let all = false;
+    let strictMath = false;
+    const searchPath: string[] = [];
+    const files: string[] = [];
+    // Everything after -- goes to the program, for env.args()
+    let programArgs: string[] = [];
+    for (let i = subcommand ? 1 : 0; i < args.length; i++) {
+        if (args[i] === "--") {
+            programArgs = args.slice(i + 1);
+            break;
+        }
+        if (args[i] === "--prelude" && args[i + 1]) preludeFile = args[++i];
+        else if (args[i] === "--no-prelude") usePrelude = false;
+        else if (args[i] === "--module-path" && args[i + 1]) searchPath.push(args[++i]);
+        else if (args[i] === "--timeout" && args[i + 1]) timeoutMs = Number(args[++i]);
+        else if (args[i] === "--listen" && args[i + 1]) listen = args[++i];
+        else if (args[i] === "--max-memory" && args[i + 1]) memoryMb = Number(args[++i]);
+        else if (args[i] === "--workers" && args[i + 1]) workers = Number(args[++i]);
+        else if (args[i] === "--explain-eval" && args[i + 1]) explain = args[++i];
+        else if (args[i] === "--lang" && args[i + 1]) loadLanguage(args[++i]);
+        else if (args[i] === "--target" && args[i + 1]) target = args[++i];
+        else if (args[i] === "--backend" && args[i + 1]) backend = args[++i];
+        else if ((args[i] === "-o" || args[i] === "--output") && args[i + 1]) output = args[++i];
+        else if (args[i] === "--emit" || args[i].startsWith("--emit=")) {
+            // --emit=c:- or --emit js:out.js, target and output in one
+            const spec = args[i] === "--emit" ? args[++i] ?? "" : args[i].slice("--emit=".length);
+            const colon = spec.indexOf(":");
+            if (colon <= 0 || colon === spec.length - 1) {
+                console.error(`--emit takes target:path, such as c:- for stdout, not ${spec}`);
+                process.exit(1);
+            }
+            target = spec.slice(0, colon);
+            output = spec.slice(colon + 1);
         }
+        else if (args[i] === "--force") force = true;
+        else if (args[i] === "--build") alsoBuild = true;
+        else if (args[i] === "--check") fmtCheck = true;
+        else if (args[i] === "--write") fmtWrite = true;
+        else if (args[i] === "--emit-ast") emitAst = true;
+        else if (args[i] === "--export") astExport = true;
+        else if (args[i] === "--from-ast") fromAst = true;
+        else if (args[i] === "--run") runBinary = true;
+        else if (args[i] === "--cc" && args[i + 1]) cc = args[++i];
+        else if (args[i] === "--no-typecheck") typecheck = false;
+        else if (args[i] === "--quiet") quiet = true;
+        else if (args[i] === "--strict-math") strictMath = true;
+        else if (args[i] === "--statement-map") statementMap = true;
+        else if (args[i] === "--migrate") migrate = true;
+        else if (args[i] === "--all") all = true;
+        else if (args[i] === "--feature-report" && args[i + 1]) reportFile = args[++i];
+        else if (args[i].startsWith("-")) {
+            console.error(`Unknown option ${args[i]}\n\n${USAGE}`);
+            process.exit(1);
+        } else files.push(args[i]);
     }
-
-    private saveStrataumfile(): void {
-        const path = `${this.projectRoot}/Strataumfile`;
-        fs.writeFileSync(path, JSON.stringify(this.strataumfile, null, 2));
-        console.log(`✓ Updated ${path}`);
+    const { aliases, rejected } = keywordAliases(project.keywordAliases());
+    // The project's own files, never the built-in prelude
+    const parseFile = (text: string) => new Parser(text, 1, undefined, undefined, aliases).parse();
+    if (rejected.length > 0 && !quiet) console.error(`warning: Strataumfile has invalid keyword aliases: ${rejected.join(", ")}`);
+    const edition = project.edition();
+    try {
+        if (edition !== undefined) setDefaultEdition(edition);
+    } catch (error) {
+        exitWithError(error, "", "Strataumfile");
     }
-
-    private saveLockFile(): void {
-        const path = `${this.projectRoot}/Strataumfile.lock`;
-        this.lockFile.timestamp = new Date().toISOString();
-        fs.writeFileSync(path, JSON.stringify(this.lockFile, null, 2));
-        console.log(`✓ Locked dependencies in ${path}`);
+    if (target !== "c" && target !== "wasm" && target !== "js") {
+        console.error(`Unknown target ${target}; expected c, wasm or js`);
+        process.exit(1);
     }
-
-    install(packageName?: string): void {
-        const packagesDir = `${this.projectRoot}/.strata/packages`;
-        
-        if (!fs.existsSync(packagesDir)) {
-            fs.mkdirSync(packagesDir, { recursive: true });
+    if (backend !== "interpreter" && backend !== "vm") {
+        console.error(`Unknown backend ${backend}; expected interpreter or vm`);
+        process.exit(1);
+    }
+    if (backend === "vm" && explain !== undefined) {
+        console.error("--explain-eval needs the interpreter backend");
+        process.exit(1);
+    }
+    if (all && subcommand !== "run" && subcommand !== "check") {
+        console.error(`--all only applies to run and check${subcommand ? `, not ${subcommand}` : ""}`);
+        process.exit(1);
+    }
+    if (alsoBuild && subcommand !== undefined && subcommand !== "run") {
+        console.error(`--build only applies to run, not ${subcommand}`);
+        process.exit(1);
+    }
+    if (runBinary && (subcommand !== "build" || target !== "c")) {
+        console.error("--run only applies to build with the c target");
+        process.exit(1);
+    }
+    if (all && backend === "vm") {
+        console.error("--all needs the interpreter backend");
+        process.exit(1);
+    }
+    if (explain !== undefined && subcommand !== undefined && subcommand !== "run") {
+        console.error(`--explain-eval only applies to run, not ${subcommand}`);
+        process.exit(1);
+    }
+    if (subcommand === "fmt") {
+        formatFiles(files, { statementMap, check: fmtCheck, write: fmtWrite, aliases });
+        return;
+    }
+    if (subcommand === "diff") {
+        diffFiles(files, aliases);
+        return;
+    }
+    if (subcommand === "fix") {
+        fixFiles(files, { migrate, quiet, aliases });
+        return;
+    }
+    if (subcommand === "ast") {
+        exportAstFile(files, { astExport, output, aliases });
+        return;
+    }
+    if (fromAst && (explain !== undefined || emitAst || (subcommand !== undefined && !["run", "check", "build"].includes(subcommand)))) {
+        console.error("--from-ast only applies to run, check and build");
+        process.exit(1);
+    }
+    if (subcommand && subcommand !== "serve" && subcommand !== "test" && files.length === 0 && explain === undefined) {
+        console.error(`strata ${subcommand} needs a file\n\n${USAGE}`);
+        process.exit(1);
+    }
+    const repl = !subcommand && (files[0] === "repl" || (files.length === 0 && explain === undefined));
+
+    const startTime = performance.now();
+
+    // Parse errors in a prelude are rendered against the prelude's source
+    let filePath = "<prelude>";
+    let source = PRELUDE_SOURCE;
+
+    try {
+        let prelude: Stmt[] = [];
+        if (usePrelude) {
+            prelude = new Parser(PRELUDE_SOURCE).parse();
+            if (preludeFile) {
+                filePath = preludeFile;
+                source = fs.readFileSync(preludeFile, "utf-8");
+                prelude = withPrelude(prelude, parseFile(source));
+            }
         }
-
-        if (packageName) {
-            // Install specific package
-            this.installPackage(packageName, packagesDir);
-        } else {
-            // Install all dependencies from Strataumfile
-            if (!this.strataumfile.dependencies) {
-                console.log("No dependencies to install.");
-                return;
+        if (repl) {
+            new Repl(prelude, true, aliases).start();
+            return;
+        }
+        if (subcommand === "replay") {
+            replayFile(files[0], prelude, aliases);
+            return;
+        }
+        if (subcommand === "serve") {
+            const colon = listen.lastIndexOf(":");
+            const host = colon < 0 ? "127.0.0.1" : listen.slice(0, colon);
+            const port = listen.slice(colon + 1);
+            if (!/^[0-9]+$/.test(port)) {
+                console.error(`--listen takes host:port, not ${listen}`);
+                process.exit(1);
             }
-            
-            for (const [pkg, version] of Object.entries(this.strataumfile.dependencies)) {
-                this.installPackage(pkg, packagesDir, version as string);
+            if (!Number.isInteger(workers) || workers < 1) {
+                console.error("--workers takes a positive number");
+                process.exit(1);
             }
+            serve({ host, port: Number(port), timeoutMs, memoryMb, workers, prelude });
+            if (!quiet) console.error(`Listening on http://${host}:${port} (POST /run, /check)`);
+            return;
+        }
+        if (all) {
+            const modules = { searchPath: [...searchPath, ...project.modulePath()], typecheck, keywordAliases: aliases };
+            batchFiles(files, subcommand === "run" ? "run" : "check", prelude, { ...modules, quiet, timeoutMs, strictMath, args: programArgs });
+            return;
+        }
+        if (subcommand === "test") {
+            const modules = { searchPath: [...searchPath, ...project.modulePath()], typecheck, keywordAliases: aliases };
+            testFiles(testSources(files.length > 0 ? files : ["."]), prelude, { ...modules, quiet, timeoutMs, strictMath, args: programArgs });
+            return;
+        }
+        if (subcommand === "minimize") {
+            filePath = files[0];
+            source = fs.readFileSync(filePath, "utf-8");
+            minimizeFile(filePath, prelude, { searchPath: [...searchPath, ...project.modulePath()], typecheck, keywordAliases: aliases, output, quiet, timeoutMs });
+            return;
         }
 
-        this.saveLockFile();
-        console.log("✓ Installation complete");
-    }
+        filePath = files[0] ?? "<explain>";
+        source = files[0] ? fs.readFileSync(filePath, "utf-8") : "";
+        // --explain-eval takes a line of the file, or an expression to
+        // evaluate after the program on a line of its own
+        let explainLine: number | undefined;
+        if (explain !== undefined && /^[0-9]+$/.test(explain)) {
+            explainLine = Number(explain);
+        } else if (explain !== undefined) {
+            explainLine = source.split("\n").length + 1;
+            source = `${source}\n${explain}`;
+        }
+        // An exported AST stands in for the source; its diagnostics have no
+        // source lines to show
+        const program = fromAst ? importAst(source) : parseFile(source);
+        if (fromAst) source = "";
+        if (emitAst) {
+            console.log(JSON.stringify(program, astReplacer, 2));
+            return;
+        }
+        const loader = new ModuleLoader({ searchPath: [...searchPath, ...project.modulePath()], prelude, typecheck, keywordAliases: aliases });
+        const linked = loader.link(program, filePath);
+        if (reportFile !== undefined) fs.writeFileSync(reportFile, JSON.stringify(featureReport(linked, filePath), null, 2) + "\n");
+        const statements = withPrelude(prelude, linked);
+
+        let typed: TypedProgram | undefined;
+        if (typecheck) {
+            const typeChecker = new TypeChecker();
+            typed = typeChecker.checkProgram(statements);
+            if (!quiet) {
+                for (const warning of typeChecker.warnings) {
+                    console.error(renderIn(warning, source, filePath));
+                }
+            }
+        } else {
+            new TypeChecker().lower(statements);
+        }
+        if (subcommand === "check") return;
+
+        if (subcommand !== "build") {
+            const maxCallDepth = process.env.STRATA_MAX_CALL_DEPTH
+                ? Number(process.env.STRATA_MAX_CALL_DEPTH)
+                : undefined;
+            if (backend === "vm") {
+                new VM({ maxCallDepth, args: programArgs, strictMath }).run(new BytecodeCompiler().compile(statements));
+            } else {
+                const options: InterpreterOptions = {
+                    inlineCaches: process.env.STRATA_INLINE_CACHE !== "0",
+                    maxCallDepth,
+                    explain: explainLine !== undefined ? explainableAt(program, explainLine) : undefined,
+                    args: programArgs,
+                    file: filePath,
+                    strictMath,
+                };
+                const interpreter = typed ? Interpreter.fromTyped(typed, options) : new Interpreter(options);
+                interpreter.interpret(statements);
+            }
+        }
 
-    private installPackage(packageName: string, packagesDir: string, version?: string): void {
-        const pkgDir = `${packagesDir}/${packageName}`;
-        
-        // Create package directory
-        if (!fs.existsSync(pkgDir)) {
-            fs.mkdirSync(pkgDir, { recursive: true });
+        // The C output is the same for --run and build
+        const cOptions: CGeneratorOptions = { lineFile: filePath, prelude };
+        if (runBinary) {
+            const code = new CGenerator(cOptions).generate(statements);
+            process.exit(compileAndRun(code, { cc, args: programArgs }));
+        }
+        // run (and a bare strata <file>) only interprets unless --build
+        if (subcommand === "build" || alsoBuild) {
+            if (target === "wasm") {
+                writeGenerated(output ?? "out.wat", new WasmGenerator().generate(statements), force);
+            } else if (target === "js") {
+                writeGenerated(output ?? "out.js", new JsGenerator().generate(statements), force);
+            } else {
+                writeGenerated(output ?? "out.c", new CGenerator(cOptions).generate(statements), force);
+            }
         }
 
-        // Create a mock package with module definition
-        const moduleContent = `
-// ${packageName} module (v${version || "1.0.0"})
-export func init() => void {
-    io.print("${packageName} loaded")
+        const endTime = performance.now();
+        const elapsed = (endTime - startTime).toFixed(2);
+        if (!quiet) console.error(`${subcommand === "build" ? "Compiled" : "Executed"} in ${elapsed}ms`);
+    } catch (error) {
+        exitWithError(error, source, filePath);
+    }
 }
-`;
-        fs.writeFileSync(`${pkgDir}/index.str`, moduleContent);
-        
-        // Create package.json in package directory
-        const pkgInfo = {
-            name: packageName,
-            version: version || "1.0.0",
-            main: "index.str",
-        };
-        fs.writeFileSync(`${pkgDir}/package.json`, JSON.stringify(pkgInfo, null, 2));
-
-        // Update lock file
-        if (!this.lockFile.packages) this.lockFile.packages = {};
-        this.lockFile.packages[packageName] = {
-            version: version || "1.0.0",
-            installed: true,
-            timestamp: new Date().toISOString(),
-        };
 
-        console.log(`✓ Installed ${packageName}@${version || "1.0.0"}`);
+// Like diff(1), exits with 1 when the programs differ
+function diffFiles(files: string[], aliases: KeywordAliases): void {
+    if (files.length !== 2) {
+        console.error(`strata diff needs two files\n\n${USAGE}`);
+        process.exit(1);
     }
-
-    add(packageName: string, version: string = "latest"): void {
-        if (!this.strataumfile.dependencies) {
-            this.strataumfile.dependencies = {};
+    const sources = files.map((file) => fs.readFileSync(file, "utf-8"));
+    sources.forEach((source, i) => {
+        try {
+            new Parser(source, 1, undefined, undefined, aliases).parse();
+        } catch (error) {
+            exitWithError(error, source, files[i]);
         }
+    });
+    const changes = diffPrograms(sources[0], sources[1], aliases);
+    if (changes.length === 0) return;
+    console.log(renderChanges(changes, files[0], files[1]));
+    process.exit(1);
+}
 
-        this.strataumfile.dependencies[packageName] = version;
-        this.saveStrataumfile();
-        
-        // Auto-install
-        const packagesDir = `${this.projectRoot}/.strata/packages`;
-        if (!fs.existsSync(packagesDir)) {
-            fs.mkdirSync(packagesDir, { recursive: true });
-        }
-        this.installPackage(packageName, packagesDir, version);
-        this.saveLockFile();
-        
-        console.log(`✓ Added ${packageName}@${version}`);
+// Generated code goes to stdout for "-". A file is only replaced if an
+// earlier build wrote it (its first line is the artifact header), unless
+// --force, so -o main.c can't clobber a hand-written file
+function writeGenerated(file: string, code: string, force: boolean): void {
+    if (file === "-") {
+        process.stdout.write(code.endsWith("\n") ? code : `${code}\n`);
+        return;
     }
-
-    remove(packageName: string): void {
-        if (this.strataumfile.dependencies && packageName in this.strataumfile.dependencies) {
-            delete this.strataumfile.dependencies[packageName];
-            this.saveStrataumfile();
-            
-            // Remove from filesystem
-            const pkgDir = `${this.projectRoot}/.strata/packages/${packageName}`;
-            if (fs.existsSync(pkgDir)) {
-                fs.rmSync(pkgDir, { recursive: true });
-            }
-            
-            // Update lock file
-            if (this.lockFile.packages && packageName in this.lockFile.packages) {
-                delete this.lockFile.packages[packageName];
-                this.saveLockFile();
-            }
-            
-            console.log(`✓ Removed ${packageName}`);
-        } else {
-            console.error(`Package ${packageName} not found in dependencies`);
+    if (!force && fs.existsSync(file)) {
+        const first = fs.readFileSync(file, "utf-8").split("\n", 1)[0];
+        if (!/^\S+ Generated by Strata /.test(first)) {
+            console.error(`${file} exists and was not generated by Strata; use --force to overwrite it`);
             process.exit(1);
         }
     }
+    fs.writeFileSync(file, code);
+}
 
-    list(): void {
-        console.log("\nInstalled Packages:");
-        console.log("==================");
-        
-        if (!this.strataumfile.dependencies || Object.keys(this.strataumfile.dependencies).length === 0) {
-            console.log("No packages installed");
-            return;
+// strata build --run: the C goes to a temporary directory, is compiled
+// with --cc (or $CC, or cc; it may carry flags, "gcc -O2") and the binary
+// runs with the arguments after --. The #line directives in the C make the
+// compiler's messages name Strata lines. Returns the exit status
+function compileAndRun(code: string, options: { cc: string; args: string[] }): number {
+    const [compiler, ...flags] = options.cc.trim().split(/\s+/);
+    const dir = fs.mkdtempSync(path.join(os.tmpdir(), "strata-"));
+    try {
+        const source = path.join(dir, "out.c");
+        const binary = path.join(dir, process.platform === "win32" ? "out.exe" : "out");
+        fs.writeFileSync(source, code);
+        const compiled = spawnSync(compiler, [...flags, source, "-o", binary, "-lm"], { stdio: "inherit" });
+        if (compiled.error) {
+            console.error(`Cannot run the C compiler ${compiler}: ${compiled.error.message}; use --cc or CC to name another`);
+            return 1;
         }
-
-        for (const [pkg, version] of Object.entries(this.strataumfile.dependencies)) {
-            const status = this.lockFile.packages?.[pkg]?.installed ? "✓" : "✗";
-            console.log(`${status} ${pkg}@${version}`);
+        if (compiled.status !== 0) return compiled.status ?? 1;
+        const run = spawnSync(binary, options.args, { stdio: "inherit" });
+        if (run.error) {
+            console.error(`Cannot run the compiled program: ${run.error.message}`);
+            return 1;
         }
+        return run.status ?? 1;
+    } finally {
+        fs.rmSync(dir, { recursive: true, force: true });
     }
+}
 
-    init(name: string, version: string = "0.0.1"): void {
-        const strataumfile: StrataumfileConfig = {
-            name,
-            version,
-            registry: "https://registry.stratauim.io",
-            dependencies: {},
-        };
-
-        fs.writeFileSync(
-            `${this.projectRoot}/Strataumfile`,
-            JSON.stringify(strataumfile, null, 2)
-        );
-
-        const lockFile = {
-            locked: true,
-            version: "1.0",
-            timestamp: new Date().toISOString(),
-            packages: {},
-        };
-
-        fs.writeFileSync(
-            `${this.projectRoot}/Strataumfile.lock`,
-            JSON.stringify(lockFile, null, 2)
-        );
-
-        console.log(`✓ Initialized Strata project: ${name}`);
+// strata ast --export: the interchange JSON of one file, to -o or stdout
+function exportAstFile(files: string[], options: { astExport: boolean; output?: string; aliases: KeywordAliases }): void {
+    if (!options.astExport || files.length !== 1) {
+        console.error(`strata ast needs --export and one file\n\n${USAGE}`);
+        process.exit(1);
     }
-
-    info(): void {
-        console.log("\nProject Information:");
-        console.log("====================");
-        console.log(`Name: ${this.strataumfile.name}`);
-        console.log(`Version: ${this.strataumfile.version}`);
-        console.log(`Registry: ${this.strataumfile.registry || "default"}`);
-        console.log(`Dependencies: ${Object.keys(this.strataumfile.dependencies || {}).length}`);
+    const source = fs.readFileSync(files[0], "utf-8");
+    let program: Stmt[];
+    try {
+        program = new Parser(source, 1, undefined, undefined, options.aliases).parse();
+    } catch (error) {
+        exitWithError(error, source, files[0]);
     }
+    const json = renderAst(program) + "\n";
+    if (options.output) fs.writeFileSync(options.output, json);
+    else process.stdout.write(json);
 }
 
-// ============================================================================
-// MAIN
-// ============================================================================
+// Rewrites each file in place, listing the calls it renamed
+function fixFiles(files: string[], options: { migrate: boolean; quiet: boolean; aliases: KeywordAliases }): void {
+    if (!options.migrate || files.length === 0) {
+        console.error(`strata fix needs --migrate and the files to rewrite\n\n${USAGE}`);
+        process.exit(1);
+    }
+    for (const file of files) {
+        const source = fs.readFileSync(file, "utf-8");
+        let result: { source: string; migrations: Migration[] };
+        try {
+            result = migrateSource(source, options.aliases);
+        } catch (error) {
+            exitWithError(error, source, file);
+        }
+        if (result.migrations.length > 0) fs.writeFileSync(file, result.source);
+        if (options.quiet) continue;
+        for (const m of result.migrations) console.log(`${file}:${m.line}: ${m.from} -> ${m.to}`);
+    }
+}
 
-const args = process.argv.slice(2);
-
-// Handle package manager commands
-if (args.length > 0) {
-    const command = args[0];
-    const pm = new PackageManager();
-
-    switch (command) {
-        case "init":
-            const projectName = args[1] || "my-strata-project";
-            const version = args[2] || "0.0.1";
-            pm.init(projectName, version);
-            process.exit(0);
-        case "install":
-            pm.install(args[1]);
-            process.exit(0);
-        case "add":
-            if (!args[1]) {
-                console.error("Usage: strataum add <package> [version]");
-                process.exit(1);
+// run --all and check --all: each file gets its own loader, checker and
+// interpreter, so nothing one file defines reaches the next and a file that
+// fails doesn't stop the rest. A file fails when it doesn't parse or check
+// and is an error when it can't be read or stops with a runtime error or
+// --timeout. Exits with 1 unless every file passes
+function batchFiles(
+    files: string[],
+    mode: "run" | "check",
+    prelude: Stmt[],
+    options: ModuleOptions & { quiet: boolean; timeoutMs: number; strictMath: boolean; args: string[] },
+): void {
+    const counts = { pass: 0, fail: 0, error: 0 };
+    const started = performance.now();
+    for (const file of files) {
+        const fileStarted = performance.now();
+        let outcome: "pass" | "fail" | "error" = "error";
+        let detail: string | undefined;
+        let source = "";
+        try {
+            source = fs.readFileSync(file, "utf-8");
+            outcome = "fail";
+            const loader = new ModuleLoader({ ...options, prelude });
+            const statements = withPrelude(prelude, loader.link(new Parser(source, 1, undefined, undefined, options.keywordAliases).parse(), file));
+            let typed: TypedProgram | undefined;
+            if (options.typecheck !== false) {
+                const checker = new TypeChecker();
+                try {
+                    typed = checker.checkProgram(statements);
+                } finally {
+                    if (!options.quiet) for (const warning of checker.warnings) console.error(renderIn(warning, source, file));
+                }
+            } else {
+                new TypeChecker().lower(statements);
             }
-            pm.add(args[1], args[2] || "latest");
-            process.exit(0);
-        case "remove":
-            if (!args[1]) {
-                console.error("Usage: strataum remove <package>");
-                process.exit(1);
+            outcome = "error";
+            if (mode === "run") {
+                const interpreterOptions: InterpreterOptions = { args: options.args, file, strictMath: options.strictMath };
+                const interpreter = typed ? Interpreter.fromTyped(typed, interpreterOptions) : new Interpreter(interpreterOptions);
+                interpreter.runWithCancel(statements, new CancellationToken({ timeoutMs: options.timeoutMs }));
             }
-            pm.remove(args[1]);
-            process.exit(0);
-        case "list":
-            pm.list();
-            process.exit(0);
-        case "info":
-            pm.info();
-            process.exit(0);
+            outcome = "pass";
+        } catch (error) {
+            const diagnostic = asStrataError(error, "E0004").diagnostic;
+            const line = diagnostic.span ? `:${diagnostic.span.line}` : "";
+            detail = `${diagnostic.file ?? file}${line}: ${diagnostic.code} ${diagnostic.message}`;
+        }
+        counts[outcome]++;
+        console.log(`${outcome.toUpperCase().padEnd(5)} ${file} (${(performance.now() - fileStarted).toFixed(2)}ms)`);
+        if (detail !== undefined) console.log(`      ${detail}`);
     }
+    const elapsed = (performance.now() - started).toFixed(2);
+    console.log(`\n${files.length} file${files.length === 1 ? "" : "s"}: ${counts.pass} passed, ${counts.fail} failed, ${counts.error} errors in ${elapsed}ms`);
+    if (counts.pass !== files.length) process.exit(1);
 }
 
-// Default: run Strata file
-if (args.length === 0) {
-    console.error("Usage: strata <file.str> or strataum <command>");
-    process.exit(1);
+// The .str files named or under the directories named, in name order;
+// node_modules and dot directories are left out
+function testSources(paths: string[]): string[] {
+    const found: string[] = [];
+    const visit = (entry: string, named: boolean) => {
+        if (!fs.statSync(entry).isDirectory()) {
+            if (named || entry.endsWith(".str")) found.push(entry);
+            return;
+        }
+        for (const name of fs.readdirSync(entry).sort()) {
+            if (name === "node_modules" || name.startsWith(".")) continue;
+            visit(path.join(entry, name), false);
+        }
+    };
+    for (const entry of paths) visit(entry, true);
+    return found;
 }
 
-const startTime = performance.now();
-
-const filePath = args[0];
-const source = fs.readFileSync(filePath, "utf-8");
-
-try {
-    const parser = new Parser(source);
-    const statements = parser.parse();
-
-    const typeChecker = new TypeChecker();
-    typeChecker.check(statements);
+// strata test: each file with test blocks is linked, checked and run on its
+// own as for run --all, its top-level code first as the setup, then every
+// test block. A file without tests is skipped; one that doesn't parse or
+// check, or whose setup fails, is an error and its tests don't run.
+// --timeout applies to each file. Exits with 1 unless every test passes
+function testFiles(
+    files: string[],
+    prelude: Stmt[],
+    options: ModuleOptions & { quiet: boolean; timeoutMs: number; strictMath: boolean; args: string[] },
+): void {
+    const counts = { pass: 0, fail: 0, error: 0 };
+    const started = performance.now();
+    const describe = (error: unknown, file: string) => {
+        const diagnostic = asStrataError(error, "E0004").diagnostic;
+        const line = diagnostic.span ? `:${diagnostic.span.line}` : "";
+        const column = diagnostic.span?.column !== undefined ? `:${diagnostic.span.column}` : "";
+        return `${diagnostic.file ?? file}${line}${column}: ${diagnostic.code} ${diagnostic.message}`;
+    };
+    let tested = 0;
+    for (const file of files) {
+        const fileStarted = performance.now();
+        let results: TestResult[];
+        try {
+            const source = fs.readFileSync(file, "utf-8");
+            // Found without parsing, so a file with tests that doesn't parse is still an error
+            const opensTest = (token: HighlightToken) => token.category === "keyword" && source.slice(token.span.start, token.span.end) === "test";
+            if (!tokenizeForHighlighting(source).some(opensTest)) continue;
+            tested++;
+            const loader = new ModuleLoader({ ...options, prelude });
+            const statements = withPrelude(prelude, loader.link(new Parser(source, 1, undefined, undefined, options.keywordAliases).parse(), file));
+            let typed: TypedProgram | undefined;
+            if (options.typecheck !== false) {
+                const checker = new TypeChecker();
+                try {
+                    typed = checker.checkProgram(statements);
+                } finally {
+                    if (!options.quiet) for (const warning of checker.warnings) console.error(renderIn(warning, source, file));
+                }
+            } else {
+                new TypeChecker().lower(statements);
+            }
+            const interpreterOptions: InterpreterOptions = { args: options.args, file, strictMath: options.strictMath };
+            const interpreter = typed ? Interpreter.fromTyped(typed, interpreterOptions) : new Interpreter(interpreterOptions);
+            results = interpreter.runTests(statements, new CancellationToken({ timeoutMs: options.timeoutMs }));
+        } catch (error) {
+            counts.error++;
+            console.log(`ERROR ${file} (${(performance.now() - fileStarted).toFixed(2)}ms)`);
+            console.log(`      ${describe(error, file)}`);
+            continue;
+        }
+        for (const result of results) {
+            counts[result.error ? "fail" : "pass"]++;
+            console.log(`${result.error ? "FAIL " : "PASS "} ${file}:${result.line ?? 0} ${result.name} (${result.ms.toFixed(2)}ms)`);
+            if (result.error) console.log(`      ${describe(result.error, file)}`);
+        }
+    }
+    const elapsed = (performance.now() - started).toFixed(2);
+    const tests = counts.pass + counts.fail;
+    console.log(
+        `\n${tests} test${tests === 1 ? "" : "s"} in ${tested} file${tested === 1 ? "" : "s"}: ` +
+        `${counts.pass} passed, ${counts.fail} failed, ${counts.error} errors in ${elapsed}ms`
+    );
+    if (counts.fail > 0 || counts.error > 0) process.exit(1);
+}
 
-    const interpreter = new Interpreter();
-    interpreter.interpret(statements);
+// Rewrites each file that isn't formatted yet; without files, formats
+// stdin to stdout. Stops at the first file that doesn't parse, unchanged.
+// --statement-map writes notes.str.statements.json next to notes.str
+// --check writes nothing and lists the files that aren't formatted, exiting
+// 1 if there are any, for CI; --write is the default for files, spelled out
+function formatFiles(files: string[], options: { statementMap: boolean; check: boolean; write: boolean; aliases: KeywordAliases }): void {
+    if (options.statementMap && files.length === 0) {
+        console.error("--statement-map needs files to write the maps next to");
+        process.exit(1);
+    }
+    if (options.check && (options.write || options.statementMap)) {
+        console.error("fmt --check writes no files, so it doesn't go with --write or --statement-map");
+        process.exit(1);
+    }
+    if (options.write && files.length === 0) {
+        console.error("fmt --write needs files to rewrite");
+        process.exit(1);
+    }
+    const unformatted: string[] = [];
+    for (const file of files.length > 0 ? files : ["<stdin>"]) {
+        const source = fs.readFileSync(files.length > 0 ? file : 0, "utf-8");
+        try {
+            new Parser(source, 1, undefined, undefined, options.aliases).parse();
+        } catch (error) {
+            exitWithError(error, source, file);
+        }
+        const formatted = canonicalSource(source);
+        if (options.check) {
+            if (formatted !== source) unformatted.push(file);
+            continue;
+        }
+        if (files.length === 0) process.stdout.write(formatted);
+        else if (formatted !== source) fs.writeFileSync(file, formatted);
+        if (options.statementMap) {
+            const map = { file: path.basename(file), statements: statementMap(formatted) };
+            fs.writeFileSync(`${file}.statements.json`, JSON.stringify(map, null, 2) + "\n");
+        }
+    }
+    if (unformatted.length > 0) {
+        for (const file of unformatted) console.log(`would reformat ${file}`);
+        process.exit(1);
+    }
+}
 
-    const generator = new CGenerator();
-    const cCode = generator.generate(statements);
-    fs.writeFileSync("out.c", cCode);
+// Only run the CLI when executed directly, not when imported by a host
+function isMainModule(): boolean {
+    if (!process.argv[1]) return false;
+    try {
+        return import.meta.url === pathToFileURL(fs.realpathSync(process.argv[1])).href;
+    } catch {
+        return false;
+    }
+}
 
-    const endTime = performance.now();
-    const elapsed = (endTime - startTime).toFixed(2);
-    console.error(`Executed in ${elapsed}ms`);
-} catch (error) {
-    console.error(
-        "Error:",
-        error instanceof Error ? error.message : String(error)
-    );
-    process.exit(1);
+// Workers started by strata serve load this file too, with their job
+if (isMainThread && isMainModule()) {
+    main(process.argv.slice(2));
+} else if (!isMainThread && workerData?.serveJob) {
+    parentPort?.postMessage(runServeJob(workerData.serveJob));
 }
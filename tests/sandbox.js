@@ -0,0 +1,43 @@
+// Sandboxed runs, as strata serve makes them, must not reach the host's
+// files through the stdlib modules or the global file builtins, nor the
+// host's Function through reflection or prototype fields
+//
+//   npm run build && node tests/sandbox.js
+
+import assert from "node:assert/strict";
+import { execute } from "../dist/index.js";
+
+const blocked = [
+    ['readFile("/etc/passwd")', "readFile"],
+    ['writeFile("sandbox-escape.txt", "x")', "writeFile"],
+    ['exists("/etc/passwd")', "exists"],
+    ['import file from std::file\nfile.read("/etc/passwd")', "file.read"],
+    ['getProperty(getProperty("", "constructor"), "constructor")("return process")()', "getProperty"],
+    ['getPrototype("")', "getPrototype"],
+    ['setProperty({"a": 1}, "a", 2)', "setProperty"],
+    ['assign({"a": 1}, {"b": 2})', "assign"],
+    ['try(() => 1)', "try"],
+];
+
+for (const [source, fn] of blocked) {
+    const report = execute(source, { sandbox: true });
+    assert.equal(report.ok, false, `${fn} ran in the sandbox`);
+    assert.match(report.diagnostics[0].message, new RegExp(`^${fn.replace(".", "\\.")} is not available in the sandbox`));
+}
+
+// Fields a value only inherits from the host prototype aren't there
+const inherited = [
+    'let f: any = get(get("", "constructor"), "constructor")\nf("return process")()',
+    'struct P { x: int }\nlet p: P = P { x: 1 }\nlet f: any = p.constructor\nf("return process")()',
+];
+
+for (const source of inherited) {
+    const report = execute(source, { sandbox: true });
+    assert.equal(report.ok, false, `reached the host's Function: ${source}`);
+    assert.doesNotMatch(report.stdout + report.stderr, /process/);
+}
+
+// Outside the sandbox the builtins still work
+assert.equal(execute('exists("/")').value, "true");
+
+console.log(`sandbox: ${blocked.length + inherited.length + 1} checks passed`);
@@ -0,0 +1,27 @@
+// Stdlib functions given input they can't handle fail with a catalog
+// message, not an error from the JavaScript that implements them
+//
+//   npm run build && node tests/stdlib.js
+
+import assert from "node:assert/strict";
+import { execute } from "../dist/index.js";
+
+const failing = [
+    ["import collections from std::collections\ncollections.min([])", "collections.min expects a non-empty array, got []"],
+    ["import collections from std::collections\ncollections.max([])", "collections.max expects a non-empty array, got []"],
+    ["import text from str\ntext.charCodeAt(\"ab\", 5)", "Index 5 out of bounds for length 2"],
+    ["import text from str\ntext.fromBytes([104, 300])", "text.fromBytes expects bytes (int[] with values 0..255), got [104, 300]"],
+    ["import text from str\ntext.fromBytes([255])", "text.fromBytes: bytes are not valid UTF-8"],
+    ["import encode from std::encode\nencode.fromHex(\"zz\")", "encode.fromHex: invalid hex input"],
+    ["import csv from std::csv\ncsv.parseRecords(\"a,b\\n\\\"x\\ny\\\",1\\n2\")", "csv.parseRecords: the record on line 4 has 1 fields, the header has 2"],
+    ["import csv from std::csv\ncsv.stringify([{\"a\": 1}, [2]])", "csv.stringify: row [2] is not a map, as the first row is"],
+    ["datetime.date(2024, 1, 1).addDays(2000000000)", "Datetime out of range: datetimes reach only about 275,000 years either side of 1970"],
+];
+
+for (const [source, expected] of failing) {
+    const report = execute(source);
+    assert.equal(report.ok, false, `${source} ran`);
+    assert.equal(report.diagnostics[0].message, expected);
+}
+
+console.log(`stdlib: ${failing.length} checks passed`);